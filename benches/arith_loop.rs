@@ -0,0 +1,35 @@
+//! Plain timing of the interpreter loop, run with `cargo bench`.
+//!
+//! The workload is a counted loop doing a little stack arithmetic per iteration, the shape of
+//! most hot loops in vyantra programs.
+
+use std::time::Instant;
+
+use vyantra::{Inst, Machine, Path, Reg, Word};
+
+const ITERATIONS: Word = 10_000_000;
+
+fn main() {
+    let program = vec![
+        Inst::SET(Reg::D, ITERATIONS),
+        Inst::PSH(0),
+        Inst::PSH(3),
+        Inst::ADD,
+        Inst::PSH(2),
+        Inst::MUL,
+        Inst::CPY(Path::REG(Reg::A), Path::STK(0)),
+        Inst::LOOP(Reg::D, -5),
+        Inst::HLT,
+    ];
+    let mut machine = Machine::new(program);
+
+    let start = Instant::now();
+    machine.resume().unwrap();
+    let elapsed = start.elapsed();
+
+    let instructions = machine.instructions();
+    println!(
+        "arith_loop: {instructions} instructions in {elapsed:.2?}, {:.2} ns/instruction",
+        elapsed.as_nanos() as f64 / instructions as f64
+    );
+}
@@ -0,0 +1,123 @@
+//! Timing of the run loops, run with `cargo bench`.
+//!
+//! A machine with no fuel, tick callback, observer or types runs in the plain dispatch loop, over
+//! its program decoded into compact ops. The
+//! same programs are timed again with fuel to spare, which takes the loop that checks every
+//! instruction, to show what the plain loop saves on loop heavy programs, with
+//! superinstructions, `MachineConfig::fuse`, and decoded from the byte format as they run,
+//! `Machine::from_bytecode`. The `big` program has a 200 000 instruction body, far more than
+//! fits the cache decoded, and decoding from bytes is still several times slower than running
+//! decoded instructions on it.
+
+use std::time::{Duration, Instant};
+
+use vyantra::{Inst, Machine, MachineConfig, Path, Reg, Word};
+
+const ITERATIONS: Word = 2_000_000;
+
+/// A counted loop of stack arithmetic
+fn arith() -> Vec<Inst> {
+    vec![
+        Inst::SET(Reg::D, ITERATIONS),
+        Inst::PSH(0),
+        Inst::PSH(3),
+        Inst::ADD,
+        Inst::PSH(2),
+        Inst::MUL,
+        Inst::CPY(Path::REG(Reg::A), Path::STK(0)),
+        Inst::LOOP(Reg::D, -5),
+        Inst::HLT,
+    ]
+}
+
+/// An inner loop of 100 iterations run `ITERATIONS / 100` times, moving values between
+/// registers and the stack
+fn nested() -> Vec<Inst> {
+    vec![
+        Inst::SET(Reg::C, ITERATIONS / 100),
+        Inst::SET(Reg::D, 100),
+        Inst::INC(Reg::A),
+        Inst::CPY(Path::REG(Reg::B), Path::REG(Reg::A)),
+        Inst::LOOP(Reg::D, -2),
+        Inst::LOOP(Reg::C, -4),
+        Inst::HLT,
+    ]
+}
+
+/// A call and a return for every iteration, the callee comparing and branching
+fn calls() -> Vec<Inst> {
+    vec![
+        Inst::SET(Reg::D, ITERATIONS),
+        Inst::CALL(3),
+        Inst::LOOP(Reg::D, -1),
+        Inst::HLT,
+        Inst::PSH(1),
+        Inst::JNZ(2),
+        Inst::INC(Reg::A),
+        Inst::RET,
+    ]
+}
+
+/// Instructions in the loop body of `big`
+const BIG_BODY: usize = 200_000;
+
+/// A loop over a body of `BIG_BODY` instructions, a program far bigger than the cache
+fn big() -> Vec<Inst> {
+    let mut program = vec![
+        Inst::SET(Reg::D, ITERATIONS / BIG_BODY as Word * 2),
+        Inst::PSH(0),
+    ];
+    for n in 0..BIG_BODY {
+        program.push(match n % 4 {
+            0 => Inst::PSH(n as Word),
+            1 => Inst::ADD,
+            2 => Inst::CPY(Path::REG(Reg::A), Path::STK(0)),
+            _ => Inst::INC(Reg::B),
+        });
+    }
+    program.extend([Inst::LOOP(Reg::D, -(BIG_BODY as isize)), Inst::HLT]);
+    program
+}
+
+/// Time to run `program` to its halt, and the instructions it took
+fn time(program: &[Inst], fuel: Option<u64>, fuse: bool) -> (Duration, u64) {
+    let config = MachineConfig {
+        fuse,
+        ..MachineConfig::default()
+    };
+    let mut machine = Machine::new_with_config(program.to_vec(), config).unwrap();
+    machine.set_fuel(fuel);
+    let start = Instant::now();
+    machine.resume().unwrap();
+    (start.elapsed(), machine.instructions())
+}
+
+fn main() {
+    let programs = [
+        ("arith", arith()),
+        ("nested", nested()),
+        ("calls", calls()),
+        ("big", big()),
+    ];
+    for (name, program) in programs {
+        let (plain, instructions) = time(&program, None, false);
+        let (checked, _) = time(&program, Some(u64::MAX), false);
+        let (fused, _) = time(&program, None, true);
+        let mut machine = Machine::from_bytecode(&vyantra::encode_program(&program)).unwrap();
+        let start = Instant::now();
+        machine.resume().unwrap();
+        let bytecode = start.elapsed();
+        let per = |elapsed: Duration| elapsed.as_nanos() as f64 / instructions as f64;
+        println!(
+            "dispatch {name}: {instructions} instructions, ns/instruction {:.2} plain, \
+             {:.2} checked ({:.2}x slower), {:.2} fused ({:.2}x faster), {:.2} bytecode ({:.2}x slower)",
+            per(plain),
+            per(checked),
+            checked.as_secs_f64() / plain.as_secs_f64(),
+            per(fused),
+            plain.as_secs_f64() / fused.as_secs_f64(),
+            per(bytecode),
+            bytecode.as_secs_f64() / plain.as_secs_f64()
+        );
+    }
+}
@@ -0,0 +1,25 @@
+; The program src/main.rs used to run, try it with
+;   cargo run -- run examples/demo.s -v
+psh 5
+psh 6
+pop
+psh 21
+add
+pop
+set a 12
+psh 0
+setp stk[0] 144
+psh 0
+cpy stk[0] reg.a
+div
+pop
+set d 3
+repeat:
+psh 1
+pop
+loop d repeat
+psh 69
+cpy reg.c stk[0]
+psh 0
+cpy stk[0] reg.c
+hlt
@@ -0,0 +1,31 @@
+use std::sync::Arc;
+use std::thread;
+
+use vyantra::*;
+
+/// Run the same program on several machines at once, one machine per thread, and add up what
+/// they leave on top of their stacks.
+fn main() {
+    let program: Arc<[Inst]> = Arc::from(vec![
+        Inst::SET(Reg::A, 3),
+        Inst::PSH(0),
+        Inst::CPY(Path::STK(0), Path::REG(Reg::A)),
+        Inst::PSH(14),
+        Inst::MUL,
+        Inst::HLT,
+    ]);
+
+    let handles: Vec<_> = (0..4)
+        .map(|_| {
+            let program = Arc::clone(&program);
+            thread::spawn(move || {
+                let mut machine = Machine::new(program);
+                machine.run().unwrap();
+                machine.stack_top().unwrap_or(0)
+            })
+        })
+        .collect();
+
+    let total: Word = handles.into_iter().map(|h| h.join().unwrap()).sum();
+    println!("sum of results: {total}");
+}
@@ -0,0 +1,65 @@
+/*
+ * C interface to the vyantra virtual machine, see src/ffi.rs. Link against the shared library
+ * built with `cargo rustc --lib --release --features ffi --crate-type cdylib`, and define
+ * VYANTRA_WORD64 when it was built with the word64 feature too.
+ *
+ * Functions return VYANTRA_OK or another status that is not negative when they did what was
+ * asked, and a negative VYANTRA_ERR_ code when they did not, vyantra_last_error saying why.
+ * Registers are numbered 0 to 5 for A to F and 6 + n for Rn.
+ */
+
+#ifndef VYANTRA_H
+#define VYANTRA_H
+
+#include <stddef.h>
+#include <stdint.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+#ifdef VYANTRA_WORD64
+typedef int64_t vyantra_word;
+#else
+typedef int32_t vyantra_word;
+#endif
+
+#define VYANTRA_OK 0
+#define VYANTRA_RUNNING 1
+#define VYANTRA_YIELDED 2
+#define VYANTRA_STOPPED 3
+#define VYANTRA_ERR_NULL (-1)
+#define VYANTRA_ERR_PROGRAM (-2)
+#define VYANTRA_ERR_EXEC (-3)
+#define VYANTRA_ERR_REGISTER (-4)
+#define VYANTRA_ERR_INDEX (-5)
+#define VYANTRA_ERR_NOT_HALTED (-6)
+#define VYANTRA_ERR_PANIC (-7)
+
+typedef struct VyantraMachine VyantraMachine;
+
+/* A machine for a program file, as written by `vyantra asm`, or for assembly source */
+int vyantra_machine_new(const uint8_t *bytes, size_t len, VyantraMachine **out);
+int vyantra_machine_assemble(const char *source, VyantraMachine **out);
+void vyantra_machine_free(VyantraMachine *machine);
+
+/* VYANTRA_OK once halted, VYANTRA_YIELDED with the value in *yielded, or VYANTRA_STOPPED */
+int vyantra_machine_run(VyantraMachine *machine, vyantra_word *yielded);
+/* Like vyantra_machine_run for one instruction, VYANTRA_RUNNING if the program goes on */
+int vyantra_machine_step(VyantraMachine *machine, vyantra_word *yielded);
+
+int vyantra_machine_get_reg(VyantraMachine *machine, int reg, vyantra_word *out);
+int vyantra_machine_set_reg(VyantraMachine *machine, int reg, vyantra_word val);
+/* The stack, index 0 at the bottom */
+size_t vyantra_machine_stack_len(VyantraMachine *machine);
+int vyantra_machine_stack_get(VyantraMachine *machine, size_t index, vyantra_word *out);
+int vyantra_machine_exit_code(VyantraMachine *machine, int *out);
+
+/* Why the last failing call on this thread failed, NULL if none has */
+const char *vyantra_last_error(void);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif
@@ -0,0 +1,1441 @@
+//! Text assembler.
+//!
+//! A program is written one instruction per line, in the same syntax instructions display in:
+//! `psh 5`, `set a 12`, `setp stk[1] 40`, `cpy stk[c-1] reg.b`. Registers are `a` to `f` and
+//! `r0`, `r1`, ..., a path is `reg.<register>`, `stk[<offset>]` or `stk[<register>+<offset>]`.
+//! The operand of `fpsh` is a float, `fpsh 1.5` or `fpsh -2e-3`, and not a name. Anything after
+//! a `;` is a comment and blank lines are ignored.
+//!
+//! A line can start with a label, `name:`, which names the instruction on that line or, for a
+//! line with only the label, the next instruction. The target of `jmp` and `loop` can be a label
+//! instead of an offset, and the assembler puts in the offset from the jump to the label. Labels
+//! can be used above the line defining them, but only as jump targets.
+//!
+//! Directives:
+//!
+//! - `.const NAME value` names a value. A name can be used anywhere a number is expected, in
+//!   instructions as well as in other directives. Constants that fit a `Word` also go into the
+//!   constant pool, in the order they are defined, and `pshc NAME` pushes the pool entry of
+//!   `NAME`. `pshc` with a number takes the pool index itself.
+//! - `.data name: items` appends words to the data segment, which is loaded at address 0 of
+//!   data memory. `name` becomes the address of the first word. An item is a number or
+//!   `zero N` for `N` zero words, and the items can end with a string, `"text"`, one word for
+//!   each character followed by a 0.
+//!
+//! - `.trap "message"` adds a message to the program's trap table. `trap "message"` raises the
+//!   trap whose code is the message's index in the table, adding the message if it is not there
+//!   yet, and the error of a failed trap shows the message. A message is any text between double
+//!   quotes, without a double quote in it.
+//!
+//! - `.string "text"` adds a string to the program's string table. `prints "text"` prints the
+//!   string whose id is its index in the table, adding it if it is not there yet, and
+//!   `prints` with a number takes the id itself.
+//!
+//! - `.import name` adds a host function to the program's import table. `hcall name` calls the
+//!   function whose import index is its position in the table, adding it if it is not there yet.
+//!   `hcall` with a number takes the index itself.
+//!
+//! Directives are processed in order before any instruction, so a directive can only use names
+//! defined above it, while an instruction can use any name. A name can be given an offset,
+//! `squares+2` or `buf-1`, for the words of a `.data` table.
+
+use alloc::collections::BTreeMap;
+use alloc::format;
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+use core::fmt;
+
+use crate::error::VmError;
+#[cfg(feature = "std")]
+use crate::link::ProgramFragment;
+use crate::{Cond, Float, Inst, Machine, Path, Reg, Word};
+
+/// An assembled program: its instructions, constant pool, the initial contents of data memory,
+/// the messages of its traps, the strings it prints and the host functions it calls.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Program {
+    pub code: Vec<Inst>,
+    pub pool: Vec<Word>,
+    pub data: Vec<Word>,
+
+    /// Message of each trap code, by code
+    pub traps: Vec<String>,
+
+    /// Strings `PRINTS` writes, by id
+    pub strings: Vec<String>,
+
+    /// Name of each host function `HCALL` calls, by import index
+    pub imports: Vec<String>,
+}
+
+impl Program {
+    /// A machine for the program, with the constant pool and data segment loaded
+    pub fn machine(&self) -> Result<Machine, VmError> {
+        let mut machine = Machine::with_pool(self.code.clone(), self.pool.clone());
+        machine.load_data(&self.data)?;
+        machine.set_trap_messages(self.traps.clone());
+        machine.set_strings(self.strings.clone());
+        machine.set_imports(self.imports.clone());
+        Ok(machine)
+    }
+}
+
+/// What is wrong with a line of assembly.
+#[derive(Clone, Debug, PartialEq)]
+pub enum AsmErrorKind {
+    UnknownMnemonic(String),
+
+    UnknownDirective(String),
+
+    /// An instruction or directive got `found` operands instead of `expected`
+    Operands {
+        expected: usize,
+        found: usize,
+    },
+
+    /// An operand that is not a number, name, register or path where one was expected
+    BadOperand(String),
+
+    /// A value that does not fit the operand it was given for
+    OutOfRange(i128),
+
+    /// A name that was never defined, or is defined below the directive using it
+    Undefined(String),
+
+    /// A name that was already defined
+    Redefined(String),
+
+    /// `pshc` of a name that has no constant pool entry
+    NotInPool(String),
+
+    /// A label used as a value rather than a jump target
+    Label(String),
+
+    /// A string with no closing quote, or text after it
+    BadString,
+
+    /// More trap messages than a trap code can number
+    TooManyTraps,
+
+    /// More host functions than `hcall` can number
+    TooManyImports,
+
+    /// More strings than `prints` can number
+    TooManyStrings,
+}
+
+/// Error raised by [`assemble`] and [`assemble_program`], `line` counts from 1.
+#[derive(Clone, Debug, PartialEq)]
+pub struct AsmError {
+    pub line: usize,
+    pub kind: AsmErrorKind,
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for AsmError {}
+
+impl fmt::Display for AsmError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "line {}: ", self.line)?;
+        match &self.kind {
+            AsmErrorKind::UnknownMnemonic(name) => write!(f, "unknown instruction `{name}`"),
+
+            AsmErrorKind::UnknownDirective(name) => write!(f, "unknown directive `{name}`"),
+
+            AsmErrorKind::Operands { expected, found } => {
+                write!(f, "expected {expected} operands, found {found}")
+            }
+
+            AsmErrorKind::BadOperand(operand) => write!(f, "invalid operand `{operand}`"),
+
+            AsmErrorKind::OutOfRange(val) => write!(f, "value {val} is out of range"),
+
+            AsmErrorKind::Undefined(name) => write!(f, "undefined name `{name}`"),
+
+            AsmErrorKind::Redefined(name) => write!(f, "`{name}` is already defined"),
+
+            AsmErrorKind::NotInPool(name) => write!(f, "`{name}` is not in the constant pool"),
+
+            AsmErrorKind::Label(name) => write!(f, "label `{name}` is only a jump target"),
+
+            AsmErrorKind::BadString => write!(f, "malformed string"),
+
+            AsmErrorKind::TooManyTraps => write!(f, "too many trap messages"),
+
+            AsmErrorKind::TooManyImports => write!(f, "too many host functions"),
+
+            AsmErrorKind::TooManyStrings => write!(f, "too many strings"),
+        }
+    }
+}
+
+/// Assemble `source` into a program, ignoring its data segment
+pub fn assemble(source: &str) -> Result<Vec<Inst>, AsmError> {
+    assemble_program(source).map(|program| program.code)
+}
+
+/// Assemble `source` into a program together with its data segment
+pub fn assemble_program(source: &str) -> Result<Program, AsmError> {
+    assemble_with_lines(source).map(|(program, _)| program)
+}
+
+/// Like [`assemble_program`], also returning the source line, counting from 1, of each
+/// instruction, for debuggers
+pub fn assemble_with_lines(source: &str) -> Result<(Program, Vec<usize>), AsmError> {
+    let mut asm = Assembler::default();
+    let (code, lines) = asm.assemble(source)?;
+    Ok((asm.into_program(code), lines))
+}
+
+/// Assemble `source` into a fragment named `name` for [`link`](crate::link()). Every label is
+/// exported, and a jump to a label `source` does not define is left to the linker as a
+/// reference. Only the code is kept, like [`assemble`] has it.
+#[cfg(feature = "std")]
+pub fn assemble_fragment(name: &str, source: &str) -> Result<ProgramFragment, AsmError> {
+    let mut asm = Assembler {
+        references: Some(Vec::new()),
+        ..Assembler::default()
+    };
+    let (code, _) = asm.assemble(source)?;
+    let mut exports: Vec<(String, usize)> = asm
+        .labels
+        .iter()
+        .filter(|&(_, &ip)| ip < code.len())
+        .map(|(&label, &ip)| (label.to_string(), ip))
+        .collect();
+    exports.sort_by_key(|&(_, ip)| ip);
+    Ok(ProgramFragment {
+        name: name.to_string(),
+        code,
+        exports,
+        references: asm.references.unwrap_or_default(),
+    })
+}
+
+/// Index of `text` in `table`, adding it at the end if it is not there
+fn intern<'a>(table: &mut Vec<&'a str>, text: &'a str) -> usize {
+    match table.iter().position(|&entry| entry == text) {
+        Some(idx) => idx,
+        None => {
+            table.push(text);
+            table.len() - 1
+        }
+    }
+}
+
+/// Tokens of a line without its comment, and the string at the end of it if there is one
+fn split_line(line: &str) -> Result<(Vec<&str>, Option<&str>), AsmErrorKind> {
+    let (code, string) = match line.find(['"', ';']) {
+        Some(at) if line[at..].starts_with('"') => {
+            let rest = &line[at + 1..];
+            let end = rest.find('"').ok_or(AsmErrorKind::BadString)?;
+            let after = rest[end + 1..].trim_start();
+            if !(after.is_empty() || after.starts_with(';')) {
+                return Err(AsmErrorKind::BadString);
+            }
+            (&line[..at], Some(&rest[..end]))
+        }
+        Some(at) => (&line[..at], None),
+        None => (line, None),
+    };
+    Ok((code.split_whitespace().collect(), string))
+}
+
+/// Text of `code`, one instruction per line, that [`assemble`] reads back into the same
+/// instructions
+pub fn disassemble(code: &[Inst]) -> String {
+    code.iter().map(|inst| format!("{inst}\n")).collect()
+}
+
+/// Text for `program` that assembles back to the same program. The constant pool is written
+/// as constants named `K0`, `K1`, ... and every `pshc` shows the value it pushes.
+pub fn disassemble_program(program: &Program) -> String {
+    let mut text = String::new();
+    for (idx, val) in program.pool.iter().enumerate() {
+        text.push_str(&format!(".const K{idx} {val}\n"));
+    }
+    for message in &program.traps {
+        text.push_str(&format!(".trap \"{message}\"\n"));
+    }
+    for string in &program.strings {
+        text.push_str(&format!(".string \"{string}\"\n"));
+    }
+    for name in &program.imports {
+        text.push_str(&format!(".import {name}\n"));
+    }
+    if !program.data.is_empty() {
+        let words: Vec<String> = program.data.iter().map(Word::to_string).collect();
+        text.push_str(&format!(".data data: {}\n", words.join(" ")));
+    }
+    for inst in &program.code {
+        match inst {
+            Inst::PSHC(idx) => match program.pool.get(*idx as usize) {
+                Some(val) => text.push_str(&format!("{inst} ; = {val}\n")),
+                None => text.push_str(&format!("{inst} ; not in the pool\n")),
+            },
+            Inst::TRAP(code) => match program.traps.get(*code as usize) {
+                Some(message) => text.push_str(&format!("trap \"{message}\"\n")),
+                None => text.push_str(&format!("{inst}\n")),
+            },
+            Inst::PRINTS(id) => match program.strings.get(*id as usize) {
+                Some(string) => text.push_str(&format!("prints \"{string}\"\n")),
+                None => text.push_str(&format!("{inst}\n")),
+            },
+            Inst::HCALL(idx) => match program.imports.get(*idx as usize) {
+                Some(name) => text.push_str(&format!("hcall {name}\n")),
+                None => text.push_str(&format!("{inst}\n")),
+            },
+            inst => text.push_str(&format!("{inst}\n")),
+        }
+    }
+    text
+}
+
+#[derive(Default)]
+struct Assembler<'a> {
+    /// Constants and data labels
+    names: BTreeMap<&'a str, i128>,
+
+    /// Code labels, by the index of the instruction they name
+    labels: BTreeMap<&'a str, usize>,
+
+    data: Vec<Word>,
+
+    /// The constant pool, and the pool index of each constant in it
+    pool: Vec<Word>,
+    pooled: BTreeMap<&'a str, u16>,
+
+    /// Trap messages, by code
+    traps: Vec<&'a str>,
+
+    /// The string table, by id
+    strings: Vec<&'a str>,
+
+    /// Names of the host functions `hcall` calls, by import index
+    imports: Vec<String>,
+
+    /// For a fragment, the jumps to labels that are not defined, by the index of the jump
+    references: Option<Vec<(usize, String)>>,
+}
+
+impl<'a> Assembler<'a> {
+    /// The code of `source` and the source line of each instruction, with the tables of
+    /// `source` left in the assembler
+    fn assemble(&mut self, source: &'a str) -> Result<(Vec<Inst>, Vec<usize>), AsmError> {
+        let mut lines = Vec::new();
+
+        for (no, line) in source.lines().enumerate() {
+            let at_line = |kind| AsmError { line: no + 1, kind };
+            let (mut tokens, string) = split_line(line).map_err(at_line)?;
+            if let Some(label) = tokens.first().and_then(|head| head.strip_suffix(':')) {
+                if !label.starts_with('.') {
+                    self.label(label, lines.len()).map_err(at_line)?;
+                    tokens.remove(0);
+                }
+            }
+            let Some(&head) = tokens.first() else {
+                if string.is_some() {
+                    return Err(at_line(AsmErrorKind::BadString));
+                }
+                continue;
+            };
+
+            if (head == ".trap" || head == ".string") && tokens.len() == 1 {
+                match string {
+                    Some(message) if head == ".trap" => {
+                        self.trap_code(message).map(drop).map_err(at_line)?
+                    }
+                    Some(text) => self.string_id(text).map(drop).map_err(at_line)?,
+                    None => return Err(at_line(AsmErrorKind::BadString)),
+                }
+            } else if string.is_some()
+                && !(matches!(head, "trap" | "prints") && tokens.len() == 1)
+                && head != ".data"
+            {
+                return Err(at_line(AsmErrorKind::BadString));
+            } else if head.starts_with('.') {
+                self.directive(head, &tokens[1..], string)
+                    .map_err(at_line)?;
+            } else {
+                lines.push((no + 1, tokens, string));
+            }
+        }
+
+        let mut code = Vec::with_capacity(lines.len());
+        let mut code_lines = Vec::with_capacity(lines.len());
+        for (line, tokens, string) in lines {
+            let inst = match string {
+                Some(text) if tokens[0] == "prints" => self.string_id(text).map(Inst::PRINTS),
+                Some(message) => self.trap_code(message).map(Inst::TRAP),
+                None => self.instruction(code.len(), tokens[0], &tokens[1..]),
+            };
+            code.push(inst.map_err(|kind| AsmError { line, kind })?);
+            code_lines.push(line);
+        }
+
+        Ok((code, code_lines))
+    }
+
+    /// The program of `code`, with the tables the assembler collected
+    fn into_program(self, code: Vec<Inst>) -> Program {
+        Program {
+            code,
+            pool: self.pool,
+            data: self.data,
+            traps: self.traps.into_iter().map(String::from).collect(),
+            strings: self.strings.into_iter().map(String::from).collect(),
+            imports: self.imports,
+        }
+    }
+
+    /// Code of the trap with `message`, adding it to the table if needed
+    fn trap_code(&mut self, message: &'a str) -> Result<u16, AsmErrorKind> {
+        let code = intern(&mut self.traps, message);
+        u16::try_from(code).map_err(|_| AsmErrorKind::TooManyTraps)
+    }
+
+    /// Id of the string `text`, adding it to the string table if needed
+    fn string_id(&mut self, text: &'a str) -> Result<u16, AsmErrorKind> {
+        let id = intern(&mut self.strings, text);
+        u16::try_from(id).map_err(|_| AsmErrorKind::TooManyStrings)
+    }
+
+    /// Import index of the host function `name`, adding it to the table if needed. A number is
+    /// taken as the index itself.
+    fn import(&mut self, name: &str) -> Result<u16, AsmErrorKind> {
+        if !is_name(name) {
+            return self.number(name);
+        }
+        let idx = match self.imports.iter().position(|import| import == name) {
+            Some(idx) => idx,
+            None => {
+                self.imports.push(name.to_string());
+                self.imports.len() - 1
+            }
+        };
+        u16::try_from(idx).map_err(|_| AsmErrorKind::TooManyImports)
+    }
+
+    /// Process a directive, `string` being the string at the end of a `.data` line
+    fn directive(
+        &mut self,
+        name: &str,
+        operands: &[&'a str],
+        string: Option<&str>,
+    ) -> Result<(), AsmErrorKind> {
+        match name {
+            ".const" => {
+                let [name, val] = operands else {
+                    return Err(AsmErrorKind::Operands {
+                        expected: 2,
+                        found: operands.len(),
+                    });
+                };
+                let val = self.value(val)?;
+                self.define(name, val)?;
+                if let (Ok(val), Ok(idx)) = (Word::try_from(val), u16::try_from(self.pool.len())) {
+                    self.pool.push(val);
+                    self.pooled.insert(name, idx);
+                }
+                Ok(())
+            }
+
+            ".data" => {
+                let Some(label) = operands.first().and_then(|op| op.strip_suffix(':')) else {
+                    let found = operands.first().copied().unwrap_or_default();
+                    return Err(AsmErrorKind::BadOperand(found.to_string()));
+                };
+                self.define(label, self.data.len() as i128)?;
+
+                let mut items = operands[1..].iter();
+                while let Some(&item) = items.next() {
+                    if item == "zero" {
+                        let count = match items.next() {
+                            Some(count) => self.value(count)?,
+                            None => return Err(AsmErrorKind::BadOperand(item.to_string())),
+                        };
+                        let count =
+                            usize::try_from(count).map_err(|_| AsmErrorKind::OutOfRange(count))?;
+                        self.data.resize(self.data.len() + count, 0);
+                    } else {
+                        let word = self.value(item)?;
+                        self.data.push(fit(word)?);
+                    }
+                }
+                if let Some(text) = string {
+                    let chars = text.chars().map(|c| u32::from(c) as Word);
+                    self.data.extend(chars.chain([0]));
+                }
+                Ok(())
+            }
+
+            ".import" => match operands {
+                [name] if is_name(name) => self.import(name).map(drop),
+                [operand] => Err(AsmErrorKind::BadOperand(operand.to_string())),
+                _ => Err(AsmErrorKind::Operands {
+                    expected: 1,
+                    found: operands.len(),
+                }),
+            },
+
+            _ => Err(AsmErrorKind::UnknownDirective(name.to_string())),
+        }
+    }
+
+    fn define(&mut self, name: &'a str, val: i128) -> Result<(), AsmErrorKind> {
+        if !is_name(name) {
+            return Err(AsmErrorKind::BadOperand(name.to_string()));
+        }
+        if self.labels.contains_key(name) || self.names.insert(name, val).is_some() {
+            return Err(AsmErrorKind::Redefined(name.to_string()));
+        }
+        Ok(())
+    }
+
+    fn label(&mut self, name: &'a str, ip: usize) -> Result<(), AsmErrorKind> {
+        if !is_name(name) {
+            return Err(AsmErrorKind::BadOperand(name.to_string()));
+        }
+        if self.names.contains_key(name) || self.labels.insert(name, ip).is_some() {
+            return Err(AsmErrorKind::Redefined(name.to_string()));
+        }
+        Ok(())
+    }
+
+    /// Offset from the jump at `ip` to `operand`, a label or a number. In a fragment a name
+    /// that is not defined is a label of another fragment, the offset 0 until it is linked.
+    fn jump(&mut self, ip: usize, operand: &str) -> Result<isize, AsmErrorKind> {
+        match (self.labels.get(operand), &mut self.references) {
+            (Some(&target), _) => Ok(target as isize - ip as isize),
+            (None, Some(references)) if is_name(operand) && !self.names.contains_key(operand) => {
+                references.push((ip, operand.to_string()));
+                Ok(0)
+            }
+            (None, _) => self.number(operand),
+        }
+    }
+
+    /// The instruction at index `ip` of the program
+    fn instruction(
+        &mut self,
+        ip: usize,
+        mnemonic: &str,
+        operands: &[&str],
+    ) -> Result<Inst, AsmErrorKind> {
+        let lower = mnemonic.to_lowercase();
+        let expected = match lower.as_str() {
+            "pop" | "dup" | "swap" | "over" | "rot" | "in" | "out" | "yld" | "clr" | "add"
+            | "sub" | "mul" | "div" | "divf" | "modf" | "divu" | "modu" | "and" | "or" | "xor"
+            | "not" | "shl" | "shr" | "mod" | "neg" | "abs" | "min" | "max" | "cmp" | "ret"
+            | "hlt" | "fadd" | "fsub" | "fmul" | "fdiv" | "itof" | "ftoi" | "qmul" | "qdiv"
+            | "aidxload" | "aidxstore" => 0,
+            "psh" | "pshc" | "trap" | "load" | "store" | "loadl" | "storel" | "jmp" | "jez"
+            | "jnz" | "jlt" | "jgt" | "jle" | "jge" | "call" | "drop" | "tbl" | "snd" | "rcv"
+            | "sys" | "hcall" | "exit" | "inc" | "dec" | "fpsh" | "prints" | "alloc" => 1,
+            "set" | "setp" | "cpy" | "loop" | "jf" | "loadr" | "storer" => 2,
+            _ => return Err(AsmErrorKind::UnknownMnemonic(mnemonic.to_string())),
+        };
+        if operands.len() != expected {
+            return Err(AsmErrorKind::Operands {
+                expected,
+                found: operands.len(),
+            });
+        }
+
+        let inst = match lower.as_str() {
+            "psh" => Inst::PSH(self.number(operands[0])?),
+            "pshc" => Inst::PSHC(self.pool_index(operands[0])?),
+            "trap" => Inst::TRAP(self.number(operands[0])?),
+            "prints" => Inst::PRINTS(self.number(operands[0])?),
+            "sys" => Inst::SYS(self.number(operands[0])?),
+            "hcall" => Inst::HCALL(self.import(operands[0])?),
+            "exit" => Inst::EXIT(self.number(operands[0])?),
+            "pop" => Inst::POP,
+            "dup" => Inst::DUP,
+            "swap" => Inst::SWAP,
+            "over" => Inst::OVER,
+            "rot" => Inst::ROT,
+            "drop" => Inst::DROP(self.number(operands[0])?),
+            "in" => Inst::IN,
+            "out" => Inst::OUT,
+            "yld" => Inst::YLD,
+            "clr" => Inst::CLR,
+            "add" => Inst::ADD,
+            "sub" => Inst::SUB,
+            "mul" => Inst::MUL,
+            "div" => Inst::DIV,
+            "divf" => Inst::DIVF,
+            "modf" => Inst::MODF,
+            "divu" => Inst::DIVU,
+            "modu" => Inst::MODU,
+            "and" => Inst::AND,
+            "or" => Inst::OR,
+            "xor" => Inst::XOR,
+            "not" => Inst::NOT,
+            "shl" => Inst::SHL,
+            "shr" => Inst::SHR,
+            "mod" => Inst::MOD,
+            "neg" => Inst::NEG,
+            "abs" => Inst::ABS,
+            "min" => Inst::MIN,
+            "max" => Inst::MAX,
+            "cmp" => Inst::CMP,
+            "qmul" => Inst::QMUL,
+            "qdiv" => Inst::QDIV,
+            "fpsh" => Inst::FPSH(float(operands[0])?),
+            "fadd" => Inst::FADD,
+            "fsub" => Inst::FSUB,
+            "fmul" => Inst::FMUL,
+            "fdiv" => Inst::FDIV,
+            "itof" => Inst::ITOF,
+            "ftoi" => Inst::FTOI,
+            "set" => Inst::SET(reg(operands[0])?, self.number(operands[1])?),
+            "inc" => Inst::INC(reg(operands[0])?),
+            "dec" => Inst::DEC(reg(operands[0])?),
+            "setp" => Inst::SETP(self.path(operands[0])?, self.number(operands[1])?),
+            "cpy" => Inst::CPY(self.path(operands[0])?, self.path(operands[1])?),
+            "load" => Inst::LOAD(self.number(operands[0])?),
+            "store" => Inst::STORE(self.number(operands[0])?),
+            "loadr" => Inst::LOADR(reg(operands[0])?, self.number(operands[1])?),
+            "storer" => Inst::STORER(reg(operands[0])?, self.number(operands[1])?),
+            "alloc" => Inst::ALLOC(self.number(operands[0])?),
+            "aidxload" => Inst::AIDXLOAD,
+            "aidxstore" => Inst::AIDXSTORE,
+            "loadl" => Inst::LOADL(self.number(operands[0])?),
+            "storel" => Inst::STOREL(self.number(operands[0])?),
+            "jmp" => Inst::JMP(self.jump(ip, operands[0])?),
+            "jez" => Inst::JEZ(self.jump(ip, operands[0])?),
+            "jnz" => Inst::JNZ(self.jump(ip, operands[0])?),
+            "jlt" => Inst::JLT(self.jump(ip, operands[0])?),
+            "jgt" => Inst::JGT(self.jump(ip, operands[0])?),
+            "jle" => Inst::JLE(self.jump(ip, operands[0])?),
+            "jge" => Inst::JGE(self.jump(ip, operands[0])?),
+            "jf" => Inst::JF(cond(operands[0])?, self.jump(ip, operands[1])?),
+            "call" => Inst::CALL(self.jump(ip, operands[0])?),
+            "ret" => Inst::RET,
+            "loop" => Inst::LOOP(reg(operands[0])?, self.jump(ip, operands[1])?),
+            "tbl" => Inst::TBL(self.number(operands[0])?),
+            "snd" => Inst::SND(self.number(operands[0])?),
+            "rcv" => Inst::RCV(self.number(operands[0])?),
+            _ => Inst::HLT,
+        };
+        Ok(inst)
+    }
+
+    /// A number or a name, converted to the operand's type
+    fn number<T: TryFrom<i128>>(&self, operand: &str) -> Result<T, AsmErrorKind> {
+        fit(self.value(operand)?)
+    }
+
+    /// Pool index of a constant, or an index given as a number
+    fn pool_index(&self, operand: &str) -> Result<u16, AsmErrorKind> {
+        match self.pooled.get(operand) {
+            Some(&idx) => Ok(idx),
+            None if self.names.contains_key(operand) => {
+                Err(AsmErrorKind::NotInPool(operand.to_string()))
+            }
+            None => self.number(operand),
+        }
+    }
+
+    /// A number, a name or a negated name
+    fn value(&self, operand: &str) -> Result<i128, AsmErrorKind> {
+        if let Some(val) = literal(operand) {
+            return Ok(val);
+        }
+        // `name+2` or `name-1`, split at the last sign so `a-1-2` is `(a-1)-2`
+        let sign = operand
+            .char_indices()
+            .skip(1)
+            .filter(|&(_, c)| c == '+' || c == '-')
+            .last();
+        if let Some((at, sign)) = sign {
+            let (base, offset) = (&operand[..at], &operand[at + 1..]);
+            if offset.is_empty() {
+                return Err(AsmErrorKind::BadOperand(operand.to_string()));
+            }
+            let (base, offset) = (self.value(base)?, self.value(offset)?);
+            let val = match sign {
+                '-' => base.checked_sub(offset),
+                _ => base.checked_add(offset),
+            };
+            return val.ok_or(AsmErrorKind::OutOfRange(base));
+        }
+        let (negative, name) = match operand.strip_prefix('-') {
+            Some(name) => (true, name),
+            None => (false, operand),
+        };
+        if !is_name(name) {
+            return Err(AsmErrorKind::BadOperand(operand.to_string()));
+        }
+        match self.names.get(name) {
+            Some(&val) if negative => val.checked_neg().ok_or(AsmErrorKind::OutOfRange(val)),
+            Some(&val) => Ok(val),
+            None if self.labels.contains_key(name) => Err(AsmErrorKind::Label(name.to_string())),
+            None => Err(AsmErrorKind::Undefined(name.to_string())),
+        }
+    }
+
+    fn path(&self, operand: &str) -> Result<Path, AsmErrorKind> {
+        let bad = || AsmErrorKind::BadOperand(operand.to_string());
+
+        if let Some(name) = operand.strip_prefix("reg.") {
+            return Ok(Path::REG(reg(name)?));
+        }
+        let offset = operand
+            .strip_prefix("stk[")
+            .and_then(|rest| rest.strip_suffix(']'))
+            .ok_or_else(bad)?;
+
+        if offset.is_empty() {
+            return Err(bad());
+        }
+
+        // `stk[c+1]`, `stk[c-1]` or just `stk[1]`, `stk[-1]`
+        let sign = offset
+            .char_indices()
+            .skip(1)
+            .find(|&(_, c)| c == '+' || c == '-');
+        match sign.map(|(at, _)| at) {
+            Some(at) => {
+                let base = reg(&offset[..at]).map_err(|_| bad())?;
+                let (sign, rest) = offset[at..].split_at(1);
+                let val = self.value(rest)?;
+                let val = if sign == "-" { -val } else { val };
+                Ok(Path::STKR(base, fit(val)?))
+            }
+            None => match reg(offset) {
+                Ok(base) => Ok(Path::STKR(base, 0)),
+                Err(_) => Ok(Path::STK(self.number(offset)?)),
+            },
+        }
+    }
+}
+
+pub(crate) fn reg(operand: &str) -> Result<Reg, AsmErrorKind> {
+    let reg = match operand.to_lowercase().as_str() {
+        "a" => Reg::A,
+        "b" => Reg::B,
+        "c" => Reg::C,
+        "d" => Reg::D,
+        "e" => Reg::E,
+        "f" => Reg::F,
+        name => match name.strip_prefix('r').and_then(|n| n.parse().ok()) {
+            Some(n) => Reg::R(n),
+            None => return Err(AsmErrorKind::BadOperand(operand.to_string())),
+        },
+    };
+    Ok(reg)
+}
+
+/// A float in Rust's syntax, `1.5`, `-2e-3`, `inf` or `NaN`
+fn float(operand: &str) -> Result<Float, AsmErrorKind> {
+    operand
+        .parse()
+        .map_err(|_| AsmErrorKind::BadOperand(operand.to_string()))
+}
+
+fn cond(name: &str) -> Result<Cond, AsmErrorKind> {
+    let cond = match name.to_lowercase().as_str() {
+        "eq" => Cond::EQ,
+        "ne" => Cond::NE,
+        "lt" => Cond::LT,
+        "le" => Cond::LE,
+        "gt" => Cond::GT,
+        "ge" => Cond::GE,
+        "ltu" => Cond::LTU,
+        "geu" => Cond::GEU,
+        _ => return Err(AsmErrorKind::BadOperand(name.to_string())),
+    };
+    Ok(cond)
+}
+
+/// A decimal or `0x` hexadecimal integer, optionally negative
+fn literal(operand: &str) -> Option<i128> {
+    let (negative, digits) = match operand.strip_prefix('-') {
+        Some(digits) => (true, digits),
+        None => (false, operand),
+    };
+    let val = match digits.strip_prefix("0x") {
+        Some(hex) => i128::from_str_radix(hex, 16).ok()?,
+        None if digits.starts_with(|c: char| c.is_ascii_digit()) => digits.parse().ok()?,
+        None => return None,
+    };
+    Some(if negative { -val } else { val })
+}
+
+fn fit<T: TryFrom<i128>>(val: i128) -> Result<T, AsmErrorKind> {
+    T::try_from(val).map_err(|_| AsmErrorKind::OutOfRange(val))
+}
+
+fn is_name(name: &str) -> bool {
+    let mut chars = name.chars();
+    matches!(chars.next(), Some(c) if c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::vec;
+
+    use super::*;
+
+    const CONSTS: &str = "
+        .const WIDTH 6
+        .const AREA 0x2a    ; constants may be hex
+        .const LIMIT WIDTH  ; and defined from others
+
+        psh AREA
+        psh WIDTH
+        div
+        set c LIMIT
+        setp stk[0] -WIDTH
+        hlt
+    ";
+
+    const DATA: &str = "
+        .const SIZE 3
+
+        ; a table of squares, then room for a result
+        .data squares: 1 4 9 16
+        .data buf: zero SIZE
+        .data last: 7
+
+        load squares
+        load 3          ; squares + 3
+        add
+        store buf
+        hlt
+    ";
+
+    #[test]
+    fn every_instruction_parses() {
+        let source = "
+            psh -5
+            pshc 7
+            pop
+            dup
+            swap
+            over
+            rot
+            drop 3
+            in
+            out
+            yld
+            clr
+            add
+            sub
+            mul
+            div
+            divf
+            modf
+            divu
+            modu
+            and
+            or
+            xor
+            not
+            shl
+            shr
+            mod
+            neg
+            abs
+            min
+            max
+            cmp
+            qmul
+            qdiv
+            fpsh -1.25
+            FADD
+            fsub
+            fmul
+            fdiv
+            itof
+            ftoi
+            SET r3 12
+            inc a
+            dec r7
+            setp reg.a 1
+            cpy stk[2] stk[c-1]
+            cpy stk[r12+3] stk[d]
+            load 10
+            store 11
+            loadr r2 0
+            storer b 0x20
+            alloc 8
+            aidxload
+            aidxstore
+            jmp -2
+            loop d -2
+            jez 1
+            jnz -1
+            jlt 2
+            jgt -2
+            jle 3
+            jge -3
+            jf ltu -4
+            JF EQ 0
+            call -5
+            ret
+            loadl -2
+            storel 3
+            tbl 1
+            trap 4
+            prints 6
+            sys 12
+            hcall 2
+            exit -1
+            snd 2
+            rcv 3
+            hlt
+        ";
+        let program = vec![
+            Inst::PSH(-5),
+            Inst::PSHC(7),
+            Inst::POP,
+            Inst::DUP,
+            Inst::SWAP,
+            Inst::OVER,
+            Inst::ROT,
+            Inst::DROP(3),
+            Inst::IN,
+            Inst::OUT,
+            Inst::YLD,
+            Inst::CLR,
+            Inst::ADD,
+            Inst::SUB,
+            Inst::MUL,
+            Inst::DIV,
+            Inst::DIVF,
+            Inst::MODF,
+            Inst::DIVU,
+            Inst::MODU,
+            Inst::AND,
+            Inst::OR,
+            Inst::XOR,
+            Inst::NOT,
+            Inst::SHL,
+            Inst::SHR,
+            Inst::MOD,
+            Inst::NEG,
+            Inst::ABS,
+            Inst::MIN,
+            Inst::MAX,
+            Inst::CMP,
+            Inst::QMUL,
+            Inst::QDIV,
+            Inst::FPSH(-1.25),
+            Inst::FADD,
+            Inst::FSUB,
+            Inst::FMUL,
+            Inst::FDIV,
+            Inst::ITOF,
+            Inst::FTOI,
+            Inst::SET(Reg::R(3), 12),
+            Inst::INC(Reg::A),
+            Inst::DEC(Reg::R(7)),
+            Inst::SETP(Path::REG(Reg::A), 1),
+            Inst::CPY(Path::STK(2), Path::STKR(Reg::C, -1)),
+            Inst::CPY(Path::STKR(Reg::R(12), 3), Path::STKR(Reg::D, 0)),
+            Inst::LOAD(10),
+            Inst::STORE(11),
+            Inst::LOADR(Reg::R(2), 0),
+            Inst::STORER(Reg::B, 32),
+            Inst::ALLOC(8),
+            Inst::AIDXLOAD,
+            Inst::AIDXSTORE,
+            Inst::JMP(-2),
+            Inst::LOOP(Reg::D, -2),
+            Inst::JEZ(1),
+            Inst::JNZ(-1),
+            Inst::JLT(2),
+            Inst::JGT(-2),
+            Inst::JLE(3),
+            Inst::JGE(-3),
+            Inst::JF(Cond::LTU, -4),
+            Inst::JF(Cond::EQ, 0),
+            Inst::CALL(-5),
+            Inst::RET,
+            Inst::LOADL(-2),
+            Inst::STOREL(3),
+            Inst::TBL(1),
+            Inst::TRAP(4),
+            Inst::PRINTS(6),
+            Inst::SYS(12),
+            Inst::HCALL(2),
+            Inst::EXIT(-1),
+            Inst::SND(2),
+            Inst::RCV(3),
+            Inst::HLT,
+        ];
+        assert_eq!(assemble(source), Ok(program.clone()));
+
+        // instructions display in the syntax the assembler reads
+        let text: Vec<String> = program.iter().map(|inst| inst.to_string()).collect();
+        assert_eq!(assemble(&text.join("\n")), Ok(program));
+    }
+
+    #[test]
+    fn disassembly_round_trips() {
+        let mut seed = 0x9e37_79b9_7f4a_7c15u64;
+        let mut next = || {
+            seed ^= seed << 13;
+            seed ^= seed >> 7;
+            seed ^= seed << 17;
+            seed
+        };
+        for _ in 0..200 {
+            let mut code = Vec::new();
+            for _ in 0..32 {
+                let (a, b) = (next(), next());
+                let reg = match a % 7 {
+                    0 => Reg::A,
+                    1 => Reg::B,
+                    2 => Reg::C,
+                    3 => Reg::D,
+                    4 => Reg::E,
+                    5 => Reg::F,
+                    _ => Reg::R(b as u8),
+                };
+                let path = match a % 3 {
+                    0 => Path::REG(reg),
+                    1 => Path::STK(b as isize),
+                    _ => Path::STKR(reg, b as i32 as isize),
+                };
+                code.push(match b % 69 {
+                    68 => Inst::ALLOC(a as usize),
+                    67 => Inst::AIDXLOAD,
+                    66 => Inst::AIDXSTORE,
+                    65 => Inst::PRINTS(a as u16),
+                    64 => Inst::QMUL,
+                    63 => Inst::QDIV,
+                    62 => Inst::FPSH(b as i16 as Float / 8.0),
+                    61 => Inst::FTOI,
+                    60 => Inst::EXIT(b as i32),
+                    59 => Inst::HCALL(a as u16),
+                    58 => Inst::SYS(a as u32),
+                    57 => Inst::OUT,
+                    52 => Inst::DUP,
+                    53 => Inst::SWAP,
+                    54 => Inst::OVER,
+                    55 => Inst::ROT,
+                    56 => Inst::DROP(a as usize),
+                    50 => Inst::INC(reg),
+                    51 => Inst::DEC(reg),
+                    45 => Inst::MOD,
+                    46 => Inst::NEG,
+                    47 => Inst::ABS,
+                    48 => Inst::MIN,
+                    49 => Inst::MAX,
+                    39 => Inst::AND,
+                    40 => Inst::OR,
+                    41 => Inst::XOR,
+                    42 => Inst::NOT,
+                    43 => Inst::SHL,
+                    44 => Inst::SHR,
+                    37 => Inst::LOADR(reg, a as usize),
+                    38 => Inst::STORER(reg, usize::MAX),
+                    35 => Inst::LOADL(a as isize),
+                    36 => Inst::STOREL(-(b as i32 as isize)),
+                    33 => Inst::CALL(a as isize),
+                    34 => Inst::RET,
+                    31 => Inst::CMP,
+                    32 => Inst::JF(Cond::GEU, a as isize),
+                    25 => Inst::JEZ(a as isize),
+                    26 => Inst::JNZ(-(b as isize)),
+                    27 => Inst::JLT(a as i32 as isize),
+                    28 => Inst::JGT(b as i16 as isize),
+                    29 => Inst::JLE(a as isize),
+                    30 => Inst::JGE(b as isize),
+                    0 => Inst::PSH(a as i32 as Word),
+                    1 => Inst::PSHC(a as u16),
+                    2 => Inst::POP,
+                    3 => Inst::IN,
+                    4 => Inst::YLD,
+                    5 => Inst::SND(a as u8),
+                    6 => Inst::RCV(a as u8),
+                    7 => Inst::CLR,
+                    8 => Inst::ADD,
+                    9 => Inst::SUB,
+                    10 => Inst::MUL,
+                    11 => Inst::DIV,
+                    12 => Inst::DIVF,
+                    13 => Inst::MODF,
+                    14 => Inst::DIVU,
+                    15 => Inst::MODU,
+                    16 => Inst::SET(reg, b as i32 as Word),
+                    17 => Inst::SETP(path, a as i32 as Word),
+                    18 => Inst::CPY(path, Path::STKR(reg, -(a as i8 as isize))),
+                    19 => Inst::LOAD(a as usize),
+                    20 => Inst::STORE(usize::MAX - (b % 4) as usize),
+                    21 => Inst::JMP(a as isize),
+                    22 => Inst::LOOP(reg, isize::MIN + (b % 4) as isize),
+                    23 => Inst::TBL(a as usize),
+                    _ => Inst::TRAP(a as u16),
+                });
+            }
+            code.push(Inst::HLT);
+            assert_eq!(assemble(&disassemble(&code)), Ok(code));
+        }
+    }
+
+    #[test]
+    fn constants() {
+        let program = assemble(CONSTS).unwrap();
+        assert_eq!(program[0], Inst::PSH(42));
+        assert_eq!(program[3], Inst::SET(Reg::C, 6));
+        assert_eq!(program[4], Inst::SETP(Path::STK(0), -6));
+
+        let mut machine = Machine::new(program);
+        machine.resume().unwrap();
+        assert_eq!(machine.stack_top(), Some(-6));
+        assert_eq!(machine.registers()[&Reg::C], 6);
+    }
+
+    #[test]
+    fn data_segment() {
+        let program = assemble_program(DATA).unwrap();
+        assert_eq!(program.data, vec![1, 4, 9, 16, 0, 0, 0, 7]);
+        assert_eq!(program.code[0], Inst::LOAD(0));
+        assert_eq!(program.code[3], Inst::STORE(4));
+
+        let mut machine = program.machine().unwrap();
+        machine.resume().unwrap();
+        assert_eq!(&machine.memory()[..8], &[1, 4, 9, 16, 17, 0, 0, 7]);
+    }
+
+    #[test]
+    fn data_strings_and_offsets() {
+        let source = r#"
+            .data sizes: 3 5
+            .data greeting: 1 "hé;"
+            .const LAST greeting+4
+
+            load sizes+1
+            load greeting-1
+            add
+            psh LAST-1-2
+            load LAST
+            hlt
+        "#;
+        let program = assemble_program(source).unwrap();
+        assert_eq!(program.data, vec![3, 5, 1, 104, 233, 59, 0]);
+        assert_eq!(program.code[0], Inst::LOAD(1));
+        assert_eq!(program.code[1], Inst::LOAD(1));
+        assert_eq!(program.code[3], Inst::PSH(3));
+        assert_eq!(program.code[4], Inst::LOAD(6));
+
+        let mut machine = program.machine().unwrap();
+        machine.resume().unwrap();
+        assert_eq!(machine.stack(), [10, 3, 0]);
+
+        assert_eq!(
+            error("psh nowhere+1").kind,
+            AsmErrorKind::Undefined("nowhere".to_string())
+        );
+        assert_eq!(error(r#".data s: "open"#).kind, AsmErrorKind::BadString);
+        assert_eq!(error(r#".import "name""#).kind, AsmErrorKind::BadString);
+    }
+
+    #[test]
+    fn source_lines() {
+        let source = "; count down\n.const N 2\nset c N\nagain:\n  loop c again\n\nhlt\n";
+        let (program, lines) = assemble_with_lines(source).unwrap();
+        assert_eq!(program, assemble_program(source).unwrap());
+        assert_eq!(lines, [3, 5, 7]);
+    }
+
+    #[test]
+    fn random_text_never_panics() {
+        let pieces = [
+            "psh",
+            "cpy",
+            ".const",
+            ".data",
+            "x:",
+            "zero",
+            "stk[",
+            "stk[c+",
+            "stk[-",
+            "]",
+            "reg.",
+            "r9",
+            "-",
+            "0x",
+            "-0x8",
+            "9999999999999999999",
+            "ü",
+            "é",
+            "ñ1",
+            "+",
+            "5+",
+            "a-",
+            "X+X",
+            ".const X 170141183460469231731687303715884105727\n",
+            ";",
+            "a",
+            "N",
+            ":",
+            " ",
+            "\n",
+        ];
+        let mut seed = 0x2545_f491_4f6c_dd1du64;
+        for _ in 0..2000 {
+            let mut source = String::new();
+            for _ in 0..12 {
+                seed ^= seed << 13;
+                seed ^= seed >> 7;
+                seed ^= seed << 17;
+                source.push_str(pieces[seed as usize % pieces.len()]);
+            }
+            let _ = assemble_program(&source);
+        }
+        for source in [
+            "psh 5+",
+            "load a-",
+            "psh é",
+            "psh ñ1",
+            "jmp é",
+            "cpy stk[é] reg.a",
+            ".const X 170141183460469231731687303715884105727\npsh X+X",
+            ".const X 170141183460469231731687303715884105727\npsh -X-X-X",
+            ".const X 170141183460469231731687303715884105727\n.const Y -X-1\npsh -Y",
+        ] {
+            assert!(assemble_program(source).is_err(), "{source}");
+        }
+    }
+
+    #[test]
+    fn constant_pool() {
+        let source = "
+            .const BIG 1000000
+            .const ADDR 0x10000000000000000 ; too big for the pool
+            .const SMALL -3
+            .data buf: 1 2
+            pshc BIG
+            pshc SMALL
+            pshc 0
+            add
+            add
+            hlt
+        ";
+        let program = assemble_program(source).unwrap();
+        assert_eq!(program.pool, vec![1_000_000, -3]);
+        assert_eq!(
+            &program.code[..3],
+            &[Inst::PSHC(0), Inst::PSHC(1), Inst::PSHC(0)]
+        );
+
+        let mut machine = program.machine().unwrap();
+        machine.resume().unwrap();
+        assert_eq!(machine.stack_top(), Some(1_999_997));
+
+        let text = disassemble_program(&program);
+        assert!(text.contains("pshc 1 ; = -3\n"));
+        assert_eq!(assemble_program(&text), Ok(program));
+
+        assert_eq!(
+            error(".const ADDR 0x10000000000000000\npshc ADDR").kind,
+            AsmErrorKind::NotInPool("ADDR".to_string())
+        );
+        assert_eq!(
+            error(".data buf: 1\npshc buf").kind,
+            AsmErrorKind::NotInPool("buf".to_string())
+        );
+
+        let mut machine = Machine::with_pool(vec![Inst::PSHC(2), Inst::HLT], vec![1, 2]);
+        let err = machine.resume().unwrap_err();
+        assert_eq!(err.fault(), Some(&crate::Fault::NoConstant(2)));
+    }
+
+    #[test]
+    fn labels() {
+        // sums 4 + 3 + 2 + 1 into A
+        let source = "
+                set c 4
+                jmp body
+                psh 99      ; jumped over
+            body:
+                psh 0
+                cpy stk[0] reg.c
+                psh 0
+                cpy stk[0] reg.a
+                add
+                cpy reg.a stk[0]
+                pop
+                loop c body
+            done: hlt
+        ";
+        let program = assemble(source).unwrap();
+        assert_eq!(program[1], Inst::JMP(2));
+        assert_eq!(program[10], Inst::LOOP(Reg::C, -7));
+        assert_eq!(program.len(), 12);
+
+        let mut machine = Machine::new(program);
+        machine.resume().unwrap();
+        assert_eq!(machine.registers()[&Reg::A], 10);
+        assert_eq!(machine.stack(), &[]);
+
+        assert_eq!(
+            error("top:\njmp top\ntop: hlt").kind,
+            AsmErrorKind::Redefined("top".to_string())
+        );
+        assert_eq!(
+            error(".const N 1\nN: hlt").kind,
+            AsmErrorKind::Redefined("N".to_string())
+        );
+        assert_eq!(
+            error("top: psh top").kind,
+            AsmErrorKind::Label("top".to_string())
+        );
+        assert_eq!(
+            error("jmp nowhere").kind,
+            AsmErrorKind::Undefined("nowhere".to_string())
+        );
+        assert_eq!(
+            error("9lives: hlt").kind,
+            AsmErrorKind::BadOperand("9lives".to_string())
+        );
+    }
+
+    #[test]
+    fn trap_messages() {
+        let source = r#"
+            .trap "declared first"
+            psh 1
+            trap "stack; not empty"   ; semicolons in a message are kept
+            psh 0
+            trap "declared first"
+            hlt
+        "#;
+        let program = assemble_program(source).unwrap();
+        assert_eq!(program.traps, vec!["declared first", "stack; not empty"]);
+        assert_eq!(program.code[1], Inst::TRAP(1));
+        assert_eq!(program.code[3], Inst::TRAP(0));
+
+        let mut machine = program.machine().unwrap();
+        let err = machine.resume().unwrap_err();
+        assert!(matches!(err, VmError::Trap { code: 0, ip: 3, .. }));
+        assert_eq!(err.to_string(), "trap 0 at ip 3: declared first");
+        assert_eq!(machine.faulted(), Some(&err));
+
+        assert_eq!(
+            assemble_program(&disassemble_program(&program)),
+            Ok(program)
+        );
+        assert_eq!(error(r#"trap "open"#).kind, AsmErrorKind::BadString);
+        assert_eq!(error(r#"psh "1""#).kind, AsmErrorKind::BadString);
+    }
+
+    #[test]
+    fn string_table() {
+        let source = r#"
+            .string "declared first"
+            prints "hello, world"
+            prints "declared first"
+            prints 1
+            hlt
+        "#;
+        let program = assemble_program(source).unwrap();
+        assert_eq!(program.strings, vec!["declared first", "hello, world"]);
+        assert_eq!(
+            program.code[..3],
+            [Inst::PRINTS(1), Inst::PRINTS(0), Inst::PRINTS(1)]
+        );
+        assert_eq!(
+            assemble_program(&disassemble_program(&program)),
+            Ok(program)
+        );
+        assert_eq!(error(r#".string "#).kind, AsmErrorKind::BadString);
+    }
+
+    #[test]
+    fn host_functions() {
+        let source = "
+            .import unused
+            psh 3
+            psh 9
+            hcall max
+            dup
+            hcall square
+            hcall max
+            hlt
+        ";
+        let program = assemble_program(source).unwrap();
+        assert_eq!(program.imports, vec!["unused", "max", "square"]);
+        assert_eq!(program.code[2], Inst::HCALL(1));
+        assert_eq!(program.code[4], Inst::HCALL(2));
+
+        let mut machine = program.machine().unwrap();
+        machine.register_host_fn("max", 2, |args| args[0].max(args[1]));
+        machine.register_host_fn("square", 1, |args| args[0] * args[0]);
+        assert_eq!(machine.unresolved_imports(), vec!["unused"]);
+        machine.resume().unwrap();
+        assert_eq!(machine.stack(), &[81]);
+
+        assert_eq!(
+            assemble_program(&disassemble_program(&program)),
+            Ok(program)
+        );
+        assert_eq!(assemble("hcall 4").unwrap(), vec![Inst::HCALL(4)]);
+        assert_eq!(
+            error(".import 4").kind,
+            AsmErrorKind::BadOperand("4".to_string())
+        );
+    }
+
+    fn error(source: &str) -> AsmError {
+        assemble(source).unwrap_err()
+    }
+
+    #[test]
+    fn undefined_names() {
+        let err = error("psh 1\n\npsh MISSING\nhlt");
+        assert_eq!(err.line, 3);
+        assert_eq!(err.kind, AsmErrorKind::Undefined("MISSING".to_string()));
+        assert_eq!(err.to_string(), "line 3: undefined name `MISSING`");
+
+        // directives only see names defined above them
+        let err = error(".data buf: zero SIZE\n.const SIZE 4");
+        assert_eq!(err.line, 1);
+        assert_eq!(err.kind, AsmErrorKind::Undefined("SIZE".to_string()));
+    }
+
+    #[test]
+    fn redefined_names() {
+        let err = error(".const N 1\n.data N: 5");
+        assert_eq!(err.line, 2);
+        assert_eq!(err.kind, AsmErrorKind::Redefined("N".to_string()));
+
+        let err = error("; header\n.const N 1\n.const N 2");
+        assert_eq!(err.line, 3);
+        assert_eq!(err.kind, AsmErrorKind::Redefined("N".to_string()));
+    }
+
+    #[test]
+    fn malformed_lines() {
+        let cases = [
+            (
+                "psh",
+                AsmErrorKind::Operands {
+                    expected: 1,
+                    found: 0,
+                },
+            ),
+            ("nop", AsmErrorKind::UnknownMnemonic("nop".to_string())),
+            (".org 4", AsmErrorKind::UnknownDirective(".org".to_string())),
+            (".const 9 1", AsmErrorKind::BadOperand("9".to_string())),
+            (".data buf 1", AsmErrorKind::BadOperand("buf".to_string())),
+            ("set x 1", AsmErrorKind::BadOperand("x".to_string())),
+            (
+                "cpy stk[] reg.a",
+                AsmErrorKind::BadOperand("stk[]".to_string()),
+            ),
+            ("load -1", AsmErrorKind::OutOfRange(-1)),
+            ("psh 0x10000000000000000", AsmErrorKind::OutOfRange(1 << 64)),
+        ];
+        for (source, kind) in cases {
+            assert_eq!(error(source), AsmError { line: 1, kind }, "{source}");
+        }
+    }
+}
@@ -0,0 +1,158 @@
+//! Creating a machine step by step, for hosts that set up more than [`Machine::new`] does.
+
+use alloc::boxed::Box;
+use alloc::sync::Arc;
+
+use crate::io::{InputSource, OutputSink};
+use crate::observe::Observer;
+use crate::stack::StackStorage;
+use crate::validate::ValidationError;
+use crate::{Inst, Machine, MachineConfig};
+
+/// Everything a new machine is created with, set one by one and put together by
+/// [`build`](MachineBuilder::build). What is not set is what [`Machine::new`] starts with: no
+/// program, the default [`MachineConfig`], no fuel limit, output to standard output and no
+/// observer.
+#[derive(Default)]
+pub struct MachineBuilder {
+    program: Arc<[Inst]>,
+    config: MachineConfig,
+    fuel: Option<u64>,
+    input: Option<Box<dyn InputSource>>,
+    output: Option<Box<dyn OutputSink>>,
+    observer: Option<Box<dyn Observer>>,
+}
+
+impl MachineBuilder {
+    pub fn new() -> Self {
+        MachineBuilder::default()
+    }
+
+    /// The program the machine runs
+    pub fn program(mut self, program: impl Into<Arc<[Inst]>>) -> Self {
+        self.program = program.into();
+        self
+    }
+
+    /// Every size and setting of [`MachineConfig`] at once, replacing the stack size if it was
+    /// set before
+    pub fn config(mut self, config: MachineConfig) -> Self {
+        self.config = config;
+        self
+    }
+
+    /// Elements the stack holds, see [`MachineConfig::stack_size`]
+    pub fn stack_size(mut self, size: usize) -> Self {
+        self.config.stack_size = size;
+        self
+    }
+
+    /// Instructions the machine may execute, see [`Machine::set_fuel`]
+    pub fn fuel(mut self, fuel: u64) -> Self {
+        self.fuel = Some(fuel);
+        self
+    }
+
+    /// Where `IN` reads from, see [`Machine::set_input`]
+    pub fn input(mut self, source: impl InputSource + 'static) -> Self {
+        self.input = Some(Box::new(source));
+        self
+    }
+
+    /// Where the machine writes what it prints, see [`Machine::set_output`]
+    pub fn output(mut self, sink: impl OutputSink + 'static) -> Self {
+        self.output = Some(Box::new(sink));
+        self
+    }
+
+    /// What watches the machine run, see [`Machine::set_observer`]
+    pub fn observer(mut self, observer: impl Observer + 'static) -> Self {
+        self.observer = Some(Box::new(observer));
+        self
+    }
+
+    /// Create the machine. A register count over 256 in the config is a
+    /// `ValidationError::RegisterCount`, like [`Machine::new_with_config`] has it.
+    pub fn build(self) -> Result<Machine, ValidationError> {
+        self.build_with_storage()
+    }
+
+    /// Create a machine that keeps its stack in `S`, see [`Machine::new_with_storage`]
+    pub fn build_with_storage<S: StackStorage>(self) -> Result<Machine<S>, ValidationError> {
+        let mut machine = Machine::new_with_storage(self.program, self.config)?;
+        machine.set_fuel(self.fuel);
+        machine.input = self.input;
+        machine.output = self.output;
+        machine.observer = self.observer;
+        Ok(machine)
+    }
+}
+
+impl Machine {
+    /// A [`MachineBuilder`] to create a machine with
+    pub fn builder() -> MachineBuilder {
+        MachineBuilder::new()
+    }
+}
+
+#[cfg(all(test, feature = "std"))]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use super::*;
+    use crate::{Fault, FixedStack, Reg, RunOutcome, StackError, VmError};
+
+    /// Counts the instructions it sees
+    struct Count(Arc<Mutex<usize>>);
+
+    impl Observer for Count {
+        fn before_inst(&mut self, _ip: usize, _inst: Inst) {
+            *self.0.lock().unwrap() += 1;
+        }
+    }
+
+    #[test]
+    fn building_machines() {
+        let program = vec![Inst::IN, Inst::DUP, Inst::OUT, Inst::PSH(1), Inst::HLT];
+        let seen = Arc::new(Mutex::new(0));
+        let mut machine = Machine::builder()
+            .program(program.clone())
+            .input([7].into_iter())
+            .output(Vec::new())
+            .observer(Count(seen.clone()))
+            .fuel(3)
+            .build()
+            .unwrap();
+        assert_eq!(machine.run(), Ok(RunOutcome::OutOfFuel));
+        assert_eq!(*seen.lock().unwrap(), 3);
+        assert_eq!(machine.stack(), [7]);
+        assert_eq!(machine.fuel(), Some(0));
+
+        let mut machine = MachineBuilder::new()
+            .program(program.clone())
+            .input([7].into_iter())
+            .output(Vec::new())
+            .stack_size(1)
+            .build_with_storage::<FixedStack<4>>()
+            .unwrap();
+        assert!(matches!(
+            machine.run(),
+            Err(VmError::Exec {
+                ip: 1,
+                fault: Fault::Stack(StackError::PushErr),
+                ..
+            })
+        ));
+
+        let config = MachineConfig {
+            registers: 300,
+            ..MachineConfig::default()
+        };
+        let built = Machine::builder().program(program).config(config).build();
+        assert!(matches!(built, Err(ValidationError::RegisterCount(300))));
+
+        let machine = Machine::builder().build().unwrap();
+        assert_eq!(machine.program_len(), 0);
+        assert_eq!(machine.registers()[&Reg::A], 0);
+    }
+}
@@ -0,0 +1,388 @@
+//! Core files: the state of a machine that failed, saved so that the failure can be examined
+//! later, for example with `vyantra postmortem`.
+//!
+//! A core file is a sequence of little endian 64 bit words. A header of four words (a magic
+//! number then the lengths of the three sections in words) is followed by the program, in the
+//! format of [`Program::to_words`], the machine state and the error message, a string in the
+//! format of trap messages. The state is the instruction pointer, the frame pointer, the count
+//! of executed instructions, the exit code as a word that is 1 when there is one followed by
+//! the code, the flags as bits (zero, negative, overflow and carry from the lowest), then the
+//! stack, the registers as pairs of a register code and a value, the calls as pairs of a return
+//! address and a frame pointer, data memory, and the arrays of the heap, each a length word
+//! followed by its items. An item of the heap is a word that is 1 for a live array followed by
+//! the array, a length and its values, or 0 for a freed slot. Values are sign extended to 64
+//! bits, the first version of the format kept them in the low 32 bits, and the heap came with
+//! the third. The types of the stack slots of a typed machine are not saved.
+
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use crate::asm::Program;
+use crate::encode::{
+    decode_reg, decode_strings, encode_strings, read_value, reg_code, value_word, DecodeError,
+    EncodeError,
+};
+use crate::{Flags, Frame, Machine, MachineSnapshot, VmError, Word};
+
+/// First word of a core file, "vycore" and a format version
+const MAGIC: u64 = u64::from_be_bytes(*b"vycore\x00\x03");
+
+/// First word of a core file in the second version of the format, before the heap was saved
+const MAGIC_V2: u64 = u64::from_be_bytes(*b"vycore\x00\x02");
+
+/// First word of a core file in the first version of the format, before 64 bit words
+const MAGIC_V1: u64 = u64::from_be_bytes(*b"vycore\x00\x01");
+
+/// A machine that failed: its program, its state when the error happened and the error, taken
+/// with [`Machine::core_dump`]. Jump tables are not part of the program, and what is attached
+/// to the machine (input, output, ports, handlers) is not saved either.
+#[derive(Clone, Debug, PartialEq)]
+pub struct CoreDump {
+    pub program: Program,
+    pub snapshot: MachineSnapshot,
+
+    /// The error with its context, as the alternate format of `VmError` shows it
+    pub error: String,
+}
+
+impl CoreDump {
+    /// The words of the core file format
+    pub fn to_words(&self) -> Result<Vec<u64>, EncodeError> {
+        let program = self.program.to_words()?;
+        let state = state_words(&self.snapshot);
+        let error = encode_strings(std::slice::from_ref(&self.error));
+        let mut words = vec![
+            MAGIC,
+            program.len() as u64,
+            state.len() as u64,
+            error.len() as u64,
+        ];
+        words.extend(program);
+        words.extend(state);
+        words.extend(error);
+        Ok(words)
+    }
+
+    /// Decode a core made by [`CoreDump::to_words`]
+    pub fn from_words(words: &[u64]) -> Result<CoreDump, DecodeError> {
+        let version = match words.first() {
+            Some(&MAGIC) if words.len() >= 4 => 3,
+            Some(&MAGIC_V2) if words.len() >= 4 => 2,
+            Some(&MAGIC_V1) if words.len() >= 4 => 1,
+            Some(&magic) if words.len() >= 4 => return Err(DecodeError::BadMagic(magic)),
+            _ => {
+                return Err(DecodeError::Length {
+                    expected: 4,
+                    found: words.len(),
+                })
+            }
+        };
+        let lens = words[1..4]
+            .iter()
+            .map(|&len| usize::try_from(len).unwrap_or(usize::MAX));
+        let lens: Vec<usize> = lens.collect();
+        let expected = lens
+            .iter()
+            .fold(4, |sum: usize, &len| sum.saturating_add(len));
+        if expected != words.len() {
+            return Err(DecodeError::Length {
+                expected,
+                found: words.len(),
+            });
+        }
+        let (program, rest) = words[4..].split_at(lens[0]);
+        let (state, error) = rest.split_at(lens[1]);
+        let mut error = decode_strings(error)?;
+        if error.len() != 1 {
+            return Err(DecodeError::BadString);
+        }
+        Ok(CoreDump {
+            program: Program::from_words(program)?,
+            snapshot: read_state(state, version)?,
+            error: error.remove(0),
+        })
+    }
+
+    /// The words of [`CoreDump::to_words`] as little endian bytes, the format of a core file
+    pub fn to_bytes(&self) -> Result<Vec<u8>, EncodeError> {
+        let words = self.to_words()?;
+        Ok(words.iter().flat_map(|word| word.to_le_bytes()).collect())
+    }
+
+    /// Decode a core file made by [`CoreDump::to_bytes`]
+    pub fn from_bytes(bytes: &[u8]) -> Result<CoreDump, DecodeError> {
+        let chunks = bytes.chunks_exact(8);
+        if !chunks.remainder().is_empty() {
+            return Err(DecodeError::Truncated);
+        }
+        let words: Vec<u64> = chunks
+            .map(|chunk| u64::from_le_bytes(chunk.try_into().unwrap()))
+            .collect();
+        CoreDump::from_words(&words)
+    }
+
+    /// Write the core file to `path`
+    pub fn write(&self, path: impl AsRef<Path>) -> io::Result<()> {
+        let bytes = self
+            .to_bytes()
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        fs::write(path, bytes)
+    }
+
+    /// Read the core file at `path`
+    pub fn read(path: impl AsRef<Path>) -> io::Result<CoreDump> {
+        let bytes = fs::read(path)?;
+        CoreDump::from_bytes(&bytes).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+
+    /// A machine for the program, put in the state the failed machine was in. Running it goes
+    /// on with the instruction that failed.
+    pub fn machine(&self) -> Result<Machine, VmError> {
+        let mut machine = self.program.machine()?;
+        machine.restore(&self.snapshot);
+        Ok(machine)
+    }
+}
+
+fn state_words(snapshot: &MachineSnapshot) -> Vec<u64> {
+    let flags = &snapshot.flags;
+    let flag_bits = [flags.zero, flags.negative, flags.overflow, flags.carry]
+        .iter()
+        .enumerate()
+        .fold(0, |bits, (bit, &set)| bits | (set as u64) << bit);
+    let mut words = vec![
+        snapshot.ip as u64,
+        snapshot.fp as u64,
+        snapshot.executed,
+        snapshot.exit_code.is_some() as u64,
+        snapshot.exit_code.unwrap_or(0) as i64 as u64,
+        flag_bits,
+    ];
+    words.push(snapshot.stack.len() as u64);
+    words.extend(snapshot.stack.iter().map(|&val| value_word(val)));
+
+    // sorted so that the same state always makes the same file
+    let mut registers: Vec<_> = snapshot.registers.iter().collect();
+    registers.sort_by_key(|(reg, _)| reg_code(**reg));
+    words.push(registers.len() as u64);
+    for (reg, &val) in registers {
+        words.extend([reg_code(*reg), value_word(val)]);
+    }
+
+    words.push(snapshot.calls.len() as u64);
+    for frame in &snapshot.calls {
+        words.extend([frame.ret as u64, frame.fp as u64]);
+    }
+    words.push(snapshot.memory.len() as u64);
+    words.extend(snapshot.memory.iter().map(|&val| value_word(val)));
+
+    words.push(snapshot.heap.len() as u64);
+    for array in &snapshot.heap {
+        match array {
+            Some(values) => {
+                words.extend([1, values.len() as u64]);
+                words.extend(values.iter().map(|&val| value_word(val)));
+            }
+            None => words.push(0),
+        }
+    }
+    words
+}
+
+/// The state section of a core file in the format `version`
+fn read_state(words: &[u64], version: u8) -> Result<MachineSnapshot, DecodeError> {
+    let mut words = Words {
+        words,
+        pos: 0,
+        narrow: version == 1,
+    };
+    let ip = words.index()?;
+    let fp = words.index()?;
+    let executed = words.next()?;
+    let exit_code = match (words.next()?, words.exit_code()?) {
+        (0, _) => None,
+        (_, code) => Some(code),
+    };
+    let flag_bits = words.next()?;
+    let flags = Flags {
+        zero: flag_bits & 1 != 0,
+        negative: flag_bits & 2 != 0,
+        overflow: flag_bits & 4 != 0,
+        carry: flag_bits & 8 != 0,
+    };
+    let stack = (0..words.index()?)
+        .map(|_| words.value())
+        .collect::<Result<_, _>>()?;
+    let mut registers = HashMap::new();
+    for _ in 0..words.index()? {
+        let reg = decode_reg(words.next()?)?;
+        registers.insert(reg, words.value()?);
+    }
+    let mut calls = Vec::new();
+    for _ in 0..words.index()? {
+        let (ret, fp) = (words.index()?, words.index()?);
+        calls.push(Frame { ret, fp });
+    }
+    let memory = (0..words.index()?)
+        .map(|_| words.value())
+        .collect::<Result<_, _>>()?;
+    let mut heap = Vec::new();
+    for _ in 0..if version >= 3 { words.index()? } else { 0 } {
+        let at = words.pos;
+        let array = match words.next()? {
+            0 => None,
+            1 => Some(
+                (0..words.index()?)
+                    .map(|_| words.value())
+                    .collect::<Result<_, _>>()?,
+            ),
+            _ => return Err(DecodeError::BadNumber(at)),
+        };
+        heap.push(array);
+    }
+    if words.pos != words.words.len() {
+        return Err(DecodeError::Length {
+            expected: words.pos,
+            found: words.words.len(),
+        });
+    }
+    Ok(MachineSnapshot {
+        ip,
+        stack,
+        types: Vec::new(),
+        registers,
+        flags,
+        calls,
+        fp,
+        memory,
+        heap,
+        executed,
+        exit_code,
+    })
+}
+
+/// Reader of the state section
+struct Words<'a> {
+    words: &'a [u64],
+    pos: usize,
+
+    /// Values are in the low 32 bits, as in the first version
+    narrow: bool,
+}
+
+impl Words<'_> {
+    fn next(&mut self) -> Result<u64, DecodeError> {
+        let word = *self.words.get(self.pos).ok_or(DecodeError::Truncated)?;
+        self.pos += 1;
+        Ok(word)
+    }
+
+    fn index(&mut self) -> Result<usize, DecodeError> {
+        let at = self.pos;
+        let word = self.next()?;
+        usize::try_from(word).map_err(|_| DecodeError::BadNumber(at))
+    }
+
+    fn value(&mut self) -> Result<Word, DecodeError> {
+        let word = self.next()?;
+        read_value(word, self.narrow)
+    }
+
+    fn exit_code(&mut self) -> Result<i32, DecodeError> {
+        let at = self.pos;
+        let word = self.next()?;
+        match self.narrow {
+            true if word >> 32 == 0 => Ok(word as u32 as i32),
+            false => i32::try_from(word as i64).map_err(|_| DecodeError::BadNumber(at)),
+            true => Err(DecodeError::ReservedBits(word)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Inst, Reg};
+
+    #[test]
+    fn cores_round_trip() {
+        let program = Program {
+            code: vec![
+                Inst::PSH(-3),
+                Inst::SET(Reg::R(2), 9),
+                Inst::PSH(4),
+                Inst::CMP,
+                Inst::STORE(1),
+                Inst::PSH(0),
+                Inst::DIV,
+                Inst::HLT,
+            ],
+            traps: vec!["never".to_string()],
+            ..Program::default()
+        };
+        let mut machine = program.machine().unwrap();
+        assert!(machine.run().is_err());
+        let core = machine.core_dump().unwrap();
+        assert_eq!(core.program.code, program.code);
+        assert!(core.error.starts_with("attempted to divide by zero"));
+
+        let bytes = core.to_bytes().unwrap();
+        let read = CoreDump::from_bytes(&bytes).unwrap();
+        assert_eq!(read, core);
+        let mut machine = read.machine().unwrap();
+        assert_eq!(machine.ip(), 6);
+        assert_eq!(machine.memory()[1], 4);
+        assert!(machine.flags().negative);
+        assert_eq!(machine.registers()[&Reg::R(2)], 9);
+        assert!(machine.run().is_err());
+
+        assert_eq!(
+            CoreDump::from_bytes(&bytes[..bytes.len() - 8]),
+            Err(DecodeError::Length {
+                expected: bytes.len() / 8,
+                found: bytes.len() / 8 - 1
+            })
+        );
+        let program = program.to_bytes().unwrap();
+        assert!(matches!(
+            CoreDump::from_bytes(&program),
+            Err(DecodeError::BadMagic(_))
+        ));
+    }
+
+    #[test]
+    fn cores_keep_the_heap() {
+        // an array holding 42 at index 1, read back after the failed division
+        let program = Program {
+            code: vec![
+                Inst::ALLOC(3),
+                Inst::DUP,
+                Inst::PSH(1),
+                Inst::PSH(42),
+                Inst::AIDXSTORE,
+                Inst::PSH(0),
+                Inst::PSH(0),
+                Inst::DIV,
+                Inst::POP,
+                Inst::PSH(1),
+                Inst::AIDXLOAD,
+                Inst::HLT,
+            ],
+            ..Program::default()
+        };
+        let mut machine = program.machine().unwrap();
+        assert!(machine.run().is_err());
+        let core = machine.core_dump().unwrap();
+        assert_eq!(core.snapshot.heap, [Some(vec![0, 42, 0])]);
+
+        let read = CoreDump::from_bytes(&core.to_bytes().unwrap()).unwrap();
+        assert_eq!(read, core);
+        let mut machine = read.machine().unwrap();
+        // divide by 1 instead, the handle in the core still reading the array
+        *machine.stack_mut().last_mut().unwrap() = 1;
+        assert!(machine.run().is_ok());
+        assert_eq!(machine.stack(), [42]);
+    }
+}
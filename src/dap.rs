@@ -0,0 +1,670 @@
+//! A Debug Adapter Protocol server, enabled by the `dap` feature, so that editors such as VS
+//! Code can debug assembly programs: breakpoints on source lines, stepping, and the registers
+//! and the stack shown as variables.
+//!
+//! The adapter talks to the editor over a reader and a writer, standard input and output when
+//! the editor starts `vyantra dap`. A `launch` request names the assembly file to debug in
+//! `program`, and `stopOnEntry` stops before the first instruction. Values written by `OUT` and
+//! yielded by `YLD` are sent to the editor as output, and `IN` has nothing to read.
+//!
+//! The machine has a single thread of execution. Stack frames are the current instruction and
+//! the `CALL` of every call in progress, and each frame shows the same registers and stack.
+
+use std::fs;
+use std::io::{self, BufRead, Write};
+use std::sync::{Arc, Mutex};
+
+use crate::asm::assemble_with_lines;
+use crate::json::Json;
+use crate::{DebugStop, Debugger, Inst, Reg, StepStatus, VmError, Word};
+
+/// `variablesReference` of the registers scope
+const REGISTERS: i64 = 1;
+
+/// `variablesReference` of the stack scope
+const STACK: i64 = 2;
+
+/// Serve requests read from `input` until the editor disconnects or closes the stream
+pub fn serve(mut input: impl BufRead, output: impl Write) -> io::Result<()> {
+    let mut adapter = Adapter {
+        out: output,
+        seq: 0,
+        session: None,
+    };
+    while let Some(request) = read_message(&mut input)? {
+        if !adapter.handle(&request)? {
+            break;
+        }
+    }
+    Ok(())
+}
+
+/// The program being debugged
+struct Session {
+    debugger: Debugger,
+    path: String,
+
+    /// Source line of each instruction
+    lines: Vec<usize>,
+
+    /// What the program wrote with `OUT` and has not been sent yet
+    output: SharedOutput,
+    stop_on_entry: bool,
+
+    /// The program halted or faulted, there is nothing left to run
+    ended: bool,
+}
+
+impl Session {
+    fn line_of(&self, ip: usize) -> usize {
+        self.lines.get(ip).copied().unwrap_or(0)
+    }
+
+    /// The first instruction on `line` or after it
+    fn ip_at(&self, line: usize) -> Option<usize> {
+        self.lines.iter().position(|at| *at >= line)
+    }
+}
+
+/// How to run the program on a resume
+#[derive(Copy, Clone, PartialEq)]
+enum Resume {
+    /// Until a breakpoint or the end
+    Continue,
+
+    /// One instruction
+    StepIn,
+
+    /// One instruction, or the whole call it makes
+    Next,
+
+    /// Until the current call returns
+    StepOut,
+}
+
+/// An output sink the adapter keeps a handle to
+#[derive(Clone, Default)]
+struct SharedOutput(Arc<Mutex<Vec<u8>>>);
+
+impl SharedOutput {
+    fn take(&self) -> String {
+        let bytes = std::mem::take(&mut *self.0.lock().unwrap());
+        String::from_utf8_lossy(&bytes).into_owned()
+    }
+}
+
+impl Write for SharedOutput {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.lock().unwrap().write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+struct Adapter<W> {
+    out: W,
+
+    /// Sequence number of the last message sent
+    seq: i64,
+    session: Option<Session>,
+}
+
+impl<W: Write> Adapter<W> {
+    /// Answer `request`, returning false once the editor has disconnected
+    fn handle(&mut self, request: &Json) -> io::Result<bool> {
+        let command = request.get("command").and_then(Json::as_str).unwrap_or("");
+        let args = request.get("arguments").cloned().unwrap_or(Json::Null);
+        let result = match command {
+            "initialize" => Ok(Json::obj(vec![(
+                "supportsConfigurationDoneRequest",
+                true.into(),
+            )])),
+            "launch" => self.launch(&args),
+            "setBreakpoints" => self.set_breakpoints(&args),
+            "configurationDone" | "threads" | "stackTrace" | "scopes" | "variables"
+                if self.session.is_none() =>
+            {
+                Err("no program has been launched".to_string())
+            }
+            "configurationDone" => Ok(Json::Null),
+            "threads" => Ok(Json::obj(vec![(
+                "threads",
+                Json::Arr(vec![Json::obj(vec![
+                    ("id", 1.into()),
+                    ("name", "main".into()),
+                ])]),
+            )])),
+            "stackTrace" => Ok(self.stack_trace()),
+            "scopes" => Ok(Json::obj(vec![(
+                "scopes",
+                Json::Arr(vec![scope("Registers", REGISTERS), scope("Stack", STACK)]),
+            )])),
+            "variables" => Ok(self.variables(&args)),
+            "continue" => Ok(Json::obj(vec![("allThreadsContinued", true.into())])),
+            "next" | "stepIn" | "stepOut" | "pause" => Ok(Json::Null),
+            "disconnect" => {
+                self.respond(request, Ok(Json::Null))?;
+                return Ok(false);
+            }
+            _ => Err(format!("unsupported request `{command}`")),
+        };
+        let launched = command == "launch" && result.is_ok();
+        self.respond(request, result)?;
+
+        match command {
+            _ if launched => self.event("initialized", Json::Null)?,
+            "configurationDone" if self.session.as_ref().is_some_and(|s| s.stop_on_entry) => {
+                self.stopped("entry", None)?
+            }
+            "configurationDone" | "continue" => self.resume(Resume::Continue)?,
+            "next" => self.resume(Resume::Next)?,
+            "stepIn" => self.resume(Resume::StepIn)?,
+            "stepOut" => self.resume(Resume::StepOut)?,
+            _ => (),
+        }
+        Ok(true)
+    }
+
+    fn launch(&mut self, args: &Json) -> Result<Json, String> {
+        let path = match args.get("program").and_then(Json::as_str) {
+            Some(path) => path.to_string(),
+            None => return Err("launch needs the `program` to debug".to_string()),
+        };
+        let source = fs::read_to_string(&path).map_err(|e| format!("cannot read {path}: {e}"))?;
+        let (program, lines) = assemble_with_lines(&source).map_err(|e| format!("{path}: {e}"))?;
+        let mut machine = program.machine().map_err(|e| e.to_string())?;
+        let output = SharedOutput::default();
+        machine.set_output(output.clone());
+
+        let stop_on_entry = args.get("stopOnEntry").and_then(Json::as_bool);
+        self.session = Some(Session {
+            debugger: Debugger::new(machine),
+            path,
+            lines,
+            output,
+            stop_on_entry: stop_on_entry.unwrap_or(false),
+            ended: false,
+        });
+        Ok(Json::Null)
+    }
+
+    /// Replace the breakpoints with the ones on the lines asked for. A line without an
+    /// instruction gets the breakpoint on the next instruction.
+    fn set_breakpoints(&mut self, args: &Json) -> Result<Json, String> {
+        let Some(session) = &mut self.session else {
+            return Err("no program has been launched".to_string());
+        };
+        let old: Vec<usize> = session.debugger.breakpoints().collect();
+        for ip in old {
+            session.debugger.remove_breakpoint(ip);
+        }
+
+        let requested = args
+            .get("breakpoints")
+            .and_then(Json::as_arr)
+            .unwrap_or(&[]);
+        let mut breakpoints = Vec::new();
+        for breakpoint in requested {
+            let line = breakpoint.get("line").and_then(Json::as_i64).unwrap_or(0);
+            let reply = match session.ip_at(line.max(0) as usize) {
+                Some(ip) => {
+                    session.debugger.add_breakpoint(ip);
+                    Json::obj(vec![
+                        ("verified", true.into()),
+                        ("line", session.line_of(ip).into()),
+                    ])
+                }
+                None => Json::obj(vec![("verified", false.into()), ("line", line.into())]),
+            };
+            breakpoints.push(reply);
+        }
+        Ok(Json::obj(vec![("breakpoints", Json::Arr(breakpoints))]))
+    }
+
+    fn stack_trace(&self) -> Json {
+        let session = self.session.as_ref().expect("stackTrace needs a session");
+        let machine = session.debugger.machine();
+        let source = Json::obj(vec![
+            ("name", file_name(&session.path).into()),
+            ("path", session.path.as_str().into()),
+        ]);
+
+        let calls = machine.call_stack().iter().rev();
+        let ips = std::iter::once(machine.ip()).chain(calls.map(|frame| frame.ret - 1));
+        let frames: Vec<Json> = ips
+            .enumerate()
+            .map(|(id, ip)| {
+                let name = match machine.instruction(ip) {
+                    Some(inst) => format!("{ip}: {inst}"),
+                    None => format!("{ip}: end of program"),
+                };
+                Json::obj(vec![
+                    ("id", id.into()),
+                    ("name", name.into()),
+                    ("source", source.clone()),
+                    ("line", session.line_of(ip).into()),
+                    ("column", 1.into()),
+                ])
+            })
+            .collect();
+        let total = frames.len();
+        Json::obj(vec![
+            ("stackFrames", Json::Arr(frames)),
+            ("totalFrames", total.into()),
+        ])
+    }
+
+    fn variables(&self, args: &Json) -> Json {
+        let session = self.session.as_ref().expect("variables needs a session");
+        let machine = session.debugger.machine();
+        let reference = args.get("variablesReference").and_then(Json::as_i64);
+        let values: Vec<(String, Word)> = match reference {
+            Some(REGISTERS) => {
+                let registers = machine.registers();
+                let named = [Reg::A, Reg::B, Reg::C, Reg::D, Reg::E, Reg::F];
+                let numbered = (0..=u8::MAX).map(Reg::R);
+                let regs = named.into_iter().chain(numbered);
+                regs.filter_map(|reg| Some((reg.to_string(), *registers.get(&reg)?)))
+                    .collect()
+            }
+            Some(STACK) => {
+                let stack = machine.stack().iter().rev();
+                stack
+                    .enumerate()
+                    .map(|(idx, val)| (format!("stk[{idx}]"), *val))
+                    .collect()
+            }
+            _ => Vec::new(),
+        };
+        let variables = values
+            .into_iter()
+            .map(|(name, val)| {
+                Json::obj(vec![
+                    ("name", name.into()),
+                    ("value", val.to_string().into()),
+                    ("variablesReference", 0.into()),
+                ])
+            })
+            .collect();
+        Json::obj(vec![("variables", Json::Arr(variables))])
+    }
+
+    /// Run the program and tell the editor where it stopped
+    fn resume(&mut self, how: Resume) -> io::Result<()> {
+        let Some(session) = &mut self.session else {
+            return Ok(());
+        };
+        if session.ended {
+            return self.event("terminated", Json::Null);
+        }
+
+        let mut yielded = Vec::new();
+        let stop = run(&mut session.debugger, how, &mut yielded);
+        let mut text = session.output.take();
+        for val in yielded {
+            text.push_str(&format!("yield {val}\n"));
+        }
+        if !text.is_empty() {
+            self.output(&text)?;
+        }
+
+        match stop {
+            Ok(Some(DebugStop::Halted)) => {
+                let session = self.session.as_mut().expect("the session is running");
+                session.ended = true;
+                let code = session.debugger.machine().exit_code().unwrap_or(0);
+                self.event("exited", Json::obj(vec![("exitCode", code.into())]))?;
+                self.event("terminated", Json::Null)
+            }
+            Ok(Some(DebugStop::Breakpoint(_))) => self.stopped("breakpoint", None),
+            Ok(Some(DebugStop::Watchpoint(_))) => self.stopped("data breakpoint", None),
+            Ok(Some(DebugStop::WaitingOnPort(port))) => {
+                self.stopped("pause", Some(format!("waiting on port {port}")))
+            }
+            Ok(_) => self.stopped("step", None),
+            Err(e) => {
+                let session = self.session.as_mut().expect("the session is running");
+                session.ended = true;
+                self.stopped("exception", Some(e.to_string()))
+            }
+        }
+    }
+
+    fn stopped(&mut self, reason: &str, text: Option<String>) -> io::Result<()> {
+        let mut body = vec![
+            ("reason", reason.into()),
+            ("threadId", 1.into()),
+            ("allThreadsStopped", true.into()),
+        ];
+        if let Some(text) = text {
+            body.push(("text", text.into()));
+        }
+        self.event("stopped", Json::obj(body))
+    }
+
+    fn output(&mut self, text: &str) -> io::Result<()> {
+        let body = Json::obj(vec![("category", "stdout".into()), ("output", text.into())]);
+        self.event("output", body)
+    }
+
+    fn respond(&mut self, request: &Json, result: Result<Json, String>) -> io::Result<()> {
+        let request_seq = request.get("seq").cloned().unwrap_or(Json::Null);
+        let command = request.get("command").cloned().unwrap_or(Json::Null);
+        let mut fields = vec![
+            ("type", "response".into()),
+            ("request_seq", request_seq),
+            ("command", command),
+        ];
+        match result {
+            Ok(body) => {
+                fields.push(("success", true.into()));
+                if body != Json::Null {
+                    fields.push(("body", body));
+                }
+            }
+            Err(message) => {
+                fields.push(("success", false.into()));
+                fields.push(("message", message.into()));
+            }
+        }
+        self.send(fields)
+    }
+
+    fn event(&mut self, event: &str, body: Json) -> io::Result<()> {
+        let mut fields = vec![("type", "event".into()), ("event", event.into())];
+        if body != Json::Null {
+            fields.push(("body", body));
+        }
+        self.send(fields)
+    }
+
+    fn send(&mut self, fields: Vec<(&str, Json)>) -> io::Result<()> {
+        self.seq += 1;
+        let mut message = vec![("seq", self.seq.into())];
+        message.extend(fields);
+        let text = Json::obj(message).to_string();
+        write!(self.out, "Content-Length: {}\r\n\r\n{text}", text.len())?;
+        self.out.flush()
+    }
+}
+
+/// Run `debugger` the way `how` asks, collecting yielded values in `yielded`. `None` when it
+/// stopped because the step is done.
+fn run(
+    debugger: &mut Debugger,
+    how: Resume,
+    yielded: &mut Vec<Word>,
+) -> Result<Option<DebugStop>, VmError> {
+    let machine = debugger.machine();
+    let depth = machine.call_stack().len();
+    let calls = matches!(machine.instruction(machine.ip()), Some(Inst::CALL(_)));
+    // stepping over a call, or out of one, is done once the call stack is below this depth
+    let until = match how {
+        Resume::Next if calls => depth + 1,
+        Resume::StepOut if depth > 0 => depth,
+        Resume::StepIn | Resume::Next => return step(debugger, yielded),
+        Resume::Continue | Resume::StepOut => loop {
+            match debugger.continue_()? {
+                DebugStop::Yielded(val) => yielded.push(val),
+                stop => return Ok(Some(stop)),
+            }
+        },
+    };
+    loop {
+        if let Some(stop) = step(debugger, yielded)? {
+            return Ok(Some(stop));
+        }
+        let machine = debugger.machine();
+        if machine.call_stack().len() < until {
+            return Ok(None);
+        }
+        let ip = machine.ip();
+        if debugger.breakpoints().any(|at| at == ip) {
+            return Ok(Some(DebugStop::Breakpoint(ip)));
+        }
+    }
+}
+
+/// Execute one instruction, `None` unless the program stopped
+fn step(debugger: &mut Debugger, yielded: &mut Vec<Word>) -> Result<Option<DebugStop>, VmError> {
+    Ok(match debugger.step()?.status {
+        StepStatus::Halted => Some(DebugStop::Halted),
+        StepStatus::WaitingOnPort(port) => Some(DebugStop::WaitingOnPort(port)),
+        StepStatus::Yielded(val) => {
+            yielded.push(val);
+            None
+        }
+        StepStatus::Running => None,
+    })
+}
+
+fn scope(name: &str, reference: i64) -> Json {
+    Json::obj(vec![
+        ("name", name.into()),
+        ("variablesReference", reference.into()),
+        ("expensive", false.into()),
+    ])
+}
+
+fn file_name(path: &str) -> &str {
+    path.rsplit(['/', '\\']).next().unwrap_or(path)
+}
+
+/// Read the next message, a `Content-Length` header and a JSON body. `None` once the stream
+/// ends.
+fn read_message(input: &mut impl BufRead) -> io::Result<Option<Json>> {
+    let mut len = None;
+    loop {
+        let mut header = String::new();
+        if input.read_line(&mut header)? == 0 {
+            return Ok(None);
+        }
+        let header = header.trim_end();
+        if header.is_empty() {
+            if len.is_some() {
+                break;
+            }
+            continue;
+        }
+        if let Some(value) = header.strip_prefix("Content-Length:") {
+            len = value.trim().parse::<usize>().ok();
+        }
+    }
+    let mut body = vec![0; len.unwrap_or(0)];
+    input.read_exact(&mut body)?;
+    let body = String::from_utf8_lossy(&body);
+    match Json::parse(&body) {
+        Some(message) => Ok(Some(message)),
+        None => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "malformed message",
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PROGRAM: &str = "\
+set a 5
+psh 1
+call double
+out
+hlt
+double:
+  psh 2
+  mul
+  ret
+";
+
+    /// Run a session of `requests` on `PROGRAM` and return every message the adapter sent
+    fn session(name: &str, requests: &[&str]) -> Vec<Json> {
+        let path =
+            std::env::temp_dir().join(format!("vyantra-dap-{}-{name}.s", std::process::id()));
+        fs::write(&path, PROGRAM).unwrap();
+        let path = path.to_str().unwrap().replace('\\', "\\\\");
+
+        let mut input = String::new();
+        for (seq, request) in requests.iter().enumerate() {
+            let request = request.replace("PATH", &path);
+            let body = format!("{{\"seq\":{},\"type\":\"request\",{request}}}", seq + 1);
+            input.push_str(&format!("Content-Length: {}\r\n\r\n{body}", body.len()));
+        }
+        let mut output = Vec::new();
+        serve(input.as_bytes(), &mut output).unwrap();
+        fs::remove_file(path).unwrap();
+
+        let mut reader = output.as_slice();
+        let mut messages = Vec::new();
+        while let Some(message) = read_message(&mut reader).unwrap() {
+            messages.push(message);
+        }
+        messages
+    }
+
+    /// `command` of a response, `event` of an event, with `!` for a failed response
+    fn kinds(messages: &[Json]) -> Vec<String> {
+        let field =
+            |message: &Json, key| message.get(key).and_then(Json::as_str).unwrap().to_string();
+        messages
+            .iter()
+            .map(|message| match message.get("success") {
+                Some(Json::Bool(false)) => format!("!{}", field(message, "command")),
+                Some(_) => field(message, "command"),
+                None => field(message, "event"),
+            })
+            .collect()
+    }
+
+    fn body<'a>(messages: &'a [Json], idx: usize, key: &str) -> &'a Json {
+        messages[idx]
+            .get("body")
+            .and_then(|body| body.get(key))
+            .unwrap()
+    }
+
+    #[test]
+    fn breakpoints_and_variables() {
+        let messages = session(
+            "breakpoints",
+            &[
+                r#""command":"initialize","arguments":{"adapterID":"vyantra"}"#,
+                r#""command":"launch","arguments":{"program":"PATH"}"#,
+                r#""command":"setBreakpoints","arguments":{"source":{"path":"PATH"},"breakpoints":[{"line":6},{"line":20}]}"#,
+                r#""command":"configurationDone""#,
+                r#""command":"stackTrace","arguments":{"threadId":1}"#,
+                r#""command":"variables","arguments":{"variablesReference":2}"#,
+                r#""command":"stepOut","arguments":{"threadId":1}"#,
+                r#""command":"variables","arguments":{"variablesReference":1}"#,
+                r#""command":"continue","arguments":{"threadId":1}"#,
+                r#""command":"disconnect""#,
+            ],
+        );
+        assert_eq!(
+            kinds(&messages),
+            [
+                "initialize",
+                "launch",
+                "initialized",
+                "setBreakpoints",
+                "configurationDone",
+                "stopped",
+                "stackTrace",
+                "variables",
+                "stepOut",
+                "stopped",
+                "variables",
+                "continue",
+                "output",
+                "exited",
+                "terminated",
+                "disconnect",
+            ]
+        );
+
+        // the label line has no instruction, the breakpoint goes on the next one
+        let breakpoints = body(&messages, 3, "breakpoints").as_arr().unwrap();
+        assert_eq!(breakpoints[0].to_string(), r#"{"verified":true,"line":7}"#);
+        assert_eq!(
+            breakpoints[1].to_string(),
+            r#"{"verified":false,"line":20}"#
+        );
+        assert_eq!(body(&messages, 5, "reason").as_str(), Some("breakpoint"));
+
+        // the breakpoint inside the call, and the call itself
+        let frames = body(&messages, 6, "stackFrames").as_arr().unwrap();
+        let lines: Vec<_> = frames
+            .iter()
+            .map(|f| f.get("line").unwrap().to_string())
+            .collect();
+        assert_eq!(lines, ["7", "3"]);
+        assert_eq!(frames[1].get("name").unwrap().as_str(), Some("2: call 3"));
+
+        let stack = body(&messages, 7, "variables").to_string();
+        assert_eq!(
+            stack,
+            r#"[{"name":"stk[0]","value":"1","variablesReference":0}]"#
+        );
+
+        assert_eq!(body(&messages, 9, "reason").as_str(), Some("step"));
+        let registers = body(&messages, 10, "variables").as_arr().unwrap();
+        assert_eq!(
+            registers[0].to_string(),
+            r#"{"name":"a","value":"5","variablesReference":0}"#
+        );
+
+        assert_eq!(body(&messages, 12, "output").as_str(), Some("2\n"));
+        assert_eq!(body(&messages, 13, "exitCode").to_string(), "0");
+    }
+
+    #[test]
+    fn stepping() {
+        let messages = session(
+            "stepping",
+            &[
+                r#""command":"initialize""#,
+                r#""command":"launch","arguments":{"program":"PATH","stopOnEntry":true}"#,
+                r#""command":"configurationDone""#,
+                r#""command":"next""#,
+                r#""command":"next""#,
+                r#""command":"next""#,
+                r#""command":"stackTrace""#,
+                r#""command":"stepIn""#,
+                r#""command":"stepIn""#,
+                r#""command":"evaluate""#,
+            ],
+        );
+        assert_eq!(
+            kinds(&messages),
+            [
+                "initialize",
+                "launch",
+                "initialized",
+                "configurationDone",
+                "stopped",
+                "next",
+                "stopped",
+                "next",
+                "stopped",
+                "next",
+                "stopped",
+                "stackTrace",
+                "stepIn",
+                "output",
+                "stopped",
+                "stepIn",
+                "exited",
+                "terminated",
+                "!evaluate",
+            ]
+        );
+        assert_eq!(body(&messages, 4, "reason").as_str(), Some("entry"));
+        // stepping over the call lands on the `out` after it
+        let frames = body(&messages, 11, "stackFrames").as_arr().unwrap();
+        assert_eq!(frames.len(), 1);
+        assert_eq!(frames[0].get("line").unwrap().to_string(), "4");
+    }
+}
@@ -0,0 +1,414 @@
+//! A machine under a debugger: breakpoints, stepping and inspection while paused.
+
+use alloc::collections::{BTreeSet, VecDeque};
+use alloc::vec::Vec;
+
+use crate::step::PastStep;
+use crate::{Inst, Machine, Path, StepOutcome, StepStatus, VmError, Word};
+
+/// Why [`Debugger::continue_`] stopped.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum DebugStop {
+    /// The next instruction to execute is the breakpoint at this index
+    Breakpoint(usize),
+
+    /// The program yielded a value with `YLD`
+    Yielded(Word),
+
+    /// An instruction changed a watched location
+    Watchpoint(WatchHit),
+
+    /// `RCV` found nothing on this port
+    WaitingOnPort(u8),
+
+    Halted,
+}
+
+/// A change to a watched location. A value is `None` where the location did not exist, such as
+/// a stack slot before the push that made it.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct WatchHit {
+    pub path: Path,
+    pub old: Option<Word>,
+    pub new: Option<Word>,
+
+    /// The instruction that changed it, and its index
+    pub ip: usize,
+    pub inst: Inst,
+}
+
+/// Wraps a [`Machine`] to run it up to breakpoints. While the debugger is paused the machine
+/// can be read through [`Debugger::machine`], and stepped one instruction at a time.
+pub struct Debugger {
+    machine: Machine,
+    breakpoints: BTreeSet<usize>,
+    watchpoints: Vec<Path>,
+
+    /// Where the debugger last paused, so continuing from there does not stop again at once
+    paused_at: Option<usize>,
+
+    /// What it takes to undo each of the last executed instructions, the latest last
+    history: VecDeque<PastStep>,
+
+    /// Most instructions kept in `history`, zero when not recording
+    history_limit: usize,
+}
+
+impl Debugger {
+    pub fn new(machine: Machine) -> Self {
+        Debugger {
+            machine,
+            breakpoints: BTreeSet::new(),
+            watchpoints: Vec::new(),
+            paused_at: None,
+            history: VecDeque::new(),
+            history_limit: 0,
+        }
+    }
+
+    /// Stop before executing the instruction at `ip`
+    pub fn add_breakpoint(&mut self, ip: usize) {
+        self.breakpoints.insert(ip);
+    }
+
+    /// Remove the breakpoint at `ip`, returning whether there was one
+    pub fn remove_breakpoint(&mut self, ip: usize) -> bool {
+        self.breakpoints.remove(&ip)
+    }
+
+    /// Instruction indices with a breakpoint, in order
+    pub fn breakpoints(&self) -> impl Iterator<Item = usize> + '_ {
+        self.breakpoints.iter().copied()
+    }
+
+    /// Stop after any instruction that changes the value at `path`, a register or a stack slot.
+    /// A stack slot is found the way the instruction operands find it, so `Path::STK(0)` watches
+    /// whatever is at the head of the stack.
+    pub fn add_watchpoint(&mut self, path: Path) {
+        if !self.watchpoints.contains(&path) {
+            self.watchpoints.push(path);
+        }
+    }
+
+    /// Remove the watchpoint on `path`, returning whether there was one
+    pub fn remove_watchpoint(&mut self, path: Path) -> bool {
+        let len = self.watchpoints.len();
+        self.watchpoints.retain(|watched| *watched != path);
+        self.watchpoints.len() != len
+    }
+
+    /// Run until the next breakpoint or watchpoint, or until the program halts, yields or waits
+    /// on a port. Continuing from a breakpoint, or after a [`Debugger::step`], executes the
+    /// instruction the machine is on even if it has a breakpoint. An instruction that yields,
+    /// halts or waits stops the run for that reason even if it also changed a watched location.
+    /// A program that never stops runs forever.
+    pub fn continue_(&mut self) -> Result<DebugStop, VmError> {
+        if self.machine.exit_code().is_some() {
+            return Ok(DebugStop::Halted);
+        }
+        loop {
+            let ip = self.machine.ip();
+            if self.breakpoints.contains(&ip) && self.paused_at != Some(ip) {
+                self.paused_at = Some(ip);
+                return Ok(DebugStop::Breakpoint(ip));
+            }
+            self.paused_at = None;
+            let inst = self.machine.instruction(ip);
+            let before = self.watched_values();
+            match self.step_recorded()?.status {
+                StepStatus::Running => {
+                    if let Some(hit) = inst.and_then(|inst| self.watch_hit(&before, ip, inst)) {
+                        return Ok(DebugStop::Watchpoint(hit));
+                    }
+                }
+                StepStatus::Yielded(val) => return Ok(DebugStop::Yielded(val)),
+                StepStatus::WaitingOnPort(port) => return Ok(DebugStop::WaitingOnPort(port)),
+                StepStatus::Halted => return Ok(DebugStop::Halted),
+            }
+        }
+    }
+
+    fn watched_values(&self) -> Vec<Option<Word>> {
+        let watched = self.watchpoints.iter();
+        watched
+            .map(|path| self.machine.get_from_path(*path).ok())
+            .collect()
+    }
+
+    /// The first watched location whose value is no longer the one in `before`, changed by
+    /// `inst` at `ip`
+    fn watch_hit(&self, before: &[Option<Word>], ip: usize, inst: Inst) -> Option<WatchHit> {
+        let after = self.watched_values();
+        let i = (0..before.len()).find(|&i| before[i] != after[i])?;
+        Some(WatchHit {
+            path: self.watchpoints[i],
+            old: before[i],
+            new: after[i],
+            ip,
+            inst,
+        })
+    }
+
+    /// Execute one instruction, ignoring breakpoints and watchpoints
+    pub fn step(&mut self) -> Result<StepOutcome, VmError> {
+        let outcome = self.step_recorded();
+        self.paused_at = Some(self.machine.ip());
+        outcome
+    }
+
+    /// Record each executed instruction, keeping the last `limit` of them, so that
+    /// [`Debugger::step_back`] and [`Debugger::reverse_continue`] can go back that far. A limit
+    /// of zero stops recording and forgets the history. An instruction is recorded as its
+    /// [`StateDelta`](crate::StateDelta), with the memory or heap word it wrote and the call it
+    /// returned from, so a step costs the values it changed. A `SYS`, an `ALLOC` and every
+    /// instruction of a typed machine are recorded as a [`MachineSnapshot`](crate::MachineSnapshot)
+    /// of the state before them instead.
+    ///
+    /// Going back undoes what the instructions did, not the world's state nor changes made
+    /// through [`Debugger::machine_mut`]: output already written, input already read and
+    /// values sent on ports stay as they are.
+    pub fn record_history(&mut self, limit: usize) {
+        self.history_limit = limit;
+        while self.history.len() > limit {
+            self.history.pop_front();
+        }
+    }
+
+    /// Undo the last executed instruction, returning false if there is no recorded state to go
+    /// back to
+    pub fn step_back(&mut self) -> bool {
+        match self.history.pop_back() {
+            Some(past) => {
+                self.undo(&past);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Go back until the machine is on a breakpoint, returning its index, or until the history
+    /// runs out, returning `None` with the machine in the oldest recorded state
+    pub fn reverse_continue(&mut self) -> Option<usize> {
+        while let Some(past) = self.history.pop_back() {
+            self.undo(&past);
+            let ip = self.machine.ip();
+            if self.breakpoints.contains(&ip) {
+                return Some(ip);
+            }
+        }
+        None
+    }
+
+    fn undo(&mut self, past: &PastStep) {
+        self.machine.undo_step(past);
+        self.paused_at = Some(self.machine.ip());
+    }
+
+    fn step_recorded(&mut self) -> Result<StepOutcome, VmError> {
+        if self.history_limit == 0 {
+            return self.machine.step();
+        }
+        if self.history.len() == self.history_limit {
+            self.history.pop_front();
+        }
+        let (outcome, past) = self.machine.step_undoable();
+        self.history.push_back(past);
+        outcome
+    }
+
+    /// The machine, for inspecting its state while paused
+    pub fn machine(&self) -> &Machine {
+        &self.machine
+    }
+
+    pub fn machine_mut(&mut self) -> &mut Machine {
+        &mut self.machine
+    }
+
+    /// Stop debugging and hand the machine back
+    pub fn into_inner(self) -> Machine {
+        self.machine
+    }
+}
+
+#[cfg(all(test, feature = "std"))]
+mod tests {
+    use super::*;
+    use crate::{testing, Inst, Reg};
+
+    #[test]
+    fn breakpoints() {
+        let program = vec![
+            Inst::SET(Reg::C, 3),
+            Inst::PSH(1),
+            Inst::LOOP(Reg::C, -1),
+            Inst::YLD,
+            Inst::HLT,
+        ];
+        let mut debugger = Debugger::new(Machine::new(program));
+        debugger.add_breakpoint(1);
+        debugger.add_breakpoint(4);
+
+        assert_eq!(debugger.continue_(), Ok(DebugStop::Breakpoint(1)));
+        testing::assert_stack_eq(debugger.machine(), &[]);
+        assert_eq!(debugger.continue_(), Ok(DebugStop::Breakpoint(1)));
+        testing::assert_stack_eq(debugger.machine(), &[1]);
+        testing::assert_reg(debugger.machine(), Reg::C, 2);
+
+        assert!(debugger.remove_breakpoint(1));
+        assert!(!debugger.remove_breakpoint(1));
+        assert_eq!(debugger.breakpoints().collect::<Vec<_>>(), [4]);
+        assert_eq!(debugger.continue_(), Ok(DebugStop::Yielded(1)));
+        assert_eq!(debugger.continue_(), Ok(DebugStop::Breakpoint(4)));
+
+        debugger.step().unwrap();
+        assert_eq!(debugger.continue_(), Ok(DebugStop::Halted));
+        let machine = debugger.into_inner();
+        testing::assert_stack_eq(&machine, &[1, 1]);
+    }
+
+    #[test]
+    fn going_back() {
+        let program = vec![
+            Inst::SET(Reg::C, 3),
+            Inst::PSH(1),
+            Inst::STORE(0),
+            Inst::LOOP(Reg::C, -2),
+            Inst::HLT,
+        ];
+        let mut debugger = Debugger::new(Machine::new(program));
+        assert!(!debugger.step_back());
+        debugger.record_history(100);
+        debugger.add_breakpoint(2);
+        assert_eq!(debugger.continue_(), Ok(DebugStop::Breakpoint(2)));
+        assert_eq!(debugger.continue_(), Ok(DebugStop::Breakpoint(2)));
+        debugger.remove_breakpoint(2);
+        assert_eq!(debugger.continue_(), Ok(DebugStop::Halted));
+
+        // back over the halt and the last loop
+        assert!(debugger.step_back());
+        assert!(debugger.step_back());
+        assert_eq!(debugger.machine().exit_code(), None);
+        testing::assert_reg(debugger.machine(), Reg::C, 1);
+        debugger.add_breakpoint(2);
+        assert_eq!(debugger.reverse_continue(), Some(2));
+        assert_eq!(debugger.machine().instructions(), 8);
+        assert_eq!(debugger.machine().memory()[0], 1);
+        testing::assert_stack_eq(debugger.machine(), &[1]);
+
+        // continuing from where it went back to runs the program again
+        assert_eq!(debugger.continue_(), Ok(DebugStop::Halted));
+        assert_eq!(debugger.reverse_continue(), Some(2));
+        assert_eq!(debugger.reverse_continue(), Some(2));
+        assert_eq!(debugger.reverse_continue(), Some(2));
+        assert_eq!(debugger.reverse_continue(), None);
+        assert_eq!(debugger.machine().instructions(), 0);
+
+        // only the last few states are kept
+        debugger.record_history(2);
+        for _ in 0..5 {
+            debugger.step().unwrap();
+        }
+        assert!(debugger.step_back() && debugger.step_back());
+        assert!(!debugger.step_back());
+        assert_eq!(debugger.machine().instructions(), 3);
+    }
+
+    #[test]
+    fn going_back_restores_every_state() {
+        let program = vec![
+            Inst::PSH(3),
+            Inst::CALL(3),
+            Inst::STORE(1),
+            Inst::HLT,
+            Inst::ALLOC(2),
+            Inst::PSH(1),
+            Inst::PSH(7),
+            Inst::AIDXSTORE,
+            Inst::PSH(5),
+            Inst::CMP,
+            Inst::STOREL(-1),
+            Inst::SETP(Path::STK(0), 9),
+            Inst::INC(Reg::A),
+            Inst::RET,
+        ];
+        let mut debugger = Debugger::new(Machine::new(program));
+        debugger.record_history(100);
+        let mut states = vec![];
+        while debugger.machine().exit_code().is_none() {
+            states.push(debugger.machine().snapshot());
+            debugger.step().unwrap();
+        }
+        assert_eq!(debugger.machine().memory()[1], 9);
+        for state in states.iter().rev() {
+            assert!(debugger.step_back());
+            assert_eq!(&debugger.machine().snapshot(), state);
+        }
+        assert!(!debugger.step_back());
+
+        // what a failed instruction did before failing is undone too
+        let mut debugger = Debugger::new(Machine::new(vec![Inst::PSH(0), Inst::JEZ(10)]));
+        debugger.record_history(100);
+        debugger.step().unwrap();
+        assert!(!debugger.step().unwrap_err().is_recoverable());
+        testing::assert_stack_eq(debugger.machine(), &[]);
+        assert!(debugger.step_back());
+        assert_eq!(debugger.machine().ip(), 1);
+        testing::assert_stack_eq(debugger.machine(), &[0]);
+    }
+
+    #[test]
+    fn watchpoints() {
+        let program = vec![
+            Inst::SET(Reg::A, 1),
+            Inst::PSH(5),
+            Inst::PSH(6),
+            Inst::CPY(Path::STK(1), Path::REG(Reg::A)),
+            Inst::SET(Reg::A, 1),
+            Inst::INC(Reg::A),
+            Inst::HLT,
+        ];
+        let mut debugger = Debugger::new(Machine::new(program));
+        debugger.add_watchpoint(Path::REG(Reg::A));
+        debugger.add_watchpoint(Path::STK(1));
+        debugger.add_watchpoint(Path::STK(1));
+
+        let hit = |path, old, new, ip, inst| {
+            Ok(DebugStop::Watchpoint(WatchHit {
+                path,
+                old,
+                new,
+                ip,
+                inst,
+            }))
+        };
+        let a = Path::REG(Reg::A);
+        assert_eq!(
+            debugger.continue_(),
+            hit(a, Some(0), Some(1), 0, Inst::SET(Reg::A, 1))
+        );
+        // the slot below the head only exists after the second push
+        assert_eq!(
+            debugger.continue_(),
+            hit(Path::STK(1), None, Some(5), 2, Inst::PSH(6))
+        );
+        assert_eq!(
+            debugger.continue_(),
+            hit(
+                Path::STK(1),
+                Some(5),
+                Some(1),
+                3,
+                Inst::CPY(Path::STK(1), a)
+            )
+        );
+
+        // writing the value a location already has is not a change
+        assert!(debugger.remove_watchpoint(Path::STK(1)));
+        assert_eq!(
+            debugger.continue_(),
+            hit(a, Some(1), Some(2), 5, Inst::INC(Reg::A))
+        );
+        assert_eq!(debugger.continue_(), Ok(DebugStop::Halted));
+    }
+}
@@ -0,0 +1,1494 @@
+//! Fixed width encoding of instructions as 64 bit words.
+//!
+//! The opcode lives in the top byte. Operands are packed below it:
+//!
+//! * an immediate (which must fit an `i32`, also with 64 bit words), a jump offset, a local
+//!   slot, a data memory address, an array length or a table id takes the low 32 bits, and so
+//!   does a float, as an `f32` (with 64 bit words, only floats that are `f32`s without rounding
+//!   encode)
+//! * a register takes bits 32..48 (`A` to `F` are 0 to 5, `R(n)` is `0x100 + n`)
+//! * the condition of `JF` takes bits 32..40, `EQ` to `GEU` in declaration order are 0 to 7
+//! * the path of `SETP` takes bits 32..56, the two paths of `CPY` take bits 28..56 (destination)
+//!   and 0..28 (source)
+//!
+//! A path field starts with a two bit kind (`REG`, `STK`, `STKR`) followed by the register
+//! and/or a signed offset in the remaining bits. Every bit not used by an operand must be zero.
+//!
+//! A whole [`Program`] encodes as a header of seven words (a magic number then the lengths of
+//! the code, the constant pool, the data segment, the trap table, the import table and the
+//! string table), followed by one word per instruction, one word per pool entry and data word,
+//! sign extended to 64 bits, the trap table, the import table and the string table. Earlier
+//! versions of the format had no string table and, before that, kept pool entries and data
+//! words in the low 32 bits. Each trap message, import name and string is a word holding its
+//! length in bytes followed by its UTF-8 bytes, eight to a word in little endian order, the
+//! last word padded with zeros.
+//!
+//! Instructions alone also have a compact byte format, [`encode_program`] and
+//! [`decode_program`]: the opcode byte followed by the operands in order. Numbers are LEB128
+//! (zigzag for signed ones), a float is its bits as a number, a register is its code as a
+//! number, a port is one byte, a path is a kind byte followed by its register and/or offset,
+//! and a condition is one byte. Any value encodes, so this format never fails to encode.
+
+use alloc::boxed::Box;
+use alloc::string::String;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::fmt;
+
+use crate::asm::Program;
+use crate::{float_word, Cond, Float, Inst, Path, Reg, UWord, Word};
+
+/// First word of an encoded program, "vyantra" and a format version
+const MAGIC: u64 = u64::from_be_bytes(*b"vyantra\x04");
+
+/// First word of a program in the third version of the format, before string tables
+const MAGIC_V3: u64 = u64::from_be_bytes(*b"vyantra\x03");
+
+/// First word of a program in the second version of the format, before 64 bit words
+const MAGIC_V2: u64 = u64::from_be_bytes(*b"vyantra\x02");
+
+/// First word of a program in the first version of the format, before import tables
+const MAGIC_V1: u64 = u64::from_be_bytes(*b"vyantra\x01");
+
+/// An instruction with an operand too large for its field in the word format.
+#[derive(Clone, Debug, PartialEq)]
+pub struct EncodeError(pub Inst);
+
+#[cfg(feature = "std")]
+impl std::error::Error for EncodeError {}
+
+impl fmt::Display for EncodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "operand out of range for the word encoding in {:?}",
+            self.0
+        )
+    }
+}
+
+/// Malformed encoded instructions.
+#[derive(Clone, Debug, PartialEq)]
+pub enum DecodeError {
+    UnknownOpcode(u8),
+
+    /// A register field holds no register
+    BadRegister(u64),
+
+    /// A path field holds no path
+    BadPath(u64),
+
+    /// A condition field of `JF` holds no condition
+    BadCondition(u64),
+
+    /// Bits that no operand uses are set
+    ReservedBits(u64),
+
+    /// An encoded program does not start with the expected magic number
+    BadMagic(u64),
+
+    /// An encoded program is shorter or longer than its header says
+    Length {
+        expected: usize,
+        found: usize,
+    },
+
+    /// A trap message that is cut short or not UTF-8
+    BadString,
+
+    /// The bytes end in the middle of an instruction
+    Truncated,
+
+    /// The number starting at this byte does not fit its operand
+    BadNumber(usize),
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for DecodeError {}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DecodeError::UnknownOpcode(op) => write!(f, "unknown opcode {op:#04x}"),
+
+            DecodeError::BadRegister(field) => write!(f, "invalid register field {field:#x}"),
+
+            DecodeError::BadPath(field) => write!(f, "invalid path field {field:#x}"),
+
+            DecodeError::BadCondition(field) => write!(f, "invalid condition field {field:#x}"),
+
+            DecodeError::ReservedBits(word) => {
+                write!(f, "reserved bits are set in instruction word {word:#018x}")
+            }
+
+            DecodeError::BadMagic(word) => write!(f, "not an encoded program, starts {word:#018x}"),
+
+            DecodeError::Length { expected, found } => {
+                write!(
+                    f,
+                    "encoded program should be {expected} words, found {found}"
+                )
+            }
+
+            DecodeError::BadString => write!(f, "malformed trap message"),
+
+            DecodeError::Truncated => write!(f, "encoded program ends inside an instruction"),
+
+            DecodeError::BadNumber(at) => write!(f, "number at byte {at} is out of range"),
+        }
+    }
+}
+
+pub(crate) mod op {
+    pub const PSH: u8 = 0x01;
+    pub const PSHC: u8 = 0x04;
+    pub const POP: u8 = 0x02;
+    pub const CLR: u8 = 0x03;
+    pub const DUP: u8 = 0x05;
+    pub const SWAP: u8 = 0x06;
+    pub const OVER: u8 = 0x07;
+    pub const ROT: u8 = 0x08;
+    pub const DROP: u8 = 0x09;
+    pub const ADD: u8 = 0x10;
+    pub const SUB: u8 = 0x11;
+    pub const MUL: u8 = 0x12;
+    pub const DIV: u8 = 0x13;
+    pub const DIVF: u8 = 0x14;
+    pub const MODF: u8 = 0x15;
+    pub const DIVU: u8 = 0x16;
+    pub const MODU: u8 = 0x17;
+    pub const CMP: u8 = 0x18;
+    pub const AND: u8 = 0x19;
+    pub const OR: u8 = 0x1a;
+    pub const XOR: u8 = 0x1b;
+    pub const NOT: u8 = 0x1c;
+    pub const SHL: u8 = 0x1d;
+    pub const SHR: u8 = 0x1e;
+    pub const SET: u8 = 0x20;
+    pub const SETP: u8 = 0x21;
+    pub const CPY: u8 = 0x22;
+    pub const LOAD: u8 = 0x23;
+    pub const STORE: u8 = 0x24;
+    pub const LOADL: u8 = 0x25;
+    pub const STOREL: u8 = 0x26;
+    pub const LOADR: u8 = 0x27;
+    pub const STORER: u8 = 0x28;
+    pub const INC: u8 = 0x29;
+    pub const DEC: u8 = 0x2a;
+    pub const JMP: u8 = 0x30;
+    pub const LOOP: u8 = 0x31;
+    pub const TBL: u8 = 0x32;
+    pub const JEZ: u8 = 0x34;
+    pub const JNZ: u8 = 0x35;
+    pub const JLT: u8 = 0x36;
+    pub const JGT: u8 = 0x37;
+    pub const JLE: u8 = 0x38;
+    pub const JGE: u8 = 0x39;
+    pub const JF: u8 = 0x3a;
+    pub const CALL: u8 = 0x3b;
+    pub const RET: u8 = 0x3c;
+    pub const TRAP: u8 = 0x33;
+    pub const MOD: u8 = 0x1f;
+    pub const NEG: u8 = 0x50;
+    pub const ABS: u8 = 0x51;
+    pub const MIN: u8 = 0x52;
+    pub const MAX: u8 = 0x53;
+    pub const QMUL: u8 = 0x54;
+    pub const QDIV: u8 = 0x55;
+    pub const FPSH: u8 = 0x60;
+    pub const FADD: u8 = 0x61;
+    pub const FSUB: u8 = 0x62;
+    pub const FMUL: u8 = 0x63;
+    pub const FDIV: u8 = 0x64;
+    pub const ITOF: u8 = 0x65;
+    pub const FTOI: u8 = 0x66;
+    pub const EXIT: u8 = 0x3e;
+    pub const HLT: u8 = 0x3f;
+    pub const IN: u8 = 0x40;
+    pub const YLD: u8 = 0x41;
+    pub const SND: u8 = 0x42;
+    pub const RCV: u8 = 0x43;
+    pub const OUT: u8 = 0x44;
+    pub const SYS: u8 = 0x45;
+    pub const HCALL: u8 = 0x46;
+    pub const PRINTS: u8 = 0x47;
+    pub const ALLOC: u8 = 0x70;
+    pub const AIDXLOAD: u8 = 0x71;
+    pub const AIDXSTORE: u8 = 0x72;
+}
+
+const REG_SHIFT: u32 = 32;
+const SETP_PATH_BITS: u32 = 24;
+const CPY_PATH_BITS: u32 = 28;
+const REG_BITS: u32 = 9;
+
+const PATH_REG: u64 = 0;
+const PATH_STK: u64 = 1;
+const PATH_STKR: u64 = 2;
+
+impl Inst {
+    /// Encode as a single word, failing if an offset does not fit its field
+    pub fn to_word(self) -> Result<u64, EncodeError> {
+        let err = || EncodeError(self);
+        let with = |op: u8, operands: u64| (op as u64) << 56 | operands;
+        let field = |val: i32| val as u32 as u64;
+        // the conversion can not fail with 32 bit words
+        #[allow(clippy::useless_conversion)]
+        let imm = |val: Word| i32::try_from(val).map(field).map_err(|_| err());
+        let offset = |step: isize| i32::try_from(step).map(field).map_err(|_| err());
+
+        let word = match self {
+            Inst::PSH(val) => with(op::PSH, imm(val)?),
+            Inst::PSHC(idx) => with(op::PSHC, idx as u64),
+            Inst::FPSH(val) => with(op::FPSH, float_field(val).ok_or_else(err)?),
+            Inst::FADD => with(op::FADD, 0),
+            Inst::FSUB => with(op::FSUB, 0),
+            Inst::FMUL => with(op::FMUL, 0),
+            Inst::FDIV => with(op::FDIV, 0),
+            Inst::ITOF => with(op::ITOF, 0),
+            Inst::FTOI => with(op::FTOI, 0),
+            Inst::POP => with(op::POP, 0),
+            Inst::DUP => with(op::DUP, 0),
+            Inst::SWAP => with(op::SWAP, 0),
+            Inst::OVER => with(op::OVER, 0),
+            Inst::ROT => with(op::ROT, 0),
+            Inst::DROP(count) => with(op::DROP, addr_field(count).ok_or_else(err)?),
+            Inst::IN => with(op::IN, 0),
+            Inst::OUT => with(op::OUT, 0),
+            Inst::PRINTS(id) => with(op::PRINTS, id as u64),
+            Inst::YLD => with(op::YLD, 0),
+            Inst::SND(port) => with(op::SND, port as u64),
+            Inst::RCV(port) => with(op::RCV, port as u64),
+            Inst::CLR => with(op::CLR, 0),
+            Inst::ADD => with(op::ADD, 0),
+            Inst::SUB => with(op::SUB, 0),
+            Inst::MUL => with(op::MUL, 0),
+            Inst::DIV => with(op::DIV, 0),
+            Inst::DIVF => with(op::DIVF, 0),
+            Inst::MODF => with(op::MODF, 0),
+            Inst::DIVU => with(op::DIVU, 0),
+            Inst::MODU => with(op::MODU, 0),
+            Inst::AND => with(op::AND, 0),
+            Inst::OR => with(op::OR, 0),
+            Inst::XOR => with(op::XOR, 0),
+            Inst::NOT => with(op::NOT, 0),
+            Inst::SHL => with(op::SHL, 0),
+            Inst::SHR => with(op::SHR, 0),
+            Inst::MOD => with(op::MOD, 0),
+            Inst::NEG => with(op::NEG, 0),
+            Inst::ABS => with(op::ABS, 0),
+            Inst::MIN => with(op::MIN, 0),
+            Inst::MAX => with(op::MAX, 0),
+            Inst::QMUL => with(op::QMUL, 0),
+            Inst::QDIV => with(op::QDIV, 0),
+            Inst::CMP => with(op::CMP, 0),
+            Inst::SET(reg, val) => with(op::SET, reg_code(reg) << REG_SHIFT | imm(val)?),
+            Inst::INC(reg) => with(op::INC, reg_code(reg) << REG_SHIFT),
+            Inst::DEC(reg) => with(op::DEC, reg_code(reg) << REG_SHIFT),
+            Inst::SETP(path, val) => {
+                let path = encode_path(path, SETP_PATH_BITS).ok_or_else(err)?;
+                with(op::SETP, path << 32 | imm(val)?)
+            }
+            Inst::CPY(dst, src) => {
+                let dst = encode_path(dst, CPY_PATH_BITS).ok_or_else(err)?;
+                let src = encode_path(src, CPY_PATH_BITS).ok_or_else(err)?;
+                with(op::CPY, dst << CPY_PATH_BITS | src)
+            }
+            Inst::LOAD(addr) => with(op::LOAD, addr_field(addr).ok_or_else(err)?),
+            Inst::STORE(addr) => with(op::STORE, addr_field(addr).ok_or_else(err)?),
+            Inst::JMP(step) => with(op::JMP, offset(step)?),
+            Inst::LOOP(reg, step) => with(op::LOOP, reg_code(reg) << REG_SHIFT | offset(step)?),
+            Inst::JEZ(step) => with(op::JEZ, offset(step)?),
+            Inst::JNZ(step) => with(op::JNZ, offset(step)?),
+            Inst::JLT(step) => with(op::JLT, offset(step)?),
+            Inst::JGT(step) => with(op::JGT, offset(step)?),
+            Inst::JLE(step) => with(op::JLE, offset(step)?),
+            Inst::JGE(step) => with(op::JGE, offset(step)?),
+            Inst::JF(cond, step) => with(op::JF, cond_code(cond) << 32 | offset(step)?),
+            Inst::TBL(id) => with(op::TBL, addr_field(id).ok_or_else(err)?),
+            Inst::TRAP(code) => with(op::TRAP, code as u64),
+            Inst::SYS(number) => with(op::SYS, number as u64),
+            Inst::HCALL(idx) => with(op::HCALL, idx as u64),
+            Inst::CALL(step) => with(op::CALL, offset(step)?),
+            Inst::LOADR(reg, addr) => with(
+                op::LOADR,
+                reg_code(reg) << REG_SHIFT | addr_field(addr).ok_or_else(err)?,
+            ),
+            Inst::STORER(reg, addr) => with(
+                op::STORER,
+                reg_code(reg) << REG_SHIFT | addr_field(addr).ok_or_else(err)?,
+            ),
+            Inst::ALLOC(len) => with(op::ALLOC, addr_field(len).ok_or_else(err)?),
+            Inst::AIDXLOAD => with(op::AIDXLOAD, 0),
+            Inst::AIDXSTORE => with(op::AIDXSTORE, 0),
+            Inst::LOADL(slot) => with(op::LOADL, offset(slot)?),
+            Inst::STOREL(slot) => with(op::STOREL, offset(slot)?),
+            Inst::RET => with(op::RET, 0),
+            Inst::HLT => with(op::HLT, 0),
+            Inst::EXIT(code) => with(op::EXIT, field(code)),
+        };
+        Ok(word)
+    }
+
+    /// Decode a word made by [`Inst::to_word`]
+    pub fn from_word(word: u64) -> Result<Inst, DecodeError> {
+        let opcode = (word >> 56) as u8;
+        let operands = word & ((1 << 56) - 1);
+        let imm = word as u32 as i32 as Word;
+        let reg = || decode_reg(operands >> REG_SHIFT);
+
+        // operand bits each opcode uses, everything else must be clear
+        let used: u64 = match opcode {
+            op::POP
+            | op::DUP
+            | op::SWAP
+            | op::OVER
+            | op::ROT
+            | op::IN
+            | op::OUT
+            | op::YLD
+            | op::CLR
+            | op::ADD
+            | op::SUB
+            | op::MUL
+            | op::DIV
+            | op::DIVF
+            | op::MODF
+            | op::DIVU
+            | op::MODU
+            | op::AND
+            | op::OR
+            | op::XOR
+            | op::NOT
+            | op::SHL
+            | op::SHR
+            | op::MOD
+            | op::NEG
+            | op::ABS
+            | op::MIN
+            | op::MAX
+            | op::QMUL
+            | op::QDIV
+            | op::FADD
+            | op::FSUB
+            | op::FMUL
+            | op::FDIV
+            | op::ITOF
+            | op::FTOI
+            | op::AIDXLOAD
+            | op::AIDXSTORE
+            | op::CMP
+            | op::RET
+            | op::HLT => 0,
+            op::PSH
+            | op::EXIT
+            | op::JMP
+            | op::JEZ
+            | op::JNZ
+            | op::JLT
+            | op::JGT
+            | op::JLE
+            | op::JGE
+            | op::CALL
+            | op::LOADL
+            | op::STOREL
+            | op::TBL
+            | op::LOAD
+            | op::STORE
+            | op::DROP
+            | op::ALLOC
+            | op::FPSH
+            | op::SYS => u32::MAX as u64,
+            op::SND | op::RCV => u8::MAX as u64,
+            op::PSHC | op::TRAP | op::HCALL | op::PRINTS => u16::MAX as u64,
+            op::SET | op::LOOP | op::LOADR | op::STORER => (1 << (REG_SHIFT + 16)) - 1,
+            op::INC | op::DEC => ((1 << 16) - 1) << REG_SHIFT,
+            op::JF => (1 << 40) - 1,
+            op::SETP => (1 << (32 + SETP_PATH_BITS)) - 1,
+            op::CPY => (1 << (2 * CPY_PATH_BITS)) - 1,
+            _ => return Err(DecodeError::UnknownOpcode(opcode)),
+        };
+        if operands & !used != 0 {
+            return Err(DecodeError::ReservedBits(word));
+        }
+
+        let inst = match opcode {
+            op::PSH => Inst::PSH(imm),
+            op::EXIT => Inst::EXIT(word as u32 as i32),
+            op::PSHC => Inst::PSHC(word as u16),
+            op::FPSH => Inst::FPSH(f32::from_bits(word as u32) as Float),
+            op::FADD => Inst::FADD,
+            op::FSUB => Inst::FSUB,
+            op::FMUL => Inst::FMUL,
+            op::FDIV => Inst::FDIV,
+            op::ITOF => Inst::ITOF,
+            op::FTOI => Inst::FTOI,
+            op::POP => Inst::POP,
+            op::DUP => Inst::DUP,
+            op::SWAP => Inst::SWAP,
+            op::OVER => Inst::OVER,
+            op::ROT => Inst::ROT,
+            op::IN => Inst::IN,
+            op::OUT => Inst::OUT,
+            op::PRINTS => Inst::PRINTS(word as u16),
+            op::YLD => Inst::YLD,
+            op::SND => Inst::SND(word as u8),
+            op::RCV => Inst::RCV(word as u8),
+            op::CLR => Inst::CLR,
+            op::ADD => Inst::ADD,
+            op::SUB => Inst::SUB,
+            op::MUL => Inst::MUL,
+            op::DIV => Inst::DIV,
+            op::DIVF => Inst::DIVF,
+            op::MODF => Inst::MODF,
+            op::DIVU => Inst::DIVU,
+            op::MODU => Inst::MODU,
+            op::AND => Inst::AND,
+            op::OR => Inst::OR,
+            op::XOR => Inst::XOR,
+            op::NOT => Inst::NOT,
+            op::SHL => Inst::SHL,
+            op::SHR => Inst::SHR,
+            op::MOD => Inst::MOD,
+            op::NEG => Inst::NEG,
+            op::ABS => Inst::ABS,
+            op::MIN => Inst::MIN,
+            op::MAX => Inst::MAX,
+            op::QMUL => Inst::QMUL,
+            op::QDIV => Inst::QDIV,
+            op::CMP => Inst::CMP,
+            op::SET => Inst::SET(reg()?, imm),
+            op::INC => Inst::INC(reg()?),
+            op::DEC => Inst::DEC(reg()?),
+            op::SETP => Inst::SETP(decode_path(operands >> 32, SETP_PATH_BITS)?, imm),
+            op::CPY => {
+                let mask = (1 << CPY_PATH_BITS) - 1;
+                Inst::CPY(
+                    decode_path(operands >> CPY_PATH_BITS & mask, CPY_PATH_BITS)?,
+                    decode_path(operands & mask, CPY_PATH_BITS)?,
+                )
+            }
+            op::LOAD => Inst::LOAD(word as u32 as usize),
+            op::DROP => Inst::DROP(word as u32 as usize),
+            op::STORE => Inst::STORE(word as u32 as usize),
+            op::JMP => Inst::JMP(imm as isize),
+            op::LOOP => Inst::LOOP(reg()?, imm as isize),
+            op::JEZ => Inst::JEZ(imm as isize),
+            op::JNZ => Inst::JNZ(imm as isize),
+            op::JLT => Inst::JLT(imm as isize),
+            op::JGT => Inst::JGT(imm as isize),
+            op::JLE => Inst::JLE(imm as isize),
+            op::JGE => Inst::JGE(imm as isize),
+            op::JF => Inst::JF(decode_cond(operands >> 32)?, imm as isize),
+            op::CALL => Inst::CALL(imm as isize),
+            op::LOADR => Inst::LOADR(reg()?, word as u32 as usize),
+            op::STORER => Inst::STORER(reg()?, word as u32 as usize),
+            op::ALLOC => Inst::ALLOC(word as u32 as usize),
+            op::AIDXLOAD => Inst::AIDXLOAD,
+            op::AIDXSTORE => Inst::AIDXSTORE,
+            op::LOADL => Inst::LOADL(imm as isize),
+            op::STOREL => Inst::STOREL(imm as isize),
+            op::RET => Inst::RET,
+            op::TBL => Inst::TBL(word as u32 as usize),
+            op::TRAP => Inst::TRAP(word as u16),
+            op::SYS => Inst::SYS(word as u32),
+            op::HCALL => Inst::HCALL(word as u16),
+            _ => Inst::HLT,
+        };
+        Ok(inst)
+    }
+}
+
+/// Strings as a word with the length in bytes followed by the UTF-8 bytes, eight to a word in
+/// little endian order and the last word padded with zeros
+pub(crate) fn encode_strings(strings: &[String]) -> Vec<u64> {
+    let mut words = Vec::new();
+    for string in strings {
+        words.push(string.len() as u64);
+        for chunk in string.as_bytes().chunks(8) {
+            let mut bytes = [0; 8];
+            bytes[..chunk.len()].copy_from_slice(chunk);
+            words.push(u64::from_le_bytes(bytes));
+        }
+    }
+    words
+}
+
+/// A value word, the value sign extended to 64 bits
+pub(crate) fn value_word(val: Word) -> u64 {
+    wide(val) as u64
+}
+
+// the cast does nothing with 64 bit words
+#[allow(clippy::unnecessary_cast)]
+fn wide(val: Word) -> i64 {
+    val as i64
+}
+
+/// Read a value word, or with `narrow` a value in the low 32 bits as older formats kept them
+pub(crate) fn read_value(word: u64, narrow: bool) -> Result<Word, DecodeError> {
+    let val = match narrow {
+        true if word >> 32 == 0 => word as u32 as i32 as i64,
+        true => return Err(DecodeError::ReservedBits(word)),
+        false => word as i64,
+    };
+    Word::try_from(val).map_err(|_| DecodeError::ReservedBits(word))
+}
+
+pub(crate) fn decode_strings(mut words: &[u64]) -> Result<Vec<String>, DecodeError> {
+    let mut strings = Vec::new();
+    while let [len, rest @ ..] = words {
+        let len = usize::try_from(*len).map_err(|_| DecodeError::BadString)?;
+        let chunks = len.div_ceil(8);
+        if chunks > rest.len() {
+            return Err(DecodeError::BadString);
+        }
+        let (chunk_words, rest) = rest.split_at(chunks);
+        let mut bytes: Vec<u8> = chunk_words.iter().flat_map(|w| w.to_le_bytes()).collect();
+        if bytes[len..].iter().any(|&b| b != 0) {
+            return Err(DecodeError::BadString);
+        }
+        bytes.truncate(len);
+        strings.push(String::from_utf8(bytes).map_err(|_| DecodeError::BadString)?);
+        words = rest;
+    }
+    Ok(strings)
+}
+
+impl Program {
+    /// Encode the program with its constant pool and data segment
+    pub fn to_words(&self) -> Result<Vec<u64>, EncodeError> {
+        let traps = encode_strings(&self.traps);
+        let imports = encode_strings(&self.imports);
+        let strings = encode_strings(&self.strings);
+        let mut words = vec![
+            MAGIC,
+            self.code.len() as u64,
+            self.pool.len() as u64,
+            self.data.len() as u64,
+            traps.len() as u64,
+            imports.len() as u64,
+            strings.len() as u64,
+        ];
+        for inst in &self.code {
+            words.push(inst.to_word()?);
+        }
+        let values = self.pool.iter().chain(&self.data);
+        words.extend(values.map(|&val| value_word(val)));
+        words.extend(traps);
+        words.extend(imports);
+        words.extend(strings);
+        Ok(words)
+    }
+
+    /// Decode a program made by [`Program::to_words`], or by an earlier version of the format:
+    /// the first had no import table and the first three no string table
+    pub fn from_words(words: &[u64]) -> Result<Program, DecodeError> {
+        let (header, narrow) = match words.first() {
+            Some(&MAGIC) => (7, false),
+            Some(&MAGIC_V3) => (6, false),
+            Some(&MAGIC_V2) => (6, true),
+            Some(&MAGIC_V1) => (5, true),
+            Some(&magic) if words.len() >= 5 => return Err(DecodeError::BadMagic(magic)),
+            _ => {
+                return Err(DecodeError::Length {
+                    expected: 5,
+                    found: words.len(),
+                })
+            }
+        };
+        if words.len() < header {
+            return Err(DecodeError::Length {
+                expected: header,
+                found: words.len(),
+            });
+        }
+        let mut lens = [0; 6];
+        for (len, &word) in lens.iter_mut().zip(&words[1..header]) {
+            *len = usize::try_from(word).unwrap_or(usize::MAX);
+        }
+        let expected = lens
+            .iter()
+            .fold(header, |sum, &len| sum.saturating_add(len));
+        if expected != words.len() {
+            return Err(DecodeError::Length {
+                expected,
+                found: words.len(),
+            });
+        }
+
+        let (code, rest) = words[header..].split_at(lens[0]);
+        let (pool, rest) = rest.split_at(lens[1]);
+        let (data, rest) = rest.split_at(lens[2]);
+        let (traps, rest) = rest.split_at(lens[3]);
+        let (imports, strings) = rest.split_at(lens[4]);
+
+        let value = |&word: &u64| read_value(word, narrow);
+        Ok(Program {
+            code: code
+                .iter()
+                .map(|&word| Inst::from_word(word))
+                .collect::<Result<_, _>>()?,
+            pool: pool.iter().map(value).collect::<Result<_, _>>()?,
+            data: data.iter().map(value).collect::<Result<_, _>>()?,
+            traps: decode_strings(traps)?,
+            strings: decode_strings(strings)?,
+            imports: decode_strings(imports)?,
+        })
+    }
+
+    /// The words of [`Program::to_words`] as little endian bytes, the format of a program file
+    pub fn to_bytes(&self) -> Result<Vec<u8>, EncodeError> {
+        let words = self.to_words()?;
+        Ok(words.iter().flat_map(|word| word.to_le_bytes()).collect())
+    }
+
+    /// Decode a program made by [`Program::to_bytes`]
+    pub fn from_bytes(bytes: &[u8]) -> Result<Program, DecodeError> {
+        let chunks = bytes.chunks_exact(8);
+        if !chunks.remainder().is_empty() {
+            return Err(DecodeError::Truncated);
+        }
+        let words: Vec<u64> = chunks
+            .map(|chunk| u64::from_le_bytes(chunk.try_into().unwrap()))
+            .collect();
+        Program::from_words(&words)
+    }
+}
+
+/// Encode instructions in the byte format, an opcode byte followed by variable length operands
+pub fn encode_program(code: &[Inst]) -> Vec<u8> {
+    let mut out = Bytes::default();
+    for inst in code {
+        out.inst(*inst);
+    }
+    out.bytes
+}
+
+/// Decode bytes made by [`encode_program`]
+pub fn decode_program(bytes: &[u8]) -> Result<Vec<Inst>, DecodeError> {
+    let mut reader = Reader { bytes, pos: 0 };
+    let mut code = Vec::new();
+    while reader.pos < bytes.len() {
+        code.push(reader.inst()?);
+    }
+    Ok(code)
+}
+
+/// Instructions from one mark of a [`Bytecode`] to the next
+const MARK_EVERY: usize = 64;
+
+/// Return addresses a [`Bytecode`] remembers
+const RETURNS: usize = 256;
+
+/// The distance of a jump whose target is too far away to keep
+const FAR: i32 = i32::MIN;
+
+/// A program kept in the byte format, for a machine created with
+/// [`Machine::from_bytecode`](crate::Machine::from_bytecode) to decode one instruction at a time
+/// as it executes. There is no table of where each instruction starts: a cursor follows the
+/// execution, every jump is followed by the distance in bytes from its end to its target, as
+/// four little endian bytes, so a branch moves the cursor at once, and an instruction reached
+/// any other way, by a return or a jump table, is found from the mark before it.
+pub(crate) struct Bytecode {
+    bytes: Box<[u8]>,
+
+    /// Number of instructions
+    len: usize,
+
+    /// Position in `bytes` of every `MARK_EVERY`th instruction
+    marks: Box<[usize]>,
+
+    /// The instruction after the last one fetched, and its position in `bytes`
+    cursor: (usize, usize),
+
+    /// The target of the last jump fetched, and its position
+    branch: (usize, usize),
+
+    /// The instructions after the calls lately fetched, where they return to, and their
+    /// positions, `(ip, pos)` kept at `ip % RETURNS`
+    returns: Box<[(usize, usize); RETURNS]>,
+}
+
+impl Bytecode {
+    /// Bytes made by [`encode_program`], checked to decode
+    pub(crate) fn new(bytes: &[u8]) -> Result<Bytecode, DecodeError> {
+        let code = decode_program(bytes)?;
+        let mut out = Bytes::default();
+        let mut starts = Vec::with_capacity(code.len() + 1);
+        for inst in &code {
+            starts.push(out.bytes.len());
+            out.inst(*inst);
+            if inst.jump_offset().is_some() {
+                out.bytes.extend_from_slice(&FAR.to_le_bytes());
+            }
+        }
+        starts.push(out.bytes.len());
+        for (ip, inst) in code.iter().enumerate() {
+            let Some(step) = inst.jump_offset() else {
+                continue;
+            };
+            let next = starts[ip + 1];
+            let target = match step {
+                0 => Some(ip + 1),
+                _ => ip.checked_add_signed(step).filter(|&t| t < code.len()),
+            };
+            let far = target.and_then(|t| i32::try_from(starts[t] as i64 - next as i64).ok());
+            let dist = far.unwrap_or(FAR).to_le_bytes();
+            out.bytes[next - dist.len()..next].copy_from_slice(&dist);
+        }
+        let marks = starts.iter().step_by(MARK_EVERY).copied().collect();
+        Ok(Bytecode {
+            bytes: out.bytes.into(),
+            len: code.len(),
+            marks,
+            cursor: (0, 0),
+            branch: (usize::MAX, 0),
+            returns: Box::new([(usize::MAX, 0); RETURNS]),
+        })
+    }
+
+    /// Number of instructions
+    pub(crate) fn len(&self) -> usize {
+        self.len
+    }
+
+    /// The instruction at `ip`, for looking at it
+    pub(crate) fn get(&self, ip: usize) -> Option<Inst> {
+        let pos = self.position(ip)?;
+        let mut reader = Reader {
+            bytes: &self.bytes,
+            pos,
+        };
+        Bytecode::read(&mut reader)
+    }
+
+    /// The instruction at `ip`, for executing it. The cursor moves on past it, and the target of
+    /// a jump and the return address of a call are remembered.
+    // kept out of line so that it does not weigh on the loops running decoded programs
+    #[inline(never)]
+    pub(crate) fn fetch(&mut self, ip: usize) -> Option<Inst> {
+        let pos = match self.branch {
+            (target, pos) if target == ip && ip != self.cursor.0 => pos,
+            _ => self.position(ip)?,
+        };
+        let mut reader = Reader {
+            bytes: &self.bytes,
+            pos,
+        };
+        let inst = reader.inst().ok()?;
+        if let Some(step) = inst.jump_offset() {
+            let dist = Bytecode::distance(&mut reader)?;
+            if step != 0 && dist != FAR {
+                let target = ip.wrapping_add_signed(step);
+                self.branch = (target, reader.pos.wrapping_add_signed(dist as isize));
+            }
+            if let Inst::CALL(_) = inst {
+                self.returns[(ip + 1) % RETURNS] = (ip + 1, reader.pos);
+            }
+        }
+        self.cursor = (ip + 1, reader.pos);
+        Some(inst)
+    }
+
+    /// Every instruction, decoded
+    pub(crate) fn to_vec(&self) -> Vec<Inst> {
+        let mut reader = Reader {
+            bytes: &self.bytes,
+            pos: 0,
+        };
+        (0..self.len)
+            .map_while(|_| Bytecode::read(&mut reader))
+            .collect()
+    }
+
+    /// Position in `bytes` of the instruction at `ip`, remembered or read on from its mark
+    fn position(&self, ip: usize) -> Option<usize> {
+        if ip == self.cursor.0 {
+            return Some(self.cursor.1);
+        }
+        if ip >= self.len {
+            return None;
+        }
+        match self.returns[ip % RETURNS] {
+            (ret, pos) if ret == ip => Some(pos),
+            _ => {
+                let mut reader = Reader {
+                    bytes: &self.bytes,
+                    pos: self.marks[ip / MARK_EVERY],
+                };
+                for _ in 0..ip % MARK_EVERY {
+                    Bytecode::read(&mut reader)?;
+                }
+                Some(reader.pos)
+            }
+        }
+    }
+
+    /// The instruction at the reader, skipping the distance after a jump
+    fn read(reader: &mut Reader) -> Option<Inst> {
+        let inst = reader.inst().ok()?;
+        if inst.jump_offset().is_some() {
+            Bytecode::distance(reader)?;
+        }
+        Some(inst)
+    }
+
+    /// The distance in bytes from the end of the jump at the reader to its target
+    fn distance(reader: &mut Reader) -> Option<i32> {
+        let dist = reader.bytes.get(reader.pos..reader.pos + 4)?;
+        reader.pos += 4;
+        Some(i32::from_le_bytes(dist.try_into().ok()?))
+    }
+}
+
+/// Writer of the byte format
+#[derive(Default)]
+struct Bytes {
+    bytes: Vec<u8>,
+}
+
+impl Bytes {
+    fn inst(&mut self, inst: Inst) {
+        match inst {
+            Inst::PSH(val) => self.op(op::PSH).signed(wide(val)),
+            Inst::PSHC(idx) => self.op(op::PSHC).unsigned(idx as u64),
+            Inst::FPSH(val) => self.op(op::FPSH).unsigned(float_word(val) as UWord as u64),
+            Inst::FADD => self.op(op::FADD),
+            Inst::FSUB => self.op(op::FSUB),
+            Inst::FMUL => self.op(op::FMUL),
+            Inst::FDIV => self.op(op::FDIV),
+            Inst::ITOF => self.op(op::ITOF),
+            Inst::FTOI => self.op(op::FTOI),
+            Inst::POP => self.op(op::POP),
+            Inst::DUP => self.op(op::DUP),
+            Inst::SWAP => self.op(op::SWAP),
+            Inst::OVER => self.op(op::OVER),
+            Inst::ROT => self.op(op::ROT),
+            Inst::DROP(count) => self.op(op::DROP).unsigned(count as u64),
+            Inst::IN => self.op(op::IN),
+            Inst::OUT => self.op(op::OUT),
+            Inst::PRINTS(id) => self.op(op::PRINTS).unsigned(id as u64),
+            Inst::YLD => self.op(op::YLD),
+            Inst::SND(port) => self.op(op::SND).op(port),
+            Inst::RCV(port) => self.op(op::RCV).op(port),
+            Inst::CLR => self.op(op::CLR),
+            Inst::ADD => self.op(op::ADD),
+            Inst::SUB => self.op(op::SUB),
+            Inst::MUL => self.op(op::MUL),
+            Inst::DIV => self.op(op::DIV),
+            Inst::DIVF => self.op(op::DIVF),
+            Inst::MODF => self.op(op::MODF),
+            Inst::DIVU => self.op(op::DIVU),
+            Inst::MODU => self.op(op::MODU),
+            Inst::AND => self.op(op::AND),
+            Inst::OR => self.op(op::OR),
+            Inst::XOR => self.op(op::XOR),
+            Inst::NOT => self.op(op::NOT),
+            Inst::SHL => self.op(op::SHL),
+            Inst::SHR => self.op(op::SHR),
+            Inst::MOD => self.op(op::MOD),
+            Inst::NEG => self.op(op::NEG),
+            Inst::ABS => self.op(op::ABS),
+            Inst::MIN => self.op(op::MIN),
+            Inst::MAX => self.op(op::MAX),
+            Inst::QMUL => self.op(op::QMUL),
+            Inst::QDIV => self.op(op::QDIV),
+            Inst::CMP => self.op(op::CMP),
+            Inst::SET(reg, val) => self.op(op::SET).reg(reg).signed(wide(val)),
+            Inst::INC(reg) => self.op(op::INC).reg(reg),
+            Inst::DEC(reg) => self.op(op::DEC).reg(reg),
+            Inst::SETP(path, val) => self.op(op::SETP).path(path).signed(wide(val)),
+            Inst::CPY(dst, src) => self.op(op::CPY).path(dst).path(src),
+            Inst::LOAD(addr) => self.op(op::LOAD).unsigned(addr as u64),
+            Inst::STORE(addr) => self.op(op::STORE).unsigned(addr as u64),
+            Inst::JMP(step) => self.op(op::JMP).signed(step as i64),
+            Inst::LOOP(reg, step) => self.op(op::LOOP).reg(reg).signed(step as i64),
+            Inst::JEZ(step) => self.op(op::JEZ).signed(step as i64),
+            Inst::JNZ(step) => self.op(op::JNZ).signed(step as i64),
+            Inst::JLT(step) => self.op(op::JLT).signed(step as i64),
+            Inst::JGT(step) => self.op(op::JGT).signed(step as i64),
+            Inst::JLE(step) => self.op(op::JLE).signed(step as i64),
+            Inst::JGE(step) => self.op(op::JGE).signed(step as i64),
+            Inst::JF(cond, step) => self
+                .op(op::JF)
+                .op(cond_code(cond) as u8)
+                .signed(step as i64),
+            Inst::TBL(id) => self.op(op::TBL).unsigned(id as u64),
+            Inst::TRAP(code) => self.op(op::TRAP).unsigned(code as u64),
+            Inst::SYS(number) => self.op(op::SYS).unsigned(number as u64),
+            Inst::HCALL(idx) => self.op(op::HCALL).unsigned(idx as u64),
+            Inst::CALL(step) => self.op(op::CALL).signed(step as i64),
+            Inst::LOADR(reg, addr) => self.op(op::LOADR).reg(reg).unsigned(addr as u64),
+            Inst::STORER(reg, addr) => self.op(op::STORER).reg(reg).unsigned(addr as u64),
+            Inst::ALLOC(len) => self.op(op::ALLOC).unsigned(len as u64),
+            Inst::AIDXLOAD => self.op(op::AIDXLOAD),
+            Inst::AIDXSTORE => self.op(op::AIDXSTORE),
+            Inst::LOADL(slot) => self.op(op::LOADL).signed(slot as i64),
+            Inst::STOREL(slot) => self.op(op::STOREL).signed(slot as i64),
+            Inst::RET => self.op(op::RET),
+            Inst::HLT => self.op(op::HLT),
+            Inst::EXIT(code) => self.op(op::EXIT).signed(code as i64),
+        };
+    }
+
+    fn op(&mut self, byte: u8) -> &mut Self {
+        self.bytes.push(byte);
+        self
+    }
+
+    /// LEB128, seven bits a byte with the top bit set on all but the last
+    fn unsigned(&mut self, mut val: u64) -> &mut Self {
+        while val >= 0x80 {
+            self.bytes.push(val as u8 | 0x80);
+            val >>= 7;
+        }
+        self.op(val as u8)
+    }
+
+    /// Zigzag encoded, so that small negative numbers stay short
+    fn signed(&mut self, val: i64) -> &mut Self {
+        self.unsigned(((val << 1) ^ (val >> 63)) as u64)
+    }
+
+    fn reg(&mut self, reg: Reg) -> &mut Self {
+        self.unsigned(reg_code(reg))
+    }
+
+    fn path(&mut self, path: Path) -> &mut Self {
+        match path {
+            Path::REG(reg) => self.op(PATH_REG as u8).reg(reg),
+            Path::STK(idx) => self.op(PATH_STK as u8).signed(idx as i64),
+            Path::STKR(reg, idx) => self.op(PATH_STKR as u8).reg(reg).signed(idx as i64),
+        }
+    }
+}
+
+/// Reader of the byte format
+struct Reader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl Reader<'_> {
+    fn inst(&mut self) -> Result<Inst, DecodeError> {
+        let inst = match self.byte()? {
+            op::PSH => Inst::PSH(self.number()?),
+            op::PSHC => Inst::PSHC(self.number()?),
+            op::FPSH => Inst::FPSH(Float::from_bits(self.number()?)),
+            op::FADD => Inst::FADD,
+            op::FSUB => Inst::FSUB,
+            op::FMUL => Inst::FMUL,
+            op::FDIV => Inst::FDIV,
+            op::ITOF => Inst::ITOF,
+            op::FTOI => Inst::FTOI,
+            op::POP => Inst::POP,
+            op::DUP => Inst::DUP,
+            op::SWAP => Inst::SWAP,
+            op::OVER => Inst::OVER,
+            op::ROT => Inst::ROT,
+            op::IN => Inst::IN,
+            op::OUT => Inst::OUT,
+            op::PRINTS => Inst::PRINTS(self.number()?),
+            op::YLD => Inst::YLD,
+            op::SND => Inst::SND(self.byte()?),
+            op::RCV => Inst::RCV(self.byte()?),
+            op::CLR => Inst::CLR,
+            op::ADD => Inst::ADD,
+            op::SUB => Inst::SUB,
+            op::MUL => Inst::MUL,
+            op::DIV => Inst::DIV,
+            op::DIVF => Inst::DIVF,
+            op::MODF => Inst::MODF,
+            op::DIVU => Inst::DIVU,
+            op::MODU => Inst::MODU,
+            op::AND => Inst::AND,
+            op::OR => Inst::OR,
+            op::XOR => Inst::XOR,
+            op::NOT => Inst::NOT,
+            op::SHL => Inst::SHL,
+            op::SHR => Inst::SHR,
+            op::MOD => Inst::MOD,
+            op::NEG => Inst::NEG,
+            op::ABS => Inst::ABS,
+            op::MIN => Inst::MIN,
+            op::MAX => Inst::MAX,
+            op::QMUL => Inst::QMUL,
+            op::QDIV => Inst::QDIV,
+            op::CMP => Inst::CMP,
+            op::SET => Inst::SET(self.reg()?, self.number()?),
+            op::INC => Inst::INC(self.reg()?),
+            op::DEC => Inst::DEC(self.reg()?),
+            op::SETP => Inst::SETP(self.path()?, self.number()?),
+            op::CPY => Inst::CPY(self.path()?, self.path()?),
+            op::LOAD => Inst::LOAD(self.number()?),
+            op::DROP => Inst::DROP(self.number()?),
+            op::STORE => Inst::STORE(self.number()?),
+            op::JMP => Inst::JMP(self.number()?),
+            op::LOOP => Inst::LOOP(self.reg()?, self.number()?),
+            op::JEZ => Inst::JEZ(self.number()?),
+            op::JNZ => Inst::JNZ(self.number()?),
+            op::JLT => Inst::JLT(self.number()?),
+            op::JGT => Inst::JGT(self.number()?),
+            op::JLE => Inst::JLE(self.number()?),
+            op::JGE => Inst::JGE(self.number()?),
+            op::JF => Inst::JF(decode_cond(self.byte()? as u64)?, self.number()?),
+            op::CALL => Inst::CALL(self.number()?),
+            op::LOADR => Inst::LOADR(self.reg()?, self.number()?),
+            op::STORER => Inst::STORER(self.reg()?, self.number()?),
+            op::ALLOC => Inst::ALLOC(self.number()?),
+            op::AIDXLOAD => Inst::AIDXLOAD,
+            op::AIDXSTORE => Inst::AIDXSTORE,
+            op::LOADL => Inst::LOADL(self.number()?),
+            op::STOREL => Inst::STOREL(self.number()?),
+            op::RET => Inst::RET,
+            op::TBL => Inst::TBL(self.number()?),
+            op::TRAP => Inst::TRAP(self.number()?),
+            op::SYS => Inst::SYS(self.number()?),
+            op::HCALL => Inst::HCALL(self.number()?),
+            op::HLT => Inst::HLT,
+            op::EXIT => Inst::EXIT(self.number()?),
+            opcode => return Err(DecodeError::UnknownOpcode(opcode)),
+        };
+        Ok(inst)
+    }
+
+    fn byte(&mut self) -> Result<u8, DecodeError> {
+        let byte = *self.bytes.get(self.pos).ok_or(DecodeError::Truncated)?;
+        self.pos += 1;
+        Ok(byte)
+    }
+
+    fn unsigned(&mut self) -> Result<u64, DecodeError> {
+        let start = self.pos;
+        let mut val = 0u64;
+        for shift in (0..64).step_by(7) {
+            let byte = self.byte()?;
+            let bits = (byte & 0x7f) as u64;
+            if bits << shift >> shift != bits {
+                break;
+            }
+            val |= bits << shift;
+            if byte & 0x80 == 0 {
+                return Ok(val);
+            }
+        }
+        Err(DecodeError::BadNumber(start))
+    }
+
+    /// A number for an operand of type `T`, signed or not as `T` is
+    fn number<T: TryFrom<i64> + TryFrom<u64>>(&mut self) -> Result<T, DecodeError> {
+        let start = self.pos;
+        let raw = self.unsigned()?;
+        let val = if T::try_from(-1i64).is_ok() {
+            T::try_from((raw >> 1) as i64 ^ -((raw & 1) as i64)).ok()
+        } else {
+            T::try_from(raw).ok()
+        };
+        val.ok_or(DecodeError::BadNumber(start))
+    }
+
+    fn reg(&mut self) -> Result<Reg, DecodeError> {
+        decode_reg(self.unsigned()?)
+    }
+
+    fn path(&mut self) -> Result<Path, DecodeError> {
+        let path = match self.byte()? as u64 {
+            PATH_REG => Path::REG(self.reg()?),
+            PATH_STK => Path::STK(self.number()?),
+            PATH_STKR => Path::STKR(self.reg()?, self.number()?),
+            kind => return Err(DecodeError::BadPath(kind)),
+        };
+        Ok(path)
+    }
+}
+
+/// A float in the low 32 bits, as an `f32`, if it is one without rounding
+// the casts do nothing with 32 bit words
+#[allow(clippy::unnecessary_cast)]
+fn float_field(val: Float) -> Option<u64> {
+    let narrow = val as f32;
+    (narrow as Float == val || val.is_nan()).then(|| narrow.to_bits() as u64)
+}
+
+/// An address or table id in the low 32 bits
+fn addr_field(val: usize) -> Option<u64> {
+    u32::try_from(val).ok().map(u64::from)
+}
+
+pub(crate) fn reg_code(reg: Reg) -> u64 {
+    match reg {
+        Reg::A => 0,
+        Reg::B => 1,
+        Reg::C => 2,
+        Reg::D => 3,
+        Reg::E => 4,
+        Reg::F => 5,
+        Reg::R(n) => 0x100 + n as u64,
+    }
+}
+
+pub(crate) fn decode_reg(code: u64) -> Result<Reg, DecodeError> {
+    let reg = match code {
+        0 => Reg::A,
+        1 => Reg::B,
+        2 => Reg::C,
+        3 => Reg::D,
+        4 => Reg::E,
+        5 => Reg::F,
+        0x100..=0x1ff => Reg::R((code - 0x100) as u8),
+        _ => return Err(DecodeError::BadRegister(code)),
+    };
+    Ok(reg)
+}
+
+fn cond_code(cond: Cond) -> u64 {
+    match cond {
+        Cond::EQ => 0,
+        Cond::NE => 1,
+        Cond::LT => 2,
+        Cond::LE => 3,
+        Cond::GT => 4,
+        Cond::GE => 5,
+        Cond::LTU => 6,
+        Cond::GEU => 7,
+    }
+}
+
+fn decode_cond(code: u64) -> Result<Cond, DecodeError> {
+    let cond = match code {
+        0 => Cond::EQ,
+        1 => Cond::NE,
+        2 => Cond::LT,
+        3 => Cond::LE,
+        4 => Cond::GT,
+        5 => Cond::GE,
+        6 => Cond::LTU,
+        7 => Cond::GEU,
+        _ => return Err(DecodeError::BadCondition(code)),
+    };
+    Ok(cond)
+}
+
+/// Two's complement `val` in the low `bits` bits, if it fits
+fn signed_field(val: isize, bits: u32) -> Option<u64> {
+    let limit = 1i64 << (bits - 1);
+    let val = val as i64;
+    if (-limit..limit).contains(&val) {
+        Some(val as u64 & ((1 << bits) - 1))
+    } else {
+        None
+    }
+}
+
+fn read_signed(field: u64, bits: u32) -> isize {
+    let shift = 64 - bits;
+    ((field << shift) as i64 >> shift) as isize
+}
+
+fn encode_path(path: Path, bits: u32) -> Option<u64> {
+    let payload = bits - 2;
+    let field = match path {
+        Path::REG(reg) => PATH_REG << payload | reg_code(reg),
+        Path::STK(idx) => PATH_STK << payload | signed_field(idx, payload)?,
+        Path::STKR(reg, idx) => {
+            let offset_bits = payload - REG_BITS;
+            PATH_STKR << payload | reg_code(reg) << offset_bits | signed_field(idx, offset_bits)?
+        }
+    };
+    Some(field)
+}
+
+fn decode_path(field: u64, bits: u32) -> Result<Path, DecodeError> {
+    let payload = bits - 2;
+    let rest = field & ((1 << payload) - 1);
+    let path = match field >> payload {
+        PATH_REG if rest >> REG_BITS == 0 => Path::REG(decode_reg(rest)?),
+        PATH_STK => Path::STK(read_signed(rest, payload)),
+        PATH_STKR => {
+            let offset_bits = payload - REG_BITS;
+            let offset = rest & ((1 << offset_bits) - 1);
+            Path::STKR(
+                decode_reg(rest >> offset_bits)?,
+                read_signed(offset, offset_bits),
+            )
+        }
+        _ => return Err(DecodeError::BadPath(field)),
+    };
+    Ok(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::string::ToString;
+
+    use super::*;
+
+    /// Every instruction, with operands at the limits of the word format
+    const EVERY_INSTRUCTION: [Inst; 85] = [
+        Inst::PSH(i32::MIN as Word),
+        Inst::PSH(i32::MAX as Word),
+        Inst::PSH(-1),
+        Inst::PSHC(u16::MAX),
+        Inst::POP,
+        Inst::DUP,
+        Inst::SWAP,
+        Inst::OVER,
+        Inst::ROT,
+        Inst::DROP(u32::MAX as usize),
+        Inst::IN,
+        Inst::OUT,
+        Inst::PRINTS(u16::MAX),
+        Inst::YLD,
+        Inst::SND(0),
+        Inst::RCV(u8::MAX),
+        Inst::CLR,
+        Inst::ADD,
+        Inst::SUB,
+        Inst::MUL,
+        Inst::DIV,
+        Inst::DIVF,
+        Inst::MODF,
+        Inst::DIVU,
+        Inst::MODU,
+        Inst::AND,
+        Inst::OR,
+        Inst::XOR,
+        Inst::NOT,
+        Inst::SHL,
+        Inst::SHR,
+        Inst::MOD,
+        Inst::NEG,
+        Inst::ABS,
+        Inst::MIN,
+        Inst::MAX,
+        Inst::QMUL,
+        Inst::QDIV,
+        Inst::FPSH(1.5),
+        Inst::FPSH(-2.5),
+        Inst::FPSH(Float::INFINITY),
+        Inst::FADD,
+        Inst::FSUB,
+        Inst::FMUL,
+        Inst::FDIV,
+        Inst::ITOF,
+        Inst::FTOI,
+        Inst::SET(Reg::F, i32::MIN as Word),
+        Inst::SET(Reg::R(255), 7),
+        Inst::INC(Reg::A),
+        Inst::DEC(Reg::R(200)),
+        Inst::SETP(Path::STK(-(1 << 21)), i32::MAX as Word),
+        Inst::SETP(Path::STKR(Reg::R(0), 4095), -5),
+        Inst::SETP(Path::REG(Reg::A), 1),
+        Inst::CPY(Path::STK((1 << 25) - 1), Path::REG(Reg::R(17))),
+        Inst::CPY(Path::STKR(Reg::C, -(1 << 16)), Path::STKR(Reg::D, 3)),
+        Inst::LOAD(0xFF00),
+        Inst::STORE(u32::MAX as usize),
+        Inst::JMP(i32::MIN as isize),
+        Inst::JMP(0),
+        Inst::LOOP(Reg::R(3), -4),
+        Inst::JEZ(i32::MAX as isize),
+        Inst::JNZ(-1),
+        Inst::JLT(2),
+        Inst::JGT(0),
+        Inst::JLE(i32::MIN as isize),
+        Inst::JGE(-7),
+        Inst::CMP,
+        Inst::JF(Cond::GEU, i32::MIN as isize),
+        Inst::JF(Cond::EQ, 3),
+        Inst::CALL(i32::MAX as isize),
+        Inst::RET,
+        Inst::LOADR(Reg::R(200), u32::MAX as usize),
+        Inst::STORER(Reg::A, 0),
+        Inst::ALLOC(u32::MAX as usize),
+        Inst::AIDXLOAD,
+        Inst::AIDXSTORE,
+        Inst::LOADL(-3),
+        Inst::STOREL(i32::MIN as isize),
+        Inst::TBL(u32::MAX as usize),
+        Inst::TRAP(9),
+        Inst::SYS(u32::MAX),
+        Inst::HCALL(u16::MAX),
+        Inst::EXIT(i32::MIN),
+        Inst::HLT,
+    ];
+
+    #[test]
+    fn every_instruction_round_trips() {
+        for inst in EVERY_INSTRUCTION {
+            let word = inst.to_word().unwrap();
+            assert_eq!(Inst::from_word(word), Ok(inst), "word {word:#018x}");
+        }
+    }
+
+    #[test]
+    fn programs_round_trip_with_pool_and_data() {
+        let program = Program {
+            code: vec![Inst::PSHC(1), Inst::LOAD(0), Inst::ADD, Inst::HLT],
+            pool: vec![Word::MIN, 1_000_000],
+            data: vec![-1, 2, 3],
+            traps: vec![
+                "".to_string(),
+                "exactly8".to_string(),
+                "ünïcode".to_string(),
+            ],
+            strings: vec!["hello".to_string()],
+            imports: vec!["max".to_string()],
+        };
+        let words = program.to_words().unwrap();
+        assert_eq!(words.len(), 7 + 4 + 2 + 3 + 6 + 2 + 2);
+        assert_eq!(Program::from_words(&words), Ok(program));
+
+        assert_eq!(
+            Program::from_words(&words[..25]),
+            Err(DecodeError::Length {
+                expected: 26,
+                found: 25
+            })
+        );
+        assert_eq!(
+            Program::from_words(&[0, 0, 0, 0, 0]),
+            Err(DecodeError::BadMagic(0))
+        );
+        let mut bad = words.clone();
+        bad[13] = 1 << 40;
+        #[cfg(not(feature = "word64"))]
+        assert_eq!(
+            Program::from_words(&bad),
+            Err(DecodeError::ReservedBits(bad[13]))
+        );
+        #[cfg(feature = "word64")]
+        assert_eq!(Program::from_words(&bad).unwrap().data[0], 1 << 40);
+        // a message longer than the trap table
+        let mut bad = words.clone();
+        bad[16] = 100;
+        assert_eq!(Program::from_words(&bad), Err(DecodeError::BadString));
+
+        // the first version of the format has no import table
+        let hlt = Inst::HLT.to_word().unwrap();
+        let old = Program::from_words(&[MAGIC_V1, 1, 0, 0, 0, hlt]).unwrap();
+        assert_eq!(old.code, vec![Inst::HLT]);
+        assert!(old.imports.is_empty());
+
+        // the third has no string table
+        let old = Program::from_words(&[MAGIC_V3, 1, 0, 0, 0, 0, hlt]).unwrap();
+        assert_eq!(old.code, vec![Inst::HLT]);
+        assert!(old.strings.is_empty());
+
+        // the second keeps values in the low 32 bits
+        let old = Program::from_words(&[MAGIC_V2, 0, 1, 0, 0, 0, 0xffff_fffe]).unwrap();
+        assert_eq!(old.pool, vec![-2]);
+        assert_eq!(
+            Program::from_words(&[MAGIC_V2, 0, 1, 0, 0, 0, u64::MAX]),
+            Err(DecodeError::ReservedBits(u64::MAX))
+        );
+    }
+
+    #[test]
+    fn program_files_round_trip() {
+        let program = Program {
+            code: vec![Inst::PSHC(0), Inst::TRAP(0), Inst::HLT],
+            pool: vec![-3],
+            data: vec![1, 2],
+            traps: vec!["not zero".to_string()],
+            strings: vec!["hi".to_string()],
+            imports: vec!["clock".to_string()],
+        };
+        let bytes = program.to_bytes().unwrap();
+        assert_eq!(&bytes[..8], b"\x04artnayv");
+        assert_eq!(Program::from_bytes(&bytes), Ok(program));
+        assert_eq!(
+            Program::from_bytes(&bytes[..bytes.len() - 1]),
+            Err(DecodeError::Truncated)
+        );
+    }
+
+    #[test]
+    fn oversized_operands_do_not_encode() {
+        for inst in [
+            Inst::JMP(i32::MAX as isize + 1),
+            Inst::SETP(Path::STK(1 << 21), 0),
+            Inst::CPY(Path::REG(Reg::A), Path::STKR(Reg::A, 1 << 16)),
+            Inst::TBL(u32::MAX as usize + 1),
+            Inst::DROP(u32::MAX as usize + 1),
+            Inst::ALLOC(u32::MAX as usize + 1),
+        ] {
+            assert_eq!(inst.to_word(), Err(EncodeError(inst)));
+        }
+    }
+
+    #[test]
+    fn byte_format_round_trips() {
+        let mut program = EVERY_INSTRUCTION.to_vec();
+        // operands too large for words encode as bytes
+        program.extend([
+            Inst::LOAD(usize::MAX),
+            Inst::JMP(isize::MIN),
+            Inst::CPY(Path::STKR(Reg::R(9), isize::MAX), Path::STK(isize::MIN)),
+        ]);
+        let bytes = encode_program(&program);
+        assert_eq!(decode_program(&bytes), Ok(program));
+
+        assert_eq!(
+            encode_program(&[Inst::PSH(5), Inst::JMP(-2), Inst::HLT]).len(),
+            5
+        );
+        assert_eq!(decode_program(&[]), Ok(vec![]));
+    }
+
+    #[test]
+    fn malformed_bytes_are_errors() {
+        assert_eq!(decode_program(&[0]), Err(DecodeError::UnknownOpcode(0)));
+        assert_eq!(decode_program(&[op::PSH]), Err(DecodeError::Truncated));
+        assert_eq!(
+            decode_program(&[op::PSH, 0x80]),
+            Err(DecodeError::Truncated)
+        );
+        // 2^32 does not fit the immediate
+        #[cfg(not(feature = "word64"))]
+        assert_eq!(
+            decode_program(&[op::HLT, op::PSH, 0x80, 0x80, 0x80, 0x80, 0x20]),
+            Err(DecodeError::BadNumber(2))
+        );
+        assert_eq!(
+            decode_program(&[
+                op::PSH,
+                0xff,
+                0xff,
+                0xff,
+                0xff,
+                0xff,
+                0xff,
+                0xff,
+                0xff,
+                0xff,
+                0x7f
+            ]),
+            Err(DecodeError::BadNumber(1))
+        );
+        assert_eq!(
+            decode_program(&[op::SET, 6, 0]),
+            Err(DecodeError::BadRegister(6))
+        );
+        assert_eq!(
+            decode_program(&[op::CPY, 3, 0, 0]),
+            Err(DecodeError::BadPath(3))
+        );
+    }
+
+    #[test]
+    fn malformed_words_are_errors() {
+        assert_eq!(Inst::from_word(0), Err(DecodeError::UnknownOpcode(0)));
+        let add = Inst::ADD.to_word().unwrap();
+        assert_eq!(
+            Inst::from_word(add | 1),
+            Err(DecodeError::ReservedBits(add | 1))
+        );
+        let set = (op::SET as u64) << 56 | 6 << REG_SHIFT;
+        assert_eq!(Inst::from_word(set), Err(DecodeError::BadRegister(6)));
+        let cpy = (op::CPY as u64) << 56 | 3 << (CPY_PATH_BITS - 2);
+        assert_eq!(Inst::from_word(cpy), Err(DecodeError::BadPath(3 << 26)));
+        let jf = (op::JF as u64) << 56 | 8 << 32;
+        assert_eq!(Inst::from_word(jf), Err(DecodeError::BadCondition(8)));
+    }
+}
@@ -0,0 +1,602 @@
+//! Errors raised while running a program.
+
+use alloc::boxed::Box;
+use alloc::format;
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+use core::fmt;
+use core::ops::Range;
+
+use crate::io::InputEvent;
+use crate::observe::TraceStep;
+use crate::value::Type;
+use crate::{Inst, Reg, Word};
+
+/// Number of executed instruction addresses kept for a `FaultContext`
+pub(crate) const HISTORY_LEN: usize = 16;
+
+/// Number of stack values, from the top, kept for a `FaultContext`
+pub(crate) const CONTEXT_STACK_DEPTH: usize = 8;
+
+/// Why writing to the machine's output failed: the kind of the I/O error with the `std`
+/// feature, the error of the `core::fmt::Write` sink without it
+#[cfg(feature = "std")]
+pub type OutputError = std::io::ErrorKind;
+#[cfg(not(feature = "std"))]
+pub type OutputError = fmt::Error;
+
+/// Path error when invalid register or stack location is accessed. Invalid register means any
+/// register that does not exists, invalid stack location means location outside the stack memory
+/// vector.
+#[derive(Clone, Debug, PartialEq)]
+pub enum PathError {
+    /// Stack offset `requested` does not exist with the stack pointer at `sp`
+    StackErr {
+        requested: isize,
+        sp: isize,
+    },
+
+    RegErr {
+        reg: Reg,
+    },
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for PathError {}
+
+impl fmt::Display for PathError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let err_message = match self {
+            PathError::StackErr { requested, sp } => {
+                format!("invalid stack access at offset {requested} (sp={sp})")
+            }
+
+            PathError::RegErr { reg } => format!("invalid register access to {reg:?}"),
+        };
+        write!(f, "{}", err_message)
+    }
+}
+
+/// Errors from pushing to a full stack or popping an empty one.
+#[derive(Clone, Debug, PartialEq)]
+pub enum StackError {
+    PushErr,
+    PopErr,
+}
+
+impl fmt::Display for StackError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let err_message = match self {
+            StackError::PushErr => "stack overflow: cannot push on a full stack".to_string(),
+
+            StackError::PopErr => "cannot pop from an empty stack".to_string(),
+        };
+        write!(f, "{}", err_message)
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for StackError {}
+
+/// What went wrong while executing a single instruction.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Fault {
+    Stack(StackError),
+
+    Path(PathError),
+
+    /// `DIV` with a zero divisor
+    DivideByZero,
+
+    /// A jump by `step` would land outside the program
+    BadJump {
+        step: isize,
+    },
+
+    /// `TBL` named a jump table the machine does not have
+    NoSuchTable(usize),
+
+    /// `TBL` popped an index the jump table has no entry (and no default) for
+    TableIndex {
+        table: usize,
+        index: Word,
+    },
+
+    /// `IN` found no input source, or the source ran dry
+    NoInput,
+
+    /// `OUT` could not write to the machine's output
+    Output(OutputError),
+
+    /// `TRAP` popped a zero. This never shows up inside `VmError::Exec`, it becomes
+    /// `VmError::Trap`.
+    Trap(u16),
+
+    /// `PSHC` of an index past the end of the constant pool
+    NoConstant(u16),
+
+    /// `SND` or `RCV` on a port that was never connected
+    NoPort(u8),
+
+    /// The other end of the port has gone away
+    PortClosed(u8),
+
+    /// `LOAD` or `STORE` outside data memory and every mapped device
+    BadAddress(usize),
+
+    /// `PRINTS` of an id past the end of the string table, or a system call given one
+    NoString(Word),
+
+    /// `HCALL` of an index past the end of the import table
+    NoImport(u16),
+
+    /// `HCALL` of an import no host function was registered for
+    NoHostFn(String),
+
+    /// `SYS` with no system call handler, or one that does not know the call number
+    NoSyscall(u32),
+
+    /// `CALL` with as many calls in progress as the machine's call depth allows
+    CallDepth,
+
+    /// `RET` with no call in progress
+    NoCaller,
+
+    /// `ADD`, `SUB` or `MUL` overflowed on a machine configured to fail on overflow
+    Overflow,
+
+    /// `LOADL` or `STOREL` of a local slot that is not on the stack
+    BadLocal(isize),
+
+    /// `ALLOC` of an array that does not fit in what is left of the heap
+    OutOfMemory(usize),
+
+    /// `AIDXLOAD` or `AIDXSTORE` of a value that is not the handle of an array
+    BadHandle(Word),
+
+    /// `AIDXLOAD` or `AIDXSTORE` of an index past the end of the array
+    ArrayIndex {
+        handle: Word,
+        index: Word,
+        len: usize,
+    },
+
+    /// An operand of the wrong type on a typed machine
+    TypeMismatch {
+        expected: Type,
+        found: Type,
+    },
+
+    /// A host callback failed. This never shows up inside `VmError::Exec`, the callback's error
+    /// is returned as is.
+    Host(Box<VmError>),
+
+    /// The program asked for input that the replayed recording does not have next. This never
+    /// shows up inside `VmError::Exec`, it becomes `VmError::ReplayDivergence`.
+    ReplayDivergence(Option<InputEvent>),
+}
+
+impl fmt::Display for Fault {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Fault::Stack(e) => write!(f, "{}", e),
+
+            Fault::Path(e) => write!(f, "{}", e),
+
+            Fault::DivideByZero => write!(f, "attempted to divide by zero"),
+
+            Fault::BadJump { step } => write!(f, "jump by {step} leaves the program"),
+
+            Fault::NoSuchTable(table) => write!(f, "jump table {table} does not exist"),
+
+            Fault::TableIndex { table, index } => {
+                write!(f, "index {index} is out of range for jump table {table}")
+            }
+
+            Fault::NoInput => write!(f, "no input available"),
+
+            Fault::Output(kind) => write!(f, "could not write output: {kind}"),
+
+            Fault::Trap(code) => write!(f, "trap {code}"),
+
+            Fault::NoConstant(idx) => write!(f, "constant {idx} is not in the pool"),
+
+            Fault::NoPort(port) => write!(f, "port {port} is not connected"),
+
+            Fault::PortClosed(port) => write!(f, "port {port} is closed"),
+
+            Fault::BadAddress(addr) => write!(f, "data memory address {addr} does not exist"),
+
+            Fault::NoString(id) => write!(f, "string {id} is not in the string table"),
+
+            Fault::NoImport(idx) => write!(f, "import {idx} is not in the import table"),
+
+            Fault::NoHostFn(name) => write!(f, "host function `{name}` is not registered"),
+
+            Fault::NoSyscall(number) => write!(f, "system call {number} does not exist"),
+
+            Fault::CallDepth => write!(f, "too many nested calls"),
+
+            Fault::NoCaller => write!(f, "return without a call"),
+
+            Fault::Overflow => write!(f, "integer overflow"),
+
+            Fault::BadLocal(slot) => write!(f, "local slot {slot} is not on the stack"),
+
+            Fault::OutOfMemory(len) => {
+                write!(f, "no room on the heap for an array of {len} words")
+            }
+
+            Fault::BadHandle(handle) => write!(f, "{handle} is not the handle of an array"),
+
+            Fault::ArrayIndex { handle, index, len } => write!(
+                f,
+                "index {index} is out of bounds for array {handle} of length {len}"
+            ),
+
+            Fault::TypeMismatch { expected, found } => {
+                write!(f, "type mismatch: expected {expected}, found {found}")
+            }
+
+            Fault::Host(e) => write!(f, "{}", e),
+
+            Fault::ReplayDivergence(recorded) => match recorded {
+                Some(event) => write!(f, "program asked for input, recording has {event:?} next"),
+                None => write!(f, "program asked for more input than was recorded"),
+            },
+        }
+    }
+}
+
+impl Fault {
+    /// A stack overflow or underflow, a division by zero, an integer overflow or a type
+    /// mismatch. The instruction that raised it is undone, having read no input and called
+    /// nothing outside the machine. A fault inside a device or a system call is not
+    /// recoverable, since what the host did before it cannot be undone, and neither is any
+    /// fault of a `SYS`.
+    pub fn is_recoverable(&self) -> bool {
+        matches!(
+            self,
+            Fault::Stack(_) | Fault::DivideByZero | Fault::Overflow | Fault::TypeMismatch { .. }
+        )
+    }
+
+    /// Whether this fault, raised by `inst`, is undone. Nothing a system call raises is, the
+    /// handler may have done more than the machine can take back, and a replayed call has
+    /// been taken off the recording.
+    pub(crate) fn is_recoverable_in(&self, inst: Inst) -> bool {
+        !matches!(inst, Inst::SYS(_)) && self.is_recoverable()
+    }
+
+    /// The error for this fault raised by `inst` at `ip`, with `depth` values left on the stack
+    pub(crate) fn at(self, ip: usize, inst: Inst, depth: usize) -> VmError {
+        match self {
+            Fault::ReplayDivergence(recorded) => VmError::ReplayDivergence {
+                ip,
+                recorded,
+                context: None,
+            },
+            Fault::Host(e) => *e,
+            Fault::Trap(code) => VmError::Trap {
+                code,
+                ip,
+                message: None,
+                context: None,
+            },
+            fault => VmError::Exec {
+                ip,
+                inst,
+                fault,
+                depth,
+                context: None,
+            },
+        }
+    }
+}
+
+impl From<StackError> for Fault {
+    fn from(e: StackError) -> Self {
+        Fault::Stack(e)
+    }
+}
+
+impl From<PathError> for Fault {
+    fn from(e: PathError) -> Self {
+        Fault::Path(e)
+    }
+}
+
+/// Everything that can stop a program before it reaches `HLT`.
+#[derive(Clone, Debug, PartialEq)]
+pub enum VmError {
+    /// Executing `inst`, found at `ip`, failed and left `depth` values on the stack
+    Exec {
+        ip: usize,
+        inst: Inst,
+        fault: Fault,
+        depth: usize,
+        context: Option<Box<FaultContext>>,
+    },
+
+    /// The instruction pointer ran past the end of the program without a `HLT`
+    IllegalInstruction {
+        ip: usize,
+        context: Option<Box<FaultContext>>,
+    },
+
+    /// A replayed run asked for input at `ip` that does not match the recording, `recorded` is
+    /// what the recording had next
+    ReplayDivergence {
+        ip: usize,
+        recorded: Option<InputEvent>,
+        context: Option<Box<FaultContext>>,
+    },
+
+    /// The assertion of the `TRAP` at `ip` failed. `message` is the text the trap code was given
+    /// in the program's trap table, if it has one.
+    Trap {
+        code: u16,
+        ip: usize,
+        message: Option<String>,
+        context: Option<Box<FaultContext>>,
+    },
+
+    /// An error raised by host code, such as a memory-mapped device
+    Host(String),
+
+    /// A device mapping would overlap one that already exists
+    OverlappingMapping { range: Range<usize> },
+
+    /// The entry point `ip` is outside a program of `len` instructions
+    BadEntry { ip: usize, len: usize },
+
+    /// The entry point can only be chosen before the machine executes anything
+    AlreadyStarted,
+
+    /// Initial data of `len` words does not fit a data memory of `size` words
+    DataTooLarge { len: usize, size: usize },
+
+    /// `len` arguments do not fit a stack of `size` values
+    TooManyArgs { len: usize, size: usize },
+
+    /// Results can only be taken once the program has executed `HLT`
+    NotHalted,
+
+    /// Asked for `requested` results with only `available` values on the stack
+    MissingResults { requested: usize, available: usize },
+
+    /// [`Machine::run_bounded`](crate::Machine::run_bounded) executed `limit` instructions
+    /// without the program stopping, the next one being at `ip`
+    InstructionLimit { limit: u64, ip: usize },
+
+    /// Step `step` of a run replayed by [`Machine::replay`](crate::Machine::replay) is `found`
+    /// where the trace has `expected`. `None` is the end of the run or of the trace.
+    TraceDivergence {
+        step: usize,
+        expected: Option<Box<TraceStep>>,
+        found: Option<Box<TraceStep>>,
+    },
+}
+
+impl VmError {
+    /// Whether the machine can go on after fixing its state. A recoverable fault (see
+    /// [`Fault::is_recoverable`]) of an instruction other than `SYS` undoes the instruction
+    /// that raised it and an illegal
+    /// instruction executes nothing, so the machine is left with its ip on the failing
+    /// instruction and the state from before it. Resuming tries the instruction again, after the
+    /// embedder has changed what made it fail, for example with
+    /// [`Machine::restore`](crate::Machine::restore).
+    pub fn is_recoverable(&self) -> bool {
+        match self {
+            VmError::Exec { fault, inst, .. } => fault.is_recoverable_in(*inst),
+            VmError::IllegalInstruction { .. } => true,
+            _ => false,
+        }
+    }
+
+    /// The instruction level fault, if this error came from executing an instruction
+    pub fn fault(&self) -> Option<&Fault> {
+        match self {
+            VmError::Exec { fault, .. } => Some(fault),
+            _ => None,
+        }
+    }
+
+    /// Machine state at the point of failure, for errors raised while executing a program.
+    /// Errors returned by host code are passed through as they are and carry no context.
+    pub fn context(&self) -> Option<&FaultContext> {
+        match self {
+            VmError::Exec { context, .. }
+            | VmError::IllegalInstruction { context, .. }
+            | VmError::ReplayDivergence { context, .. }
+            | VmError::Trap { context, .. } => context.as_deref(),
+            _ => None,
+        }
+    }
+
+    /// Attach `ctx` to the error if it is one that carries a context
+    pub(crate) fn with_context(mut self, ctx: FaultContext) -> Self {
+        match &mut self {
+            VmError::Exec { context, .. }
+            | VmError::IllegalInstruction { context, .. }
+            | VmError::ReplayDivergence { context, .. }
+            | VmError::Trap { context, .. } => *context = Some(Box::new(ctx)),
+            _ => {}
+        }
+        self
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for VmError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self.fault() {
+            Some(Fault::Stack(e)) => Some(e),
+            Some(Fault::Path(e)) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for VmError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            VmError::Exec {
+                ip,
+                inst,
+                fault,
+                depth,
+                ..
+            } => write!(
+                f,
+                "{fault} while executing `{inst}` at ip {ip}, stack depth {depth}"
+            )?,
+
+            VmError::IllegalInstruction { ip, .. } => {
+                write!(f, "illegal instruction at ip {ip}...abrupt halt")?
+            }
+
+            VmError::ReplayDivergence { ip, recorded, .. } => {
+                let fault = Fault::ReplayDivergence(recorded.clone());
+                write!(f, "replay diverged at ip {ip}: {fault}")?
+            }
+
+            VmError::Trap {
+                code, ip, message, ..
+            } => match message {
+                Some(message) => write!(f, "trap {code} at ip {ip}: {message}")?,
+                None => write!(f, "trap {code} at ip {ip}")?,
+            },
+
+            VmError::Host(message) => write!(f, "host error: {message}")?,
+
+            VmError::OverlappingMapping { range } => {
+                write!(f, "mapping {range:?} overlaps an existing mapping")?
+            }
+
+            VmError::BadEntry { ip, len } => write!(
+                f,
+                "entry point {ip} is outside the program ({len} instructions)"
+            )?,
+
+            VmError::AlreadyStarted => write!(f, "the machine has already started running")?,
+
+            VmError::DataTooLarge { len, size } => write!(
+                f,
+                "{len} words of data do not fit in {size} words of memory"
+            )?,
+
+            VmError::TooManyArgs { len, size } => {
+                write!(f, "{len} arguments do not fit in a stack of {size} values")?
+            }
+
+            VmError::NotHalted => write!(f, "the program has not halted")?,
+
+            VmError::MissingResults {
+                requested,
+                available,
+            } => write!(
+                f,
+                "asked for {requested} results but the stack holds {available} values"
+            )?,
+
+            VmError::InstructionLimit { limit, ip } => write!(
+                f,
+                "stopped at ip {ip} after executing the limit of {limit} instructions"
+            )?,
+
+            VmError::TraceDivergence {
+                step,
+                expected,
+                found,
+            } => match (expected, found) {
+                (Some(expected), Some(found)) => write!(
+                    f,
+                    "replay diverged at step {step}: the trace has `{expected}`, the run `{found}`"
+                )?,
+                (Some(expected), None) => write!(
+                    f,
+                    "replay diverged at step {step}: the run ended, the trace has `{expected}`"
+                )?,
+                (None, Some(found)) => write!(
+                    f,
+                    "replay diverged at step {step}: the trace ended, the run has `{found}`"
+                )?,
+                (None, None) => write!(f, "replay diverged at step {step}")?,
+            },
+        }
+        match self.context() {
+            Some(context) if f.alternate() => write!(f, "\n{context}"),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Machine state captured when a program fails, shown by the alternate format of `VmError`
+/// (`{:#}`).
+#[derive(Clone, Debug, PartialEq)]
+pub struct FaultContext {
+    pub ip: usize,
+
+    /// The faulting instruction, `None` when `ip` is outside the program
+    pub inst: Option<Inst>,
+
+    /// Up to the top few values of the stack, top first
+    pub stack: Vec<Word>,
+
+    /// Every register with its value, named registers first
+    pub registers: Vec<(Reg, Word)>,
+
+    /// Addresses of the last few instructions executed, oldest first. Ends with `ip` when the
+    /// instruction at `ip` was the one that failed.
+    pub recent: Vec<usize>,
+}
+
+impl fmt::Display for FaultContext {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.inst {
+            Some(inst) => writeln!(f, "  at ip {}: {inst}", self.ip)?,
+            None => writeln!(f, "  at ip {}: <outside the program>", self.ip)?,
+        }
+
+        write!(f, "  stack (top first):")?;
+        for val in &self.stack {
+            write!(f, " {val}")?;
+        }
+
+        write!(f, "\n  registers:")?;
+        for (reg, val) in &self.registers {
+            write!(f, " {reg}={val}")?;
+        }
+
+        write!(f, "\n  recent ips:")?;
+        for ip in &self.recent {
+            write!(f, " {ip}")?;
+        }
+        Ok(())
+    }
+}
+
+/// Ring buffer of the last `HISTORY_LEN` executed instruction addresses
+#[derive(Clone, Debug, Default)]
+pub(crate) struct IpHistory {
+    ips: [usize; HISTORY_LEN],
+    len: usize,
+    next: usize,
+}
+
+impl IpHistory {
+    pub(crate) fn record(&mut self, ip: usize) {
+        self.ips[self.next] = ip;
+        self.next = (self.next + 1) % HISTORY_LEN;
+        self.len = (self.len + 1).min(HISTORY_LEN);
+    }
+
+    /// Recorded addresses, oldest first
+    pub(crate) fn to_vec(&self) -> Vec<usize> {
+        let start = (self.next + HISTORY_LEN - self.len) % HISTORY_LEN;
+        (0..self.len)
+            .map(|i| self.ips[(start + i) % HISTORY_LEN])
+            .collect()
+    }
+}
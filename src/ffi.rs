@@ -0,0 +1,434 @@
+//! A C interface to the machine, enabled by the `ffi` feature, for C and C++ programs that embed
+//! the VM. Build it as a shared library with
+//! `cargo rustc --lib --release --features ffi --crate-type cdylib` and include
+//! `include/vyantra.h`.
+//!
+//! Every function returns a status instead of panicking: `VYANTRA_OK` or another status that is
+//! not negative when it did what was asked, and a negative `VYANTRA_ERR_` code when it did not,
+//! with [`vyantra_last_error`] describing why. A panic inside the library is caught and becomes
+//! `VYANTRA_ERR_PANIC`. A machine is only used from one thread at a time.
+//!
+//! Registers are numbered 0 to 5 for `A` to `F` and `6 + n` for `Reg::R(n)`.
+
+use std::cell::RefCell;
+use std::ffi::{c_char, c_int, CStr, CString};
+use std::panic::{self, AssertUnwindSafe};
+use std::ptr;
+
+use crate::{assemble_program, Machine, Program, Reg, RunOutcome, StepStatus, Word};
+
+/// The call succeeded, or the program halted
+pub const VYANTRA_OK: c_int = 0;
+
+/// The step executed an instruction and the program goes on
+pub const VYANTRA_RUNNING: c_int = 1;
+
+/// The program yielded a value, running again continues after the `YLD`
+pub const VYANTRA_YIELDED: c_int = 2;
+
+/// The program stopped for another reason, such as waiting on a port, and can be run again
+pub const VYANTRA_STOPPED: c_int = 3;
+
+/// A pointer argument was null
+pub const VYANTRA_ERR_NULL: c_int = -1;
+
+/// The program did not decode or assemble, or no machine could be made for it
+pub const VYANTRA_ERR_PROGRAM: c_int = -2;
+
+/// The program failed while running
+pub const VYANTRA_ERR_EXEC: c_int = -3;
+
+/// The machine has no such register
+pub const VYANTRA_ERR_REGISTER: c_int = -4;
+
+/// The index is past the end of the stack
+pub const VYANTRA_ERR_INDEX: c_int = -5;
+
+/// The program has not halted, so it has no exit code
+pub const VYANTRA_ERR_NOT_HALTED: c_int = -6;
+
+/// The library panicked, which is a bug in it
+pub const VYANTRA_ERR_PANIC: c_int = -7;
+
+/// A machine owned by C code, made by [`vyantra_machine_new`] or [`vyantra_machine_assemble`]
+/// and given back to [`vyantra_machine_free`]
+pub struct VyantraMachine {
+    machine: Machine,
+}
+
+thread_local! {
+    /// What the last failing call on this thread went wrong with
+    static LAST_ERROR: RefCell<Option<CString>> = const { RefCell::new(None) };
+}
+
+/// A failed call: its status and what went wrong
+struct Failure {
+    code: c_int,
+    message: String,
+}
+
+impl Failure {
+    fn new(code: c_int, message: impl ToString) -> Self {
+        Failure {
+            code,
+            message: message.to_string(),
+        }
+    }
+}
+
+/// Run `f`, turning a failure or a panic into its status and keeping its message
+fn guard(f: impl FnOnce() -> Result<c_int, Failure>) -> c_int {
+    let failure = match panic::catch_unwind(AssertUnwindSafe(f)) {
+        Ok(Ok(status)) => return status,
+        Ok(Err(failure)) => failure,
+        Err(_) => Failure::new(VYANTRA_ERR_PANIC, "vyantra panicked"),
+    };
+    // a message with a NUL in it is cut short there
+    let bytes = failure.message.into_bytes();
+    let end = bytes.iter().position(|&b| b == 0).unwrap_or(bytes.len());
+    let message = CString::new(&bytes[..end]).unwrap_or_default();
+    LAST_ERROR.with(|last| *last.borrow_mut() = Some(message));
+    failure.code
+}
+
+/// The machine behind `machine`, failing if it is null
+///
+/// # Safety
+///
+/// `machine` is null or was made by this library and not freed.
+unsafe fn machine_mut<'a>(machine: *mut VyantraMachine) -> Result<&'a mut Machine, Failure> {
+    match machine.as_mut() {
+        Some(owned) => Ok(&mut owned.machine),
+        None => Err(Failure::new(VYANTRA_ERR_NULL, "machine is null")),
+    }
+}
+
+/// Register number `reg`, failing if the machine has no such register
+fn register(machine: &Machine, reg: c_int) -> Result<Reg, Failure> {
+    let named = [Reg::A, Reg::B, Reg::C, Reg::D, Reg::E, Reg::F];
+    let found = match usize::try_from(reg) {
+        Ok(n) if n < named.len() => Some(named[n]),
+        Ok(n) => u8::try_from(n - named.len()).ok().map(Reg::R),
+        Err(_) => None,
+    };
+    match found {
+        Some(found) if machine.registers().contains_key(&found) => Ok(found),
+        _ => Err(Failure::new(
+            VYANTRA_ERR_REGISTER,
+            format!("no register {reg}"),
+        )),
+    }
+}
+
+/// Hand a new machine for `program` to C through `out`
+///
+/// # Safety
+///
+/// `out` is null or valid for a write.
+unsafe fn give(program: Program, out: *mut *mut VyantraMachine) -> Result<c_int, Failure> {
+    let machine = program
+        .machine()
+        .map_err(|e| Failure::new(VYANTRA_ERR_PROGRAM, e))?;
+    *out = Box::into_raw(Box::new(VyantraMachine { machine }));
+    Ok(VYANTRA_OK)
+}
+
+/// Make a machine for a program file of `len` bytes, as written by `vyantra asm`, storing it in
+/// `*out`.
+///
+/// # Safety
+///
+/// `bytes` points to `len` readable bytes and `out` is valid for a write.
+#[no_mangle]
+pub unsafe extern "C" fn vyantra_machine_new(
+    bytes: *const u8,
+    len: usize,
+    out: *mut *mut VyantraMachine,
+) -> c_int {
+    guard(|| {
+        if bytes.is_null() || out.is_null() {
+            return Err(Failure::new(VYANTRA_ERR_NULL, "bytes or out is null"));
+        }
+        let bytes = std::slice::from_raw_parts(bytes, len);
+        let program =
+            Program::from_bytes(bytes).map_err(|e| Failure::new(VYANTRA_ERR_PROGRAM, e))?;
+        give(program, out)
+    })
+}
+
+/// Make a machine for the assembly `source`, storing it in `*out`
+///
+/// # Safety
+///
+/// `source` is a NUL terminated string and `out` is valid for a write.
+#[no_mangle]
+pub unsafe extern "C" fn vyantra_machine_assemble(
+    source: *const c_char,
+    out: *mut *mut VyantraMachine,
+) -> c_int {
+    guard(|| {
+        if source.is_null() || out.is_null() {
+            return Err(Failure::new(VYANTRA_ERR_NULL, "source or out is null"));
+        }
+        let source = CStr::from_ptr(source)
+            .to_str()
+            .map_err(|e| Failure::new(VYANTRA_ERR_PROGRAM, e))?;
+        let program = assemble_program(source).map_err(|e| Failure::new(VYANTRA_ERR_PROGRAM, e))?;
+        give(program, out)
+    })
+}
+
+/// Free a machine. Null is ignored.
+///
+/// # Safety
+///
+/// `machine` is null or was made by this library and not freed.
+#[no_mangle]
+pub unsafe extern "C" fn vyantra_machine_free(machine: *mut VyantraMachine) {
+    if !machine.is_null() {
+        let _ = panic::catch_unwind(AssertUnwindSafe(|| drop(Box::from_raw(machine))));
+    }
+}
+
+/// Run until the program halts, yields or stops: `VYANTRA_OK` once halted, `VYANTRA_YIELDED`
+/// with the value in `*yielded` unless it is null, or `VYANTRA_STOPPED`
+///
+/// # Safety
+///
+/// `machine` was made by this library and not freed, `yielded` is null or valid for a write.
+#[no_mangle]
+pub unsafe extern "C" fn vyantra_machine_run(
+    machine: *mut VyantraMachine,
+    yielded: *mut Word,
+) -> c_int {
+    guard(|| {
+        let machine = machine_mut(machine)?;
+        let outcome = machine
+            .resume()
+            .map_err(|e| Failure::new(VYANTRA_ERR_EXEC, e))?;
+        Ok(match outcome {
+            RunOutcome::Halted => VYANTRA_OK,
+            RunOutcome::Yielded(val) => {
+                if !yielded.is_null() {
+                    *yielded = val;
+                }
+                VYANTRA_YIELDED
+            }
+            _ => VYANTRA_STOPPED,
+        })
+    })
+}
+
+/// Execute one instruction: `VYANTRA_RUNNING` if the program goes on, otherwise as
+/// [`vyantra_machine_run`]
+///
+/// # Safety
+///
+/// As [`vyantra_machine_run`].
+#[no_mangle]
+pub unsafe extern "C" fn vyantra_machine_step(
+    machine: *mut VyantraMachine,
+    yielded: *mut Word,
+) -> c_int {
+    guard(|| {
+        let machine = machine_mut(machine)?;
+        let outcome = machine
+            .step()
+            .map_err(|e| Failure::new(VYANTRA_ERR_EXEC, e))?;
+        Ok(match outcome.status {
+            StepStatus::Running => VYANTRA_RUNNING,
+            StepStatus::Halted => VYANTRA_OK,
+            StepStatus::Yielded(val) => {
+                if !yielded.is_null() {
+                    *yielded = val;
+                }
+                VYANTRA_YIELDED
+            }
+            StepStatus::WaitingOnPort(_) => VYANTRA_STOPPED,
+        })
+    })
+}
+
+/// Store the value of register `reg` in `*out`
+///
+/// # Safety
+///
+/// `machine` was made by this library and not freed, `out` is valid for a write.
+#[no_mangle]
+pub unsafe extern "C" fn vyantra_machine_get_reg(
+    machine: *mut VyantraMachine,
+    reg: c_int,
+    out: *mut Word,
+) -> c_int {
+    guard(|| {
+        let machine = machine_mut(machine)?;
+        if out.is_null() {
+            return Err(Failure::new(VYANTRA_ERR_NULL, "out is null"));
+        }
+        *out = machine.registers()[&register(machine, reg)?];
+        Ok(VYANTRA_OK)
+    })
+}
+
+/// Set register `reg` to `val`
+///
+/// # Safety
+///
+/// `machine` was made by this library and not freed.
+#[no_mangle]
+pub unsafe extern "C" fn vyantra_machine_set_reg(
+    machine: *mut VyantraMachine,
+    reg: c_int,
+    val: Word,
+) -> c_int {
+    guard(|| {
+        let machine = machine_mut(machine)?;
+        let reg = register(machine, reg)?;
+        machine
+            .set_reg_value(reg, val)
+            .map_err(|e| Failure::new(VYANTRA_ERR_REGISTER, e))?;
+        Ok(VYANTRA_OK)
+    })
+}
+
+/// Number of values on the stack, 0 for a null machine
+///
+/// # Safety
+///
+/// `machine` is null or was made by this library and not freed.
+#[no_mangle]
+pub unsafe extern "C" fn vyantra_machine_stack_len(machine: *mut VyantraMachine) -> usize {
+    machine
+        .as_ref()
+        .map_or(0, |owned| owned.machine.stack().len())
+}
+
+/// Store the stack value at `index`, counting from the bottom of the stack, in `*out`
+///
+/// # Safety
+///
+/// `machine` was made by this library and not freed, `out` is valid for a write.
+#[no_mangle]
+pub unsafe extern "C" fn vyantra_machine_stack_get(
+    machine: *mut VyantraMachine,
+    index: usize,
+    out: *mut Word,
+) -> c_int {
+    guard(|| {
+        let machine = machine_mut(machine)?;
+        if out.is_null() {
+            return Err(Failure::new(VYANTRA_ERR_NULL, "out is null"));
+        }
+        let stack = machine.stack();
+        match stack.get(index) {
+            Some(&val) => *out = val,
+            None => {
+                let message = format!("index {index} with {} values on the stack", stack.len());
+                return Err(Failure::new(VYANTRA_ERR_INDEX, message));
+            }
+        }
+        Ok(VYANTRA_OK)
+    })
+}
+
+/// Store the exit code of the halted program in `*out`
+///
+/// # Safety
+///
+/// `machine` was made by this library and not freed, `out` is valid for a write.
+#[no_mangle]
+pub unsafe extern "C" fn vyantra_machine_exit_code(
+    machine: *mut VyantraMachine,
+    out: *mut c_int,
+) -> c_int {
+    guard(|| {
+        let machine = machine_mut(machine)?;
+        if out.is_null() {
+            return Err(Failure::new(VYANTRA_ERR_NULL, "out is null"));
+        }
+        match machine.exit_code() {
+            Some(code) => *out = code,
+            None => return Err(Failure::new(VYANTRA_ERR_NOT_HALTED, "not halted")),
+        }
+        Ok(VYANTRA_OK)
+    })
+}
+
+/// What the last failing call on this thread went wrong with, null if none has failed. The string
+/// stays valid until the next call that fails.
+#[no_mangle]
+pub extern "C" fn vyantra_last_error() -> *const c_char {
+    LAST_ERROR.with(|last| match &*last.borrow() {
+        Some(message) => message.as_ptr(),
+        None => ptr::null(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn last_error() -> String {
+        let message = vyantra_last_error();
+        assert!(!message.is_null());
+        unsafe { CStr::from_ptr(message) }
+            .to_string_lossy()
+            .into_owned()
+    }
+
+    #[test]
+    fn c_interface() {
+        let source = CString::new("set a 5\npsh 7\nyld\nloop a 0\ninc b\nexit 3\n").unwrap();
+        let mut machine = ptr::null_mut();
+        unsafe {
+            assert_eq!(
+                vyantra_machine_assemble(source.as_ptr(), &mut machine),
+                VYANTRA_OK
+            );
+            assert_eq!(vyantra_machine_set_reg(machine, 6 + 2, 40), VYANTRA_OK);
+            let mut val = 0;
+            assert_eq!(vyantra_machine_run(machine, &mut val), VYANTRA_YIELDED);
+            assert_eq!(val, 7);
+            assert_eq!(
+                vyantra_machine_step(machine, ptr::null_mut()),
+                VYANTRA_RUNNING
+            );
+            assert_eq!(vyantra_machine_run(machine, ptr::null_mut()), VYANTRA_OK);
+
+            let mut code = 0;
+            assert_eq!(vyantra_machine_exit_code(machine, &mut code), VYANTRA_OK);
+            assert_eq!(code, 3);
+            for (reg, expected) in [(0, 4), (1, 1), (6 + 2, 40)] {
+                assert_eq!(vyantra_machine_get_reg(machine, reg, &mut val), VYANTRA_OK);
+                assert_eq!(val, expected);
+            }
+            assert_eq!(vyantra_machine_stack_len(machine), 0);
+            let status = vyantra_machine_stack_get(machine, 0, &mut val);
+            assert_eq!(status, VYANTRA_ERR_INDEX);
+            assert_eq!(last_error(), "index 0 with 0 values on the stack");
+            let status = vyantra_machine_get_reg(machine, 6 + 200, &mut val);
+            assert_eq!(status, VYANTRA_ERR_REGISTER);
+            vyantra_machine_free(machine);
+
+            let bytes = assemble_program("psh 1\nadd\n")
+                .unwrap()
+                .to_bytes()
+                .unwrap();
+            assert_eq!(
+                vyantra_machine_new(bytes.as_ptr(), bytes.len(), &mut machine),
+                VYANTRA_OK
+            );
+            let status = vyantra_machine_run(machine, ptr::null_mut());
+            assert_eq!(status, VYANTRA_ERR_EXEC);
+            assert!(last_error().starts_with("cannot pop from an empty stack"));
+            assert_eq!(vyantra_machine_stack_get(machine, 0, &mut val), VYANTRA_OK);
+            assert_eq!(val, 1);
+            vyantra_machine_free(machine);
+
+            let status = vyantra_machine_new(bytes.as_ptr(), 3, &mut machine);
+            assert_eq!(status, VYANTRA_ERR_PROGRAM);
+            let status = vyantra_machine_run(ptr::null_mut(), ptr::null_mut());
+            assert_eq!(status, VYANTRA_ERR_NULL);
+        }
+    }
+}
@@ -0,0 +1,25 @@
+//! Superinstructions: common short sequences of instructions that a machine configured with
+//! [`MachineConfig::fuse`](crate::MachineConfig::fuse) executes in one dispatch.
+//!
+//! The program itself is not rewritten. Fusing finds where a sequence starts and the program
+//! decoded for the plain run loop has the superinstruction there instead of the op of the first
+//! instruction, which moves the instruction pointer past the whole sequence. Every other
+//! position keeps its op, so a jump into the middle of a sequence, a step or a fault sees the
+//! program as it was written.
+
+use crate::op::Op;
+use crate::{Inst, Path};
+
+/// Number of instructions every superinstruction stands for
+pub(crate) const FUSED_LEN: usize = 2;
+
+/// The superinstruction of `first` followed by `second`, if they make one
+pub(crate) fn superinstruction(first: Inst, second: Inst) -> Option<Op> {
+    match (first, second) {
+        (Inst::PSH(val), Inst::ADD) => Some(Op::AddImm(val)),
+        (Inst::PSH(val), Inst::SUB) => Some(Op::SubImm(val)),
+        (Inst::PSH(val), Inst::MUL) => Some(Op::MulImm(val)),
+        (Inst::CPY(Path::REG(dst), Path::STK(0)), Inst::POP) => Some(Op::PopTo(dst)),
+        _ => None,
+    }
+}
@@ -0,0 +1,405 @@
+//! A stub for the GDB remote serial protocol, so a debugger frontend that speaks it can attach
+//! to a machine over TCP.
+//!
+//! The stub answers one packet at a time: stop reasons, reading the registers and data memory,
+//! setting and removing software breakpoints, stepping and continuing. Registers are words (32
+//! bits, or 64 with the `word64` feature), `A` to `F`, then the numbered registers the machine
+//! has, then `ip` as a 32 bit program counter, in the layout of the target description the stub
+//! serves. Data memory is addressed in bytes, little endian. A breakpoint address is an
+//! instruction index.
+//!
+//! Continuing runs until the program stops and cannot be interrupted from the frontend.
+
+use std::io::{self, BufReader, Read, Write};
+use std::net::{TcpListener, ToSocketAddrs};
+
+use crate::{DebugStop, Debugger, Machine, Reg, StepStatus, Word};
+
+/// `SIGTRAP`, reported for breakpoints, steps, yields and waits
+const SIGTRAP: u8 = 5;
+
+/// `SIGILL`, reported when the program faults
+const SIGILL: u8 = 4;
+
+/// Serves the remote protocol for one machine.
+pub struct GdbStub {
+    debugger: Debugger,
+}
+
+impl GdbStub {
+    pub fn new(machine: Machine) -> Self {
+        GdbStub {
+            debugger: Debugger::new(machine),
+        }
+    }
+
+    /// Stop serving and hand the machine back
+    pub fn into_machine(self) -> Machine {
+        self.debugger.into_inner()
+    }
+
+    /// Wait for a frontend to connect on `addr` and serve it until it detaches or kills the
+    /// program
+    pub fn listen(&mut self, addr: impl ToSocketAddrs) -> io::Result<()> {
+        let (stream, _) = TcpListener::bind(addr)?.accept()?;
+        self.serve(stream)
+    }
+
+    /// Serve packets read from `conn` until the frontend detaches, kills the program or closes
+    /// the connection
+    pub fn serve(&mut self, conn: impl Read + Write) -> io::Result<()> {
+        let mut conn = BufReader::new(conn);
+        while let Some(packet) = read_packet(&mut conn)? {
+            let packet = match packet {
+                Some(packet) => packet,
+                None => {
+                    // a corrupted packet, ask for it again
+                    conn.get_mut().write_all(b"-")?;
+                    continue;
+                }
+            };
+            conn.get_mut().write_all(b"+")?;
+            match packet.as_str() {
+                "k" => return Ok(()),
+                "D" => {
+                    send(conn.get_mut(), "OK")?;
+                    return Ok(());
+                }
+                _ => {
+                    let reply = self.reply(&packet);
+                    send(conn.get_mut(), &reply)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// The reply to a packet, empty for packets the stub does not support
+    fn reply(&mut self, packet: &str) -> String {
+        let (cmd, args) = packet.split_at(packet.chars().next().map_or(0, char::len_utf8));
+        match cmd {
+            "?" => format!("S{SIGTRAP:02x}"),
+            "g" => {
+                let machine = self.debugger.machine();
+                let mut regs: Vec<String> = register_order(machine)
+                    .iter()
+                    .map(|reg| hex_word(machine.registers()[reg]))
+                    .collect();
+                regs.push(hex_ip(machine.ip()));
+                regs.concat()
+            }
+            "p" => {
+                let machine = self.debugger.machine();
+                let regs = register_order(machine);
+                match usize::from_str_radix(args, 16) {
+                    Ok(n) if n < regs.len() => hex_word(machine.registers()[&regs[n]]),
+                    Ok(n) if n == regs.len() => hex_ip(machine.ip()),
+                    _ => "E01".to_string(),
+                }
+            }
+            "m" => match parse_pair(args, ',') {
+                Some((addr, len)) => self.read_memory(addr, len),
+                None => "E01".to_string(),
+            },
+            "Z" | "z" => match args
+                .strip_prefix("0,")
+                .and_then(|rest| parse_pair(rest, ','))
+            {
+                Some((ip, _)) => {
+                    if cmd == "Z" {
+                        self.debugger.add_breakpoint(ip);
+                    } else {
+                        self.debugger.remove_breakpoint(ip);
+                    }
+                    "OK".to_string()
+                }
+                // only software breakpoints, watchpoints go through `Debugger`
+                None => String::new(),
+            },
+            "s" => self.stop_reply(|debugger| debugger.step().map(|outcome| outcome.status)),
+            "c" => self.stop_reply(|debugger| {
+                debugger.continue_().map(|stop| match stop {
+                    DebugStop::Halted => StepStatus::Halted,
+                    _ => StepStatus::Running,
+                })
+            }),
+            "H" => "OK".to_string(),
+            "q" if args.starts_with("Supported") => {
+                "PacketSize=1000;qXfer:features:read+".to_string()
+            }
+            "q" if args == "Attached" => "1".to_string(),
+            "q" => match args.strip_prefix("Xfer:features:read:target.xml:") {
+                Some(range) => match parse_pair(range, ',') {
+                    Some((offset, len)) => read_part(&self.target_xml(), offset, len),
+                    None => "E01".to_string(),
+                },
+                None => String::new(),
+            },
+            _ => String::new(),
+        }
+    }
+
+    /// Run `resume` and report how the program stopped
+    fn stop_reply(
+        &mut self,
+        resume: impl FnOnce(&mut Debugger) -> Result<StepStatus, crate::VmError>,
+    ) -> String {
+        if let Some(code) = self.debugger.machine().exit_code() {
+            return format!("W{:02x}", code as u8);
+        }
+        match resume(&mut self.debugger) {
+            Ok(StepStatus::Halted) => {
+                let code = self.debugger.machine().exit_code().unwrap_or(0);
+                format!("W{:02x}", code as u8)
+            }
+            Ok(_) => format!("S{SIGTRAP:02x}"),
+            Err(_) => format!("S{SIGILL:02x}"),
+        }
+    }
+
+    /// `len` bytes of data memory from byte address `addr`, as hex
+    fn read_memory(&self, addr: usize, len: usize) -> String {
+        let memory = self.debugger.machine().memory();
+        let bytes: Vec<u8> = memory.iter().flat_map(|word| word.to_le_bytes()).collect();
+        match addr.checked_add(len).and_then(|end| bytes.get(addr..end)) {
+            Some(bytes) => hex(bytes),
+            None => "E01".to_string(),
+        }
+    }
+
+    /// The target description, listing the registers in the order of `g`
+    fn target_xml(&self) -> String {
+        let machine = self.debugger.machine();
+        let mut xml = String::from(
+            "<?xml version=\"1.0\"?><!DOCTYPE target SYSTEM \"gdb-target.dtd\">\
+             <target><feature name=\"org.vyantra.core\">",
+        );
+        let bits = Word::BITS;
+        for reg in register_order(machine) {
+            xml.push_str(&format!(
+                "<reg name=\"{reg}\" bitsize=\"{bits}\" type=\"int{bits}\"/>"
+            ));
+        }
+        xml.push_str("<reg name=\"pc\" bitsize=\"32\" type=\"code_ptr\"/></feature></target>");
+        xml
+    }
+}
+
+/// The registers of `machine` in protocol order, `A` to `F` and then the numbered ones
+fn register_order(machine: &Machine) -> Vec<Reg> {
+    let named = [Reg::A, Reg::B, Reg::C, Reg::D, Reg::E, Reg::F];
+    let numbered = (0..=u8::MAX).map(Reg::R);
+    let registers = machine.registers();
+    named
+        .into_iter()
+        .chain(numbered)
+        .filter(|reg| registers.contains_key(reg))
+        .collect()
+}
+
+/// A word as the protocol sends it, little endian hex
+fn hex_word(word: Word) -> String {
+    hex(&word.to_le_bytes())
+}
+
+/// The instruction pointer as the 32 bit program counter
+fn hex_ip(ip: usize) -> String {
+    hex(&(ip as u32).to_le_bytes())
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{byte:02x}")).collect()
+}
+
+/// Two hex numbers separated by `sep`
+fn parse_pair(text: &str, sep: char) -> Option<(usize, usize)> {
+    let (a, b) = text.split_once(sep)?;
+    Some((
+        usize::from_str_radix(a, 16).ok()?,
+        usize::from_str_radix(b, 16).ok()?,
+    ))
+}
+
+/// The part of `doc` a `qXfer` read asked for, `m` if more follows and `l` if it is the last
+fn read_part(doc: &str, offset: usize, len: usize) -> String {
+    let rest = doc.get(offset..).unwrap_or("");
+    if rest.len() > len {
+        format!("m{}", &rest[..len])
+    } else {
+        format!("l{rest}")
+    }
+}
+
+/// Read the next `$data#checksum` packet, skipping acks and anything between packets. `None`
+/// once the connection is closed, `Some(None)` for a packet with the wrong checksum.
+fn read_packet(conn: &mut impl Read) -> io::Result<Option<Option<String>>> {
+    let mut byte = [0];
+    loop {
+        if conn.read(&mut byte)? == 0 {
+            return Ok(None);
+        }
+        if byte[0] == b'$' {
+            break;
+        }
+    }
+    let mut data = Vec::new();
+    loop {
+        if conn.read(&mut byte)? == 0 {
+            return Ok(None);
+        }
+        if byte[0] == b'#' {
+            break;
+        }
+        data.push(byte[0]);
+    }
+    let mut checksum = [0; 2];
+    conn.read_exact(&mut checksum)?;
+    let expected = std::str::from_utf8(&checksum)
+        .ok()
+        .and_then(|text| u8::from_str_radix(text, 16).ok());
+    if expected == Some(checksum_of(&data)) {
+        Ok(Some(Some(String::from_utf8_lossy(&data).into_owned())))
+    } else {
+        Ok(Some(None))
+    }
+}
+
+fn send(conn: &mut impl Write, data: &str) -> io::Result<()> {
+    write!(conn, "${data}#{:02x}", checksum_of(data.as_bytes()))?;
+    conn.flush()
+}
+
+fn checksum_of(data: &[u8]) -> u8 {
+    data.iter().fold(0, |sum, byte| sum.wrapping_add(*byte))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Inst;
+    use std::io::Cursor;
+
+    /// A connection replaying what a frontend sent and keeping what the stub wrote
+    struct Conn {
+        input: Cursor<Vec<u8>>,
+        output: Vec<u8>,
+    }
+
+    impl Read for Conn {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.input.read(buf)
+        }
+    }
+
+    impl Write for Conn {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.output.write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    /// Send `packets` to a stub for `program` and return the replies, acks left out
+    fn session(program: Vec<Inst>, packets: &[&str]) -> Vec<String> {
+        let mut input = Vec::new();
+        for packet in packets {
+            send(&mut input, packet).unwrap();
+            input.push(b'+');
+        }
+        let mut conn = Conn {
+            input: Cursor::new(input),
+            output: Vec::new(),
+        };
+        let machine = Machine::with_registers(program, 1).unwrap();
+        GdbStub::new(machine).serve(&mut conn).unwrap();
+
+        let output = String::from_utf8(conn.output).unwrap();
+        let replies = output.split('$').skip(1);
+        replies
+            .map(|reply| reply.split('#').next().unwrap().to_string())
+            .collect()
+    }
+
+    #[test]
+    fn debugging_session() {
+        let program = vec![
+            Inst::SET(Reg::A, 0x102),
+            Inst::PSH(7),
+            Inst::STORE(1),
+            Inst::EXIT(3),
+        ];
+        // the second word of data memory
+        let size = Word::BITS / 8;
+        let read = format!("m{size:x},{size:x}");
+        let replies = session(
+            program,
+            &[
+                "qSupported:multiprocess+",
+                "?",
+                "Z0,2,1",
+                "c",
+                "g",
+                "p7",
+                &read,
+                "z0,2,1",
+                "s",
+                &read,
+                "vMustReplyEmpty",
+                "c",
+                "D",
+            ],
+        );
+        assert_eq!(
+            replies,
+            [
+                "PacketSize=1000;qXfer:features:read+",
+                "S05",
+                "OK",
+                "S05",
+                // a to f, r0 and ip
+                &([0x102, 0, 0, 0, 0, 0, 0].map(hex_word).concat() + "02000000"),
+                "02000000",
+                &hex_word(0),
+                "OK",
+                "S05",
+                &hex_word(7),
+                "",
+                "W03",
+                "OK",
+            ]
+        );
+    }
+
+    #[test]
+    fn target_description() {
+        let replies = session(
+            vec![Inst::HLT],
+            &[
+                "qXfer:features:read:target.xml:0,20",
+                "qXfer:features:read:target.xml:0,1000",
+            ],
+        );
+        assert_eq!(replies[0], "m<?xml version=\"1.0\"?><!DOCTYPE t");
+        assert!(replies[1].starts_with('l'));
+        let bits = Word::BITS;
+        assert!(replies[1].contains(&format!(
+            "<reg name=\"r0\" bitsize=\"{bits}\" type=\"int{bits}\"/>"
+        )));
+        assert!(replies[1]
+            .ends_with("<reg name=\"pc\" bitsize=\"32\" type=\"code_ptr\"/></feature></target>"));
+    }
+
+    #[test]
+    fn corrupted_packets_are_asked_again() {
+        let mut conn = Conn {
+            input: Cursor::new(b"$?#00$?#3f".to_vec()),
+            output: Vec::new(),
+        };
+        GdbStub::new(Machine::new(vec![Inst::HLT]))
+            .serve(&mut conn)
+            .unwrap();
+        assert_eq!(conn.output, b"-+$S05#b8");
+    }
+}
@@ -0,0 +1,138 @@
+//! Arrays of words allocated by `ALLOC`, known to the program by their handles, and the garbage
+//! collector that frees the ones the program can no longer reach.
+
+use alloc::vec;
+use alloc::vec::Vec;
+
+use crate::error::Fault;
+use crate::Word;
+
+pub(crate) struct Heap {
+    /// The arrays, the one with handle `h` at `h - 1`, so that no array has the handle 0.
+    /// `None` where an array was freed, its handle goes to the next array allocated.
+    pub(crate) arrays: Vec<Option<Vec<Word>>>,
+
+    /// Most words the arrays can take together
+    size: usize,
+
+    /// Words the arrays take now
+    used: usize,
+}
+
+impl Heap {
+    pub(crate) fn new(size: usize) -> Self {
+        Heap {
+            arrays: Vec::new(),
+            size,
+            used: 0,
+        }
+    }
+
+    /// Whether an array of `len` words fits in what is left
+    pub(crate) fn fits(&self, len: usize) -> bool {
+        len <= self.size - self.used
+    }
+
+    /// A new array of `len` zeros, returning its handle
+    pub(crate) fn alloc(&mut self, len: usize) -> Result<Word, Fault> {
+        if !self.fits(len) {
+            return Err(Fault::OutOfMemory(len));
+        }
+        let free = self.arrays.iter().position(Option::is_none);
+        let pos = free.unwrap_or(self.arrays.len());
+        let handle = Word::try_from(pos + 1).map_err(|_| Fault::OutOfMemory(len))?;
+        match self.arrays.get_mut(pos) {
+            Some(slot) => *slot = Some(vec![0; len]),
+            None => self.arrays.push(Some(vec![0; len])),
+        }
+        self.used += len;
+        Ok(handle)
+    }
+
+    pub(crate) fn load(&self, handle: Word, index: Word) -> Result<Word, Fault> {
+        let array = self.array(handle)?;
+        let slot = usize::try_from(index).ok().and_then(|idx| array.get(idx));
+        slot.copied().ok_or(Fault::ArrayIndex {
+            handle,
+            index,
+            len: array.len(),
+        })
+    }
+
+    pub(crate) fn store(&mut self, handle: Word, index: Word, val: Word) -> Result<(), Fault> {
+        let array = Heap::position(handle).and_then(|pos| self.arrays.get_mut(pos));
+        let array = array
+            .and_then(Option::as_mut)
+            .ok_or(Fault::BadHandle(handle))?;
+        let len = array.len();
+        match usize::try_from(index)
+            .ok()
+            .and_then(|idx| array.get_mut(idx))
+        {
+            Some(slot) => {
+                *slot = val;
+                Ok(())
+            }
+            None => Err(Fault::ArrayIndex { handle, index, len }),
+        }
+    }
+
+    /// Mark every array reachable from `roots`, directly or through other arrays, and free the
+    /// rest, returning the number of words freed. The collector is conservative: any word that
+    /// is the handle of an array keeps it, whether or not the program means it as a handle.
+    pub(crate) fn collect(&mut self, roots: impl IntoIterator<Item = Word>) -> usize {
+        let mut marked = vec![false; self.arrays.len()];
+        let mut pending: Vec<usize> = roots.into_iter().filter_map(|w| self.live(w)).collect();
+        while let Some(pos) = pending.pop() {
+            if marked[pos] {
+                continue;
+            }
+            marked[pos] = true;
+            if let Some(array) = &self.arrays[pos] {
+                pending.extend(array.iter().filter_map(|&w| self.live(w)));
+            }
+        }
+
+        let mut freed = 0;
+        for (slot, marked) in self.arrays.iter_mut().zip(marked) {
+            if let (false, Some(array)) = (marked, &slot) {
+                freed += array.len();
+                *slot = None;
+            }
+        }
+        while let Some(None) = self.arrays.last() {
+            self.arrays.pop();
+        }
+        self.used -= freed;
+        freed
+    }
+
+    /// Put back arrays taken from another heap
+    pub(crate) fn replace(&mut self, arrays: &[Option<Vec<Word>>]) {
+        self.arrays = arrays.to_vec();
+        self.used = arrays.iter().flatten().map(Vec::len).sum();
+    }
+
+    pub(crate) fn clear(&mut self) {
+        self.arrays.clear();
+        self.used = 0;
+    }
+
+    fn array(&self, handle: Word) -> Result<&Vec<Word>, Fault> {
+        let array = Heap::position(handle).and_then(|pos| self.arrays.get(pos));
+        array
+            .and_then(Option::as_ref)
+            .ok_or(Fault::BadHandle(handle))
+    }
+
+    /// Position in `arrays` of the array with `handle`, if there is one
+    fn live(&self, handle: Word) -> Option<usize> {
+        let pos = Heap::position(handle)?;
+        matches!(self.arrays.get(pos), Some(Some(_))).then_some(pos)
+    }
+
+    /// Position in `arrays` that an array with `handle` would have
+    fn position(handle: Word) -> Option<usize> {
+        usize::try_from(handle).ok()?.checked_sub(1)
+    }
+}
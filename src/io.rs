@@ -0,0 +1,127 @@
+//! Where a program's input comes from and its output goes, and recording the input for later
+//! replay.
+
+use alloc::vec::Vec;
+#[cfg(feature = "std")]
+use std::io::{self, BufRead, BufReader, Stdin};
+#[cfg(feature = "std")]
+use std::sync::mpsc::{Receiver, Sender};
+
+use crate::{Reg, Word};
+
+/// A source of values for the `IN` instruction. Any iterator of `Word`s is one, which is handy
+/// for scripted input in tests.
+pub trait InputSource: Send {
+    /// The next value, or `None` if the source has run dry
+    fn next_input(&mut self) -> Option<Word>;
+}
+
+impl<I: Iterator<Item = Word> + Send> InputSource for I {
+    fn next_input(&mut self) -> Option<Word> {
+        self.next()
+    }
+}
+
+/// An input source reading integers separated by whitespace from a reader. It runs dry at the
+/// end of the reader, on a read error, or at the first word that is not a `Word`.
+#[cfg(feature = "std")]
+pub struct ReadInput<R> {
+    reader: R,
+    line: String,
+    pos: usize,
+}
+
+#[cfg(feature = "std")]
+impl<R: BufRead> ReadInput<R> {
+    pub fn new(reader: R) -> Self {
+        ReadInput {
+            reader,
+            line: String::new(),
+            pos: 0,
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl ReadInput<BufReader<Stdin>> {
+    /// Read from standard input
+    pub fn stdin() -> Self {
+        ReadInput::new(BufReader::new(io::stdin()))
+    }
+}
+
+#[cfg(feature = "std")]
+impl<R: BufRead + Send> InputSource for ReadInput<R> {
+    fn next_input(&mut self) -> Option<Word> {
+        loop {
+            let rest = &self.line[self.pos..];
+            let start = rest.len() - rest.trim_start().len();
+            let word = rest[start..].split_whitespace().next();
+            if let Some(word) = word {
+                self.pos += start + word.len();
+                return word.parse().ok();
+            }
+            self.line.clear();
+            self.pos = 0;
+            match self.reader.read_line(&mut self.line) {
+                Ok(0) | Err(_) => return None,
+                Ok(_) => {}
+            }
+        }
+    }
+}
+
+/// Where a machine writes what it prints, set with
+/// [`Machine::set_output`](crate::Machine::set_output): any `std::io::Write`, or any
+/// `core::fmt::Write` without the `std` feature. It must be `Send` so that the machine stays
+/// `Send`.
+#[cfg(feature = "std")]
+pub trait OutputSink: std::io::Write + Send {}
+
+#[cfg(feature = "std")]
+impl<W: std::io::Write + Send> OutputSink for W {}
+
+#[cfg(not(feature = "std"))]
+pub trait OutputSink: core::fmt::Write + Send {}
+
+#[cfg(not(feature = "std"))]
+impl<W: core::fmt::Write + Send> OutputSink for W {}
+
+/// Both ends of a numbered port used by `SND` and `RCV`
+#[cfg(feature = "std")]
+pub(crate) struct Port {
+    pub(crate) tx: Sender<Word>,
+    pub(crate) rx: Receiver<Word>,
+}
+
+/// One value delivered to the program from outside the machine.
+#[derive(Clone, Debug, PartialEq)]
+pub enum InputEvent {
+    /// A value read by `IN`
+    In(Word),
+
+    /// What the system call `number` made by a `SYS` did to the machine, in order
+    Sys {
+        number: u32,
+        effects: Vec<SysEffect>,
+    },
+
+    /// The value a host function called by `HCALL` returned for its `arity` arguments
+    HostCall { arity: usize, result: Word },
+}
+
+/// A change a system call made to the machine through its [`SysCtx`](crate::sys::SysCtx)
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum SysEffect {
+    Pop,
+    Push(Word),
+    SetReg(Reg, Word),
+}
+
+/// Every value delivered to a program during a run, in order. A machine created with
+/// [`Machine::with_replay`](crate::Machine::with_replay) takes its input from here instead of
+/// its input source, which makes a run that depended on live input reproducible.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Recording {
+    pub events: Vec<InputEvent>,
+}
@@ -0,0 +1,298 @@
+//! A small JSON value with a parser and a printer, shared by the debug adapter and traces.
+
+// without `std` traces are only printed, reading them back is left to the tools
+#![cfg_attr(not(feature = "std"), allow(dead_code))]
+
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+
+/// Just enough JSON for the messages of the debug adapter and for traces
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) enum Json {
+    Null,
+    Bool(bool),
+    Num(f64),
+    Str(String),
+    Arr(Vec<Json>),
+    Obj(Vec<(String, Json)>),
+}
+
+impl Json {
+    pub(crate) fn obj(fields: Vec<(&str, Json)>) -> Json {
+        let fields = fields.into_iter().map(|(key, val)| (key.to_string(), val));
+        Json::Obj(fields.collect())
+    }
+
+    pub(crate) fn get(&self, key: &str) -> Option<&Json> {
+        match self {
+            Json::Obj(fields) => fields.iter().find(|(k, _)| k == key).map(|(_, v)| v),
+            _ => None,
+        }
+    }
+
+    pub(crate) fn as_str(&self) -> Option<&str> {
+        match self {
+            Json::Str(s) => Some(s),
+            _ => None,
+        }
+    }
+
+    pub(crate) fn as_i64(&self) -> Option<i64> {
+        match self {
+            Json::Num(n) if *n as i64 as f64 == *n => Some(*n as i64),
+            _ => None,
+        }
+    }
+
+    pub(crate) fn as_bool(&self) -> Option<bool> {
+        match self {
+            Json::Bool(b) => Some(*b),
+            _ => None,
+        }
+    }
+
+    pub(crate) fn as_arr(&self) -> Option<&[Json]> {
+        match self {
+            Json::Arr(items) => Some(items),
+            _ => None,
+        }
+    }
+
+    pub(crate) fn parse(text: &str) -> Option<Json> {
+        let mut parser = Parser {
+            text: text.as_bytes(),
+            pos: 0,
+        };
+        let value = parser.value()?;
+        parser.space();
+        (parser.pos == text.len()).then_some(value)
+    }
+}
+
+impl From<bool> for Json {
+    fn from(b: bool) -> Self {
+        Json::Bool(b)
+    }
+}
+
+impl From<i64> for Json {
+    fn from(n: i64) -> Self {
+        Json::Num(n as f64)
+    }
+}
+
+impl From<i32> for Json {
+    fn from(n: i32) -> Self {
+        Json::Num(n.into())
+    }
+}
+
+impl From<usize> for Json {
+    fn from(n: usize) -> Self {
+        Json::Num(n as f64)
+    }
+}
+
+impl From<&str> for Json {
+    fn from(s: &str) -> Self {
+        Json::Str(s.to_string())
+    }
+}
+
+impl From<String> for Json {
+    fn from(s: String) -> Self {
+        Json::Str(s)
+    }
+}
+
+impl core::fmt::Display for Json {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            Json::Null => write!(f, "null"),
+            Json::Bool(b) => write!(f, "{b}"),
+            Json::Num(n) => write!(f, "{n}"),
+            Json::Str(s) => write_string(f, s),
+            Json::Arr(items) => {
+                write!(f, "[")?;
+                for (idx, item) in items.iter().enumerate() {
+                    if idx > 0 {
+                        write!(f, ",")?;
+                    }
+                    write!(f, "{item}")?;
+                }
+                write!(f, "]")
+            }
+            Json::Obj(fields) => {
+                write!(f, "{{")?;
+                for (idx, (key, val)) in fields.iter().enumerate() {
+                    if idx > 0 {
+                        write!(f, ",")?;
+                    }
+                    write_string(f, key)?;
+                    write!(f, ":{val}")?;
+                }
+                write!(f, "}}")
+            }
+        }
+    }
+}
+
+fn write_string(f: &mut core::fmt::Formatter<'_>, s: &str) -> core::fmt::Result {
+    write!(f, "\"")?;
+    for c in s.chars() {
+        match c {
+            '"' => write!(f, "\\\"")?,
+            '\\' => write!(f, "\\\\")?,
+            '\n' => write!(f, "\\n")?,
+            '\r' => write!(f, "\\r")?,
+            '\t' => write!(f, "\\t")?,
+            c if (c as u32) < 0x20 => write!(f, "\\u{:04x}", c as u32)?,
+            c => write!(f, "{c}")?,
+        }
+    }
+    write!(f, "\"")
+}
+
+struct Parser<'a> {
+    text: &'a [u8],
+    pos: usize,
+}
+
+impl Parser<'_> {
+    fn space(&mut self) {
+        while self.text.get(self.pos).is_some_and(u8::is_ascii_whitespace) {
+            self.pos += 1;
+        }
+    }
+
+    /// Skip `word` if it comes next
+    fn eat(&mut self, word: &str) -> bool {
+        let found = self.text[self.pos..].starts_with(word.as_bytes());
+        if found {
+            self.pos += word.len();
+        }
+        found
+    }
+
+    fn value(&mut self) -> Option<Json> {
+        self.space();
+        match *self.text.get(self.pos)? {
+            b'{' => self.object(),
+            b'[' => self.array(),
+            b'"' => self.string().map(Json::Str),
+            b't' if self.eat("true") => Some(Json::Bool(true)),
+            b'f' if self.eat("false") => Some(Json::Bool(false)),
+            b'n' if self.eat("null") => Some(Json::Null),
+            _ => self.number(),
+        }
+    }
+
+    fn object(&mut self) -> Option<Json> {
+        self.pos += 1;
+        let mut fields = Vec::new();
+        self.space();
+        if self.eat("}") {
+            return Some(Json::Obj(fields));
+        }
+        loop {
+            self.space();
+            let key = self.string()?;
+            self.space();
+            if !self.eat(":") {
+                return None;
+            }
+            fields.push((key, self.value()?));
+            self.space();
+            if self.eat("}") {
+                return Some(Json::Obj(fields));
+            }
+            if !self.eat(",") {
+                return None;
+            }
+        }
+    }
+
+    fn array(&mut self) -> Option<Json> {
+        self.pos += 1;
+        let mut items = Vec::new();
+        self.space();
+        if self.eat("]") {
+            return Some(Json::Arr(items));
+        }
+        loop {
+            items.push(self.value()?);
+            self.space();
+            if self.eat("]") {
+                return Some(Json::Arr(items));
+            }
+            if !self.eat(",") {
+                return None;
+            }
+        }
+    }
+
+    fn string(&mut self) -> Option<String> {
+        if !self.eat("\"") {
+            return None;
+        }
+        let mut s = String::new();
+        loop {
+            let rest = core::str::from_utf8(&self.text[self.pos..]).ok()?;
+            let c = rest.chars().next()?;
+            self.pos += c.len_utf8();
+            match c {
+                '"' => return Some(s),
+                '\\' => {
+                    let escaped = *self.text.get(self.pos)?;
+                    self.pos += 1;
+                    s.push(match escaped {
+                        b'n' => '\n',
+                        b'r' => '\r',
+                        b't' => '\t',
+                        b'b' => '\u{8}',
+                        b'f' => '\u{c}',
+                        b'u' => {
+                            let hex = self.text.get(self.pos..self.pos + 4)?;
+                            self.pos += 4;
+                            let code = u32::from_str_radix(core::str::from_utf8(hex).ok()?, 16);
+                            char::from_u32(code.ok()?).unwrap_or('\u{fffd}')
+                        }
+                        other => other as char,
+                    });
+                }
+                c => s.push(c),
+            }
+        }
+    }
+
+    fn number(&mut self) -> Option<Json> {
+        let start = self.pos;
+        let is_number = |b: &u8| b.is_ascii_digit() || b"+-.eE".contains(b);
+        while self.text.get(self.pos).is_some_and(is_number) {
+            self.pos += 1;
+        }
+        let text = core::str::from_utf8(&self.text[start..self.pos]).ok()?;
+        text.parse().ok().map(Json::Num)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn json() {
+        let text = r#" {"a": [1, -2.5, true, null], "b\"\nA": {}, "c": []} "#;
+        let value = Json::parse(text).unwrap();
+        assert_eq!(
+            value.get("a").unwrap().as_arr().unwrap()[1],
+            Json::Num(-2.5)
+        );
+        assert_eq!(
+            value.to_string(),
+            r#"{"a":[1,-2.5,true,null],"b\"\nA":{},"c":[]}"#
+        );
+        assert_eq!(Json::parse(r#"{"a":1"#), None);
+        assert_eq!(Json::parse("[1] 2"), None);
+    }
+}
@@ -1,53 +1,202 @@
-use std::collections::HashMap;
-use std::error::Error;
-use std::fmt;
-// use std::borrow::Borrow;
+//! A virtual machine: a stack machine, its assembler and the tools around it.
+//!
+//! Without the default `std` feature the crate is `no_std` and needs only `alloc`: the machine,
+//! the assembler and the byte format are there, the machine's output goes to a
+//! [`core::fmt::Write`] sink and nothing is written when none is set. Ports, timeouts, the
+//! tracer, core dumps, linking, translating and the tools built on them need `std`.
+
+#![cfg_attr(not(feature = "std"), no_std)]
+
+extern crate alloc;
+
+pub mod asm;
+pub mod builder;
+#[cfg(feature = "std")]
+pub mod coredump;
+#[cfg(any(all(test, feature = "std"), feature = "dap"))]
+pub mod dap;
+pub mod debugger;
+pub mod encode;
+pub mod error;
+#[cfg(any(all(test, feature = "std"), feature = "ffi"))]
+pub mod ffi;
+mod fuse;
+#[cfg(feature = "std")]
+pub mod gdb;
+mod heap;
+pub mod io;
+mod json;
+#[cfg(feature = "std")]
+pub mod link;
+mod machine;
+pub mod memory;
+pub mod observe;
+mod op;
+pub mod registers;
+pub mod report;
+pub mod snapshot;
+mod stack;
+pub mod step;
+pub mod sys;
+#[cfg(any(all(test, feature = "std"), feature = "test-util"))]
+pub mod testing;
+pub mod tick;
+#[cfg(feature = "std")]
+pub mod transpile;
+pub mod validate;
+pub mod value;
+
+use alloc::vec::Vec;
+use core::fmt;
+
+#[cfg(feature = "std")]
+pub use asm::assemble_fragment;
+pub use asm::{
+    assemble, assemble_program, assemble_with_lines, disassemble, disassemble_program, AsmError,
+    AsmErrorKind, Program,
+};
+#[cfg(feature = "std")]
+pub use builder::MachineBuilder;
+#[cfg(feature = "std")]
+pub use coredump::CoreDump;
+pub use debugger::{DebugStop, Debugger, WatchHit};
+pub use encode::{decode_program, encode_program, DecodeError, EncodeError};
+pub use error::{Fault, FaultContext, PathError, StackError, VmError};
+#[cfg(feature = "std")]
+pub use io::ReadInput;
+pub use io::{InputEvent, InputSource, OutputSink, Recording, SysEffect};
+#[cfg(feature = "std")]
+pub use link::{link, link_program, LinkError, Linked, ProgramFragment};
+pub use machine::{Machine, MachineConfig, Overflow};
+pub use memory::MmioHandler;
+pub use observe::{Observer, TraceStep};
+#[cfg(feature = "std")]
+pub use observe::{Trace, Tracer};
+pub use registers::{RegisterFile, RegisterMap};
+pub use report::{ExecutionReport, HaltCause, HaltState, RunOutcome};
+pub use snapshot::MachineSnapshot;
+pub use stack::{FixedStack, StackStorage};
+pub use step::{
+    FlagsChange, HeapChange, MemoryChange, RegisterChange, SlotChange, StateDelta, StepOutcome,
+    StepStatus,
+};
+pub use sys::{HostFn, StringSyscalls, SysCtx, SyscallHandler};
+pub use tick::{TickAction, TickCtx, TickFn};
+#[cfg(feature = "std")]
+pub use transpile::{transpile, transpile_c, TranspileError};
+pub use validate::{
+    analyze_cfg, analyze_cfg_with_tables, strip_unreachable, validate_program, BasicBlock, Cfg,
+    ValidationError,
+};
+pub use value::{Type, Value};
+
+/// The machine word: stack elements, registers, data memory and immediates. It is an `i32`,
+/// or an `i64` with the `word64` feature.
+#[cfg(not(feature = "word64"))]
+pub type Word = i32;
+#[cfg(feature = "word64")]
+pub type Word = i64;
+
+/// The machine word as unsigned, for unsigned comparisons, division and shifts
+#[cfg(not(feature = "word64"))]
+pub type UWord = u32;
+#[cfg(feature = "word64")]
+pub type UWord = u64;
+
+/// A float as the float instructions see it, the same size as a `Word`, which holds its bits
+#[cfg(not(feature = "word64"))]
+pub type Float = f32;
+#[cfg(feature = "word64")]
+pub type Float = f64;
+
+/// The float whose bits are in `word`
+pub fn float(word: Word) -> Float {
+    Float::from_bits(word as UWord)
+}
+
+/// A word holding the bits of `val`
+pub fn float_word(val: Float) -> Word {
+    val.to_bits() as Word
+}
 
-/// Fixed stack size
+/// Fractional bits of the fixed point numbers of `QMUL` and `QDIV`, which are Q16.16 with 32 bit
+/// words: `0x10000` is 1 and `0x18000` is 1.5
+pub const FIXED_POINT_BITS: u32 = 16;
+
+/// Default stack size, in elements
 pub const STACK_SIZE: usize = 1024;
 
-/// A path is either a register or a stack pointer
-#[derive(Copy, Clone, Debug)]
-pub enum Path {
-    REG(Reg),
-    STK(isize), // zero means the head of the stack, +ve means before it, -ve means after it
-}
+/// Default size of the data memory, in words
+pub const MEMORY_SIZE: usize = 1024;
 
-/// Path error when invalid register or stack location is accessed. Invalid register means any
-/// register that does not exists, invalid stack location means location outside the stack memory
-/// vector.
-#[derive(Debug)]
-pub enum PathError {
-    StackErr,
-    RegErr,
-}
+/// Default number of words the heap arrays of a machine can take together
+pub const HEAP_SIZE: usize = 1 << 16;
 
-impl Error for PathError {}
+/// Default number of instructions between clock checks of `Machine::run_with_timeout`
+pub const TIMEOUT_CHECK_INTERVAL: u64 = 1024;
 
-impl fmt::Display for PathError {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        let err_message = match self {
-            PathError::StackErr => {
-                format!("invalid stack pointer")
-            }
+/// Default number of calls a machine can have in progress at once
+pub const CALL_DEPTH: usize = 1024;
 
-            PathError::RegErr => {
-                format!("invalid register access")
-            }
-        };
-        write!(f, "{}", err_message)
-    }
+/// Default number of numbered registers, `Reg::R(0)` to `Reg::R(15)`
+pub const GP_REGISTERS: usize = 16;
+
+/// A path is either a register or a stack pointer
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Path {
+    REG(Reg),
+    STK(isize), // zero means the head of the stack, +ve means before it, -ve means after it
+    STKR(Reg, isize), // like STK, with the register's value added to the offset when executed
 }
 
 /// A really simple ISA
-#[derive(Copy, Clone, Debug)]
+#[derive(Copy, Clone, Debug, PartialEq)]
 pub enum Inst {
     /// Push an integer to the stack
-    PSH(i32),
+    PSH(Word),
+
+    /// Push an entry of the machine's constant pool
+    PSHC(u16),
 
     /// Pop the stack
     POP,
 
+    /// Push a copy of the head of the stack
+    DUP,
+
+    /// Swap the last two stack elements
+    SWAP,
+
+    /// Push a copy of the element below the head of the stack
+    OVER,
+
+    /// Move the third element from the head of the stack to the head, `a b c` becomes `b c a`
+    ROT,
+
+    /// Pop this many elements, failing without popping any if the stack holds fewer
+    DROP(usize),
+
+    /// Read an integer from the machine's input and push it
+    IN,
+
+    /// Pop the stack and write the value to the machine's output, one line per value
+    OUT,
+
+    /// Write a string of the machine's string table to its output, on a line of its own
+    PRINTS(u16),
+
+    /// Pop the stack and hand the value to the host, suspending the run until it is resumed
+    YLD,
+
+    /// Pop the stack and send the value out of a port
+    SND(u8),
+
+    /// Push a value received on a port, waiting for one if none has arrived
+    RCV(u8),
+
+    /// Empty the whole stack, does nothing on an empty stack
+    CLR,
+
     /// Integer addition
     ADD,
 
@@ -57,342 +206,492 @@ pub enum Inst {
     /// Integer multiplication
     MUL,
 
-    /// Integer division
+    /// Integer division, rounding towards zero
     DIV,
 
+    /// Remainder of `DIV`, it has the sign of the dividend, `-7 % 2` is `-1`
+    MOD,
+
+    /// Integer division rounding towards negative infinity, `-7 / 2` is `-4`
+    DIVF,
+
+    /// Remainder of `DIVF`, it has the sign of the divisor, `-7 % 2` is `1`
+    MODF,
+
+    /// Division of the operands as unsigned words
+    DIVU,
+
+    /// Remainder of the operands as unsigned words
+    MODU,
+
+    /// Bitwise and
+    AND,
+
+    /// Bitwise or
+    OR,
+
+    /// Bitwise exclusive or
+    XOR,
+
+    /// Pop a value and push its bitwise complement
+    NOT,
+
+    /// Shift left by the head of the stack, counted modulo the bits of a word
+    SHL,
+
+    /// Shift right by the head of the stack, counted modulo the bits of a word, filling with zeros
+    SHR,
+
+    /// Pop a value and push it negated
+    NEG,
+
+    /// Pop a value and push its absolute value
+    ABS,
+
+    /// The smaller of the last two stack elements
+    MIN,
+
+    /// The larger of the last two stack elements
+    MAX,
+
+    /// Push a float, as a word holding its bits
+    FPSH(Float),
+
+    /// Float addition of the last two stack elements, read as floats
+    FADD,
+
+    /// Float subtraction
+    FSUB,
+
+    /// Float multiplication
+    FMUL,
+
+    /// Float division, dividing by zero gives an infinity or NaN
+    FDIV,
+
+    /// Pop an integer and push it as the nearest float
+    ITOF,
+
+    /// Pop a float and push it as an integer, rounding towards zero and saturating, NaN is 0
+    FTOI,
+
+    /// Fixed point multiplication, the product shifted right by `FIXED_POINT_BITS`, rounding
+    /// towards negative infinity. Overflow is handled like `MUL` handles it.
+    QMUL,
+
+    /// Fixed point division, the dividend shifted left by `FIXED_POINT_BITS` then divided,
+    /// rounding towards zero
+    QDIV,
+
+    /// Compare the last two stack elements like `SUB` would subtract them and set the flags,
+    /// leaving the stack as it is
+    CMP,
+
     /// Set a register value
-    SET(Reg, i32),
+    SET(Reg, Word),
+
+    /// Add one to a register, wrapping around on overflow
+    INC(Reg),
+
+    /// Subtract one from a register, wrapping around on overflow
+    DEC(Reg),
+
+    /// Store an immediate to a register or an existing stack slot
+    SETP(Path, Word),
 
     /// Move data from one location(register or stack pointer) to another
     CPY(Path, Path),
 
+    /// Push the word at a data memory address
+    LOAD(usize),
+
+    /// Pop the stack into a data memory address
+    STORE(usize),
+
+    /// Push the word at a data memory address, the value of a register plus an offset. The
+    /// register is read as an unsigned integer.
+    LOADR(Reg, usize),
+
+    /// Pop the stack into a data memory address computed like `LOADR` does
+    STORER(Reg, usize),
+
+    /// Allocate a heap array of this many words, all zero, and push its handle
+    ALLOC(usize),
+
+    /// Pop an index and the handle of an array pushed before it, and push the element of the
+    /// array at that index
+    AIDXLOAD,
+
+    /// Pop a value, an index and the handle of an array, pushed in the reverse order, and store
+    /// the value in the element of the array at that index
+    AIDXSTORE,
+
     /// Move the instruction pointer from its current position
     JMP(isize),
 
-    /// Halt the program execution, end the machine
-    HLT,
-}
+    /// Decrement a register and move the instruction pointer like `JMP` if it did not reach zero.
+    /// The decrement wraps, so a register at `Word::MIN` goes on at `Word::MAX` and one at 0 at
+    /// -1, looping again until it comes around to zero.
+    LOOP(Reg, isize),
 
-/// Six general purpose registers
-#[derive(Copy, Clone, Debug, Eq, Hash, PartialEq)]
-pub enum Reg {
-    A,
-    B,
-    C,
-    D,
-    E,
-    F,
-}
+    /// Pop the stack and move the instruction pointer like `JMP` if the value is zero
+    JEZ(isize),
 
-#[derive(Debug)]
-struct Stack {
-    memory: Vec<i32>,
-    sp: isize,
-}
+    /// Pop the stack and jump like `JMP` if the value is not zero
+    JNZ(isize),
 
-#[derive(Debug)]
-enum StackError {
-    PushErr,
-    PopErr,
-}
+    /// Pop the stack and jump like `JMP` if the value is negative
+    JLT(isize),
 
-impl fmt::Display for StackError {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        let err_message = match self {
-            StackError::PushErr => {
-                format!(
-                    "stack overflow: cannot push more than {} elements on stack",
-                    STACK_SIZE
-                )
-            }
+    /// Pop the stack and jump like `JMP` if the value is positive
+    JGT(isize),
 
-            StackError::PopErr => {
-                format!("cannot pop from an empty stack")
-            }
-        };
-        write!(f, "{}", err_message)
-    }
-}
+    /// Pop the stack and jump like `JMP` if the value is zero or negative
+    JLE(isize),
+
+    /// Pop the stack and jump like `JMP` if the value is zero or positive
+    JGE(isize),
+
+    /// Jump like `JMP` if the flags meet the condition
+    JF(Cond, isize),
+
+    /// Jump like `JMP` and remember the next instruction on the call stack. The frame of the
+    /// call starts at the head of the stack.
+    CALL(isize),
+
+    /// Return to the instruction after the latest `CALL` and back to the caller's frame
+    RET,
+
+    /// Push the stack slot at this offset from the frame pointer. Slots from zero up are the
+    /// locals of the current call, negative slots are the values below its frame.
+    LOADL(isize),
+
+    /// Pop the stack into the slot at this offset from the frame pointer, like `LOADL`
+    STOREL(isize),
 
-impl Error for StackError {}
+    /// Pop an index and jump to that entry of the given jump table
+    TBL(usize),
+
+    /// Pop the stack and stop the run with `VmError::Trap` if the value is zero, an assertion
+    TRAP(u16),
+
+    /// Make a numbered system call to the host's `SyscallHandler`
+    SYS(u32),
+
+    /// Call the host function named by this entry of the machine's import table
+    HCALL(u16),
+
+    /// Halt the program execution, end the machine
+    HLT,
+
+    /// Halt with an exit code, `HLT` halts with 0
+    EXIT(i32),
+}
 
-impl Stack {
-    fn new() -> Self {
-        Stack {
-            memory: Vec::with_capacity(STACK_SIZE),
-            sp: -1,
+impl Inst {
+    /// Name of the instruction without its operands
+    pub fn mnemonic(&self) -> &'static str {
+        match self {
+            Inst::PSH(_) => "PSH",
+            Inst::PSHC(_) => "PSHC",
+            Inst::POP => "POP",
+            Inst::DUP => "DUP",
+            Inst::SWAP => "SWAP",
+            Inst::OVER => "OVER",
+            Inst::ROT => "ROT",
+            Inst::DROP(_) => "DROP",
+            Inst::IN => "IN",
+            Inst::OUT => "OUT",
+            Inst::PRINTS(_) => "PRINTS",
+            Inst::YLD => "YLD",
+            Inst::SND(_) => "SND",
+            Inst::RCV(_) => "RCV",
+            Inst::CLR => "CLR",
+            Inst::ADD => "ADD",
+            Inst::SUB => "SUB",
+            Inst::MUL => "MUL",
+            Inst::DIV => "DIV",
+            Inst::DIVF => "DIVF",
+            Inst::MODF => "MODF",
+            Inst::DIVU => "DIVU",
+            Inst::MODU => "MODU",
+            Inst::AND => "AND",
+            Inst::OR => "OR",
+            Inst::XOR => "XOR",
+            Inst::NOT => "NOT",
+            Inst::SHL => "SHL",
+            Inst::SHR => "SHR",
+            Inst::MOD => "MOD",
+            Inst::NEG => "NEG",
+            Inst::ABS => "ABS",
+            Inst::MIN => "MIN",
+            Inst::MAX => "MAX",
+            Inst::FPSH(_) => "FPSH",
+            Inst::FADD => "FADD",
+            Inst::FSUB => "FSUB",
+            Inst::FMUL => "FMUL",
+            Inst::FDIV => "FDIV",
+            Inst::ITOF => "ITOF",
+            Inst::FTOI => "FTOI",
+            Inst::QMUL => "QMUL",
+            Inst::QDIV => "QDIV",
+            Inst::CMP => "CMP",
+            Inst::SET(..) => "SET",
+            Inst::INC(_) => "INC",
+            Inst::DEC(_) => "DEC",
+            Inst::SETP(..) => "SETP",
+            Inst::CPY(..) => "CPY",
+            Inst::LOAD(_) => "LOAD",
+            Inst::STORE(_) => "STORE",
+            Inst::LOADR(..) => "LOADR",
+            Inst::STORER(..) => "STORER",
+            Inst::ALLOC(_) => "ALLOC",
+            Inst::AIDXLOAD => "AIDXLOAD",
+            Inst::AIDXSTORE => "AIDXSTORE",
+            Inst::JMP(_) => "JMP",
+            Inst::LOOP(..) => "LOOP",
+            Inst::JEZ(_) => "JEZ",
+            Inst::JNZ(_) => "JNZ",
+            Inst::JLT(_) => "JLT",
+            Inst::JGT(_) => "JGT",
+            Inst::JLE(_) => "JLE",
+            Inst::JGE(_) => "JGE",
+            Inst::JF(..) => "JF",
+            Inst::CALL(_) => "CALL",
+            Inst::RET => "RET",
+            Inst::LOADL(_) => "LOADL",
+            Inst::STOREL(_) => "STOREL",
+            Inst::TBL(_) => "TBL",
+            Inst::TRAP(_) => "TRAP",
+            Inst::SYS(_) => "SYS",
+            Inst::HCALL(_) => "HCALL",
+            Inst::EXIT(_) => "EXIT",
+            Inst::HLT => "HLT",
         }
     }
 
-    fn _get_sp(&self) -> isize {
-        self.sp
+    /// Offset of a relative jump: `JMP`, `LOOP`, a conditional jump or `CALL`
+    pub fn jump_offset(&self) -> Option<isize> {
+        let mut inst = *self;
+        inst.jump_offset_mut().copied()
     }
 
-    /// get value at stack index
-    fn get_at_idx(&self, idx: isize) -> Result<i32, PathError> {
-        assert!(self.sp >= 0 && (self.sp as usize) < self.memory.len());
-        if idx >= 0 {
-            self.memory
-                .get(self.sp as usize - idx as usize)
-                .ok_or(PathError::StackErr)
-                .copied()
-        } else {
-            self.memory
-                .get(self.sp as usize + (-1 * idx) as usize)
-                .ok_or(PathError::StackErr)
-                .copied()
+    pub(crate) fn jump_offset_mut(&mut self) -> Option<&mut isize> {
+        match self {
+            Inst::JMP(step)
+            | Inst::LOOP(_, step)
+            | Inst::JEZ(step)
+            | Inst::JNZ(step)
+            | Inst::JLT(step)
+            | Inst::JGT(step)
+            | Inst::JLE(step)
+            | Inst::JGE(step)
+            | Inst::JF(_, step)
+            | Inst::CALL(step) => Some(step),
+            _ => None,
         }
     }
+}
 
-    /// set value at stack index
-    fn set_at_idx(&mut self, idx: isize, val: i32) -> Result<(), PathError> {
-        assert!(self.sp >= 0 && (self.sp as usize) < self.memory.len());
-        let reference = if idx >= 0 {
-            self.memory.get_mut(self.sp as usize - idx as usize)
-        } else {
-            self.memory.get_mut(self.sp as usize + (-1 * idx) as usize)
-        };
-        match reference {
-            Some(elem) => {
-                *elem = val;
-                Ok(())
+/// Instructions print in assembler syntax, `cpy stk[0] reg.b`
+impl fmt::Display for Inst {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = self.mnemonic().to_lowercase();
+        match self {
+            Inst::PSH(val) => write!(f, "{name} {val}"),
+            Inst::PSHC(idx) => write!(f, "{name} {idx}"),
+            Inst::FPSH(val) => write!(f, "{name} {val}"),
+            Inst::SET(reg, val) => write!(f, "{name} {reg} {val}"),
+            Inst::INC(reg) | Inst::DEC(reg) => write!(f, "{name} {reg}"),
+            Inst::SETP(path, val) => write!(f, "{name} {path} {val}"),
+            Inst::CPY(dst, src) => write!(f, "{name} {dst} {src}"),
+            Inst::LOAD(addr) | Inst::STORE(addr) => write!(f, "{name} {addr}"),
+            Inst::LOADL(slot) | Inst::STOREL(slot) => write!(f, "{name} {slot}"),
+            Inst::LOADR(reg, offset) | Inst::STORER(reg, offset) => {
+                write!(f, "{name} {reg} {offset}")
             }
-            None => Err(PathError::StackErr),
+            Inst::JMP(step)
+            | Inst::JEZ(step)
+            | Inst::JNZ(step)
+            | Inst::JLT(step)
+            | Inst::JGT(step)
+            | Inst::JLE(step)
+            | Inst::JGE(step)
+            | Inst::CALL(step) => write!(f, "{name} {step}"),
+            Inst::LOOP(reg, step) => write!(f, "{name} {reg} {step}"),
+            Inst::JF(cond, step) => write!(f, "{name} {cond} {step}"),
+            Inst::TBL(id) => write!(f, "{name} {id}"),
+            Inst::DROP(count) => write!(f, "{name} {count}"),
+            Inst::ALLOC(len) => write!(f, "{name} {len}"),
+            Inst::TRAP(code) => write!(f, "{name} {code}"),
+            Inst::PRINTS(id) => write!(f, "{name} {id}"),
+            Inst::SYS(number) => write!(f, "{name} {number}"),
+            Inst::HCALL(idx) => write!(f, "{name} {idx}"),
+            Inst::EXIT(code) => write!(f, "{name} {code}"),
+            Inst::SND(port) | Inst::RCV(port) => write!(f, "{name} {port}"),
+            _ => write!(f, "{name}"),
         }
     }
+}
 
-    /// Pop from the stack
-    fn pop(&mut self) -> Result<i32, StackError> {
-        if self.memory.len() > 0 {
-            self.sp -= 1;
-            Ok(self.memory.pop().unwrap())
-        } else {
-            Err(StackError::PopErr)
+impl fmt::Display for Path {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Path::REG(reg) => write!(f, "reg.{reg}"),
+            Path::STK(idx) => write!(f, "stk[{idx}]"),
+            Path::STKR(reg, idx) if *idx < 0 => write!(f, "stk[{reg}{idx}]"),
+            Path::STKR(reg, idx) => write!(f, "stk[{reg}+{idx}]"),
         }
     }
+}
 
-    /// Push something on to the stack
-    fn push(&mut self, value: i32) -> Result<(), StackError> {
-        if self.memory.len() < STACK_SIZE {
-            self.sp += 1;
-            self.memory.push(value);
-            Ok(())
-        } else {
-            Err(StackError::PushErr)
+/// Six named general purpose registers, plus numbered ones. How many numbered registers a
+/// machine has is chosen when it is created, [`GP_REGISTERS`] by default.
+#[derive(Copy, Clone, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub enum Reg {
+    A,
+    B,
+    C,
+    D,
+    E,
+    F,
+    R(u8),
+}
+
+impl fmt::Display for Reg {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Reg::A => write!(f, "a"),
+            Reg::B => write!(f, "b"),
+            Reg::C => write!(f, "c"),
+            Reg::D => write!(f, "d"),
+            Reg::E => write!(f, "e"),
+            Reg::F => write!(f, "f"),
+            Reg::R(n) => write!(f, "r{n}"),
         }
     }
 }
 
-pub struct Machine {
-    /// Array of instructions
-    program: Vec<Inst>,
+/// Flags set by `CMP`, describing the subtraction of the head of the stack from the element
+/// below it.
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct Flags {
+    /// The two values are equal
+    pub zero: bool,
 
-    /// Index of the next to-be-executed instruction
-    ip: usize,
+    /// The wrapped difference is negative
+    pub negative: bool,
 
-    /// THE STACK
-    stack: Stack,
+    /// The difference overflowed as a signed subtraction
+    pub overflow: bool,
 
-    /// THE REGISTERS
-    registers: HashMap<Reg, i32>,
+    /// The subtraction borrowed, the first value is below the second as unsigned integers
+    pub carry: bool,
 }
 
-impl Machine {
-    /// Create a new machine instance.
-    /// It fails if the input program sequence is empty
-    pub fn new(program: Vec<Inst>) -> Self {
-        assert!(program.len() > 0);
-
-        let mut registers = HashMap::new();
-        registers.insert(Reg::A, 0);
-        registers.insert(Reg::B, 0);
-        registers.insert(Reg::C, 0);
-        registers.insert(Reg::D, 0);
-        registers.insert(Reg::E, 0);
-        registers.insert(Reg::F, 0);
-
-        Machine {
-            program,
-            ip: 0,
-            stack: Stack::new(),
-            registers,
+impl Flags {
+    /// Flags for the comparison of `a` with `b`
+    pub fn compare(a: Word, b: Word) -> Flags {
+        let (diff, overflow) = a.overflowing_sub(b);
+        Flags {
+            zero: diff == 0,
+            negative: diff < 0,
+            overflow,
+            carry: (a as UWord) < (b as UWord),
         }
     }
 
-    /// Run the machine and execute the program sequentially one instruction at a time.
-    /// `HLT` instruction causes the machine to stop execution and report current state.
-    /// If the final instruction is not `HLT` then it panics.
-    pub fn run(&mut self) {
-        loop {
-            let inst = self.get_next_inst();
-            match inst {
-                Some(Inst::PSH(val)) => {
-                    if let Err(e) = self.stack.push(val) {
-                        panic!("{}", e);
-                    }
-                    println!("machine: push {val}");
-                }
-                Some(Inst::ADD) => {
-                    let arg_2 = match self.stack.pop() {
-                        Ok(arg) => arg,
-                        Err(e) => panic!("missing addition argument: {}", e),
-                    };
-                    let arg_1 = match self.stack.pop() {
-                        Ok(arg) => arg,
-                        Err(e) => panic!("missing addition argument: {}", e),
-                    };
-                    if let Err(e) = self.stack.push(arg_1 + arg_2) {
-                        panic!("{}", e);
-                    }
-                    println!("machine: add: {arg_1} {arg_2}");
-                }
-                Some(Inst::SUB) => {
-                    let arg_2 = match self.stack.pop() {
-                        Ok(arg) => arg,
-                        Err(e) => panic!("missing subtraction argument: {}", e),
-                    };
-                    let arg_1 = match self.stack.pop() {
-                        Ok(arg) => arg,
-                        Err(e) => panic!("missing subtraction argument: {}", e),
-                    };
-                    if let Err(e) = self.stack.push(arg_1 - arg_2) {
-                        panic!("{}", e);
-                    }
-                    println!("machine: sub: {arg_1} {arg_2}");
-                }
-                Some(Inst::MUL) => {
-                    let arg_2 = match self.stack.pop() {
-                        Ok(arg) => arg,
-                        Err(e) => panic!("missing multiplication argument: {}", e),
-                    };
-                    let arg_1 = match self.stack.pop() {
-                        Ok(arg) => arg,
-                        Err(e) => panic!("missing multiplication argument: {}", e),
-                    };
-                    if let Err(e) = self.stack.push(arg_1 * arg_2) {
-                        panic!("{}", e);
-                    }
-                    println!("machine: mul: {arg_1} {arg_2}");
-                }
-                Some(Inst::DIV) => {
-                    let arg_2 = match self.stack.pop() {
-                        Ok(arg) => arg,
-                        Err(e) => panic!("missing division argument: {}", e),
-                    };
-                    if arg_2 == 0 {
-                        panic!("attempted to divide by zero");
-                    }
-                    let arg_1 = match self.stack.pop() {
-                        Ok(arg) => arg,
-                        Err(e) => panic!("missing division argument: {}", e),
-                    };
-                    if let Err(e) = self.stack.push(arg_1 / arg_2) {
-                        panic!("{}", e);
-                    }
-                    println!("machine: div: {arg_1} {arg_2}");
-                }
-                Some(Inst::POP) => {
-                    let val = match self.stack.pop() {
-                        Ok(val) => val,
-                        Err(e) => panic!("{}", e),
-                    };
-                    println!("machine: pop: {val}");
-                }
-                Some(Inst::SET(reg, val)) => {
-                    match self.set_reg_value(reg, val) {
-                        Ok(_) => (),
-                        Err(e) => panic!("{}", e),
-                    };
-                    println!("machine: set: {reg:?} {val}");
-                }
-                Some(Inst::CPY(dst, src)) => {
-                    let val = match self.get_from_path(src) {
-                        Ok(val) => val,
-                        Err(e) => panic!("{}", e),
-                    };
-                    match self.set_at_path(dst, val) {
-                        Ok(_) => (),
-                        Err(e) => panic!("{}", e),
-                    };
-                    println!("machine: cpy {dst:?} {src:?}");
-                }
-                Some(Inst::JMP(step)) => {
-                    if step < 0 {
-                        self.ip -= (-1 * (step - 1)) as usize;
-                    } else if step > 0 {
-                        self.ip += step as usize - 1;
-                    } else {
-                    }
-                }
-                Some(Inst::HLT) => {
-                    println!("machine: halting...");
-                    self.dump();
-                    break;
-                }
-                None => panic!("error: illegal instruction...abrupt halt"),
-            }
+    /// Whether the compared values meet `cond`
+    pub fn test(&self, cond: Cond) -> bool {
+        let less = self.negative != self.overflow;
+        match cond {
+            Cond::EQ => self.zero,
+            Cond::NE => !self.zero,
+            Cond::LT => less,
+            Cond::LE => less || self.zero,
+            Cond::GT => !less && !self.zero,
+            Cond::GE => !less,
+            Cond::LTU => self.carry,
+            Cond::GEU => !self.carry,
         }
     }
+}
 
-    fn get_from_path(&self, path: Path) -> Result<i32, PathError> {
-        match path {
-            Path::REG(reg) => self.get_reg_value(&reg),
-            Path::STK(rel_idx) => self.stack.get_at_idx(rel_idx),
-        }
-    }
+/// Condition of a `JF`, named for how the first value compared by `CMP` relates to the second.
+/// The `U` conditions compare them as unsigned integers.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Cond {
+    EQ,
+    NE,
+    LT,
+    LE,
+    GT,
+    GE,
+    LTU,
+    GEU,
+}
 
-    fn set_at_path(&mut self, path: Path, val: i32) -> Result<(), PathError> {
-        match path {
-            Path::REG(reg) => self.set_reg_value(reg, val),
-            Path::STK(rel_idx) => self.stack.set_at_idx(rel_idx, val),
-        }
+impl fmt::Display for Cond {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Cond::EQ => "eq",
+            Cond::NE => "ne",
+            Cond::LT => "lt",
+            Cond::LE => "le",
+            Cond::GT => "gt",
+            Cond::GE => "ge",
+            Cond::LTU => "ltu",
+            Cond::GEU => "geu",
+        };
+        write!(f, "{name}")
     }
+}
 
-    fn dump(&self) {
-        println!("\n\nmachine dump:");
-        println!("\tprogram: {:?}", self.program);
-        println!("\tip: {}", self.ip);
-        println!("\tstack: {:?}", self.stack);
-        println!("\tregisters: {:?}", self.registers);
-    }
+/// A call in progress.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Frame {
+    /// Where `RET` continues
+    pub ret: usize,
+
+    /// Frame pointer of the caller, restored by `RET`
+    pub fp: usize,
+}
+
+/// A jump table used by `TBL`, a list of absolute instruction indices.
+///
+/// An index popped by `TBL` that falls outside `targets` jumps to `default` when one is set,
+/// otherwise it is an error. It never falls through to the next instruction.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct JumpTable {
+    pub targets: Vec<usize>,
+    pub default: Option<usize>,
+}
 
-    /// get next instruction and update the `ip`
-    fn get_next_inst(&mut self) -> Option<Inst> {
-        if let Some(inst) = self.program.get(self.ip) {
-            self.ip += 1;
-            Some(*inst)
-        } else {
-            None
+impl JumpTable {
+    pub fn new(targets: Vec<usize>) -> Self {
+        JumpTable {
+            targets,
+            default: None,
         }
     }
 
-    fn get_reg_value(&self, reg: &Reg) -> Result<i32, PathError> {
-        match self.registers.get(reg) {
-            Some(val) => Ok(*val),
-            None => Err(PathError::RegErr),
-        }
+    /// Jump to `target` when the index is out of the table's range
+    pub fn with_default(mut self, target: usize) -> Self {
+        self.default = Some(target);
+        self
     }
 
-    /// It will panic on trying to insert in a non existent register.
-    fn set_reg_value(&mut self, reg: Reg, value: i32) -> Result<(), PathError> {
-        // insert returns None if the key didn't existed.
-        if self.registers.contains_key(&reg) {
-            self.registers.insert(reg, value).unwrap();
-            Ok(())
-        } else {
-            Err(PathError::RegErr)
-        }
+    /// Target for an index popped off the stack
+    fn lookup(&self, idx: Word) -> Option<usize> {
+        usize::try_from(idx)
+            .ok()
+            .and_then(|idx| self.targets.get(idx).copied())
+            .or(self.default)
     }
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-
-    #[test]
-    fn it_works() {
-        let program = vec![Inst::PSH(5), Inst::PSH(6), Inst::ADD, Inst::POP, Inst::HLT];
-        let mut machine = Machine::new(program);
-        machine.run();
+impl From<Vec<usize>> for JumpTable {
+    fn from(targets: Vec<usize>) -> Self {
+        JumpTable::new(targets)
     }
 }
@@ -0,0 +1,273 @@
+//! Linking program fragments into one program.
+//!
+//! A fragment is a piece of code that can be laid out anywhere: jumps inside it are relative and
+//! keep working wherever it lands. Jumps out of it name a label instead, which some fragment
+//! exports, and the linker fills in the offset once every fragment has its place.
+
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+
+use crate::Inst;
+
+/// A named piece of a program, with the labels it exports and the jumps it leaves to the linker.
+/// [`assemble_fragment`](crate::assemble_fragment) makes one from assembly.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ProgramFragment {
+    pub name: String,
+    pub code: Vec<Inst>,
+
+    /// Labels other fragments can jump to, as indices into `code`
+    pub exports: Vec<(String, usize)>,
+
+    /// Jump instructions, by index into `code`, whose offset is filled in with the
+    /// distance to a label. The offset they hold in `code` is ignored.
+    pub references: Vec<(usize, String)>,
+}
+
+impl ProgramFragment {
+    pub fn new(name: impl Into<String>, code: Vec<Inst>) -> Self {
+        ProgramFragment {
+            name: name.into(),
+            code,
+            ..Default::default()
+        }
+    }
+
+    /// Export `label` at index `ip` of the fragment's code
+    pub fn export(mut self, label: impl Into<String>, ip: usize) -> Self {
+        self.exports.push((label.into(), ip));
+        self
+    }
+
+    /// Make the jump at index `ip` of the fragment's code go to `label`
+    pub fn reference(mut self, ip: usize, label: impl Into<String>) -> Self {
+        self.references.push((ip, label.into()));
+        self
+    }
+}
+
+/// Problems found while linking, naming the fragment they were found in.
+#[derive(Clone, Debug, PartialEq)]
+pub enum LinkError {
+    /// `label` is exported by both `first` and `second`
+    DuplicateLabel {
+        label: String,
+        first: String,
+        second: String,
+    },
+
+    /// No fragment exports `label`, which `fragment` refers to
+    UnresolvedLabel { fragment: String, label: String },
+
+    /// An export or reference points outside the fragment's code
+    OutOfFragment { fragment: String, ip: usize },
+
+    /// A reference is on an instruction that is not a jump
+    NotAJump { fragment: String, ip: usize },
+}
+
+impl Error for LinkError {}
+
+impl fmt::Display for LinkError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LinkError::DuplicateLabel {
+                label,
+                first,
+                second,
+            } => write!(
+                f,
+                "label `{label}` is exported by both `{first}` and `{second}`"
+            ),
+
+            LinkError::UnresolvedLabel { fragment, label } => {
+                write!(
+                    f,
+                    "`{fragment}` refers to label `{label}`, which nothing exports"
+                )
+            }
+
+            LinkError::OutOfFragment { fragment, ip } => {
+                write!(f, "index {ip} is outside fragment `{fragment}`")
+            }
+
+            LinkError::NotAJump { fragment, ip } => {
+                write!(f, "reference at {ip} in `{fragment}` is not on a jump")
+            }
+        }
+    }
+}
+
+/// A linked program, with the address every exported label ended up at.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Linked {
+    pub code: Vec<Inst>,
+    pub symbols: HashMap<String, usize>,
+}
+
+/// Lay `fragments` out one after the other, in order, and resolve their references
+pub fn link(fragments: &[ProgramFragment]) -> Result<Vec<Inst>, LinkError> {
+    link_program(fragments).map(|linked| linked.code)
+}
+
+/// Like [`link`], also returning where each label is, to pick an entry point for example
+pub fn link_program(fragments: &[ProgramFragment]) -> Result<Linked, LinkError> {
+    let mut symbols = HashMap::new();
+    let mut owners: HashMap<&str, &str> = HashMap::new();
+    let mut base = 0;
+    for fragment in fragments {
+        for (label, ip) in &fragment.exports {
+            if *ip >= fragment.code.len() {
+                return Err(LinkError::OutOfFragment {
+                    fragment: fragment.name.clone(),
+                    ip: *ip,
+                });
+            }
+            if let Some(first) = owners.insert(label, &fragment.name) {
+                return Err(LinkError::DuplicateLabel {
+                    label: label.clone(),
+                    first: first.to_string(),
+                    second: fragment.name.clone(),
+                });
+            }
+            symbols.insert(label.clone(), base + ip);
+        }
+        base += fragment.code.len();
+    }
+
+    let mut code = Vec::with_capacity(base);
+    for fragment in fragments {
+        let start = code.len();
+        code.extend_from_slice(&fragment.code);
+        for (ip, label) in &fragment.references {
+            let Some(&target) = symbols.get(label) else {
+                return Err(LinkError::UnresolvedLabel {
+                    fragment: fragment.name.clone(),
+                    label: label.clone(),
+                });
+            };
+            let at = start + ip;
+            let step = target as isize - at as isize;
+            let inst = code.get_mut(at).filter(|_| *ip < fragment.code.len());
+            match inst.map(Inst::jump_offset_mut) {
+                Some(Some(offset)) => *offset = step,
+                Some(None) => {
+                    return Err(LinkError::NotAJump {
+                        fragment: fragment.name.clone(),
+                        ip: *ip,
+                    })
+                }
+                None => {
+                    return Err(LinkError::OutOfFragment {
+                        fragment: fragment.name.clone(),
+                        ip: *ip,
+                    })
+                }
+            }
+        }
+    }
+
+    Ok(Linked { code, symbols })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{assemble_fragment, Machine, Path, Reg};
+
+    /// Squares the head of the stack and jumps back to `after_square`
+    fn math() -> ProgramFragment {
+        let source = "square: psh 0\ncpy stk[0] stk[1]\nmul\njmp after_square\n";
+        assemble_fragment("math", source).unwrap()
+    }
+
+    /// Computes 3 * 3 + 1, with 3 squared by `math`, and counts down C with a local loop
+    fn main_fragment() -> ProgramFragment {
+        let source = "
+            main: set c 2
+            loop c 0
+            psh 3
+            jmp square
+            after_square: psh 1
+            add
+            hlt
+        ";
+        assemble_fragment("main", source).unwrap()
+    }
+
+    #[test]
+    fn fragments_from_assembly() {
+        let code = vec![
+            Inst::PSH(0),
+            Inst::CPY(Path::STK(0), Path::STK(1)),
+            Inst::MUL,
+            Inst::JMP(0),
+        ];
+        let built = ProgramFragment::new("math", code)
+            .export("square", 0)
+            .reference(3, "after_square");
+        assert_eq!(math(), built);
+
+        let main = main_fragment();
+        assert_eq!(main.code[1], Inst::LOOP(Reg::C, 0));
+        assert_eq!(main.references, [(3, "square".to_string())]);
+        let exports: Vec<_> = main
+            .exports
+            .iter()
+            .map(|(label, ip)| (label.as_str(), *ip))
+            .collect();
+        assert_eq!(exports, [("main", 0), ("after_square", 4)]);
+    }
+
+    #[test]
+    fn fragments_link_in_any_order() {
+        for fragments in [[main_fragment(), math()], [math(), main_fragment()]] {
+            let linked = link_program(&fragments).unwrap();
+            let entry = linked.symbols["main"];
+            let mut machine = Machine::with_entry(linked.code, entry).unwrap();
+            machine.resume().unwrap();
+            assert_eq!(machine.stack_top(), Some(10));
+        }
+
+        let code = link(&[math(), main_fragment()]).unwrap();
+        assert_eq!(code[3], Inst::JMP(8 - 3));
+        assert_eq!(code[7], Inst::JMP(0 - 7));
+    }
+
+    #[test]
+    fn link_errors_name_the_fragment() {
+        let twice = ProgramFragment::new("extra", vec![Inst::HLT]).export("square", 0);
+        let err = link(&[math(), main_fragment(), twice]).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "label `square` is exported by both `math` and `extra`"
+        );
+
+        let err = link(&[main_fragment()]).unwrap_err();
+        assert_eq!(
+            err,
+            LinkError::UnresolvedLabel {
+                fragment: "main".to_string(),
+                label: "square".to_string()
+            }
+        );
+
+        let bad = main_fragment().reference(0, "square");
+        assert_eq!(
+            link(&[math(), bad]),
+            Err(LinkError::NotAJump {
+                fragment: "main".to_string(),
+                ip: 0
+            })
+        );
+        let bad = ProgramFragment::new("tiny", vec![Inst::HLT]).export("end", 1);
+        assert_eq!(
+            link(&[bad]),
+            Err(LinkError::OutOfFragment {
+                fragment: "tiny".to_string(),
+                ip: 1
+            })
+        );
+    }
+}
@@ -0,0 +1,5242 @@
+use alloc::boxed::Box;
+use alloc::collections::BTreeMap;
+use alloc::format;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::ops::Range;
+#[cfg(feature = "std")]
+use std::sync::mpsc::{Receiver, Sender, TryRecvError};
+#[cfg(feature = "std")]
+use std::sync::Mutex;
+#[cfg(feature = "std")]
+use std::time::{Duration, Instant};
+
+#[cfg(feature = "std")]
+use crate::asm::Program;
+#[cfg(feature = "std")]
+use crate::coredump::CoreDump;
+use crate::encode::{Bytecode, DecodeError};
+use crate::error::OutputError;
+use crate::error::{
+    Fault, FaultContext, IpHistory, PathError, StackError, VmError, CONTEXT_STACK_DEPTH,
+};
+use crate::fuse::FUSED_LEN;
+use crate::heap::Heap;
+#[cfg(feature = "std")]
+use crate::io::Port;
+use crate::io::{InputEvent, InputSource, OutputSink, Recording, SysEffect};
+use crate::memory::{Memory, MmioHandler};
+use crate::observe::Observer;
+#[cfg(feature = "std")]
+use crate::observe::{Trace, TraceStep, Tracer};
+use crate::op::{decode, Op};
+use crate::registers::RegisterFile;
+#[cfg(feature = "std")]
+use crate::report::{ExecutionReport, HaltCause};
+use crate::report::{HaltState, RunOutcome};
+use crate::snapshot::MachineSnapshot;
+use crate::stack::{Stack, StackStorage};
+use crate::step::{
+    FlagsChange, HeapChange, MemoryChange, PastStep, RegisterChange, SlotChange, StateDelta,
+    StepOutcome, StepStatus,
+};
+use crate::sys::{HostFunction, SysCtx, SyscallHandler};
+use crate::tick::{TickAction, TickCtx, TickFn, Ticker};
+use crate::validate::{validate_program, ValidationError};
+use crate::value::{Type, Value};
+use crate::{
+    float, float_word, Flags, Float, Frame, Inst, JumpTable, Path, Reg, UWord, Word, CALL_DEPTH,
+    FIXED_POINT_BITS, GP_REGISTERS, HEAP_SIZE, MEMORY_SIZE, STACK_SIZE, TIMEOUT_CHECK_INTERVAL,
+};
+
+/// Write a trace line to the machine's output, only formatting it when the machine is verbose
+macro_rules! trace {
+    ($machine:expr, $($arg:tt)*) => {
+        if $machine.verbose {
+            let line = format!($($arg)*);
+            $machine.write_line(&line);
+        }
+    };
+}
+
+/// When a timed run gives up. Without the `std` feature there is no clock and no run has one.
+#[cfg(feature = "std")]
+type Deadline = Instant;
+#[cfg(not(feature = "std"))]
+type Deadline = core::convert::Infallible;
+
+/// Whether `deadline` has passed
+#[cfg(feature = "std")]
+fn passed(deadline: &Deadline) -> bool {
+    Instant::now() >= *deadline
+}
+
+#[cfg(not(feature = "std"))]
+fn passed(deadline: &Deadline) -> bool {
+    match *deadline {}
+}
+
+/// A stack change to undo
+enum Undo {
+    /// Of a value of this type, for a typed machine to tag the slot with
+    Push(Type),
+    Pop(Word),
+    /// Of a register, which held this value
+    Reg(Reg, Word),
+}
+
+/// How an instruction changes the types of the stack slots of a typed machine
+struct TypeEffect {
+    /// Types of the values it pushes, in order. A value pushed past these has the type it was
+    /// pushed with, an int unless a system call pushed something else.
+    pushes: Vec<Type>,
+
+    /// A slot it writes and the type written, the position counted from the bottom
+    slot: Option<(usize, Type)>,
+}
+
+/// What to do after an instruction has been executed
+enum Flow {
+    Continue,
+    Yield(Word),
+    /// Only `RCV` waits, and it needs the ports of the `std` feature
+    #[cfg_attr(not(feature = "std"), allow(dead_code))]
+    Wait(u8),
+    Halt(i32),
+}
+
+/// Sizes of a machine's stack, data memory, heap, call stack and register file, and how it does
+/// arithmetic, given to [`Machine::new_with_config`]. The default is what [`Machine::new`]
+/// uses.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct MachineConfig {
+    /// Elements the stack has room for when the machine is created, and the most it holds
+    /// unless it can grow. [`STACK_SIZE`] by default
+    pub stack_size: usize,
+
+    /// Let the stack grow past `stack_size`, up to this many elements; pushing past the ceiling
+    /// is a `StackError::PushErr`. A ceiling below `stack_size` is `stack_size`. `None`, the
+    /// default, keeps the stack at `stack_size`
+    pub max_stack_size: Option<usize>,
+
+    /// Words of data memory, [`MEMORY_SIZE`] by default
+    pub memory_size: usize,
+
+    /// Words the heap arrays can take together, [`HEAP_SIZE`] by default
+    pub heap_size: usize,
+
+    /// Most calls in progress at once, [`CALL_DEPTH`] by default
+    pub call_depth: usize,
+
+    /// Number of numbered registers, [`GP_REGISTERS`] by default
+    pub registers: usize,
+
+    /// What `ADD`, `SUB` and `MUL` do when the result does not fit a `Word`, wrapping around by
+    /// default
+    pub overflow: Overflow,
+
+    /// Keep the [`Type`] of every stack slot and fail with `Fault::TypeMismatch` when an
+    /// instruction gets an operand of the wrong type, for example an int to `FADD`. Off by
+    /// default
+    pub typed: bool,
+
+    /// Execute common pairs of instructions, like `PSH` then `ADD`, as one superinstruction
+    /// when nothing watches the run instruction by instruction. A program cannot tell, it only
+    /// runs faster. Off by default
+    pub fuse: bool,
+}
+
+/// How `ADD`, `SUB` and `MUL` handle a result that does not fit a `Word`. The same in debug and
+/// release builds.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Overflow {
+    /// Wrap around, two's complement
+    #[default]
+    Wrap,
+
+    /// Fail with `Fault::Overflow`, undoing the instruction
+    Trap,
+
+    /// Stop at `Word::MIN` or `Word::MAX`
+    Saturate,
+}
+
+impl Default for MachineConfig {
+    fn default() -> Self {
+        MachineConfig {
+            stack_size: STACK_SIZE,
+            max_stack_size: None,
+            memory_size: MEMORY_SIZE,
+            heap_size: HEAP_SIZE,
+            call_depth: CALL_DEPTH,
+            registers: GP_REGISTERS,
+            overflow: Overflow::Wrap,
+            typed: false,
+            fuse: false,
+        }
+    }
+}
+
+/// The virtual machine.
+///
+/// A `Machine` owns all of its mutable state, so it is `Send` and can be moved to another thread
+/// to run there. The program is held in an `Arc<[Inst]>`, letting many machines share one
+/// program without copying it. Anything that gets attached to a machine (sinks, hooks, handlers)
+/// must be `Send` as well, this is checked by a test.
+///
+/// Being `Sync` is intentionally not part of the contract: every way of running a machine takes
+/// `&mut self`, so sharing a `&Machine` between threads is not a goal and may stop compiling in
+/// the future. Give each thread its own machine instead.
+///
+/// The values on the stack are kept in `S`, a `Vec<Word>` unless the machine was created with
+/// [`Machine::new_with_storage`].
+pub struct Machine<S: StackStorage = Vec<Word>> {
+    /// Array of instructions, empty when the machine runs `bytecode`
+    program: Arc<[Inst]>,
+
+    /// The program in the byte format, for a machine created with [`Machine::from_bytecode`]
+    bytecode: Option<Bytecode>,
+
+    /// Index of the next to-be-executed instruction
+    ip: usize,
+
+    /// Index of the first instruction executed
+    entry: usize,
+
+    /// Whether the program's superinstructions are found, see [`MachineConfig::fuse`]
+    fuse: bool,
+
+    /// The program decoded for the plain run loop, empty when the machine runs `bytecode`
+    ops: Arc<[Op]>,
+
+    /// THE STACK
+    stack: Stack<S>,
+
+    /// Type of each stack slot, the bottom first, on a typed machine
+    types: Option<Vec<Type>>,
+
+    /// THE REGISTERS
+    registers: RegisterFile,
+
+    /// Set by `CMP`, tested by `JF`
+    flags: Flags,
+
+    /// Calls in progress, the latest last
+    calls: Vec<Frame>,
+
+    /// Most calls that can be in progress at once
+    call_depth: usize,
+
+    /// What `ADD`, `SUB` and `MUL` do on overflow
+    overflow: Overflow,
+
+    /// Position in the stack, counted from the bottom, of local slot zero of the current call
+    fp: usize,
+
+    /// Data memory for `LOAD` and `STORE`
+    memory: Memory,
+
+    /// Initial contents of data memory, which [`reset`](Machine::reset) puts back
+    data: Vec<Word>,
+
+    /// Arrays of `ALLOC`
+    heap: Heap,
+
+    /// Jump tables for `TBL`, indexed by table id
+    tables: Vec<JumpTable>,
+
+    /// Where a division by zero calls, if the program handles it
+    divide_handler: Option<usize>,
+
+    /// Constant pool for `PSHC`
+    pool: Vec<Word>,
+
+    /// Message of each `TRAP` code, by code
+    traps: Vec<String>,
+
+    /// Strings of `PRINTS`, by id
+    strings: Vec<String>,
+
+    /// The error that stopped the last run, if one did
+    faulted: Option<VmError>,
+
+    /// Stack changes made by the instruction being executed, undone if it overflows or
+    /// underflows the stack
+    undo: Vec<Undo>,
+
+    /// Number of instructions executed so far
+    executed: u64,
+
+    /// Exit code of the `HLT` or `EXIT` the program halted with, `None` if it has not halted
+    exit_code: Option<i32>,
+
+    /// Where `IN` reads from
+    pub(crate) input: Option<Box<dyn InputSource>>,
+
+    /// Where `OUT`, the trace and the dump write to, standard output if `None`, or nowhere
+    /// without the `std` feature
+    pub(crate) output: Option<Box<dyn OutputSink>>,
+
+    /// Input delivered so far, while recording
+    recording: Option<Recording>,
+
+    /// Recorded input still to be delivered, while replaying
+    replay: Option<vec::IntoIter<InputEvent>>,
+
+    /// The host side of `SYS`
+    syscalls: Option<Box<dyn SyscallHandler>>,
+
+    /// Called around every executed instruction
+    pub(crate) observer: Option<Box<dyn Observer>>,
+
+    /// Names of the host functions `HCALL` calls, by import index
+    imports: Vec<String>,
+
+    /// Host functions, by name
+    host_fns: BTreeMap<String, HostFunction>,
+
+    /// Ports for `SND` and `RCV`
+    #[cfg(feature = "std")]
+    ports: BTreeMap<u8, Port>,
+
+    /// Instructions left to execute, unlimited if `None`
+    fuel: Option<u64>,
+
+    /// Fuel charged for each instruction
+    fuel_cost: fn(&Inst) -> u64,
+
+    /// Callback fired every so many instructions
+    ticker: Option<Ticker>,
+
+    /// Instructions between clock checks when running with a timeout
+    timeout_check_interval: u64,
+
+    /// Changes made by the current instruction, only tracked while stepping
+    delta: Option<StateDelta>,
+
+    /// Print a line per executed instruction and the dump on halt
+    verbose: bool,
+
+    /// Addresses of the last few executed instructions, for fault contexts
+    history: IpHistory,
+}
+
+impl Machine {
+    /// Create a new machine instance with the default [`MachineConfig`].
+    /// An empty program is accepted, running it is a `VmError::IllegalInstruction` at ip 0.
+    pub fn new(program: impl Into<Arc<[Inst]>>) -> Self {
+        Machine::build(program.into(), &MachineConfig::default())
+    }
+
+    /// Create a new machine with the sizes in `config`. A register count over 256 is a
+    /// `ValidationError::RegisterCount`.
+    pub fn new_with_config(
+        program: impl Into<Arc<[Inst]>>,
+        config: MachineConfig,
+    ) -> Result<Self, ValidationError> {
+        Machine::new_with_storage(program, config)
+    }
+
+    /// Create a new machine with jump tables for the `TBL` instruction. The program and the
+    /// tables are validated first, see [`validate_program`].
+    pub fn with_tables<T: Into<JumpTable>>(
+        program: impl Into<Arc<[Inst]>>,
+        tables: Vec<T>,
+    ) -> Result<Self, ValidationError> {
+        let program = program.into();
+        let tables: Vec<JumpTable> = tables.into_iter().map(Into::into).collect();
+        validate_program(&program, &tables)?;
+
+        let mut machine = Machine::new(program);
+        machine.tables = tables;
+        Ok(machine)
+    }
+
+    /// Create a new machine with `count` numbered registers, `Reg::R(0)` to `Reg::R(count - 1)`.
+    /// Using a numbered register past that is a `PathError::RegErr`.
+    pub fn with_registers(
+        program: impl Into<Arc<[Inst]>>,
+        count: usize,
+    ) -> Result<Self, ValidationError> {
+        let config = MachineConfig {
+            registers: count,
+            ..MachineConfig::default()
+        };
+        Machine::new_with_config(program, config)
+    }
+
+    /// Create a new machine that replays `recording`: `IN`, `SYS` and `HCALL` get the recorded
+    /// results, in order, without asking the host, and any input the recording does not have
+    /// next is a `VmError::ReplayDivergence`.
+    pub fn with_replay(program: impl Into<Arc<[Inst]>>, recording: Recording) -> Self {
+        let mut machine = Machine::new(program);
+        machine.replay = Some(recording.events.into_iter());
+        machine
+    }
+
+    /// Create a new machine with `data` copied to the start of data memory
+    pub fn with_data(program: impl Into<Arc<[Inst]>>, data: &[Word]) -> Result<Self, VmError> {
+        let mut machine = Machine::new(program);
+        machine.load_data(data)?;
+        Ok(machine)
+    }
+
+    /// Create a new machine with a constant pool, `PSHC(n)` pushes `pool[n]`
+    pub fn with_pool(program: impl Into<Arc<[Inst]>>, pool: Vec<Word>) -> Self {
+        let mut machine = Machine::new(program);
+        machine.pool = pool;
+        machine
+    }
+
+    /// Create a new machine that keeps its program in the byte format of
+    /// [`encode_program`](crate::encode_program) and decodes each instruction as it executes it,
+    /// instead of holding decoded instructions. A big program takes a fraction of the memory, but
+    /// runs several times slower than decoded, even when the decoded program does not fit the
+    /// cache (see `benches/dispatch.rs`), and never in the plain run loop's decoded ops. The
+    /// bytes are checked to decode first.
+    pub fn from_bytecode(bytes: &[u8]) -> Result<Self, DecodeError> {
+        let mut machine = Machine::new(Vec::new());
+        machine.bytecode = Some(Bytecode::new(bytes)?);
+        Ok(machine)
+    }
+
+    /// Create a new machine that starts executing at `ip` instead of the first instruction
+    pub fn with_entry(program: impl Into<Arc<[Inst]>>, ip: usize) -> Result<Self, VmError> {
+        let mut machine = Machine::new(program);
+        machine.set_entry(ip)?;
+        Ok(machine)
+    }
+
+    /// Create a new machine that writes its output to `sink`, see [`Machine::set_output`]
+    pub fn with_output(program: impl Into<Arc<[Inst]>>, sink: impl OutputSink + 'static) -> Self {
+        let mut machine = Machine::new(program);
+        machine.set_output(sink);
+        machine
+    }
+}
+
+impl<S: StackStorage> Machine<S> {
+    /// Create a new machine that keeps its stack in `S`, with the sizes in `config` like
+    /// [`Machine::new_with_config`]. `Machine::<FixedStack<256>>::new_with_storage` makes a
+    /// machine whose stack is an array of 256 values inside it, see
+    /// [`FixedStack`](crate::FixedStack).
+    pub fn new_with_storage(
+        program: impl Into<Arc<[Inst]>>,
+        config: MachineConfig,
+    ) -> Result<Self, ValidationError> {
+        if config.registers > u8::MAX as usize + 1 {
+            return Err(ValidationError::RegisterCount(config.registers));
+        }
+        Ok(Machine::build(program.into(), &config))
+    }
+
+    fn build(program: Arc<[Inst]>, config: &MachineConfig) -> Self {
+        let registers = RegisterFile::new(config.registers);
+
+        Machine {
+            ops: decode(&program, config.fuse),
+            fuse: config.fuse,
+            program,
+            bytecode: None,
+            ip: 0,
+            entry: 0,
+            stack: Stack::new(
+                config.stack_size,
+                config.max_stack_size.unwrap_or(0).max(config.stack_size),
+            ),
+            types: config.typed.then(Vec::new),
+            registers,
+            flags: Flags::default(),
+            calls: Vec::new(),
+            call_depth: config.call_depth,
+            overflow: config.overflow,
+            fp: 0,
+            memory: Memory::new(config.memory_size),
+            data: Vec::new(),
+            heap: Heap::new(config.heap_size),
+            tables: Vec::new(),
+            divide_handler: None,
+            pool: Vec::new(),
+            traps: Vec::new(),
+            strings: Vec::new(),
+            faulted: None,
+            undo: Vec::new(),
+            executed: 0,
+            exit_code: None,
+            input: None,
+            output: None,
+            recording: None,
+            replay: None,
+            syscalls: None,
+            observer: None,
+            imports: Vec::new(),
+            host_fns: BTreeMap::new(),
+            #[cfg(feature = "std")]
+            ports: BTreeMap::new(),
+            fuel: None,
+            fuel_cost: |_| 1,
+            ticker: None,
+            timeout_check_interval: TIMEOUT_CHECK_INTERVAL,
+            delta: None,
+            verbose: false,
+            history: IpHistory::default(),
+        }
+    }
+
+    /// Handle divisions by zero in the program: a `DIV`, `MOD`, `DIVF`, `MODF`, `DIVU` or `MODU`
+    /// of zero calls the instruction at `handler` instead, like a `CALL` that returns to the
+    /// instruction after the division. The handler is called with both operands on the
+    /// stack and replaces them with the result. With no handler, a division by zero is a
+    /// recoverable error, so the embedder can handle it.
+    pub fn set_divide_handler(&mut self, handler: Option<usize>) {
+        self.divide_handler = handler;
+    }
+
+    /// Messages shown by the error of a failed `TRAP`, indexed by trap code
+    pub fn set_trap_messages(&mut self, messages: Vec<String>) {
+        self.traps = messages;
+    }
+
+    /// The string table, `PRINTS(id)` writes `strings[id]`
+    pub fn set_strings(&mut self, strings: Vec<String>) {
+        self.strings = strings;
+    }
+
+    /// String `id` of the string table
+    pub(crate) fn string(&self, id: Word) -> Option<&str> {
+        let string = usize::try_from(id).ok().and_then(|id| self.strings.get(id));
+        string.map(String::as_str)
+    }
+
+    /// The error that stopped the machine, if it faulted. It stays set after the error is
+    /// returned, for inspection, until the machine runs again.
+    pub fn faulted(&self) -> Option<&VmError> {
+        self.faulted.as_ref()
+    }
+
+    /// Copy `data` to the start of data memory
+    pub(crate) fn load_data(&mut self, data: &[Word]) -> Result<(), VmError> {
+        let size = self.memory.words.len();
+        if data.len() > size {
+            return Err(VmError::DataTooLarge {
+                len: data.len(),
+                size,
+            });
+        }
+        self.memory.words[..data.len()].copy_from_slice(data);
+        self.data = data.to_vec();
+        Ok(())
+    }
+
+    /// Start executing at `ip` instead of the first instruction, useful when one program holds
+    /// several routines. It must be called before the machine executes anything.
+    pub fn set_entry(&mut self, ip: usize) -> Result<(), VmError> {
+        if self.executed > 0 || self.ip != self.entry {
+            return Err(VmError::AlreadyStarted);
+        }
+        if ip >= self.program_len() {
+            return Err(VmError::BadEntry {
+                ip,
+                len: self.program_len(),
+            });
+        }
+        self.entry = ip;
+        self.ip = ip;
+        Ok(())
+    }
+
+    /// Index of the instruction the program started at
+    pub fn entry(&self) -> usize {
+        self.entry
+    }
+
+    /// Put the machine back in the state it was created in, to run the program again: the
+    /// stack, the call stack and the flags are cleared, data memory holds only the program's
+    /// initial data again, every register is zero and the instruction pointer is back at the
+    /// entry point. What is attached to the machine
+    /// (input, output, ports, handlers, host functions, fuel and the tick callback) stays, and
+    /// so does the size of everything.
+    pub fn reset(&mut self) {
+        self.ip = self.entry;
+        self.stack.clear();
+        if let Some(types) = &mut self.types {
+            types.clear();
+        }
+        self.registers.clear();
+        self.flags = Flags::default();
+        self.calls.clear();
+        self.fp = 0;
+        self.memory.words.fill(0);
+        self.memory.words[..self.data.len()].copy_from_slice(&self.data);
+        self.heap.clear();
+        self.faulted = None;
+        self.executed = 0;
+        self.exit_code = None;
+        self.delta = None;
+        self.history = IpHistory::default();
+    }
+
+    /// Copy the machine's state, to [`restore`](Machine::restore) it later
+    pub fn snapshot(&self) -> MachineSnapshot {
+        MachineSnapshot {
+            ip: self.ip,
+            stack: self.stack.values().to_vec(),
+            types: self.types.clone().unwrap_or_default(),
+            registers: self.registers.to_map(),
+            flags: self.flags,
+            calls: self.calls.clone(),
+            fp: self.fp,
+            memory: self.memory.words.clone(),
+            heap: self.heap.arrays.clone(),
+            executed: self.executed,
+            exit_code: self.exit_code,
+        }
+    }
+
+    /// The program, state and error of a machine that faulted, to write to a core file and
+    /// examine later. The state has the ip of the instruction that failed. `None` unless the
+    /// machine is stopped by an error.
+    #[cfg(feature = "std")]
+    pub fn core_dump(&self) -> Option<CoreDump> {
+        let error = self.faulted.as_ref()?;
+        let program = Program {
+            code: self.code(),
+            pool: self.pool.clone(),
+            data: Vec::new(),
+            traps: self.traps.clone(),
+            strings: self.strings.clone(),
+            imports: self.imports.clone(),
+        };
+        let mut snapshot = self.snapshot();
+        if let Some(context) = error.context() {
+            snapshot.ip = context.ip;
+        }
+        Some(CoreDump {
+            program,
+            snapshot,
+            error: format!("{error:#}"),
+        })
+    }
+
+    /// Put the machine back in the state `snapshot` was taken in. The snapshot should come from
+    /// this machine or one created with the same program and config. An error the machine
+    /// faulted with since is forgotten.
+    pub fn restore(&mut self, snapshot: &MachineSnapshot) {
+        self.ip = snapshot.ip;
+        self.stack.replace(&snapshot.stack);
+        if let Some(types) = &mut self.types {
+            types.clone_from(&snapshot.types);
+        }
+        self.registers.replace(&snapshot.registers);
+        self.flags = snapshot.flags;
+        self.calls.clone_from(&snapshot.calls);
+        self.fp = snapshot.fp;
+        self.memory.words.clone_from(&snapshot.memory);
+        self.heap.replace(&snapshot.heap);
+        self.executed = snapshot.executed;
+        self.exit_code = snapshot.exit_code;
+        self.faulted = None;
+        self.delta = None;
+    }
+
+    /// Replace the program and [`reset`](Machine::reset) the machine to run it from its first
+    /// instruction. The jump tables, divide handler, constant pool, trap messages, string table
+    /// and import table belonged to the old program and are dropped.
+    pub fn load_program(&mut self, program: impl Into<Arc<[Inst]>>) {
+        self.program = program.into();
+        self.bytecode = None;
+        self.ops = decode(&self.program, self.fuse);
+        self.entry = 0;
+        self.tables.clear();
+        self.divide_handler = None;
+        self.pool.clear();
+        self.traps.clear();
+        self.strings.clear();
+        self.imports.clear();
+        self.data.clear();
+        self.reset();
+    }
+
+    /// Read input for `IN` from `source`. Ignored while replaying a recording.
+    pub fn set_input(&mut self, source: impl InputSource + 'static) {
+        self.input = Some(Box::new(source));
+    }
+
+    /// Write everything the machine prints to `sink` instead of standard output: the values of
+    /// `OUT` and, when verbose, the trace and the dump. `io::sink()` silences the machine.
+    /// Without the `std` feature the sink is a `core::fmt::Write`.
+    pub fn set_output(&mut self, sink: impl OutputSink + 'static) {
+        self.output = Some(Box::new(sink));
+    }
+
+    /// Connect `port`: `SND` on it sends to `tx` and `RCV` receives from `rx`. To connect two
+    /// machines give each the sending end of the channel the other receives from. Values received
+    /// on ports are not part of a recording.
+    #[cfg(feature = "std")]
+    pub fn connect_port(&mut self, port: u8, tx: Sender<Word>, rx: Receiver<Word>) {
+        self.ports.insert(port, Port { tx, rx });
+    }
+
+    /// Start recording every value delivered to the program, dropping any earlier recording
+    pub fn start_recording(&mut self) {
+        self.recording = Some(Recording::default());
+    }
+
+    /// Stop recording and hand over what was recorded
+    pub fn take_recording(&mut self) -> Option<Recording> {
+        self.recording.take()
+    }
+
+    /// Execute exactly one instruction and report what it changed. Fuel and the tick callback
+    /// only apply to the run methods, a step never runs out of fuel.
+    pub fn step(&mut self) -> Result<StepOutcome, VmError> {
+        let (status, delta) = self.step_delta();
+        Ok(StepOutcome {
+            status: status?,
+            delta,
+        })
+    }
+
+    /// Execute one instruction, returning what it changed even if it failed. A recoverable
+    /// fault changed nothing.
+    fn step_delta(&mut self) -> (Result<StepStatus, VmError>, StateDelta) {
+        self.faulted = None;
+        self.delta = Some(StateDelta::default());
+        let flow = self.step_inner();
+        let delta = self.delta.take().unwrap_or_default();
+        let status = flow.map(|flow| match flow {
+            Flow::Continue => StepStatus::Running,
+            Flow::Yield(val) => StepStatus::Yielded(val),
+            Flow::Wait(port) => StepStatus::WaitingOnPort(port),
+            Flow::Halt(_) => StepStatus::Halted,
+        });
+        (status, delta)
+    }
+
+    /// Execute one instruction like [`Machine::step`], and keep what it takes to undo it with
+    /// [`Machine::undo_step`], whether or not it failed
+    pub(crate) fn step_undoable(&mut self) -> (Result<StepOutcome, VmError>, PastStep) {
+        let inst = self.instruction(self.ip);
+        if self.types.is_some() || matches!(inst, Some(Inst::SYS(_) | Inst::ALLOC(_))) {
+            let before = PastStep::State(self.snapshot());
+            return (self.step(), before);
+        }
+        let (ip, executed, exit_code, fp) = (self.ip, self.executed, self.exit_code, self.fp);
+        let (calls, frame) = (self.calls.len(), self.calls.last().copied());
+        let (status, delta) = self.step_delta();
+        let outcome = status.map(|status| StepOutcome {
+            status,
+            delta: delta.clone(),
+        });
+        let before = PastStep::Delta {
+            ip,
+            executed,
+            exit_code,
+            fp,
+            calls,
+            frame,
+            delta,
+        };
+        (outcome, before)
+    }
+
+    /// Go back to the state before the step `past` was kept for, which must be the last step
+    /// executed or gone back over. What was changed through other methods since is kept.
+    pub(crate) fn undo_step(&mut self, past: &PastStep) {
+        let (ip, executed, exit_code, fp, calls, frame, delta) = match past {
+            PastStep::State(snapshot) => return self.restore(snapshot),
+            PastStep::Delta {
+                ip,
+                executed,
+                exit_code,
+                fp,
+                calls,
+                frame,
+                delta,
+            } => (ip, executed, exit_code, fp, calls, frame, delta),
+        };
+        // in the reverse order of the changes, the write to a slot comes after the pops and
+        // pushes of an instruction
+        if let Some(write) = delta.stack_write {
+            let _ = self.stack.set_at_idx(write.offset, write.old);
+        }
+        for _ in &delta.pushed {
+            let _ = self.stack.pop();
+        }
+        for &val in delta.popped.iter().rev() {
+            let _ = self.stack.push(val);
+        }
+        if let Some(change) = delta.register {
+            if let Some(slot) = self.registers.get_mut(&change.reg) {
+                *slot = change.old;
+            }
+        }
+        if let Some(change) = delta.memory_write {
+            if let Some(word) = self.memory.words.get_mut(change.addr) {
+                *word = change.old;
+            }
+        }
+        if let Some(change) = delta.heap_write {
+            let _ = self.heap.store(change.handle, change.index, change.old);
+        }
+        if let Some(change) = delta.flags {
+            self.flags = change.old;
+        }
+        self.calls.truncate(*calls);
+        if self.calls.len() < *calls {
+            self.calls.extend(*frame);
+        }
+        self.fp = *fp;
+        self.ip = *ip;
+        self.executed = *executed;
+        self.exit_code = *exit_code;
+        self.faulted = None;
+    }
+
+    /// Print a trace line for every executed instruction, and the machine dump when `run`
+    /// halts. Machines are silent by default, when silent no trace output is even formatted.
+    pub fn set_verbose(&mut self, verbose: bool) {
+        self.verbose = verbose;
+    }
+
+    /// Run the machine and execute the program sequentially one instruction at a time.
+    /// `HLT` instruction causes the machine to stop execution and, when verbose, dump its state.
+    /// Running past the final instruction without a `HLT` is a `VmError::IllegalInstruction`.
+    /// It also returns, without the dump, when the machine is paused or runs out of fuel.
+    pub fn run(&mut self) -> Result<RunOutcome, VmError> {
+        let outcome = self.resume()?;
+        if outcome == RunOutcome::Halted && self.verbose {
+            self.dump();
+        }
+        Ok(outcome)
+    }
+
+    /// Run the machine from where it is until it halts, yields, runs out of fuel, is paused by
+    /// the tick callback, or faults. Calling it again after a pause or after adding fuel continues
+    /// the program.
+    pub fn resume(&mut self) -> Result<RunOutcome, VmError> {
+        self.run_until(None, None)
+    }
+
+    /// Like [`Machine::resume`], but give up with `RunOutcome::TimedOut` once `timeout` has
+    /// passed. The machine is left resumable.
+    ///
+    /// Reading the clock costs far more than executing an instruction, so it is only read every
+    /// [`Machine::set_timeout_check_interval`] instructions. The run can overshoot the deadline
+    /// by however long that many instructions take, which is also the most time a single slow
+    /// instruction (a blocking device or input source) can add.
+    #[cfg(feature = "std")]
+    pub fn run_with_timeout(&mut self, timeout: Duration) -> Result<RunOutcome, VmError> {
+        self.run_until(Some(Instant::now() + timeout), None)
+    }
+
+    /// Like [`Machine::resume`], but give up with `VmError::InstructionLimit` instead of
+    /// executing more than `max_instructions` instructions, for programs that may never halt.
+    /// A program that halts on its last allowed instruction is not an error.
+    pub fn run_bounded(&mut self, max_instructions: u64) -> Result<RunOutcome, VmError> {
+        self.run_until(None, Some(max_instructions))
+    }
+
+    /// Read the clock every `interval` instructions in [`Machine::run_with_timeout`], by
+    /// default every [`TIMEOUT_CHECK_INTERVAL`]. Smaller is more precise, larger is faster.
+    pub fn set_timeout_check_interval(&mut self, interval: u64) {
+        self.timeout_check_interval = interval.max(1);
+    }
+
+    fn run_until(
+        &mut self,
+        deadline: Option<Deadline>,
+        limit: Option<u64>,
+    ) -> Result<RunOutcome, VmError> {
+        let mut until_check = self.timeout_check_interval;
+        let start = self.executed;
+        self.faulted = None;
+        let plain = self.fuel.is_none() && self.ticker.is_none() && self.observer.is_none();
+        if deadline.is_none() && limit.is_none() && plain && self.types.is_none() {
+            return self.dispatch();
+        }
+        loop {
+            if let Some(limit) = limit {
+                if self.executed - start >= limit {
+                    return Err(VmError::InstructionLimit { limit, ip: self.ip });
+                }
+            }
+            if let Some(deadline) = deadline {
+                until_check -= 1;
+                if until_check == 0 {
+                    until_check = self.timeout_check_interval;
+                    if passed(&deadline) {
+                        return Ok(RunOutcome::TimedOut);
+                    }
+                }
+            }
+            let mut cost = 0;
+            if let Some(fuel) = self.fuel {
+                // running off the end still costs something, it is an error either way
+                cost = self
+                    .instruction(self.ip)
+                    .map_or(1, |inst| (self.fuel_cost)(&inst));
+                if fuel < cost {
+                    return Ok(RunOutcome::OutOfFuel);
+                }
+                self.fuel = Some(fuel - cost);
+            }
+            let flow = self.step_inner()?;
+            match flow {
+                Flow::Halt(_) => return Ok(RunOutcome::Halted),
+                Flow::Wait(port) => {
+                    // nothing was executed, give the fuel back
+                    if let Some(fuel) = &mut self.fuel {
+                        *fuel += cost;
+                    }
+                    return Ok(RunOutcome::WaitingOnPort(port));
+                }
+                _ => (),
+            }
+            // the yield is still counted towards the tick, a pause on it is left to the host
+            let action = self.tick();
+            if let Flow::Yield(val) = flow {
+                return Ok(RunOutcome::Yielded(val));
+            }
+            if let TickAction::Pause = action {
+                return Ok(RunOutcome::Paused);
+            }
+        }
+    }
+
+    /// Run the machine until it stops, without panicking, and summarize the run.
+    /// A fault is reported through [`ExecutionReport::halt`], the machine is left in the state
+    /// it faulted in, or the state before the failing instruction when the error
+    /// [is recoverable](VmError::is_recoverable).
+    #[cfg(feature = "std")]
+    pub fn run_report(&mut self) -> ExecutionReport {
+        let start = Instant::now();
+        let halt = match self.resume() {
+            Ok(outcome) => outcome.into(),
+            Err(e) => HaltCause::Fault(e),
+        };
+
+        ExecutionReport {
+            entry: self.entry,
+            instructions: self.executed,
+            halt,
+            elapsed: start.elapsed(),
+            exit_code: self.exit_code,
+            stack_top: self.stack_top(),
+            registers: self.registers.to_map(),
+        }
+    }
+
+    /// Send `LOAD` and `STORE` on addresses in `range` to `handler` instead of data memory.
+    /// The range may lie past the end of data memory, but it must not overlap another mapping.
+    pub fn map_io(
+        &mut self,
+        range: Range<usize>,
+        handler: Box<dyn MmioHandler>,
+    ) -> Result<(), VmError> {
+        self.memory.map(range, handler)
+    }
+
+    /// Handle the system calls the program makes with `SYS`, replacing any earlier handler
+    pub fn set_syscall_handler(&mut self, handler: Box<dyn SyscallHandler>) {
+        self.syscalls = Some(handler);
+    }
+
+    /// Report every executed instruction and stack change to `observer`, replacing any earlier
+    /// observer
+    pub fn set_observer(&mut self, observer: impl Observer + 'static) {
+        self.observer = Some(Box::new(observer));
+    }
+
+    /// Detach the observer and hand it back
+    pub fn take_observer(&mut self) -> Option<Box<dyn Observer>> {
+        self.observer.take()
+    }
+
+    /// Run the program again the way `trace` recorded it, with the input of the trace replayed
+    /// like [`Machine::with_replay`] does, and check every executed instruction against its
+    /// step of the trace: the same instruction at the same ip, leaving the stack as deep and
+    /// writing the same registers. The first step that differs, or a run that is longer or
+    /// shorter than the trace, is a `VmError::TraceDivergence`.
+    ///
+    /// The machine should be in the state the traced run started from, and the observer is set
+    /// aside until the replay returns. Yielded values are skipped. Values received on ports are
+    /// not recorded, so a `RCV` with nothing to receive returns `RunOutcome::WaitingOnPort`
+    /// without checking the rest of the trace.
+    #[cfg(feature = "std")]
+    pub fn replay(&mut self, trace: &Trace) -> Result<RunOutcome, VmError> {
+        self.replay = Some(trace.input.events.clone().into_iter());
+        let steps = Arc::new(Mutex::new(Vec::new()));
+        let tracer: Box<dyn Observer> = Box::new(Tracer::collect(steps.clone()));
+        let observer = self.observer.replace(tracer);
+        let outcome = self.replay_steps(&trace.steps, &steps);
+        self.observer = observer;
+        outcome
+    }
+
+    #[cfg(feature = "std")]
+    fn replay_steps(
+        &mut self,
+        expected: &[TraceStep],
+        steps: &Mutex<Vec<TraceStep>>,
+    ) -> Result<RunOutcome, VmError> {
+        let diverged = |step: usize, found: Option<TraceStep>| VmError::TraceDivergence {
+            step,
+            expected: expected.get(step).cloned().map(Box::new),
+            found: found.map(Box::new),
+        };
+        let mut executed = 0;
+        loop {
+            let outcome = self.step();
+            if outcome.is_err() {
+                // the tracer writes a faulted step when it goes away
+                self.observer = None;
+            }
+            for step in core::mem::take(&mut *steps.lock().unwrap()) {
+                if expected.get(executed) != Some(&step) {
+                    return Err(diverged(executed, Some(step)));
+                }
+                executed += 1;
+            }
+            let status = match outcome {
+                Ok(outcome) => outcome.status,
+                Err(e) if executed == expected.len() => return Err(e),
+                Err(_) => return Err(diverged(executed, None)),
+            };
+            match status {
+                StepStatus::Running | StepStatus::Yielded(_) => {}
+                StepStatus::WaitingOnPort(port) => return Ok(RunOutcome::WaitingOnPort(port)),
+                StepStatus::Halted if executed == expected.len() => return Ok(RunOutcome::Halted),
+                StepStatus::Halted => return Err(diverged(executed, None)),
+            }
+        }
+    }
+
+    /// Register the host function `name`, replacing any earlier one of that name. `HCALL` of an
+    /// import with this name pops `arity` values, passes them to `f` in the order they were
+    /// pushed, and pushes what it returns.
+    pub fn register_host_fn(
+        &mut self,
+        name: impl Into<String>,
+        arity: usize,
+        f: impl FnMut(&[Word]) -> Word + Send + 'static,
+    ) {
+        let f = Box::new(f);
+        self.host_fns.insert(name.into(), HostFunction { arity, f });
+    }
+
+    /// The import table, the name of the host function each `HCALL` index calls.
+    /// `Program::machine` sets it from the program.
+    pub fn set_imports(&mut self, names: Vec<String>) {
+        self.imports = names;
+    }
+
+    /// Imports with no host function registered, to check a program before running it
+    pub fn unresolved_imports(&self) -> Vec<&str> {
+        let imports = self.imports.iter();
+        let unresolved = imports.filter(|name| !self.host_fns.contains_key(*name));
+        unresolved.map(String::as_str).collect()
+    }
+
+    /// Data memory, not including mapped devices
+    pub fn memory(&self) -> &[Word] {
+        &self.memory.words
+    }
+
+    /// Limit the machine to `fuel` more instructions, or lift the limit with `None`. Running out
+    /// stops the run with `RunOutcome::OutOfFuel` before the next instruction.
+    pub fn set_fuel(&mut self, fuel: Option<u64>) {
+        self.fuel = fuel;
+    }
+
+    /// Give the machine `fuel`, replacing what it had left, and resume it. Stopping with
+    /// `RunOutcome::OutOfFuel` leaves it resumable, so an untrusted program can be run in
+    /// slices by calling this again.
+    pub fn run_with_fuel(&mut self, fuel: u64) -> Result<RunOutcome, VmError> {
+        self.fuel = Some(fuel);
+        self.resume()
+    }
+
+    /// Charge `cost(inst)` fuel for each instruction instead of one. An instruction that costs
+    /// more than the fuel left is not executed, the run stops with `RunOutcome::OutOfFuel`.
+    /// Instructions that cost nothing are not limited by fuel at all.
+    pub fn set_fuel_cost(&mut self, cost: fn(&Inst) -> u64) {
+        self.fuel_cost = cost;
+    }
+
+    /// Fuel left, `None` if unlimited
+    pub fn fuel(&self) -> Option<u64> {
+        self.fuel
+    }
+
+    /// Call `callback` after every `interval` executed instructions, replacing any earlier
+    /// callback. The count carries on across pauses and resumes. An interval of zero removes
+    /// the callback.
+    pub fn set_tick(&mut self, interval: u64, callback: TickFn) {
+        self.ticker = (interval > 0).then(|| Ticker {
+            interval,
+            since: 0,
+            count: 0,
+            callback,
+        });
+    }
+
+    /// Index of the next instruction to execute
+    pub fn ip(&self) -> usize {
+        self.ip
+    }
+
+    /// The program the machine runs, empty for a machine created with
+    /// [`Machine::from_bytecode`], whose instructions [`Machine::instruction`] decodes
+    pub fn program(&self) -> &[Inst] {
+        &self.program
+    }
+
+    /// The instruction at `ip`, `None` past the end of the program
+    pub fn instruction(&self, ip: usize) -> Option<Inst> {
+        match &self.bytecode {
+            None => self.program.get(ip).copied(),
+            Some(code) => code.get(ip),
+        }
+    }
+
+    /// The instruction at `ip` to execute it, which moves the cursor of a machine running
+    /// bytecode on
+    fn fetch(&mut self, ip: usize) -> Option<Inst> {
+        match &mut self.bytecode {
+            None => self.program.get(ip).copied(),
+            Some(code) => code.fetch(ip),
+        }
+    }
+
+    /// Number of instructions in the program
+    pub fn program_len(&self) -> usize {
+        match &self.bytecode {
+            None => self.program.len(),
+            Some(code) => code.len(),
+        }
+    }
+
+    /// Every instruction of the program, decoded for a machine running bytecode
+    fn code(&self) -> Vec<Inst> {
+        match &self.bytecode {
+            None => self.program.to_vec(),
+            Some(code) => code.to_vec(),
+        }
+    }
+
+    /// Instructions executed so far
+    pub fn instructions(&self) -> u64 {
+        self.executed
+    }
+
+    /// Every register the machine has, with its value
+    pub fn registers(&self) -> &RegisterFile {
+        &self.registers
+    }
+
+    /// Calls in progress, the latest last
+    pub fn call_stack(&self) -> &[Frame] {
+        &self.calls
+    }
+
+    /// Position in the stack, counted from the bottom, of local slot zero of the current call.
+    /// Outside any call it is zero, the bottom of the stack.
+    pub fn frame_pointer(&self) -> usize {
+        self.fp
+    }
+
+    /// Flags of the last `CMP`, all clear before the first one
+    pub fn flags(&self) -> Flags {
+        self.flags
+    }
+
+    /// Empty the stack, the same as executing `CLR`
+    pub fn clear_stack(&mut self) {
+        if let Some(observer) = &mut self.observer {
+            observer.on_stack_clear(self.stack.len());
+        }
+        self.stack.clear();
+    }
+
+    /// Push the arguments of the program before it starts, in order, so the last argument is at
+    /// the head of the stack, the opposite of [`take_results`](Machine::take_results). It must
+    /// be called before the machine executes anything, and [`reset`](Machine::reset) clears the
+    /// arguments with the rest of the stack.
+    pub fn push_args(&mut self, args: &[Word]) -> Result<(), VmError> {
+        if self.executed > 0 || self.ip != self.entry {
+            return Err(VmError::AlreadyStarted);
+        }
+        let depth = self.stack.len();
+        for &arg in args {
+            if self.stack.push(arg).is_err() {
+                self.stack.truncate(depth);
+                return Err(VmError::TooManyArgs {
+                    len: args.len(),
+                    size: self.stack.limit(),
+                });
+            }
+        }
+        Ok(())
+    }
+
+    /// Pop the `n` results of a halted program, returned in the order the program pushed them.
+    /// By convention a program pushes its results one after another before `HLT`, so the last
+    /// result is at the head of the stack.
+    pub fn take_results(&mut self, n: usize) -> Result<Vec<Word>, VmError> {
+        if self.exit_code.is_none() {
+            return Err(VmError::NotHalted);
+        }
+        let available = self.stack.len();
+        if n > available {
+            return Err(VmError::MissingResults {
+                requested: n,
+                available,
+            });
+        }
+        let results = self.stack.values()[available - n..].to_vec();
+        self.stack.truncate(available - n);
+        Ok(results)
+    }
+
+    /// Exit code of a halted program: the operand of the `EXIT` it halted with, or 0 for `HLT`
+    pub fn exit_code(&self) -> Option<i32> {
+        self.exit_code
+    }
+
+    /// The exit code, head of the stack and registers of a halted program, `None` if the program
+    /// has not halted
+    pub fn halt_state(&self) -> Option<HaltState> {
+        Some(HaltState {
+            exit_code: self.exit_code?,
+            stack_top: self.stack_top(),
+            registers: self.registers.to_map(),
+        })
+    }
+
+    /// Values of `regs`, in the same order, for programs that leave their results in
+    /// registers. A register the machine does not have reads as zero.
+    pub fn results_named(&self, regs: &[Reg]) -> Vec<Word> {
+        regs.iter()
+            .map(|reg| self.registers.get(reg).copied().unwrap_or(0))
+            .collect()
+    }
+
+    /// Every value on the stack, the bottom first and the head of the stack last
+    pub fn stack(&self) -> &[Word] {
+        self.stack.values()
+    }
+
+    /// Every value on the stack with its type, the bottom first. Every value of a machine that is
+    /// not typed is an int.
+    pub fn values(&self) -> Vec<Value> {
+        let types = self.types.as_deref().unwrap_or_default();
+        self.stack
+            .values()
+            .iter()
+            .enumerate()
+            .map(|(pos, &val)| Value::from_word(val, types.get(pos).copied().unwrap_or(Type::Int)))
+            .collect()
+    }
+
+    /// Free the heap arrays the program can no longer reach, returning the number of words
+    /// freed. `ALLOC` collects by itself when the heap is full. Every value on the stack, in a
+    /// register or in data memory that is the handle of an array keeps the array, and so does
+    /// every such value in an array that is kept.
+    pub fn collect_garbage(&mut self) -> usize {
+        let roots = self.stack.values().iter().chain(self.registers.values());
+        let roots = roots.chain(&self.memory.words).copied();
+        self.heap.collect(roots)
+    }
+
+    /// Value at the head of the stack, if the stack is not empty
+    pub fn stack_top(&self) -> Option<Word> {
+        self.stack.values().last().copied()
+    }
+
+    /// The registers, for a debugger to change
+    #[cfg(any(test, feature = "debug"))]
+    pub fn registers_mut(&mut self) -> &mut RegisterFile {
+        &mut self.registers
+    }
+
+    /// The values on the stack, the bottom first, for a debugger to change. The depth of the
+    /// stack stays as it is.
+    #[cfg(any(test, feature = "debug"))]
+    pub fn stack_mut(&mut self) -> &mut [Word] {
+        self.stack.values_mut()
+    }
+
+    /// Move the instruction pointer. An `ip` past the end of the program is a
+    /// `VmError::IllegalInstruction` when the machine runs.
+    #[cfg(any(test, feature = "debug"))]
+    pub fn set_ip(&mut self, ip: usize) {
+        self.ip = ip;
+    }
+
+    /// Count an executed instruction towards the tick interval, firing the callback when due
+    fn tick(&mut self) -> TickAction {
+        let mut ticker = match self.ticker.take() {
+            Some(ticker) => ticker,
+            None => return TickAction::Continue,
+        };
+        ticker.since += 1;
+        let mut action = TickAction::Continue;
+        if ticker.since == ticker.interval {
+            ticker.since = 0;
+            ticker.count += 1;
+            let mut ctx = TickCtx {
+                ip: self.ip,
+                instructions: self.executed,
+                stack_top: self.stack_top(),
+                registers: &self.registers,
+                tick: ticker.count,
+            };
+            action = (ticker.callback)(&mut ctx);
+        }
+        self.ticker = Some(ticker);
+        action
+    }
+
+    /// The run loop of a machine that nothing watches instruction by instruction: no limit,
+    /// deadline, fuel, tick callback, observer or types. It runs over the decoded program, an op
+    /// that continues costs its execution and nothing else, and whatever else happens goes
+    /// through [`Machine::finish`] as it does for a step.
+    fn dispatch(&mut self) -> Result<RunOutcome, VmError> {
+        let ops = Arc::clone(&self.ops);
+        // a trace line is written for every instruction
+        let ops = if self.verbose { &ops[..0] } else { &ops[..] };
+        loop {
+            let ip = self.ip;
+            if let Some(&op) = ops.get(ip) {
+                if self.execute_op(ip, op) {
+                    continue;
+                }
+            }
+            let Some(inst) = self.fetch(ip) else {
+                return Err(self.ran_off(ip));
+            };
+            self.ip = ip + 1;
+            self.history.record(ip);
+            self.undo.clear();
+            let flow = match self.execute(inst) {
+                Ok(Flow::Continue) => {
+                    self.executed += 1;
+                    self.exit_code = None;
+                    continue;
+                }
+                flow => self.finish(ip, inst, flow)?,
+            };
+            match flow {
+                Flow::Continue => (),
+                Flow::Yield(val) => return Ok(RunOutcome::Yielded(val)),
+                Flow::Wait(port) => return Ok(RunOutcome::WaitingOnPort(port)),
+                Flow::Halt(_) => return Ok(RunOutcome::Halted),
+            }
+        }
+    }
+
+    /// Execute `op`, decoded from the instruction at `ip`, if it cannot fail, returning whether
+    /// it did. Nothing watches the run, so there is nothing to undo, record or report. If it
+    /// could fail nothing is changed, and executing the instruction the usual way fails with the
+    /// error it always did.
+    #[inline(always)]
+    fn execute_op(&mut self, ip: usize, op: Op) -> bool {
+        let stack = &mut self.stack;
+        let next = match op {
+            Op::Inst => return false,
+            Op::Psh(val) => {
+                if stack.push(val).is_err() {
+                    return false;
+                }
+                ip + 1
+            }
+            Op::Pop => {
+                if stack.pop().is_err() {
+                    return false;
+                }
+                ip + 1
+            }
+            Op::Dup => match stack.get_at_idx(0) {
+                Ok(val) if stack.push(val).is_ok() => ip + 1,
+                _ => return false,
+            },
+            Op::Add | Op::Sub | Op::Mul => {
+                let inst = match op {
+                    Op::Add => Inst::ADD,
+                    Op::Sub => Inst::SUB,
+                    _ => Inst::MUL,
+                };
+                let (Ok(arg_2), Ok(arg_1)) = (stack.get_at_idx(0), stack.get_at_idx(1)) else {
+                    return false;
+                };
+                let Ok(val) = self.arithmetic(inst, arg_1, arg_2) else {
+                    return false;
+                };
+                let _ = self.stack.pop();
+                let _ = self.stack.set_at_idx(0, val);
+                ip + 1
+            }
+            Op::Cmp => {
+                let (Ok(arg_2), Ok(arg_1)) = (stack.get_at_idx(0), stack.get_at_idx(1)) else {
+                    return false;
+                };
+                self.flags = Flags::compare(arg_1, arg_2);
+                ip + 1
+            }
+            Op::Set(reg, val) => {
+                let Some(slot) = self.registers.get_mut(&reg) else {
+                    return false;
+                };
+                *slot = val;
+                ip + 1
+            }
+            Op::Inc(reg) | Op::Dec(reg) => {
+                let Some(slot) = self.registers.get_mut(&reg) else {
+                    return false;
+                };
+                *slot = match op {
+                    Op::Inc(_) => slot.wrapping_add(1),
+                    _ => slot.wrapping_sub(1),
+                };
+                ip + 1
+            }
+            Op::CpyReg(dst, src) => {
+                let Some(&val) = self.registers.get(&src) else {
+                    return false;
+                };
+                let Some(slot) = self.registers.get_mut(&dst) else {
+                    return false;
+                };
+                *slot = val;
+                ip + 1
+            }
+            Op::CpyTop(reg) => {
+                let (Ok(val), Some(slot)) = (stack.get_at_idx(0), self.registers.get_mut(&reg))
+                else {
+                    return false;
+                };
+                *slot = val;
+                ip + 1
+            }
+            Op::Jmp(target) => target as usize,
+            Op::Jez(target) | Op::Jnz(target) => {
+                let Ok(val) = stack.pop() else {
+                    return false;
+                };
+                match op {
+                    Op::Jez(_) if val == 0 => target as usize,
+                    Op::Jnz(_) if val != 0 => target as usize,
+                    _ => ip + 1,
+                }
+            }
+            Op::Loop(reg, target) => {
+                let Some(slot) = self.registers.get_mut(&reg) else {
+                    return false;
+                };
+                *slot = slot.wrapping_sub(1);
+                match *slot {
+                    0 => ip + 1,
+                    _ => target as usize,
+                }
+            }
+            Op::Call(target) => {
+                if self.calls.len() >= self.call_depth {
+                    return false;
+                }
+                self.calls.push(Frame {
+                    ret: ip + 1,
+                    fp: self.fp,
+                });
+                self.fp = stack.len();
+                target as usize
+            }
+            Op::Ret => {
+                let Some(frame) = self.calls.pop() else {
+                    return false;
+                };
+                self.fp = frame.fp;
+                frame.ret
+            }
+            Op::AddImm(val) | Op::SubImm(val) | Op::MulImm(val) => {
+                let inst = match op {
+                    Op::AddImm(_) => Inst::ADD,
+                    Op::SubImm(_) => Inst::SUB,
+                    _ => Inst::MUL,
+                };
+                // the `PSH` needs room for its value
+                let head = match stack.get_at_idx(0) {
+                    Ok(head) if !stack.is_full() => head,
+                    _ => return false,
+                };
+                let Ok(val) = self.arithmetic(inst, head, val) else {
+                    return false;
+                };
+                let _ = self.stack.set_at_idx(0, val);
+                return self.executed_fused(ip);
+            }
+            Op::PopTo(reg) => {
+                let (Ok(val), Some(slot)) = (stack.get_at_idx(0), self.registers.get_mut(&reg))
+                else {
+                    return false;
+                };
+                *slot = val;
+                let _ = self.stack.pop();
+                return self.executed_fused(ip);
+            }
+        };
+        self.history.record(ip);
+        self.ip = next;
+        self.executed += 1;
+        self.exit_code = None;
+        true
+    }
+
+    /// Count the superinstruction executed at `ip`
+    fn executed_fused(&mut self, ip: usize) -> bool {
+        for pos in ip..ip + FUSED_LEN {
+            self.history.record(pos);
+        }
+        self.ip = ip + FUSED_LEN;
+        self.executed += FUSED_LEN as u64;
+        self.exit_code = None;
+        true
+    }
+
+    /// Fetch and execute the next instruction
+    fn step_inner(&mut self) -> Result<Flow, VmError> {
+        let ip = self.ip;
+        let inst = match self.get_next_inst() {
+            Some(inst) => inst,
+            None => return Err(self.ran_off(ip)),
+        };
+        self.history.record(ip);
+        if let Some(observer) = &mut self.observer {
+            observer.before_inst(ip, inst);
+        }
+        self.undo.clear();
+        let flow = self.execute_typed(inst);
+        self.finish(ip, inst, flow)
+    }
+
+    /// The error of running past the last instruction, at `ip`
+    #[cold]
+    fn ran_off(&mut self, ip: usize) -> VmError {
+        let err = VmError::IllegalInstruction { ip, context: None };
+        let err = err.with_context(self.fault_context(ip, None));
+        self.faulted = Some(err.clone());
+        err
+    }
+
+    /// Count `inst`, executed at `ip` with `flow`, or turn its fault into the error the run
+    /// stops with
+    fn finish(
+        &mut self,
+        ip: usize,
+        inst: Inst,
+        flow: Result<Flow, Fault>,
+    ) -> Result<Flow, VmError> {
+        let flow = flow.map_err(|fault| {
+            if fault.is_recoverable_in(inst) {
+                self.rewind(ip);
+            }
+            let context = self.fault_context(ip, Some(inst));
+            let depth = self.stack.len();
+            let mut err = fault.at(ip, inst, depth).with_context(context);
+            if let VmError::Trap { code, message, .. } = &mut err {
+                *message = self.traps.get(*code as usize).cloned();
+            }
+            self.faulted = Some(err.clone());
+            err
+        })?;
+        if let Flow::Wait(_) = flow {
+            self.ip = ip;
+            return Ok(flow);
+        }
+        self.executed += 1;
+        self.exit_code = match flow {
+            Flow::Halt(code) => Some(code),
+            _ => None,
+        };
+        if let Some(observer) = &mut self.observer {
+            observer.after_inst(ip, inst);
+            if let Flow::Halt(code) = flow {
+                observer.on_halt(code);
+            }
+        }
+        Ok(flow)
+    }
+
+    /// Registers and their values, `A` to `F` first and then the numbered ones in order
+    pub(crate) fn sorted_registers(&self) -> Vec<(Reg, Word)> {
+        self.registers.iter().collect()
+    }
+
+    /// Snapshot of the machine for an error raised by `inst` at `ip`
+    fn fault_context(&self, ip: usize, inst: Option<Inst>) -> FaultContext {
+        let registers = self.sorted_registers();
+        FaultContext {
+            ip,
+            inst,
+            stack: self
+                .stack
+                .values()
+                .iter()
+                .rev()
+                .take(CONTEXT_STACK_DEPTH)
+                .copied()
+                .collect(),
+            registers,
+            recent: self.history.to_vec(),
+        }
+    }
+
+    /// Execute `inst`, on a typed machine checking the types of its operands first and then
+    /// tagging the slots it changed
+    fn execute_typed(&mut self, inst: Inst) -> Result<Flow, Fault> {
+        let Some(mut types) = self.types.take() else {
+            return self.execute(inst);
+        };
+        // the host may have changed the stack since, what it pushed is an int
+        types.resize(self.stack.len(), Type::Int);
+        let flow = self
+            .type_effect(inst, &types)
+            .and_then(|effect| Ok((self.execute(inst)?, effect)));
+        let flow = flow.map(|(flow, effect)| {
+            let mut pushes = effect.pushes.into_iter();
+            for change in &self.undo {
+                match change {
+                    Undo::Push(ty) => types.push(pushes.next().unwrap_or(*ty)),
+                    Undo::Pop(_) => {
+                        types.pop();
+                    }
+                    Undo::Reg(..) => (),
+                }
+            }
+            if let Some(slot) = effect
+                .slot
+                .and_then(|(pos, ty)| Some((types.get_mut(pos)?, ty)))
+            {
+                *slot.0 = slot.1;
+            }
+            types.resize(self.stack.len(), Type::Int);
+            flow
+        });
+        self.types = Some(types);
+        flow
+    }
+
+    /// What `inst` does to the types of the stack slots, `types`, failing if an operand has
+    /// the wrong type. A missing operand is left for the instruction to fail on.
+    fn type_effect(&self, inst: Inst, types: &[Type]) -> Result<TypeEffect, Fault> {
+        let at = |n: usize| types.len().checked_sub(n + 1).map(|pos| types[pos]);
+        let expect = |n: usize, expected: Type| match at(n) {
+            Some(found) if found != expected => Err(Fault::TypeMismatch { expected, found }),
+            _ => Ok(()),
+        };
+        let expect_pair = |expected: Type| expect(0, expected).and_then(|_| expect(1, expected));
+        // logic operations and conditions take bools as well as ints
+        let logic = match at(0) {
+            Some(Type::Bool) => Type::Bool,
+            _ => Type::Int,
+        };
+        // position from the bottom of the slot `offset` from the head or `slot` from the frame
+        let pos = |offset: isize| {
+            let pos = (types.len() as isize).checked_sub(offset.checked_add(1)?)?;
+            usize::try_from(pos).ok().filter(|&pos| pos < types.len())
+        };
+        let local = |slot: isize| {
+            let pos = usize::try_from((self.fp as isize).checked_add(slot)?).ok()?;
+            (pos < types.len()).then_some(pos)
+        };
+        let path_pos = |path: Path| match path {
+            Path::REG(_) => None,
+            Path::STK(offset) => pos(offset),
+            Path::STKR(reg, offset) => pos(self.stack_offset(reg, offset).ok()?),
+        };
+
+        let mut slot = None;
+        let pushes = match inst {
+            Inst::PSH(_)
+            | Inst::PSHC(_)
+            | Inst::IN
+            | Inst::RCV(_)
+            | Inst::LOAD(_)
+            | Inst::LOADR(..) => vec![Type::Int],
+            Inst::ALLOC(_) => vec![Type::Ref],
+            Inst::AIDXLOAD => {
+                expect(0, Type::Int)?;
+                expect(1, Type::Ref)?;
+                vec![Type::Int]
+            }
+            Inst::AIDXSTORE => {
+                expect_pair(Type::Int)?;
+                expect(2, Type::Ref)?;
+                vec![]
+            }
+            Inst::FPSH(_) => vec![Type::Float],
+            Inst::ADD
+            | Inst::SUB
+            | Inst::MUL
+            | Inst::QMUL
+            | Inst::DIV
+            | Inst::MOD
+            | Inst::DIVF
+            | Inst::MODF
+            | Inst::DIVU
+            | Inst::MODU
+            | Inst::QDIV
+            | Inst::SHL
+            | Inst::SHR
+            | Inst::MIN
+            | Inst::MAX => {
+                expect_pair(Type::Int)?;
+                vec![Type::Int]
+            }
+            Inst::CMP => {
+                expect_pair(Type::Int)?;
+                vec![]
+            }
+            Inst::AND | Inst::OR | Inst::XOR => {
+                expect_pair(logic)?;
+                vec![logic]
+            }
+            Inst::NOT | Inst::NEG | Inst::ABS => {
+                expect(0, Type::Int)?;
+                vec![Type::Int]
+            }
+            Inst::FADD | Inst::FSUB | Inst::FMUL | Inst::FDIV => {
+                expect_pair(Type::Float)?;
+                vec![Type::Float]
+            }
+            Inst::ITOF => {
+                expect(0, Type::Int)?;
+                vec![Type::Float]
+            }
+            Inst::FTOI => {
+                expect(0, Type::Float)?;
+                vec![Type::Int]
+            }
+            Inst::JEZ(_) | Inst::JNZ(_) | Inst::TRAP(_) => {
+                expect(0, logic)?;
+                vec![]
+            }
+            Inst::JLT(_)
+            | Inst::JGT(_)
+            | Inst::JLE(_)
+            | Inst::JGE(_)
+            | Inst::TBL(_)
+            | Inst::STORE(_)
+            | Inst::STORER(..) => {
+                expect(0, Type::Int)?;
+                vec![]
+            }
+            Inst::DUP => vec![at(0).unwrap_or(Type::Int)],
+            Inst::OVER => vec![at(1).unwrap_or(Type::Int)],
+            Inst::SWAP => [at(0), at(1)].map(|ty| ty.unwrap_or(Type::Int)).to_vec(),
+            Inst::ROT => [at(1), at(0), at(2)]
+                .map(|ty| ty.unwrap_or(Type::Int))
+                .to_vec(),
+            Inst::LOADL(local_slot) => {
+                vec![local(local_slot).map_or(Type::Int, |pos| types[pos])]
+            }
+            Inst::STOREL(local_slot) => {
+                slot = local(local_slot).zip(at(0));
+                vec![]
+            }
+            Inst::SETP(dst, _) => {
+                slot = path_pos(dst).map(|pos| (pos, Type::Int));
+                vec![]
+            }
+            Inst::CPY(dst, src) => {
+                let ty = path_pos(src).map_or(Type::Int, |pos| types[pos]);
+                match dst {
+                    Path::REG(_) if ty != Type::Int => {
+                        return Err(Fault::TypeMismatch {
+                            expected: Type::Int,
+                            found: ty,
+                        })
+                    }
+                    _ => slot = path_pos(dst).map(|pos| (pos, ty)),
+                }
+                vec![]
+            }
+            Inst::HCALL(idx) => {
+                for n in 0..self.host_arity(idx) {
+                    expect(n, Type::Int)?;
+                }
+                vec![Type::Int]
+            }
+            Inst::POP
+            | Inst::DROP(_)
+            | Inst::OUT
+            | Inst::PRINTS(_)
+            | Inst::YLD
+            | Inst::SND(_)
+            | Inst::CLR
+            | Inst::SET(..)
+            | Inst::INC(_)
+            | Inst::DEC(_)
+            | Inst::JF(..)
+            | Inst::CALL(_)
+            | Inst::RET
+            | Inst::JMP(_)
+            | Inst::LOOP(..)
+            | Inst::SYS(_)
+            | Inst::HLT
+            | Inst::EXIT(_) => vec![],
+        };
+        Ok(TypeEffect { pushes, slot })
+    }
+
+    /// Arguments `HCALL(idx)` pops, zero if it is going to fail
+    fn host_arity(&self, idx: u16) -> usize {
+        if let Some(replay) = &self.replay {
+            return match replay.as_slice().first() {
+                Some(InputEvent::HostCall { arity, .. }) => *arity,
+                _ => 0,
+            };
+        }
+        let name = self.imports.get(idx as usize);
+        let host_fn = name.and_then(|name| self.host_fns.get(name));
+        host_fn.map_or(0, |host_fn| host_fn.arity)
+    }
+
+    fn execute(&mut self, inst: Inst) -> Result<Flow, Fault> {
+        match inst {
+            Inst::PSH(val) => {
+                self.push(val)?;
+                trace!(self, "machine: push {val}");
+            }
+            Inst::PSHC(idx) => {
+                let val = match self.pool.get(idx as usize) {
+                    Some(&val) => val,
+                    None => return Err(Fault::NoConstant(idx)),
+                };
+                self.push(val)?;
+                trace!(self, "machine: pshc {idx}: {val}");
+            }
+            Inst::ADD => {
+                let (arg_1, arg_2) = self.pop_pair()?;
+                self.push(self.arithmetic(inst, arg_1, arg_2)?)?;
+                trace!(self, "machine: add: {arg_1} {arg_2}");
+            }
+            Inst::SUB => {
+                let (arg_1, arg_2) = self.pop_pair()?;
+                self.push(self.arithmetic(inst, arg_1, arg_2)?)?;
+                trace!(self, "machine: sub: {arg_1} {arg_2}");
+            }
+            Inst::CMP => {
+                let arg_2 = self.stack.get_at_idx(0)?;
+                let arg_1 = self.stack.get_at_idx(1)?;
+                let flags = Flags::compare(arg_1, arg_2);
+                if let Some(delta) = &mut self.delta {
+                    delta.flags = Some(FlagsChange {
+                        old: self.flags,
+                        new: flags,
+                    });
+                }
+                self.flags = flags;
+                trace!(self, "machine: cmp: {arg_1} {arg_2}");
+            }
+            Inst::MUL => {
+                let (arg_1, arg_2) = self.pop_pair()?;
+                self.push(self.arithmetic(inst, arg_1, arg_2)?)?;
+                trace!(self, "machine: mul: {arg_1} {arg_2}");
+            }
+            Inst::QMUL => {
+                let (arg_1, arg_2) = self.pop_pair()?;
+                self.push(self.arithmetic(inst, arg_1, arg_2)?)?;
+                trace!(self, "machine: qmul: {arg_1} {arg_2}");
+            }
+            Inst::DIV
+            | Inst::MOD
+            | Inst::DIVF
+            | Inst::MODF
+            | Inst::DIVU
+            | Inst::MODU
+            | Inst::QDIV => {
+                let (arg_1, arg_2) = self.pop_pair()?;
+                if arg_2 == 0 {
+                    let Some(handler) = self.divide_handler else {
+                        return Err(Fault::DivideByZero);
+                    };
+                    if self.calls.len() >= self.call_depth {
+                        return Err(Fault::CallDepth);
+                    }
+                    self.push(arg_1)?;
+                    self.push(arg_2)?;
+                    let frame = Frame {
+                        ret: self.ip,
+                        fp: self.fp,
+                    };
+                    self.jump_to(handler);
+                    self.calls.push(frame);
+                    self.fp = self.stack.len();
+                    trace!(self, "machine: divide by zero: calling {handler}");
+                    return Ok(Flow::Continue);
+                }
+                self.push(divide(inst, arg_1, arg_2))?;
+                trace!(
+                    self,
+                    "machine: {}: {arg_1} {arg_2}",
+                    inst.mnemonic().to_lowercase()
+                );
+            }
+            Inst::AND | Inst::OR | Inst::XOR | Inst::SHL | Inst::SHR => {
+                let (arg_1, arg_2) = self.pop_pair()?;
+                self.push(bitwise(inst, arg_1, arg_2))?;
+                trace!(
+                    self,
+                    "machine: {}: {arg_1} {arg_2}",
+                    inst.mnemonic().to_lowercase()
+                );
+            }
+            Inst::NOT | Inst::NEG | Inst::ABS => {
+                let arg = self.pop()?;
+                self.push(match inst {
+                    Inst::NOT => !arg,
+                    Inst::NEG => arg.wrapping_neg(),
+                    _ => arg.wrapping_abs(),
+                })?;
+                trace!(self, "machine: {}: {arg}", inst.mnemonic().to_lowercase());
+            }
+            Inst::MIN | Inst::MAX => {
+                let (arg_1, arg_2) = self.pop_pair()?;
+                let val = match inst {
+                    Inst::MIN => arg_1.min(arg_2),
+                    _ => arg_1.max(arg_2),
+                };
+                self.push(val)?;
+                trace!(
+                    self,
+                    "machine: {}: {arg_1} {arg_2}",
+                    inst.mnemonic().to_lowercase()
+                );
+            }
+            Inst::FPSH(val) => {
+                self.push(float_word(val))?;
+                trace!(self, "machine: fpsh {val}");
+            }
+            Inst::FADD | Inst::FSUB | Inst::FMUL | Inst::FDIV => {
+                let (arg_1, arg_2) = self.pop_pair()?;
+                self.push(float_arithmetic(inst, arg_1, arg_2))?;
+                trace!(
+                    self,
+                    "machine: {}: {} {}",
+                    inst.mnemonic().to_lowercase(),
+                    float(arg_1),
+                    float(arg_2)
+                );
+            }
+            Inst::ITOF => {
+                let arg = self.pop()?;
+                self.push(float_word(arg as Float))?;
+                trace!(self, "machine: itof: {arg}");
+            }
+            Inst::FTOI => {
+                let arg = float(self.pop()?);
+                self.push(arg as Word)?;
+                trace!(self, "machine: ftoi: {arg}");
+            }
+            Inst::JF(cond, step) => {
+                let taken = self.flags.test(cond);
+                if taken {
+                    self.jump(step)?;
+                }
+                trace!(self, "machine: jf: {cond} {taken}");
+            }
+            Inst::CALL(step) => {
+                if self.calls.len() >= self.call_depth {
+                    return Err(Fault::CallDepth);
+                }
+                let frame = Frame {
+                    ret: self.ip,
+                    fp: self.fp,
+                };
+                self.jump(step)?;
+                self.calls.push(frame);
+                self.fp = self.stack.len();
+                trace!(self, "machine: call: {step} returns to {}", frame.ret);
+            }
+            Inst::RET => {
+                let frame = self.calls.pop().ok_or(Fault::NoCaller)?;
+                self.jump_to(frame.ret);
+                self.fp = frame.fp;
+                trace!(self, "machine: ret: {}", frame.ret);
+            }
+            Inst::LOADL(slot) => {
+                let offset = self.local(slot, 0)?;
+                let val = self.stack.get_at_idx(offset)?;
+                self.push(val)?;
+                trace!(self, "machine: loadl: {slot} {val}");
+            }
+            Inst::STOREL(slot) => {
+                self.local(slot, 1)?;
+                let val = self.pop()?;
+                let offset = self.local(slot, 0)?;
+                self.set_at_path(Path::STK(offset), val)?;
+                trace!(self, "machine: storel: {slot} {val}");
+            }
+            Inst::POP => {
+                let val = self.pop()?;
+                trace!(self, "machine: pop: {val}");
+            }
+            Inst::DUP => {
+                let val = self.stack.get_at_idx(0)?;
+                self.push(val)?;
+                trace!(self, "machine: dup: {val}");
+            }
+            Inst::SWAP => {
+                let (arg_1, arg_2) = self.pop_pair()?;
+                self.push(arg_2)?;
+                self.push(arg_1)?;
+                trace!(self, "machine: swap: {arg_1} {arg_2}");
+            }
+            Inst::OVER => {
+                let val = self.stack.get_at_idx(1)?;
+                self.push(val)?;
+                trace!(self, "machine: over: {val}");
+            }
+            Inst::ROT => {
+                let (arg_2, arg_3) = self.pop_pair()?;
+                let arg_1 = self.pop()?;
+                self.push(arg_2)?;
+                self.push(arg_3)?;
+                self.push(arg_1)?;
+                trace!(self, "machine: rot: {arg_1} {arg_2} {arg_3}");
+            }
+            Inst::DROP(count) => {
+                if self.stack.len() < count {
+                    return Err(StackError::PopErr.into());
+                }
+                for _ in 0..count {
+                    self.pop()?;
+                }
+                trace!(self, "machine: drop: {count}");
+            }
+            Inst::IN => {
+                // an input value read must not be lost to a full stack
+                if self.stack.is_full() {
+                    return Err(StackError::PushErr.into());
+                }
+                let val = self.read_input()?;
+                self.push(val)?;
+                trace!(self, "machine: in: {val}");
+            }
+            Inst::OUT => {
+                let val = self.pop()?;
+                self.write_output(&format!("{val}\n"))
+                    .map_err(Fault::Output)?;
+                trace!(self, "machine: out: {val}");
+            }
+            Inst::PRINTS(id) => {
+                let Some(text) = self.string(Word::from(id)) else {
+                    return Err(Fault::NoString(Word::from(id)));
+                };
+                let line = format!("{text}\n");
+                self.write_output(&line).map_err(Fault::Output)?;
+                trace!(self, "machine: prints {id}");
+            }
+            Inst::YLD => {
+                let val = self.pop()?;
+                trace!(self, "machine: yield: {val}");
+                return Ok(Flow::Yield(val));
+            }
+            #[cfg(not(feature = "std"))]
+            Inst::SND(port) | Inst::RCV(port) => return Err(Fault::NoPort(port)),
+            #[cfg(feature = "std")]
+            Inst::SND(port) => {
+                if !self.ports.contains_key(&port) {
+                    return Err(Fault::NoPort(port));
+                }
+                let val = self.pop()?;
+                match self.ports.get(&port) {
+                    Some(port_ref) if port_ref.tx.send(val).is_ok() => (),
+                    _ => return Err(Fault::PortClosed(port)),
+                }
+                trace!(self, "machine: snd {port}: {val}");
+            }
+            #[cfg(feature = "std")]
+            Inst::RCV(port) => {
+                let port_ref = self.ports.get(&port).ok_or(Fault::NoPort(port))?;
+                // a value taken off the port must not be lost to a full stack
+                if self.stack.is_full() {
+                    return Err(StackError::PushErr.into());
+                }
+                let val = match port_ref.rx.try_recv() {
+                    Ok(val) => val,
+                    Err(TryRecvError::Empty) => return Ok(Flow::Wait(port)),
+                    Err(TryRecvError::Disconnected) => return Err(Fault::PortClosed(port)),
+                };
+                self.push(val)?;
+                trace!(self, "machine: rcv {port}: {val}");
+            }
+            Inst::CLR => {
+                self.clear();
+                trace!(self, "machine: clr");
+            }
+            Inst::SET(reg, val) => {
+                self.set_reg_value(reg, val)?;
+                trace!(self, "machine: set: {reg:?} {val}");
+            }
+            Inst::INC(reg) | Inst::DEC(reg) => {
+                let val = self.get_reg_value(&reg)?;
+                let val = match inst {
+                    Inst::INC(_) => val.wrapping_add(1),
+                    _ => val.wrapping_sub(1),
+                };
+                self.set_reg_value(reg, val)?;
+                trace!(
+                    self,
+                    "machine: {}: {reg:?} {val}",
+                    inst.mnemonic().to_lowercase()
+                );
+            }
+            Inst::SETP(dst, val) => {
+                self.set_at_path(dst, val)?;
+                trace!(self, "machine: setp: {dst:?} {val}");
+            }
+            Inst::CPY(dst, src) => {
+                let val = self.get_from_path(src)?;
+                self.set_at_path(dst, val)?;
+                trace!(self, "machine: cpy {dst:?} {src:?}");
+            }
+            Inst::LOAD(addr) => {
+                // nor a value read from a device
+                if self.stack.is_full() {
+                    return Err(StackError::PushErr.into());
+                }
+                let val = self.memory.load(addr)?;
+                self.push(val)?;
+                trace!(self, "machine: load: {addr} {val}");
+            }
+            Inst::STORE(addr) => {
+                let val = self.pop()?;
+                self.store(addr, val)?;
+                trace!(self, "machine: store: {addr} {val}");
+            }
+            Inst::LOADR(reg, offset) => {
+                let addr = self.indirect(reg, offset)?;
+                if self.stack.is_full() {
+                    return Err(StackError::PushErr.into());
+                }
+                let val = self.memory.load(addr)?;
+                self.push(val)?;
+                trace!(self, "machine: loadr: {addr} {val}");
+            }
+            Inst::STORER(reg, offset) => {
+                let addr = self.indirect(reg, offset)?;
+                let val = self.pop()?;
+                self.store(addr, val)?;
+                trace!(self, "machine: storer: {addr} {val}");
+            }
+            Inst::ALLOC(len) => {
+                // nor the handle of an allocated block
+                if self.stack.is_full() {
+                    return Err(StackError::PushErr.into());
+                }
+                if !self.heap.fits(len) {
+                    let freed = self.collect_garbage();
+                    trace!(self, "machine: collected {freed} words");
+                }
+                let handle = self.heap.alloc(len)?;
+                self.push(handle)?;
+                trace!(self, "machine: alloc: {len} {handle}");
+            }
+            Inst::AIDXLOAD => {
+                let (handle, index) = self.pop_pair()?;
+                let val = self.heap.load(handle, index)?;
+                self.push(val)?;
+                trace!(self, "machine: aidxload: {handle} {index} {val}");
+            }
+            Inst::AIDXSTORE => {
+                let (index, val) = self.pop_pair()?;
+                let handle = self.pop()?;
+                if let Some(delta) = &mut self.delta {
+                    let old = self.heap.load(handle, index)?;
+                    delta.heap_write = Some(HeapChange {
+                        handle,
+                        index,
+                        old,
+                        new: val,
+                    });
+                }
+                self.heap.store(handle, index, val)?;
+                trace!(self, "machine: aidxstore: {handle} {index} {val}");
+            }
+            Inst::JMP(step) => {
+                self.jump(step)?;
+            }
+            Inst::LOOP(reg, step) => {
+                let count = self.get_reg_value(&reg)?.wrapping_sub(1);
+                self.set_reg_value(reg, count)?;
+                if count != 0 {
+                    self.jump(step)?;
+                }
+                trace!(self, "machine: loop: {reg:?} {count}");
+            }
+            Inst::JEZ(step)
+            | Inst::JNZ(step)
+            | Inst::JLT(step)
+            | Inst::JGT(step)
+            | Inst::JLE(step)
+            | Inst::JGE(step) => {
+                let val = self.pop()?;
+                let taken = branch_taken(inst, val);
+                if taken {
+                    self.jump(step)?;
+                }
+                trace!(
+                    self,
+                    "machine: {}: {val} {taken}",
+                    inst.mnemonic().to_lowercase()
+                );
+            }
+            Inst::TBL(id) => {
+                let table = match self.tables.get(id) {
+                    Some(table) => table,
+                    None => return Err(Fault::NoSuchTable(id)),
+                };
+                let idx = self.stack.pop()?;
+                self.undo.push(Undo::Pop(idx));
+                let target = table.lookup(idx);
+                if let Some(delta) = &mut self.delta {
+                    delta.popped.push(idx);
+                }
+                if let Some(observer) = &mut self.observer {
+                    observer.on_stack_pop(idx);
+                }
+                match target {
+                    Some(target) => self.jump_to(target),
+                    None => {
+                        return Err(Fault::TableIndex {
+                            table: id,
+                            index: idx,
+                        })
+                    }
+                }
+                trace!(self, "machine: tbl {id} {idx}");
+            }
+            Inst::TRAP(code) => {
+                let val = self.pop()?;
+                trace!(self, "machine: trap {code}: {val}");
+                if val == 0 {
+                    return Err(Fault::Trap(code));
+                }
+            }
+            Inst::SYS(number) => {
+                if let Some(replay) = &mut self.replay {
+                    let effects = match replay.next() {
+                        Some(InputEvent::Sys {
+                            number: recorded,
+                            effects,
+                        }) if recorded == number => effects,
+                        other => return Err(Fault::ReplayDivergence(other)),
+                    };
+                    for effect in effects {
+                        match effect {
+                            SysEffect::Pop => {
+                                self.pop()?;
+                            }
+                            SysEffect::Push(val) => self.push(val)?,
+                            SysEffect::SetReg(reg, val) => self.set_reg_value(reg, val)?,
+                        }
+                    }
+                    trace!(self, "machine: sys: {number} (replayed)");
+                    return Ok(Flow::Continue);
+                }
+                let Some(mut handler) = self.syscalls.take() else {
+                    return Err(Fault::NoSyscall(number));
+                };
+                let ip = self.ip.saturating_sub(1);
+                let recording = self.recording.is_some();
+                let mut ctx = SysCtx {
+                    machine: self,
+                    ip,
+                    number,
+                    effects: recording.then(Vec::new),
+                };
+                let result = handler.syscall(number, &mut ctx);
+                let effects = ctx.effects.take();
+                self.syscalls = Some(handler);
+                result.map_err(|e| Fault::Host(Box::new(e)))?;
+                if let (Some(recording), Some(effects)) = (&mut self.recording, effects) {
+                    recording.events.push(InputEvent::Sys { number, effects });
+                }
+                trace!(self, "machine: sys: {number}");
+            }
+            Inst::HCALL(idx) => {
+                let Some(name) = self.imports.get(idx as usize) else {
+                    return Err(Fault::NoImport(idx));
+                };
+                if let Some(replay) = &mut self.replay {
+                    // take the call off the recording only once it can finish
+                    if let Some(&InputEvent::HostCall { arity, .. }) = replay.as_slice().first() {
+                        if self.stack.len() < arity {
+                            return Err(StackError::PopErr.into());
+                        }
+                        if arity == 0 && self.stack.is_full() {
+                            return Err(StackError::PushErr.into());
+                        }
+                    }
+                    let (arity, val) = match replay.next() {
+                        Some(InputEvent::HostCall { arity, result }) => (arity, result),
+                        other => return Err(Fault::ReplayDivergence(other)),
+                    };
+                    for _ in 0..arity {
+                        self.pop()?;
+                    }
+                    self.push(val)?;
+                    trace!(self, "machine: hcall {idx}: {val} (replayed)");
+                    return Ok(Flow::Continue);
+                }
+                let Some(host_fn) = self.host_fns.get_mut(name) else {
+                    return Err(Fault::NoHostFn(name.clone()));
+                };
+                let len = self.stack.len();
+                if len < host_fn.arity {
+                    return Err(StackError::PopErr.into());
+                }
+                // the host function is not called again when the instruction is resumed
+                if host_fn.arity == 0 && self.stack.is_full() {
+                    return Err(StackError::PushErr.into());
+                }
+                let args = self.stack.values()[len - host_fn.arity..].to_vec();
+                let val = (host_fn.f)(&args);
+                for _ in 0..args.len() {
+                    self.pop()?;
+                }
+                self.push(val)?;
+                if let Some(recording) = &mut self.recording {
+                    let arity = args.len();
+                    let event = InputEvent::HostCall { arity, result: val };
+                    recording.events.push(event);
+                }
+                trace!(self, "machine: hcall {idx}: {args:?} {val}");
+            }
+            Inst::HLT => {
+                trace!(self, "machine: halting...");
+                return Ok(Flow::Halt(0));
+            }
+            Inst::EXIT(code) => {
+                trace!(self, "machine: exit: {code}");
+                return Ok(Flow::Halt(code));
+            }
+        }
+        Ok(Flow::Continue)
+    }
+
+    /// Next input value, from the replayed recording if there is one
+    fn read_input(&mut self) -> Result<Word, Fault> {
+        let val = match &mut self.replay {
+            Some(replay) => match replay.next() {
+                Some(InputEvent::In(val)) => val,
+                other => return Err(Fault::ReplayDivergence(other)),
+            },
+            None => match self.input.as_mut().and_then(|input| input.next_input()) {
+                Some(val) => val,
+                None => return Err(Fault::NoInput),
+            },
+        };
+        if let Some(recording) = &mut self.recording {
+            recording.events.push(InputEvent::In(val));
+        }
+        Ok(val)
+    }
+
+    /// Undo the stack and register changes of the instruction at `ip`, which failed, and go back
+    /// to it
+    fn rewind(&mut self, ip: usize) {
+        if let Some(delta) = &mut self.delta {
+            *delta = StateDelta::default();
+        }
+        for change in self.undo.drain(..).rev() {
+            match change {
+                Undo::Push(_) => {
+                    let val = self.stack.pop();
+                    if let (Some(observer), Ok(val)) = (&mut self.observer, val) {
+                        observer.on_stack_pop(val);
+                    }
+                }
+                Undo::Pop(val) => {
+                    // the value was on the stack a moment ago, so there is room for it
+                    let _ = self.stack.push(val);
+                    if let Some(observer) = &mut self.observer {
+                        observer.on_stack_push(val);
+                    }
+                }
+                Undo::Reg(reg, old) => {
+                    let Some(slot) = self.registers.get_mut(&reg) else {
+                        continue;
+                    };
+                    if let Some(observer) = &mut self.observer {
+                        observer.on_register_write(reg, *slot, old);
+                    }
+                    *slot = old;
+                }
+            }
+        }
+        self.ip = ip;
+    }
+
+    pub(crate) fn push(&mut self, val: Word) -> Result<(), StackError> {
+        self.push_typed(val, Type::Int)
+    }
+
+    /// Push `val`, a value of type `ty` for a typed machine
+    pub(crate) fn push_typed(&mut self, val: Word, ty: Type) -> Result<(), StackError> {
+        self.stack.push(val)?;
+        self.undo.push(Undo::Push(ty));
+        if let Some(delta) = &mut self.delta {
+            delta.pushed.push(val);
+        }
+        if let Some(observer) = &mut self.observer {
+            observer.on_stack_push(val);
+        }
+        Ok(())
+    }
+
+    pub(crate) fn pop(&mut self) -> Result<Word, StackError> {
+        let val = self.stack.pop()?;
+        self.undo.push(Undo::Pop(val));
+        if let Some(delta) = &mut self.delta {
+            delta.popped.push(val);
+        }
+        if let Some(observer) = &mut self.observer {
+            observer.on_stack_pop(val);
+        }
+        Ok(val)
+    }
+
+    fn clear(&mut self) {
+        let popped = self.stack.values().iter().rev();
+        self.undo.extend(popped.map(|&val| Undo::Pop(val)));
+        if let Some(delta) = &mut self.delta {
+            delta.popped.extend(self.stack.values().iter().rev());
+        }
+        if let Some(observer) = &mut self.observer {
+            observer.on_stack_clear(self.stack.len());
+        }
+        self.stack.clear();
+    }
+
+    fn jump_to(&mut self, target: usize) {
+        self.ip = target;
+        if let Some(delta) = &mut self.delta {
+            delta.jump = Some(target);
+        }
+    }
+
+    /// `ADD`, `SUB` or `MUL` of the two operands, overflowing the way the machine was
+    /// configured to
+    fn arithmetic(&self, inst: Inst, arg_1: Word, arg_2: Word) -> Result<Word, Fault> {
+        let (wrapped, overflowed) = match inst {
+            Inst::ADD => arg_1.overflowing_add(arg_2),
+            Inst::SUB => arg_1.overflowing_sub(arg_2),
+            Inst::MUL => arg_1.overflowing_mul(arg_2),
+            _ => {
+                let product = (i128::from(arg_1) * i128::from(arg_2)) >> FIXED_POINT_BITS;
+                (product as Word, Word::try_from(product).is_err())
+            }
+        };
+        match self.overflow {
+            _ if !overflowed => Ok(wrapped),
+            Overflow::Wrap => Ok(wrapped),
+            Overflow::Trap => Err(Fault::Overflow),
+            Overflow::Saturate => Ok(match inst {
+                Inst::ADD => arg_1.saturating_add(arg_2),
+                Inst::SUB => arg_1.saturating_sub(arg_2),
+                Inst::MUL => arg_1.saturating_mul(arg_2),
+                _ if (arg_1 < 0) != (arg_2 < 0) => Word::MIN,
+                _ => Word::MAX,
+            }),
+        }
+    }
+
+    /// Pop the two arguments of a binary operation, the first argument is the one pushed first
+    fn pop_pair(&mut self) -> Result<(Word, Word), Fault> {
+        let arg_2 = self.pop()?;
+        let arg_1 = self.pop()?;
+        Ok((arg_1, arg_2))
+    }
+
+    pub(crate) fn get_from_path(&self, path: Path) -> Result<Word, PathError> {
+        match path {
+            Path::REG(reg) => self.get_reg_value(&reg),
+            Path::STK(rel_idx) => self.stack.get_at_idx(rel_idx),
+            Path::STKR(reg, rel_idx) => {
+                let rel_idx = self.stack_offset(reg, rel_idx)?;
+                self.stack.get_at_idx(rel_idx)
+            }
+        }
+    }
+
+    fn set_at_path(&mut self, path: Path, val: Word) -> Result<(), PathError> {
+        let rel_idx = match path {
+            Path::REG(reg) => return self.set_reg_value(reg, val),
+            Path::STK(rel_idx) => rel_idx,
+            Path::STKR(reg, rel_idx) => self.stack_offset(reg, rel_idx)?,
+        };
+        let old = self.stack.get_at_idx(rel_idx)?;
+        self.stack.set_at_idx(rel_idx, val)?;
+        if let Some(delta) = &mut self.delta {
+            delta.stack_write = Some(SlotChange {
+                offset: rel_idx,
+                old,
+                new: val,
+            });
+        }
+        Ok(())
+    }
+
+    /// Store `val` at data memory address `addr`, keeping the word it replaced in the delta
+    fn store(&mut self, addr: usize, val: Word) -> Result<(), Fault> {
+        let old = match self.delta {
+            Some(_) if !self.memory.is_mapped(addr) => self.memory.words.get(addr).copied(),
+            _ => None,
+        };
+        self.memory.store(addr, val)?;
+        if let (Some(delta), Some(old)) = (&mut self.delta, old) {
+            delta.memory_write = Some(MemoryChange {
+                addr,
+                old,
+                new: val,
+            });
+        }
+        Ok(())
+    }
+
+    /// Data memory address of `LOADR` and `STORER`
+    fn indirect(&self, reg: Reg, offset: usize) -> Result<usize, Fault> {
+        let base = self.get_reg_value(&reg)? as UWord as usize;
+        Ok(base.saturating_add(offset))
+    }
+
+    /// Offset from the head of the stack of local `slot`, once `pop` more values are popped
+    fn local(&self, slot: isize, pop: usize) -> Result<isize, Fault> {
+        let len = self.stack.len().saturating_sub(pop) as isize;
+        match (self.fp as isize).checked_add(slot) {
+            Some(pos) if pos >= 0 && pos < len => Ok(len - 1 - pos),
+            _ => Err(Fault::BadLocal(slot)),
+        }
+    }
+
+    /// Effective offset of a `Path::STKR`
+    fn stack_offset(&self, reg: Reg, rel_idx: isize) -> Result<isize, PathError> {
+        Ok(rel_idx.saturating_add(self.get_reg_value(&reg)? as isize))
+    }
+
+    fn dump(&mut self) {
+        use core::fmt::Write;
+
+        let mut dump = String::from("\n\nmachine dump:\n");
+        let _ = writeln!(dump, "\tprogram: {:?}", self.code());
+        let _ = writeln!(dump, "\tentry: {}", self.entry);
+        let _ = writeln!(dump, "\tip: {}", self.ip);
+        let _ = writeln!(dump, "\tstack: {:?}", self.stack);
+        let _ = writeln!(dump, "\tregisters: {:?}", self.registers);
+        let _ = writeln!(dump, "\tflags: {:?}", self.flags);
+        if !self.calls.is_empty() {
+            let _ = writeln!(dump, "\tcalls: {:?}", self.calls);
+            let _ = writeln!(dump, "\tframe pointer: {}", self.fp);
+        }
+        if !self.tables.is_empty() {
+            let _ = writeln!(dump, "\ttables: {:?}", self.tables);
+        }
+        let _ = self.write_output(&dump);
+    }
+
+    /// Write `text` to the machine's output
+    #[cfg(feature = "std")]
+    pub(crate) fn write_output(&mut self, text: &str) -> Result<(), OutputError> {
+        use std::io::Write;
+
+        let written = match &mut self.output {
+            Some(sink) => sink.write_all(text.as_bytes()),
+            None => std::io::stdout().write_all(text.as_bytes()),
+        };
+        written.map_err(|e| e.kind())
+    }
+
+    /// Write `text` to the machine's output
+    #[cfg(not(feature = "std"))]
+    pub(crate) fn write_output(&mut self, text: &str) -> Result<(), OutputError> {
+        match &mut self.output {
+            Some(sink) => sink.write_str(text),
+            None => Ok(()),
+        }
+    }
+
+    /// Write a trace line, ignoring write errors since tracing must not change how a run goes
+    fn write_line(&mut self, line: &str) {
+        let _ = self.write_output(&format!("{line}\n"));
+    }
+
+    /// Move `ip` by `step` relative to the instruction that was just executed. A zero step
+    /// continues with the next instruction.
+    fn jump(&mut self, step: isize) -> Result<(), Fault> {
+        let current = self.ip.saturating_sub(1);
+        if step != 0 {
+            match (current as isize).checked_add(step) {
+                Some(target) if target >= 0 && (target as usize) < self.program_len() => {
+                    self.jump_to(target as usize);
+                }
+                _ => return Err(Fault::BadJump { step }),
+            }
+        }
+        Ok(())
+    }
+
+    /// get next instruction and update the `ip`
+    fn get_next_inst(&mut self) -> Option<Inst> {
+        let inst = self.fetch(self.ip)?;
+        self.ip += 1;
+        Some(inst)
+    }
+
+    pub(crate) fn get_reg_value(&self, reg: &Reg) -> Result<Word, PathError> {
+        match self.registers.get(reg) {
+            Some(val) => Ok(*val),
+            None => Err(PathError::RegErr { reg: *reg }),
+        }
+    }
+
+    pub(crate) fn set_reg_value(&mut self, reg: Reg, value: Word) -> Result<(), PathError> {
+        match self.registers.get_mut(&reg) {
+            Some(slot) => {
+                if let Some(delta) = &mut self.delta {
+                    delta.register = Some(RegisterChange {
+                        reg,
+                        old: *slot,
+                        new: value,
+                    });
+                }
+                self.undo.push(Undo::Reg(reg, *slot));
+                if let Some(observer) = &mut self.observer {
+                    observer.on_register_write(reg, *slot, value);
+                }
+                *slot = value;
+                Ok(())
+            }
+            None => Err(PathError::RegErr { reg }),
+        }
+    }
+}
+
+/// Result of the division instruction `inst` on a non-zero divisor. `Word::MIN / -1` wraps
+/// around to `Word::MIN`, with a remainder of zero, and a `QDIV` quotient that does not fit
+/// wraps around too.
+fn divide(inst: Inst, dividend: Word, divisor: Word) -> Word {
+    let (a, b) = (dividend, divisor);
+    let (q, r) = (a.wrapping_div(b), a.wrapping_rem(b));
+    // truncation rounded up when the operands have different signs and it was inexact
+    let floor_adjust = r != 0 && (r < 0) != (b < 0);
+    match inst {
+        Inst::DIVF if floor_adjust => q.wrapping_sub(1),
+        Inst::MODF if floor_adjust => r.wrapping_add(b),
+        Inst::MOD | Inst::MODF => r,
+        Inst::DIVU => (a as UWord / b as UWord) as Word,
+        Inst::MODU => (a as UWord % b as UWord) as Word,
+        Inst::QDIV => ((i128::from(a) << FIXED_POINT_BITS) / i128::from(b)) as Word,
+        _ => q,
+    }
+}
+
+/// Result of the two operand bitwise instruction `inst`. Shift amounts are taken modulo the bits
+/// of a word.
+fn bitwise(inst: Inst, a: Word, b: Word) -> Word {
+    match inst {
+        Inst::AND => a & b,
+        Inst::OR => a | b,
+        Inst::XOR => a ^ b,
+        Inst::SHL => a.wrapping_shl(b as u32),
+        _ => (a as UWord).wrapping_shr(b as u32) as Word,
+    }
+}
+
+/// Result of the float arithmetic instruction `inst` on the words holding `a` and `b`
+fn float_arithmetic(inst: Inst, a: Word, b: Word) -> Word {
+    let (a, b) = (float(a), float(b));
+    float_word(match inst {
+        Inst::FADD => a + b,
+        Inst::FSUB => a - b,
+        Inst::FMUL => a * b,
+        _ => a / b,
+    })
+}
+
+/// Whether the conditional jump `inst` is taken for the popped value `val`
+fn branch_taken(inst: Inst, val: Word) -> bool {
+    match inst {
+        Inst::JEZ(_) => val == 0,
+        Inst::JNZ(_) => val != 0,
+        Inst::JLT(_) => val < 0,
+        Inst::JGT(_) => val > 0,
+        Inst::JLE(_) => val <= 0,
+        Inst::JGE(_) => val >= 0,
+        _ => false,
+    }
+}
+
+#[cfg(all(test, feature = "std"))]
+mod tests {
+    use super::*;
+    use crate::{
+        testing, Cond, FixedStack, HaltCause, ReadInput, StringSyscalls, SysCtx, SyscallHandler,
+    };
+
+    #[test]
+    fn it_works() {
+        let program = vec![Inst::PSH(5), Inst::PSH(6), Inst::ADD, Inst::POP, Inst::HLT];
+        let mut machine = Machine::new(program);
+        assert_eq!(machine.run().unwrap(), RunOutcome::Halted);
+        testing::assert_stack_eq(&machine, &[]);
+    }
+
+    #[test]
+    fn results_stay_on_the_stack() {
+        let program = vec![
+            Inst::PSH(5),
+            Inst::PSH(6),
+            Inst::ADD,
+            Inst::PSH(2),
+            Inst::HLT,
+        ];
+        let mut machine = Machine::new(program);
+        assert_eq!(machine.run().unwrap(), RunOutcome::Halted);
+        testing::assert_stack_eq(&machine, &[11, 2]);
+        testing::assert_reg(&machine, Reg::A, 0);
+    }
+
+    #[test]
+    fn loop_runs_body_exactly_n_times() {
+        // the body prints the count, one line each time it runs
+        let program = vec![
+            Inst::SET(Reg::C, 7),
+            Inst::PSH(0),
+            Inst::CPY(Path::STK(0), Path::REG(Reg::C)),
+            Inst::OUT,
+            Inst::LOOP(Reg::C, -3),
+            Inst::HLT,
+        ];
+        let out = SharedOutput::default();
+        let mut machine = Machine::with_output(program, out.clone());
+        machine.run().unwrap();
+        assert_eq!(out.text(), "7\n6\n5\n4\n3\n2\n1\n");
+        assert_eq!(machine.registers[&Reg::C], 0);
+
+        // the decrement wraps, Word::MIN going on at Word::MAX
+        let program = vec![
+            Inst::SET(Reg::C, Word::MIN),
+            Inst::INC(Reg::A),
+            Inst::LOOP(Reg::C, -1),
+        ];
+        let mut machine = Machine::new(program);
+        assert_eq!(
+            machine.run_bounded(5),
+            Err(VmError::InstructionLimit { limit: 5, ip: 1 })
+        );
+        assert_eq!(machine.registers[&Reg::A], 2);
+        assert_eq!(machine.registers[&Reg::C], Word::MAX - 1);
+    }
+
+    #[test]
+    fn loop_target_is_bounds_checked() {
+        let program = vec![Inst::SET(Reg::C, 2), Inst::LOOP(Reg::C, -5), Inst::HLT];
+        let mut machine = Machine::new(program);
+        let err = machine.run().unwrap_err();
+        assert!(err.to_string().contains("leaves the program"));
+    }
+
+    #[test]
+    fn clear_empties_the_stack() {
+        let program = vec![
+            Inst::CLR,
+            Inst::PSH(1),
+            Inst::PSH(2),
+            Inst::PSH(3),
+            Inst::CLR,
+            Inst::PSH(4),
+            Inst::CPY(Path::REG(Reg::A), Path::STK(0)),
+            Inst::HLT,
+        ];
+        let mut machine = Machine::new(program);
+        machine.run().unwrap();
+        assert_eq!(machine.stack.memory, vec![4]);
+        assert_eq!(machine.stack.sp, 0);
+        assert_eq!(machine.registers[&Reg::A], 4);
+
+        machine.clear_stack();
+        assert_eq!(machine.stack_top(), None);
+        assert_eq!(machine.stack.sp, -1);
+    }
+
+    #[test]
+    fn jump_table_dispatch() {
+        let program = vec![
+            Inst::PSH(1),
+            Inst::TBL(0),
+            Inst::SET(Reg::A, 10),
+            Inst::HLT,
+            Inst::SET(Reg::A, 20),
+            Inst::HLT,
+            Inst::SET(Reg::A, 30),
+            Inst::HLT,
+        ];
+        let table = JumpTable::new(vec![2, 4]).with_default(6);
+
+        let mut machine = Machine::with_tables(program.clone(), vec![table.clone()]).unwrap();
+        machine.run().unwrap();
+        assert_eq!(machine.registers[&Reg::A], 20);
+
+        let mut program = program;
+        program[0] = Inst::PSH(7);
+        let mut machine = Machine::with_tables(program, vec![table]).unwrap();
+        machine.run().unwrap();
+        assert_eq!(machine.registers[&Reg::A], 30);
+    }
+
+    #[test]
+    fn jump_table_index_out_of_range() {
+        let program = vec![Inst::PSH(-1), Inst::TBL(0), Inst::HLT];
+        let mut machine = Machine::with_tables(program, vec![vec![2]]).unwrap();
+        let err = machine.run().unwrap_err();
+        assert!(err.to_string().contains("out of range for jump table 0"));
+    }
+
+    #[test]
+    fn report_after_halt() {
+        let program = vec![
+            Inst::SET(Reg::B, 3),
+            Inst::PSH(4),
+            Inst::PSH(5),
+            Inst::ADD,
+            Inst::HLT,
+        ];
+        let mut machine = Machine::new(program);
+        let report = machine.run_report();
+
+        assert!(report.halted());
+        assert_eq!(report.instructions, 5);
+        assert_eq!(report.stack_top, Some(9));
+        assert_eq!(report.registers[&Reg::B], 3);
+    }
+
+    #[test]
+    fn report_after_fault() {
+        let program = vec![Inst::PSH(4), Inst::PSH(0), Inst::DIV, Inst::HLT];
+        let mut machine = Machine::new(program);
+        let report = machine.run_report();
+
+        assert!(matches!(
+            report.halt,
+            HaltCause::Fault(VmError::Exec {
+                ip: 2,
+                inst: Inst::DIV,
+                fault: Fault::DivideByZero,
+                ..
+            })
+        ));
+        assert_eq!(report.instructions, 2);
+        // the division is undone, so that it can be retried
+        assert_eq!(machine.ip, 2);
+        testing::assert_stack_eq(&machine, &[4, 0]);
+    }
+
+    #[test]
+    fn debugger_changes_state() {
+        let program = vec![Inst::PSH(1), Inst::PSH(2), Inst::ADD, Inst::HLT];
+        let mut machine = Machine::new(program);
+        machine.step().unwrap();
+        machine.step().unwrap();
+        machine.stack_mut()[0] = 40;
+        machine.registers_mut().insert(Reg::A, 7);
+        machine.run().unwrap();
+        testing::assert_stack_eq(&machine, &[42]);
+        assert_eq!(machine.registers()[&Reg::A], 7);
+
+        let program = vec![Inst::PSH(1), Inst::PSH(2), Inst::HLT];
+        let mut machine = Machine::new(program);
+        machine.set_ip(1);
+        machine.run().unwrap();
+        testing::assert_stack_eq(&machine, &[2]);
+        assert_eq!(machine.ip(), 3);
+    }
+
+    #[test]
+    fn reset_and_reload() {
+        let program = vec![
+            Inst::PSH(3),
+            Inst::STORE(0),
+            Inst::SET(Reg::B, 2),
+            Inst::PSH(1),
+            Inst::PSH(2),
+            Inst::CMP,
+            Inst::EXIT(4),
+        ];
+        let mut machine = Machine::with_entry(program, 2).unwrap();
+        machine.run().unwrap();
+        machine.reset();
+        assert_eq!((machine.ip(), machine.instructions()), (2, 0));
+        assert_eq!(machine.exit_code(), None);
+        testing::assert_stack_eq(&machine, &[]);
+        testing::assert_reg(&machine, Reg::B, 0);
+        assert_eq!(machine.flags(), Flags::default());
+        assert_eq!(machine.run(), Ok(RunOutcome::Halted));
+        assert_eq!(machine.exit_code(), Some(4));
+
+        machine.load_program(vec![Inst::PSH(9), Inst::STORE(1), Inst::HLT]);
+        machine.run().unwrap();
+        assert_eq!(machine.entry(), 0);
+        assert_eq!(&machine.memory()[..2], &[0, 9]);
+        assert_eq!(machine.exit_code(), Some(0));
+        assert_eq!(machine.registers().len(), 6 + GP_REGISTERS);
+    }
+
+    #[test]
+    fn reset_restores_initial_data() {
+        let source = ".data d: 42 7\nload d\npsh 1\nadd\nstore d\nload d\nhlt\n";
+        let mut machine = crate::assemble_program(source).unwrap().machine().unwrap();
+        for _ in 0..2 {
+            machine.run().unwrap();
+            testing::assert_stack_eq(&machine, &[43]);
+            assert_eq!(&machine.memory()[..3], &[43, 7, 0]);
+            machine.reset();
+            assert_eq!(&machine.memory()[..3], &[42, 7, 0]);
+        }
+
+        // another program does not get the data of the last one
+        machine.load_program(vec![Inst::LOAD(0), Inst::HLT]);
+        machine.run().unwrap();
+        testing::assert_stack_eq(&machine, &[0]);
+    }
+
+    #[test]
+    fn snapshot_and_restore() {
+        let program = vec![
+            Inst::PSH(2),
+            Inst::CALL(2),
+            Inst::HLT,
+            Inst::SET(Reg::A, 5),
+            Inst::STORE(3),
+            Inst::RET,
+        ];
+        let mut machine = Machine::new(program);
+        machine.step().unwrap();
+        machine.step().unwrap();
+        let snapshot = machine.snapshot();
+        assert_eq!(snapshot.calls.len(), 1);
+        machine.run().unwrap();
+        assert_eq!(machine.memory()[3], 2);
+
+        machine.restore(&snapshot);
+        assert_eq!(machine.snapshot(), snapshot);
+        assert_eq!((machine.ip(), machine.exit_code()), (3, None));
+        testing::assert_stack_eq(&machine, &[2]);
+        testing::assert_reg(&machine, Reg::A, 0);
+        assert_eq!(machine.memory()[3], 0);
+
+        // the rewound run ends the same way
+        machine.run().unwrap();
+        assert_eq!(machine.instructions(), 6);
+        testing::assert_reg(&machine, Reg::A, 5);
+    }
+
+    #[test]
+    fn numbered_registers() {
+        let program = vec![
+            Inst::SET(Reg::R(3), 8),
+            Inst::CPY(Path::REG(Reg::A), Path::REG(Reg::R(3))),
+            Inst::PSH(0),
+            Inst::CPY(Path::STK(0), Path::REG(Reg::A)),
+            Inst::CPY(Path::REG(Reg::R(0)), Path::STK(0)),
+            Inst::HLT,
+        ];
+        let mut machine = Machine::with_registers(program, 4).unwrap();
+        machine.run().unwrap();
+        assert_eq!(machine.registers[&Reg::A], 8);
+        assert_eq!(machine.registers[&Reg::R(0)], 8);
+
+        let program = vec![Inst::SET(Reg::R(4), 1), Inst::HLT];
+        let mut machine = Machine::with_registers(program, 4).unwrap();
+        assert!(matches!(
+            machine.run_report().halt,
+            HaltCause::Fault(VmError::Exec {
+                ip: 0,
+                inst: Inst::SET(Reg::R(4), 1),
+                fault: Fault::Path(PathError::RegErr { reg: Reg::R(4) }),
+                ..
+            })
+        ));
+
+        assert_eq!(
+            Machine::with_registers(vec![Inst::HLT], 257).err(),
+            Some(ValidationError::RegisterCount(257))
+        );
+    }
+
+    #[test]
+    fn overflow_modes() {
+        let program = vec![Inst::PSH(Word::MAX), Inst::PSH(2), Inst::MUL, Inst::HLT];
+        let run = |overflow| {
+            let config = MachineConfig {
+                overflow,
+                ..MachineConfig::default()
+            };
+            let mut machine = Machine::new_with_config(program.clone(), config).unwrap();
+            machine.run().map(|_| machine.stack().to_vec())
+        };
+        assert_eq!(run(Overflow::Wrap), Ok(vec![-2]));
+        assert_eq!(run(Overflow::Saturate), Ok(vec![Word::MAX]));
+        let err = run(Overflow::Trap).unwrap_err();
+        assert_eq!(err.fault(), Some(&Fault::Overflow));
+        assert!(err.is_recoverable());
+
+        let config = MachineConfig {
+            overflow: Overflow::Saturate,
+            ..MachineConfig::default()
+        };
+        let program = vec![Inst::PSH(Word::MIN), Inst::PSH(1), Inst::SUB, Inst::HLT];
+        let mut machine = Machine::new_with_config(program, config).unwrap();
+        machine.run().unwrap();
+        testing::assert_stack_eq(&machine, &[Word::MIN]);
+    }
+
+    #[test]
+    fn configured_sizes() {
+        let config = MachineConfig {
+            stack_size: 2,
+            memory_size: 4,
+            call_depth: 1,
+            ..MachineConfig::default()
+        };
+        let program = vec![Inst::PSH(1), Inst::PSH(2), Inst::PSH(3), Inst::HLT];
+        let mut machine = Machine::new_with_config(program, config).unwrap();
+        assert!(matches!(
+            machine.run(),
+            Err(VmError::Exec {
+                ip: 2,
+                fault: Fault::Stack(StackError::PushErr),
+                ..
+            })
+        ));
+        testing::assert_stack_eq(&machine, &[1, 2]);
+
+        let program = vec![Inst::LOAD(4), Inst::HLT];
+        let mut machine = Machine::new_with_config(program, config).unwrap();
+        assert!(matches!(
+            machine.run(),
+            Err(VmError::Exec {
+                fault: Fault::BadAddress(4),
+                ..
+            })
+        ));
+
+        let program = vec![Inst::CALL(0), Inst::CALL(0), Inst::HLT];
+        let mut machine = Machine::new_with_config(program, config).unwrap();
+        assert!(matches!(
+            machine.run(),
+            Err(VmError::Exec {
+                ip: 1,
+                fault: Fault::CallDepth,
+                ..
+            })
+        ));
+
+        let config = MachineConfig {
+            registers: 300,
+            ..MachineConfig::default()
+        };
+        assert_eq!(
+            Machine::new_with_config(vec![Inst::HLT], config).err(),
+            Some(ValidationError::RegisterCount(300))
+        );
+    }
+
+    #[test]
+    fn growing_stack() {
+        let config = MachineConfig {
+            stack_size: 2,
+            max_stack_size: Some(4),
+            ..MachineConfig::default()
+        };
+        let mut program = vec![Inst::PSH(1); 4];
+        program.push(Inst::HLT);
+        let mut machine = Machine::new_with_config(program.clone(), config).unwrap();
+        machine.run().unwrap();
+        testing::assert_stack_eq(&machine, &[1; 4]);
+
+        program.insert(0, Inst::PSH(0));
+        let mut machine = Machine::new_with_config(program, config).unwrap();
+        assert!(matches!(
+            machine.run(),
+            Err(VmError::Exec {
+                ip: 4,
+                fault: Fault::Stack(StackError::PushErr),
+                ..
+            })
+        ));
+        testing::assert_stack_eq(&machine, &[0, 1, 1, 1]);
+
+        // a ceiling below the initial size does not shrink the stack
+        let config = MachineConfig {
+            stack_size: 2,
+            max_stack_size: Some(1),
+            ..MachineConfig::default()
+        };
+        let program = vec![Inst::PSH(1), Inst::PSH(2), Inst::HLT];
+        let mut machine = Machine::new_with_config(program, config).unwrap();
+        machine.run().unwrap();
+        testing::assert_stack_eq(&machine, &[1, 2]);
+    }
+
+    #[test]
+    fn fixed_stack() {
+        // the array sets the size, the larger one of the config does not apply
+        let mut program = vec![
+            Inst::PSH(1),
+            Inst::PSH(2),
+            Inst::ADD,
+            Inst::PSH(4),
+            Inst::DUP,
+        ];
+        program.extend([Inst::PSH(5), Inst::HLT]);
+        let config = MachineConfig::default();
+        let mut machine =
+            Machine::<FixedStack<3>>::new_with_storage(program.clone(), config).unwrap();
+        let snapshot = machine.snapshot();
+        assert!(matches!(
+            machine.run(),
+            Err(VmError::Exec {
+                ip: 5,
+                fault: Fault::Stack(StackError::PushErr),
+                ..
+            })
+        ));
+        assert_eq!(machine.stack(), [3, 4, 4]);
+        assert_eq!(machine.stack_top(), Some(4));
+
+        machine.restore(&snapshot);
+        assert_eq!(machine.stack(), []);
+        machine.set_ip(5);
+        assert_eq!(machine.resume(), Ok(RunOutcome::Halted));
+        assert_eq!(machine.take_results(1), Ok(vec![5]));
+        assert_eq!(machine.stack(), []);
+
+        // a smaller stack size of the config still applies
+        let config = MachineConfig {
+            stack_size: 2,
+            ..MachineConfig::default()
+        };
+        let mut machine = Machine::<FixedStack<8>>::new_with_storage(program, config).unwrap();
+        assert!(machine.run().is_err());
+        assert_eq!(machine.stack(), [3, 4]);
+    }
+
+    #[test]
+    fn setp_stores_immediates() {
+        let program = vec![
+            Inst::PSH(1),
+            Inst::PSH(2),
+            Inst::SETP(Path::STK(1), 40),
+            Inst::SETP(Path::REG(Reg::E), 2),
+            Inst::ADD,
+            Inst::HLT,
+        ];
+        let mut machine = Machine::new(program);
+        machine.run().unwrap();
+        assert_eq!(machine.stack_top(), Some(42));
+        assert_eq!(machine.registers[&Reg::E], 2);
+
+        let program = vec![Inst::SETP(Path::STK(0), 1), Inst::HLT];
+        let mut machine = Machine::new(program);
+        assert!(matches!(
+            machine.run_report().halt,
+            HaltCause::Fault(VmError::Exec {
+                ip: 0,
+                inst: Inst::SETP(Path::STK(0), 1),
+                fault: Fault::Path(PathError::StackErr {
+                    requested: 0,
+                    sp: -1
+                }),
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn register_offset_paths_reverse_slots() {
+        let mut program = vec![
+            Inst::PSH(10),
+            Inst::PSH(20),
+            Inst::PSH(30),
+            Inst::PSH(40),
+            Inst::PSH(50),
+            Inst::SET(Reg::C, 0),
+            Inst::SET(Reg::D, 4),
+            Inst::SET(Reg::E, 2),
+            // swap the slots at offsets C and D
+            Inst::CPY(Path::REG(Reg::A), Path::STKR(Reg::C, 0)),
+            Inst::CPY(Path::REG(Reg::B), Path::STKR(Reg::D, 0)),
+            Inst::CPY(Path::STKR(Reg::C, 0), Path::REG(Reg::B)),
+            Inst::CPY(Path::STKR(Reg::D, 0), Path::REG(Reg::A)),
+        ];
+        // C += 1, D -= 1
+        for (reg, op) in [(Reg::C, Inst::ADD), (Reg::D, Inst::SUB)] {
+            program.extend([
+                Inst::PSH(0),
+                Inst::CPY(Path::STK(0), Path::REG(reg)),
+                Inst::PSH(1),
+                op,
+                Inst::CPY(Path::REG(reg), Path::STK(0)),
+                Inst::POP,
+            ]);
+        }
+        program.extend([Inst::LOOP(Reg::E, -16), Inst::HLT]);
+
+        let mut machine = Machine::new(program);
+        machine.run().unwrap();
+        assert_eq!(machine.stack.memory, vec![50, 40, 30, 20, 10]);
+    }
+
+    #[test]
+    fn register_offset_paths_are_bounds_checked() {
+        let base = [Inst::PSH(1), Inst::PSH(2), Inst::SET(Reg::C, 1)];
+        for (src, requested) in [(Path::STKR(Reg::C, 1), 2), (Path::STKR(Reg::C, -2), -1)] {
+            let mut program = base.to_vec();
+            program.extend([Inst::CPY(Path::REG(Reg::A), src), Inst::HLT]);
+            let mut machine = Machine::new(program);
+            match machine.run_report().halt {
+                HaltCause::Fault(e) => assert_eq!(
+                    e.fault(),
+                    Some(&Fault::Path(PathError::StackErr { requested, sp: 1 }))
+                ),
+                _ => panic!("out of bounds access did not fault"),
+            }
+        }
+    }
+
+    #[test]
+    fn input_from_a_reader() {
+        let program = vec![Inst::IN, Inst::IN, Inst::ADD, Inst::IN, Inst::HLT];
+        let mut machine = Machine::new(program.clone());
+        machine.set_input(ReadInput::new(&b"  3\n\n-10 7 8\n"[..]));
+        machine.run().unwrap();
+        testing::assert_stack_eq(&machine, &[-7, 7]);
+
+        // input stops at a word that is not a number
+        let mut machine = Machine::new(program);
+        machine.set_input(ReadInput::new(&b"3 x 4"[..]));
+        let err = machine.run().unwrap_err();
+        assert_eq!(err.fault(), Some(&Fault::NoInput));
+    }
+
+    #[test]
+    fn record_and_replay_input() {
+        // read a count, then sum that many values
+        let program = vec![
+            Inst::IN,
+            Inst::CPY(Path::REG(Reg::C), Path::STK(0)),
+            Inst::POP,
+            Inst::PSH(0),
+            Inst::IN,
+            Inst::ADD,
+            Inst::LOOP(Reg::C, -2),
+            Inst::HLT,
+        ];
+        let mut live = Machine::new(program.clone());
+        live.set_input(vec![3, 4, 5, 1].into_iter());
+        live.start_recording();
+        let live_report = live.run_report();
+        assert!(live_report.halted());
+        assert_eq!(live_report.stack_top, Some(10));
+
+        let recording = live.take_recording().unwrap();
+        assert_eq!(
+            recording.events,
+            vec![
+                InputEvent::In(3),
+                InputEvent::In(4),
+                InputEvent::In(5),
+                InputEvent::In(1)
+            ]
+        );
+
+        let mut replayed = Machine::with_replay(program.clone(), recording.clone());
+        replayed.set_input(vec![7, 7, 7].into_iter());
+        let replay_report = replayed.run_report();
+        assert!(replay_report.halted());
+        assert_eq!(replay_report.instructions, live_report.instructions);
+        assert_eq!(replayed.stack.memory, live.stack.memory);
+        assert_eq!(replay_report.registers, live_report.registers);
+
+        let mut short = recording;
+        short.events.pop();
+        let mut replayed = Machine::with_replay(program, short);
+        assert!(matches!(
+            replayed.run_report().halt,
+            HaltCause::Fault(VmError::ReplayDivergence {
+                ip: 4,
+                recorded: None,
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn tick_pauses_on_instruction_boundary() {
+        let program = vec![Inst::PSH(1), Inst::POP, Inst::JMP(-2)];
+        let mut machine = Machine::new(program.clone());
+        machine.set_tick(
+            10,
+            Box::new(|ctx| {
+                if ctx.tick() % 3 == 0 {
+                    TickAction::Pause
+                } else {
+                    TickAction::Continue
+                }
+            }),
+        );
+        assert_eq!(machine.resume(), Ok(RunOutcome::Paused));
+        assert_eq!(machine.instructions(), 30);
+        assert_eq!(machine.ip(), 0);
+        assert_eq!(machine.resume(), Ok(RunOutcome::Paused));
+        assert_eq!(machine.instructions(), 60);
+
+        // fuel and ticks together, the interval carries on after refuelling
+        let mut machine = Machine::new(program);
+        machine.set_tick(
+            10,
+            Box::new(|ctx| match ctx.tick() {
+                3 => TickAction::Pause,
+                _ => TickAction::Continue,
+            }),
+        );
+        machine.set_fuel(Some(25));
+        assert_eq!(machine.resume(), Ok(RunOutcome::OutOfFuel));
+        assert_eq!(machine.instructions(), 25);
+        machine.set_fuel(Some(100));
+        assert_eq!(machine.resume(), Ok(RunOutcome::Paused));
+        assert_eq!(machine.instructions(), 30);
+        assert_eq!(machine.fuel(), Some(95));
+    }
+
+    #[test]
+    fn fuel_costs() {
+        let program = vec![Inst::PSH(2), Inst::PSH(3), Inst::MUL, Inst::JMP(-3)];
+        let mut machine = Machine::new(program);
+        machine.set_fuel_cost(|inst| match inst {
+            Inst::MUL => 5,
+            _ => 1,
+        });
+        // the MUL is not executed with only 4 left
+        assert_eq!(machine.run_with_fuel(6), Ok(RunOutcome::OutOfFuel));
+        assert_eq!((machine.ip(), machine.fuel()), (2, Some(4)));
+        testing::assert_stack_eq(&machine, &[2, 3]);
+
+        assert_eq!(machine.run_with_fuel(6), Ok(RunOutcome::OutOfFuel));
+        assert_eq!((machine.ip(), machine.fuel()), (0, Some(0)));
+        testing::assert_stack_eq(&machine, &[6]);
+        assert_eq!(machine.instructions(), 4);
+    }
+
+    #[test]
+    fn plain_and_checked_runs_agree() {
+        // a yield, a recoverable fault and running off the end, with and without fuel
+        let program = vec![
+            Inst::SET(Reg::C, 3),
+            Inst::PSH(2),
+            Inst::DUP,
+            Inst::MUL,
+            Inst::LOOP(Reg::C, -2),
+            Inst::DUP,
+            Inst::YLD,
+            Inst::PSH(0),
+            Inst::DIV,
+        ];
+        let mut plain = Machine::new(program.clone());
+        let mut checked = Machine::new(program);
+        checked.set_fuel(Some(u64::MAX));
+        for machine in [&mut plain, &mut checked] {
+            assert_eq!(machine.resume(), Ok(RunOutcome::Yielded(256)));
+            let err = machine.resume().unwrap_err();
+            assert_eq!(err.fault(), Some(&Fault::DivideByZero));
+            machine.set_ip(9);
+            let err = machine.resume().unwrap_err();
+            assert!(matches!(err, VmError::IllegalInstruction { ip: 9, .. }));
+        }
+        assert_eq!(plain.snapshot(), checked.snapshot());
+    }
+
+    #[test]
+    fn superinstructions() {
+        let fused = MachineConfig {
+            fuse: true,
+            ..MachineConfig::default()
+        };
+        // the jump lands on the `ADD` of a fused pair
+        let program = vec![
+            Inst::SET(Reg::C, 4),
+            Inst::PSH(10),
+            Inst::PSH(3),
+            Inst::ADD,
+            Inst::PSH(2),
+            Inst::MUL,
+            Inst::DUP,
+            Inst::CPY(Path::REG(Reg::A), Path::STK(0)),
+            Inst::POP,
+            Inst::PSH(5),
+            Inst::LOOP(Reg::C, -7),
+            Inst::HLT,
+        ];
+        let mut plain = Machine::new(program.clone());
+        let mut machine = Machine::new_with_config(program, fused).unwrap();
+        plain.run().unwrap();
+        machine.run().unwrap();
+        assert_eq!(machine.snapshot(), plain.snapshot());
+        assert_eq!(machine.registers()[&Reg::A], 278);
+
+        // a pair that would fail runs one instruction at a time and fails the same way
+        let config = MachineConfig {
+            overflow: Overflow::Trap,
+            ..fused
+        };
+        let program = vec![Inst::PSH(Word::MAX), Inst::PSH(1), Inst::ADD, Inst::HLT];
+        let mut machine = Machine::new_with_config(program, config).unwrap();
+        let err = machine.run().unwrap_err();
+        assert!(matches!(
+            err,
+            VmError::Exec {
+                ip: 2,
+                fault: Fault::Overflow,
+                ..
+            }
+        ));
+        testing::assert_stack_eq(&machine, &[Word::MAX, 1]);
+
+        let config = MachineConfig {
+            stack_size: 1,
+            ..fused
+        };
+        let program = vec![Inst::PSH(1), Inst::PSH(1), Inst::ADD, Inst::HLT];
+        let mut machine = Machine::new_with_config(program, config).unwrap();
+        let err = machine.run().unwrap_err();
+        assert_eq!(err.fault(), Some(&Fault::Stack(StackError::PushErr)));
+    }
+
+    #[test]
+    fn bytecode_machines() {
+        let program = vec![
+            Inst::SET(Reg::C, 5),
+            Inst::PSH(1),
+            Inst::PSH(2),
+            Inst::MUL,
+            Inst::LOOP(Reg::C, -2),
+            Inst::CPY(Path::REG(Reg::A), Path::STK(0)),
+            Inst::PSH(0),
+            Inst::DIV,
+        ];
+        let bytes = crate::encode_program(&program);
+        let mut machine = Machine::from_bytecode(&bytes).unwrap();
+        let mut decoded = Machine::new(program.clone());
+        assert_eq!(machine.program_len(), program.len());
+        assert_eq!(machine.instruction(3), Some(Inst::MUL));
+        assert_eq!(machine.run().unwrap_err(), decoded.run().unwrap_err());
+        assert_eq!(machine.snapshot(), decoded.snapshot());
+        assert_eq!(machine.registers()[&Reg::A], 32);
+        assert_eq!(machine.core_dump().unwrap().program.code, program);
+
+        // cut in the middle of the `PSH`
+        let truncated = &bytes[..bytes.len() - 2];
+        let err = Machine::from_bytecode(truncated).err();
+        assert_eq!(err, Some(DecodeError::Truncated));
+    }
+
+    #[test]
+    fn bounded_runs() {
+        let mut machine = Machine::new(vec![Inst::PSH(1), Inst::JMP(-1)]);
+        assert_eq!(
+            machine.run_bounded(10),
+            Err(VmError::InstructionLimit { limit: 10, ip: 0 })
+        );
+        assert_eq!(machine.instructions(), 10);
+        // the limit counts from where the run starts
+        assert!(machine.run_bounded(5).is_err());
+        assert_eq!(machine.instructions(), 15);
+
+        let program = vec![Inst::PSH(1), Inst::PSH(2), Inst::ADD, Inst::HLT];
+        let mut machine = Machine::new(program);
+        assert_eq!(machine.run_bounded(4), Ok(RunOutcome::Halted));
+        testing::assert_stack_eq(&machine, &[3]);
+    }
+
+    #[test]
+    fn load_and_store() {
+        let program = vec![
+            Inst::PSH(5),
+            Inst::STORE(10),
+            Inst::LOAD(10),
+            Inst::LOAD(10),
+            Inst::ADD,
+            Inst::HLT,
+        ];
+        let mut machine = Machine::new(program);
+        machine.run().unwrap();
+        assert_eq!(machine.stack_top(), Some(10));
+        assert_eq!(machine.memory()[10], 5);
+
+        let program = vec![Inst::LOAD(MEMORY_SIZE), Inst::HLT];
+        let mut machine = Machine::new(program);
+        assert!(matches!(
+            machine.run_report().halt,
+            HaltCause::Fault(VmError::Exec {
+                ip: 0,
+                inst: Inst::LOAD(MEMORY_SIZE),
+                fault: Fault::BadAddress(MEMORY_SIZE),
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn stack_shuffling() {
+        let cases = [
+            (Inst::DUP, vec![1, 2, 3, 3]),
+            (Inst::SWAP, vec![1, 3, 2]),
+            (Inst::OVER, vec![1, 2, 3, 2]),
+            (Inst::ROT, vec![2, 3, 1]),
+            (Inst::DROP(2), vec![1]),
+            (Inst::DROP(0), vec![1, 2, 3]),
+        ];
+        for (inst, expected) in cases {
+            let program = vec![Inst::PSH(1), Inst::PSH(2), Inst::PSH(3), inst, Inst::HLT];
+            let mut machine = Machine::new(program);
+            machine.run().unwrap();
+            testing::assert_stack_eq(&machine, &expected);
+        }
+
+        let mut machine = Machine::new(vec![Inst::PSH(1), Inst::PSH(2), Inst::DROP(3)]);
+        let err = machine.run().unwrap_err();
+        assert_eq!(err.fault(), Some(&Fault::Stack(StackError::PopErr)));
+        testing::assert_stack_eq(&machine, &[1, 2]);
+    }
+
+    #[test]
+    fn increment_and_decrement() {
+        // counts A up to 3 with B counting down to zero
+        let program = vec![
+            Inst::SET(Reg::B, 3),
+            Inst::INC(Reg::A),
+            Inst::DEC(Reg::B),
+            Inst::PSH(0),
+            Inst::CPY(Path::STK(0), Path::REG(Reg::B)),
+            Inst::JNZ(-4),
+            Inst::SET(Reg::C, Word::MAX),
+            Inst::INC(Reg::C),
+            Inst::HLT,
+        ];
+        let mut machine = Machine::new(program);
+        machine.run().unwrap();
+        testing::assert_reg(&machine, Reg::A, 3);
+        testing::assert_reg(&machine, Reg::B, 0);
+        testing::assert_reg(&machine, Reg::C, Word::MIN);
+
+        let err = testing::run_expect_err(vec![Inst::DEC(Reg::R(16)), Inst::HLT]);
+        assert!(matches!(err.fault(), Some(Fault::Path(_))));
+    }
+
+    #[test]
+    fn indirect_memory() {
+        // doubles every entry of `arr` in place and sums them, walking it from the end with C
+        let source = "
+                .data pad: 0
+                .data arr: 3 1 4 1 5
+                set c 5
+                psh 0
+            next:
+                loadr c pad
+                psh 2
+                mul
+                psh 0
+                cpy stk[0] stk[1]
+                storer c pad
+                add
+                loop c next
+                hlt
+        ";
+        let mut machine = crate::assemble_program(source).unwrap().machine().unwrap();
+        machine.run().unwrap();
+        testing::assert_stack_eq(&machine, &[28]);
+        assert_eq!(&machine.memory()[..6], &[0, 6, 2, 8, 2, 10]);
+
+        // a negative register is a very large address
+        let program = vec![Inst::SET(Reg::A, -1), Inst::LOADR(Reg::A, 0), Inst::HLT];
+        let err = testing::run_expect_err(program);
+        assert_eq!(err.fault(), Some(&Fault::BadAddress(UWord::MAX as usize)));
+    }
+
+    /// An observer writing down what it sees, the test keeps a handle to it
+    #[derive(Clone, Default)]
+    struct Events(std::sync::Arc<std::sync::Mutex<Vec<String>>>);
+
+    impl Observer for Events {
+        fn before_inst(&mut self, ip: usize, inst: Inst) {
+            self.0.lock().unwrap().push(format!("{ip}: {inst}"));
+        }
+
+        fn after_inst(&mut self, ip: usize, _inst: Inst) {
+            self.0.lock().unwrap().push(format!("{ip} done"));
+        }
+
+        fn on_stack_push(&mut self, val: Word) {
+            self.0.lock().unwrap().push(format!("push {val}"));
+        }
+
+        fn on_stack_pop(&mut self, val: Word) {
+            self.0.lock().unwrap().push(format!("pop {val}"));
+        }
+
+        fn on_stack_clear(&mut self, count: usize) {
+            self.0.lock().unwrap().push(format!("clear {count}"));
+        }
+
+        fn on_halt(&mut self, exit_code: i32) {
+            self.0.lock().unwrap().push(format!("exit {exit_code}"));
+        }
+    }
+
+    #[test]
+    fn observers_see_every_instruction() {
+        let events = Events::default();
+        let program = vec![Inst::PSH(4), Inst::PSH(0), Inst::CLR, Inst::EXIT(2)];
+        let mut machine = Machine::new(program);
+        machine.set_observer(events.clone());
+        machine.run().unwrap();
+        assert_eq!(
+            *events.0.lock().unwrap(),
+            [
+                "0: psh 4",
+                "push 4",
+                "0 done",
+                "1: psh 0",
+                "push 0",
+                "1 done",
+                "2: clr",
+                "clear 2",
+                "2 done",
+                "3: exit 2",
+                "3 done",
+                "exit 2",
+            ]
+        );
+
+        // a fault ends the instruction without an after_inst
+        let events = Events::default();
+        let mut machine = Machine::new(vec![Inst::POP, Inst::HLT]);
+        machine.set_observer(events.clone());
+        assert!(machine.run().is_err());
+        assert!(machine.take_observer().is_some());
+        assert_eq!(*events.0.lock().unwrap(), ["0: pop"]);
+    }
+
+    /// An output sink the test keeps a handle to
+    #[derive(Clone, Default)]
+    struct SharedOutput(std::sync::Arc<std::sync::Mutex<Vec<u8>>>);
+
+    impl SharedOutput {
+        fn text(&self) -> String {
+            String::from_utf8(self.0.lock().unwrap().clone()).unwrap()
+        }
+    }
+
+    impl std::io::Write for SharedOutput {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    struct BrokenPipe;
+
+    impl std::io::Write for BrokenPipe {
+        fn write(&mut self, _buf: &[u8]) -> std::io::Result<usize> {
+            Err(std::io::ErrorKind::BrokenPipe.into())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn output_goes_to_the_sink() {
+        let program = vec![
+            Inst::PSH(-4),
+            Inst::OUT,
+            Inst::PSH(12),
+            Inst::OUT,
+            Inst::HLT,
+        ];
+        let out = SharedOutput::default();
+        let mut machine = Machine::with_output(program.clone(), out.clone());
+        machine.run().unwrap();
+        assert_eq!(out.text(), "-4\n12\n");
+
+        // the trace and the dump go to the same place
+        let out = SharedOutput::default();
+        let mut machine = Machine::with_output(program, out.clone());
+        machine.set_verbose(true);
+        machine.run().unwrap();
+        let text = out.text();
+        assert!(text.contains("\n-4\nmachine: out: -4\n"));
+        assert!(text.contains("machine dump:"));
+
+        let mut machine = Machine::with_output(vec![Inst::PSH(1), Inst::OUT], BrokenPipe);
+        let err = machine.run().unwrap_err();
+        assert_eq!(
+            err.fault(),
+            Some(&Fault::Output(std::io::ErrorKind::BrokenPipe))
+        );
+    }
+
+    /// Call 0 pops two values and pushes their sum, call 1 reads the host clock into A, call 2
+    /// pushes true
+    struct Host;
+
+    impl SyscallHandler for Host {
+        fn syscall(&mut self, number: u32, ctx: &mut SysCtx) -> Result<(), VmError> {
+            match number {
+                0 => {
+                    let (b, a) = (ctx.pop()?, ctx.pop()?);
+                    ctx.push(a + b)
+                }
+                1 => ctx.set_reg(Reg::A, 1234),
+                2 => ctx.push_value(Value::Bool(true)),
+                _ => Err(ctx.unknown()),
+            }
+        }
+    }
+
+    #[test]
+    fn host_function_calls() {
+        let program = vec![Inst::PSH(7), Inst::PSH(2), Inst::HCALL(0), Inst::HLT];
+        let mut machine = Machine::new(program.clone());
+        machine.set_imports(vec!["sub".to_string()]);
+        assert_eq!(machine.unresolved_imports(), vec!["sub"]);
+        machine.register_host_fn("sub", 2, |args| args[0] - args[1]);
+        assert!(machine.unresolved_imports().is_empty());
+        machine.run().unwrap();
+        testing::assert_stack_eq(&machine, &[5]);
+
+        let err = testing::run_expect_err(program.clone());
+        assert_eq!(err.fault(), Some(&Fault::NoImport(0)));
+
+        let mut machine = Machine::new(program);
+        machine.set_imports(vec!["sub".to_string()]);
+        let err = machine.run().unwrap_err();
+        assert_eq!(err.fault(), Some(&Fault::NoHostFn("sub".to_string())));
+
+        // too few arguments leaves the stack alone
+        let mut machine = Machine::new(vec![Inst::PSH(1), Inst::HCALL(0)]);
+        machine.set_imports(vec!["three".to_string()]);
+        machine.register_host_fn("three", 3, |_| 0);
+        let err = machine.run().unwrap_err();
+        assert_eq!(err.fault(), Some(&Fault::Stack(StackError::PopErr)));
+        testing::assert_stack_eq(&machine, &[1]);
+    }
+
+    #[test]
+    fn system_calls() {
+        let program = vec![
+            Inst::PSH(2),
+            Inst::PSH(3),
+            Inst::SYS(0),
+            Inst::SYS(1),
+            Inst::HLT,
+        ];
+        let mut machine = Machine::new(program);
+        machine.set_syscall_handler(Box::new(Host));
+        machine.run().unwrap();
+        testing::assert_stack_eq(&machine, &[5]);
+        testing::assert_reg(&machine, Reg::A, 1234);
+
+        let err = testing::run_expect_err(vec![Inst::SYS(0), Inst::HLT]);
+        assert_eq!(err.fault(), Some(&Fault::NoSyscall(0)));
+
+        // errors inside a call are raised by the SYS instruction
+        for (program, fault) in [
+            (
+                vec![Inst::PSH(1), Inst::SYS(0)],
+                Fault::Stack(StackError::PopErr),
+            ),
+            (vec![Inst::SYS(7)], Fault::NoSyscall(7)),
+        ] {
+            let last = program.len() - 1;
+            let mut machine = Machine::new(program);
+            machine.set_syscall_handler(Box::new(Host));
+            let err = machine.run().unwrap_err();
+            assert!(
+                matches!(&err, VmError::Exec { ip, context: Some(_), .. } if *ip == last),
+                "{err:?}"
+            );
+            assert_eq!(err.fault(), Some(&fault));
+        }
+    }
+
+    #[test]
+    fn printing_strings() {
+        let program = vec![
+            Inst::PRINTS(0),
+            Inst::PSH(1),
+            Inst::SYS(StringSyscalls::APPEND),
+            Inst::PSH(-7),
+            Inst::SYS(StringSyscalls::APPEND_INT),
+            Inst::PSH('!' as Word),
+            Inst::SYS(StringSyscalls::APPEND_CHAR),
+            Inst::SYS(StringSyscalls::LEN),
+            Inst::SYS(StringSyscalls::PRINT),
+            Inst::HLT,
+        ];
+        let out = SharedOutput::default();
+        let mut machine = Machine::with_output(program, out.clone());
+        machine.set_strings(vec!["hello, world".to_string(), "x = ".to_string()]);
+        machine.set_syscall_handler(Box::new(StringSyscalls::default()));
+        machine.run().unwrap();
+        assert_eq!(out.text(), "hello, world\nx = -7!");
+        testing::assert_stack_eq(&machine, &[7]);
+
+        let err = testing::run_expect_err(vec![Inst::PRINTS(2), Inst::HLT]);
+        assert_eq!(err.fault(), Some(&Fault::NoString(2)));
+    }
+
+    #[test]
+    fn replaying_a_trace() {
+        let program = vec![
+            Inst::PSH(10),
+            Inst::IN,
+            Inst::SYS(0),
+            Inst::PSH(3),
+            Inst::HCALL(0),
+            Inst::SYS(1),
+            Inst::HLT,
+        ];
+        let out = SharedOutput::default();
+        let mut live = Machine::new(program.clone());
+        live.set_input(vec![5].into_iter());
+        live.set_syscall_handler(Box::new(Host));
+        live.set_imports(vec!["sub".to_string()]);
+        live.register_host_fn("sub", 2, |args| args[0] - args[1]);
+        live.set_observer(Tracer::new(out.clone()));
+        live.start_recording();
+        live.run().unwrap();
+        drop(live.take_observer());
+        let recording = live.take_recording().unwrap();
+        assert_eq!(
+            recording.events[1..],
+            [
+                InputEvent::Sys {
+                    number: 0,
+                    effects: vec![SysEffect::Pop, SysEffect::Pop, SysEffect::Push(15)]
+                },
+                InputEvent::HostCall {
+                    arity: 2,
+                    result: 12
+                },
+                InputEvent::Sys {
+                    number: 1,
+                    effects: vec![SysEffect::SetReg(Reg::A, 1234)]
+                },
+            ]
+        );
+        let trace = Trace::read(out.text().as_bytes(), recording).unwrap();
+        assert_eq!(trace.steps.len(), 7);
+
+        // the host is not asked for anything
+        let mut replayed = Machine::new(program.clone());
+        replayed.set_imports(vec!["sub".to_string()]);
+        assert_eq!(replayed.replay(&trace), Ok(RunOutcome::Halted));
+        testing::assert_stack_eq(&replayed, &[12]);
+        testing::assert_reg(&replayed, Reg::A, 1234);
+
+        let mut changed = program.clone();
+        changed[3] = Inst::PSH(4);
+        let mut replayed = Machine::new(changed);
+        replayed.set_imports(vec!["sub".to_string()]);
+        let err = replayed.replay(&trace).unwrap_err();
+        assert!(
+            matches!(&err, VmError::TraceDivergence { step: 3, found: Some(found), .. }
+                if found.operands == ["4"]),
+            "{err:?}"
+        );
+
+        let mut short = trace.clone();
+        short.steps.pop();
+        let mut replayed = Machine::new(program);
+        replayed.set_imports(vec!["sub".to_string()]);
+        let err = replayed.replay(&short).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            r#"replay diverged at step 6: the trace ended, the run has `{"ip":6,"op":"hlt","operands":[],"depth":1}`"#
+        );
+
+        assert!(Trace::read("{}\n".as_bytes(), Recording::default()).is_err());
+    }
+
+    struct Console {
+        written: std::sync::Arc<std::sync::Mutex<Vec<Word>>>,
+        keys: Vec<Word>,
+    }
+
+    impl MmioHandler for Console {
+        fn read(&mut self, _addr: usize) -> Result<Word, VmError> {
+            self.keys
+                .pop()
+                .ok_or_else(|| VmError::Host("no key pressed".to_string()))
+        }
+
+        fn write(&mut self, _addr: usize, val: Word) -> Result<(), VmError> {
+            self.written.lock().unwrap().push(val);
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn memory_mapped_console() {
+        let written = std::sync::Arc::new(std::sync::Mutex::new(Vec::new()));
+        let console = Console {
+            written: written.clone(),
+            keys: vec![7],
+        };
+        let program = vec![
+            Inst::PSH(72),
+            Inst::STORE(0xFF00),
+            Inst::LOAD(0xFF01),
+            Inst::STORE(0xFF00),
+            Inst::LOAD(0xFF01),
+            Inst::HLT,
+        ];
+        let mut machine = Machine::new(program);
+        machine.map_io(0xFF00..0xFF02, Box::new(console)).unwrap();
+        assert!(matches!(
+            machine.map_io(
+                0xFF01..0xFF05,
+                Box::new(Console {
+                    written: written.clone(),
+                    keys: vec![]
+                })
+            ),
+            Err(VmError::OverlappingMapping { .. })
+        ));
+
+        assert_eq!(
+            machine.run_report().halt,
+            HaltCause::Fault(VmError::Host("no key pressed".to_string()))
+        );
+        assert_eq!(*written.lock().unwrap(), vec![72, 7]);
+    }
+
+    #[test]
+    fn timeout_stops_an_infinite_loop() {
+        let program = vec![Inst::PSH(1), Inst::POP, Inst::JMP(-2)];
+        let mut machine = Machine::new(program);
+        machine.set_timeout_check_interval(100);
+
+        let start = Instant::now();
+        assert_eq!(
+            machine.run_with_timeout(Duration::from_millis(50)),
+            Ok(RunOutcome::TimedOut)
+        );
+        assert!(start.elapsed() < Duration::from_secs(5));
+
+        let executed = machine.instructions();
+        assert!(executed > 0);
+        assert_eq!(
+            machine.run_with_timeout(Duration::from_millis(10)),
+            Ok(RunOutcome::TimedOut)
+        );
+        assert!(machine.instructions() > executed);
+    }
+
+    #[test]
+    fn step_reports_deltas() {
+        let program = vec![
+            Inst::PSH(3),
+            Inst::PSH(4),
+            Inst::ADD,
+            Inst::SET(Reg::C, 2),
+            Inst::SETP(Path::STK(0), 9),
+            Inst::LOOP(Reg::C, -1),
+            Inst::CLR,
+            Inst::JMP(0),
+            Inst::HLT,
+        ];
+        let mut machine = Machine::new(program);
+        let mut step = || machine.step().unwrap();
+
+        let psh = step();
+        assert_eq!(psh.status, StepStatus::Running);
+        assert_eq!(psh.delta.pushed, vec![3]);
+        step();
+        let add = step();
+        assert_eq!(add.delta.popped, vec![4, 3]);
+        assert_eq!(add.delta.pushed, vec![7]);
+        assert_eq!(add.delta.jump, None);
+
+        let set = step();
+        let change = RegisterChange {
+            reg: Reg::C,
+            old: 0,
+            new: 2,
+        };
+        assert_eq!(set.delta.register, Some(change));
+
+        let setp = step();
+        let write = SlotChange {
+            offset: 0,
+            old: 7,
+            new: 9,
+        };
+        assert_eq!(setp.delta.stack_write, Some(write));
+        assert!(setp.delta.pushed.is_empty() && setp.delta.popped.is_empty());
+
+        // C goes 2 -> 1 and jumps back onto the LOOP itself, then falls through at 0
+        let taken = step();
+        assert_eq!(taken.delta.jump, Some(4));
+        assert_eq!(taken.delta.register.unwrap().new, 1);
+        step();
+        let fallthrough = step();
+        assert_eq!(fallthrough.delta.jump, None);
+        assert_eq!(fallthrough.delta.register.unwrap().new, 0);
+
+        assert_eq!(step().delta.popped, vec![9]);
+        assert_eq!(step().delta, StateDelta::default());
+        assert_eq!(step().status, StepStatus::Halted);
+    }
+
+    #[test]
+    fn fault_context_inside_loop() {
+        // pushes 12 / (C - 1) for C = 3, 2, 1 and divides by zero on the third pass
+        let program = vec![
+            Inst::SET(Reg::C, 3),
+            Inst::PSH(12),
+            Inst::PSH(0),
+            Inst::CPY(Path::STK(0), Path::REG(Reg::C)),
+            Inst::PSH(1),
+            Inst::SUB,
+            Inst::DIV,
+            Inst::LOOP(Reg::C, -6),
+            Inst::HLT,
+        ];
+        let mut machine = Machine::new(program);
+        let err = machine.resume().unwrap_err();
+        let context = err.context().unwrap();
+
+        assert_eq!(context.ip, 6);
+        assert_eq!(context.inst, Some(Inst::DIV));
+        assert_eq!(context.stack, vec![0, 12, 12, 6]);
+        assert!(context.registers.contains(&(Reg::C, 1)));
+        assert_eq!(
+            context.recent,
+            vec![5, 6, 7, 1, 2, 3, 4, 5, 6, 7, 1, 2, 3, 4, 5, 6]
+        );
+
+        let plain = err.to_string();
+        assert_eq!(
+            plain,
+            "attempted to divide by zero while executing `div` at ip 6, stack depth 4"
+        );
+        let verbose = format!("{err:#}");
+        assert!(verbose.starts_with(&plain));
+        assert!(verbose.contains("at ip 6: div"));
+        assert!(verbose.contains("stack (top first): 0 12 12 6"));
+        assert!(verbose.ends_with("recent ips: 5 6 7 1 2 3 4 5 6 7 1 2 3 4 5 6"));
+
+        let mut machine = Machine::new(vec![Inst::PSH(1)]);
+        let err = machine.resume().unwrap_err();
+        assert_eq!(err.context().unwrap().inst, None);
+        assert_eq!(err.context().unwrap().recent, vec![0]);
+    }
+
+    #[test]
+    fn instructions_display_as_assembly() {
+        let cpy = Inst::CPY(Path::STK(0), Path::REG(Reg::B));
+        assert_eq!(cpy.to_string(), "cpy stk[0] reg.b");
+        let cpy = Inst::CPY(Path::STKR(Reg::C, -1), Path::STKR(Reg::R(2), 3));
+        assert_eq!(cpy.to_string(), "cpy stk[c-1] stk[r2+3]");
+        assert_eq!(Inst::LOOP(Reg::D, -2).to_string(), "loop d -2");
+        assert_eq!(Inst::HLT.to_string(), "hlt");
+    }
+
+    #[test]
+    fn entry_point_selects_routine() {
+        // two routines in one program, 2 + 3 at 0 and 6 * 7 at 4
+        let program = vec![
+            Inst::PSH(2),
+            Inst::PSH(3),
+            Inst::ADD,
+            Inst::HLT,
+            Inst::PSH(6),
+            Inst::PSH(7),
+            Inst::MUL,
+            Inst::HLT,
+        ];
+        let mut machine = Machine::with_entry(program.clone(), 4).unwrap();
+        let report = machine.run_report();
+        assert!(report.halted());
+        assert_eq!(report.entry, 4);
+        assert_eq!(report.stack_top, Some(42));
+        assert_eq!(report.instructions, 4);
+
+        let mut machine = Machine::new(program.clone());
+        machine.set_entry(4).unwrap();
+        machine.set_entry(0).unwrap();
+        assert_eq!(machine.run_report().stack_top, Some(5));
+        assert_eq!(machine.set_entry(4), Err(VmError::AlreadyStarted));
+
+        assert_eq!(
+            Machine::with_entry(program, 8).err(),
+            Some(VmError::BadEntry { ip: 8, len: 8 })
+        );
+    }
+
+    #[test]
+    fn exit_codes() {
+        let mut machine = Machine::new(vec![Inst::PSH(4), Inst::SET(Reg::B, 2), Inst::EXIT(3)]);
+        assert_eq!(machine.halt_state(), None);
+        let report = machine.run_report();
+        assert!(report.halted());
+        assert_eq!(report.exit_code, Some(3));
+        let state = machine.halt_state().unwrap();
+        assert_eq!(state.exit_code, 3);
+        assert_eq!(state.stack_top, Some(4));
+        assert_eq!(state.registers[&Reg::B], 2);
+        assert_eq!(machine.take_results(1).unwrap(), vec![4]);
+
+        let mut machine = Machine::new(vec![Inst::HLT]);
+        machine.run().unwrap();
+        assert_eq!(machine.exit_code(), Some(0));
+
+        // resuming after the halt runs on and forgets the exit code
+        let mut machine = Machine::new(vec![Inst::EXIT(1), Inst::PSH(0), Inst::YLD]);
+        machine.run().unwrap();
+        assert_eq!(machine.resume().unwrap(), RunOutcome::Yielded(0));
+        assert_eq!(machine.exit_code(), None);
+    }
+
+    #[test]
+    fn program_arguments() {
+        // the difference of the two arguments
+        let mut machine = Machine::new(vec![Inst::SUB, Inst::HLT]);
+        machine.push_args(&[10, 3]).unwrap();
+        machine.run().unwrap();
+        assert_eq!(machine.take_results(1), Ok(vec![7]));
+        assert_eq!(machine.push_args(&[1]), Err(VmError::AlreadyStarted));
+
+        let config = MachineConfig {
+            stack_size: 2,
+            ..MachineConfig::default()
+        };
+        let mut machine = Machine::new_with_config(vec![Inst::HLT], config).unwrap();
+        assert_eq!(
+            machine.push_args(&[1, 2, 3]),
+            Err(VmError::TooManyArgs { len: 3, size: 2 })
+        );
+        assert_eq!(machine.stack(), []);
+    }
+
+    #[test]
+    fn quotient_and_remainder_results() {
+        // 17 / 5 and 17 - (17 / 5) * 5, also copied to C and D
+        let program = vec![
+            Inst::SET(Reg::A, 17),
+            Inst::SET(Reg::B, 5),
+            Inst::PSH(0),
+            Inst::CPY(Path::STK(0), Path::REG(Reg::A)),
+            Inst::PSH(0),
+            Inst::CPY(Path::STK(0), Path::REG(Reg::B)),
+            Inst::DIV,
+            Inst::PSH(0),
+            Inst::CPY(Path::STK(0), Path::REG(Reg::A)),
+            Inst::PSH(0),
+            Inst::CPY(Path::STK(0), Path::STK(2)),
+            Inst::PSH(0),
+            Inst::CPY(Path::STK(0), Path::REG(Reg::B)),
+            Inst::MUL,
+            Inst::SUB,
+            Inst::CPY(Path::REG(Reg::C), Path::STK(1)),
+            Inst::CPY(Path::REG(Reg::D), Path::STK(0)),
+            Inst::HLT,
+        ];
+        let mut machine = Machine::new(program);
+        assert_eq!(machine.take_results(2), Err(VmError::NotHalted));
+        machine.resume().unwrap();
+
+        assert_eq!(machine.results_named(&[Reg::C, Reg::D]), vec![3, 2]);
+        assert_eq!(
+            machine.take_results(3),
+            Err(VmError::MissingResults {
+                requested: 3,
+                available: 2
+            })
+        );
+        assert_eq!(machine.take_results(2), Ok(vec![3, 2]));
+        assert_eq!(machine.stack_top(), None);
+    }
+
+    /// xorshift64, enough randomness to shake out panics
+    struct Rng(u64);
+
+    impl Rng {
+        fn next(&mut self) -> u64 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            self.0
+        }
+
+        fn below(&mut self, n: u64) -> u64 {
+            self.next() % n
+        }
+
+        /// Mostly small values, sometimes the extremes
+        fn int(&mut self) -> i64 {
+            match self.below(8) {
+                0 => i64::from(i32::MIN),
+                1 => i64::from(i32::MAX),
+                2 => -1,
+                3 => self.next() as i64,
+                _ => self.below(16) as i64 - 8,
+            }
+        }
+
+        fn reg(&mut self) -> Reg {
+            match self.below(8) {
+                0 => Reg::A,
+                1 => Reg::B,
+                2 => Reg::C,
+                3 => Reg::D,
+                _ => Reg::R(self.next() as u8),
+            }
+        }
+
+        fn cond(&mut self) -> Cond {
+            [
+                Cond::EQ,
+                Cond::NE,
+                Cond::LT,
+                Cond::LE,
+                Cond::GT,
+                Cond::GE,
+                Cond::LTU,
+                Cond::GEU,
+            ][self.below(8) as usize]
+        }
+
+        fn path(&mut self) -> Path {
+            match self.below(3) {
+                0 => Path::REG(self.reg()),
+                1 => Path::STK(self.int() as isize),
+                _ => Path::STKR(self.reg(), self.int() as isize),
+            }
+        }
+
+        fn inst(&mut self) -> Inst {
+            match self.below(75) {
+                74 => Inst::ALLOC(self.below(4) as usize),
+                73 => Inst::AIDXLOAD,
+                72 => Inst::AIDXSTORE,
+                71 => Inst::PRINTS(self.below(2) as u16),
+                70 => Inst::QMUL,
+                69 => Inst::QDIV,
+                68 => Inst::FPSH(float(self.next() as Word)),
+                67 => Inst::FADD,
+                66 => Inst::FSUB,
+                65 => Inst::FMUL,
+                64 => Inst::FDIV,
+                63 => Inst::ITOF,
+                62 => Inst::FTOI,
+                61 => Inst::EXIT(self.int() as i32),
+                60 => Inst::HCALL(self.below(2) as u16),
+                59 => Inst::SYS(self.below(3) as u32),
+                58 => Inst::OUT,
+                53 => Inst::DUP,
+                54 => Inst::SWAP,
+                55 => Inst::OVER,
+                56 => Inst::ROT,
+                57 => Inst::DROP(self.below(4) as usize),
+                51 => Inst::INC(self.reg()),
+                52 => Inst::DEC(self.reg()),
+                46 => Inst::MOD,
+                47 => Inst::NEG,
+                48 => Inst::ABS,
+                49 => Inst::MIN,
+                50 => Inst::MAX,
+                40 => Inst::AND,
+                41 => Inst::OR,
+                42 => Inst::XOR,
+                43 => Inst::NOT,
+                44 => Inst::SHL,
+                45 => Inst::SHR,
+                26 => Inst::JEZ(self.int() as isize),
+                27 => Inst::JNZ(self.int() as isize),
+                28 => Inst::JLT(self.int() as isize),
+                29 => Inst::JGT(self.int() as isize),
+                30 => Inst::JLE(self.int() as isize),
+                31 => Inst::JGE(self.int() as isize),
+                32 => Inst::CMP,
+                33 => Inst::JF(self.cond(), self.int() as isize),
+                34 => Inst::CALL(self.int() as isize),
+                35 => Inst::RET,
+                36 => Inst::LOADL(self.int() as isize),
+                37 => Inst::STOREL(self.int() as isize),
+                38 => Inst::LOADR(self.reg(), self.int() as usize),
+                39 => Inst::STORER(self.reg(), self.below(8) as usize),
+                17 => Inst::YLD,
+                18 => Inst::SND(self.next() as u8),
+                19 => Inst::RCV(self.next() as u8),
+                20 => Inst::PSHC(self.below(3) as u16),
+                21 => Inst::TRAP(self.next() as u16),
+                22 => Inst::DIVF,
+                23 => Inst::MODF,
+                24 => Inst::DIVU,
+                25 => Inst::MODU,
+                0 => Inst::PSH(self.int() as Word),
+                1 => Inst::POP,
+                2 => Inst::IN,
+                3 => Inst::CLR,
+                4 => Inst::ADD,
+                5 => Inst::SUB,
+                6 => Inst::MUL,
+                7 => Inst::DIV,
+                8 => Inst::SET(self.reg(), self.int() as Word),
+                9 => Inst::SETP(self.path(), self.int() as Word),
+                10 => Inst::CPY(self.path(), self.path()),
+                11 => Inst::LOAD(self.int() as usize),
+                12 => Inst::STORE(self.int() as usize),
+                13 => Inst::JMP(self.int() as isize),
+                14 => Inst::LOOP(self.reg(), self.int() as isize),
+                15 => Inst::TBL(self.below(2) as usize),
+                _ => Inst::HLT,
+            }
+        }
+    }
+
+    #[test]
+    fn random_programs_never_panic() {
+        let mut rng = Rng(0x9e37_79b9_7f4a_7c15);
+        for _ in 0..5000 {
+            let len = rng.below(12) as usize;
+            let program: Vec<Inst> = (0..len).map(|_| rng.inst()).collect();
+            let tables = vec![JumpTable::new(vec![0, len / 2]).with_default(0)];
+
+            let mut machine = match Machine::with_tables(program.clone(), tables) {
+                Ok(machine) => machine,
+                Err(_) => Machine::with_pool(program.clone(), vec![7, -7]),
+            };
+            machine.set_fuel(Some(200));
+            machine.set_input((0..4).map(|n| n * 1000));
+            machine.set_output(std::io::sink());
+            let _ = machine.set_entry(rng.below(len as u64 + 1) as usize);
+            let report = machine.run_report();
+            assert!(report.instructions <= 200);
+            if let HaltCause::Fault(e) = &report.halt {
+                let _ = format!("{e:#}");
+            }
+            let _ = machine.take_results(rng.below(3) as usize);
+
+            let mut machine = Machine::with_output(program, std::io::sink());
+            for _ in 0..50 {
+                if !matches!(machine.step(), Ok(outcome) if outcome.status == StepStatus::Running) {
+                    break;
+                }
+            }
+
+            let _ = Inst::from_word(rng.next());
+        }
+    }
+
+    #[test]
+    fn bytecode_runs_like_decoded() {
+        // returns and far jumps find their instruction from a mark
+        let mut program = vec![
+            Inst::SET(Reg::C, 20),
+            Inst::CALL(300),
+            Inst::LOOP(Reg::C, -1),
+        ];
+        program.push(Inst::HLT);
+        program.resize(301, Inst::INC(Reg::B));
+        program.extend([Inst::INC(Reg::A), Inst::JMP(149)]);
+        program.resize(451, Inst::DEC(Reg::A));
+        program.extend([Inst::INC(Reg::D), Inst::RET]);
+        let mut machine = Machine::from_bytecode(&crate::encode_program(&program)).unwrap();
+        assert_eq!(machine.run(), Ok(RunOutcome::Halted));
+        let mut decoded = Machine::new(program);
+        decoded.run().unwrap();
+        assert_eq!(machine.snapshot(), decoded.snapshot());
+        assert_eq!(machine.registers()[&Reg::D], 20);
+        assert_eq!(machine.instruction(400), Some(Inst::DEC(Reg::A)));
+
+        let mut rng = Rng(0x2545_f491_4f6c_dd1d);
+        for _ in 0..300 {
+            let len = rng.below(400) as usize;
+            let program: Vec<Inst> = (0..len).map(|_| rng.inst()).collect();
+            let run = |mut machine: Machine| {
+                machine.set_fuel(Some(2000));
+                machine.set_input((0..4).map(|n| n * 1000));
+                machine.set_output(std::io::sink());
+                (machine.resume(), machine.snapshot())
+            };
+            let bytes = crate::encode_program(&program);
+            let decoded = run(Machine::new(program));
+            assert_eq!(run(Machine::from_bytecode(&bytes).unwrap()), decoded);
+        }
+    }
+
+    #[test]
+    fn plain_loop_ops_run_like_instructions() {
+        let mut rng = Rng(0x6a09_e667_f3bc_c908);
+        // mostly the instructions the plain loop has ops for
+        let inst = |rng: &mut Rng| match rng.below(12) {
+            0 => Inst::PSH(rng.int() as Word),
+            1 => [Inst::POP, Inst::DUP, Inst::CMP, Inst::RET][rng.below(4) as usize],
+            2 => [Inst::ADD, Inst::SUB, Inst::MUL][rng.below(3) as usize],
+            3 => Inst::SET(rng.reg(), rng.int() as Word),
+            4 => [Inst::INC(rng.reg()), Inst::DEC(rng.reg())][rng.below(2) as usize],
+            5 => Inst::CPY(Path::REG(rng.reg()), Path::REG(rng.reg())),
+            6 => Inst::CPY(Path::REG(rng.reg()), Path::STK(0)),
+            7 => {
+                let step = rng.below(9) as isize - 4;
+                [
+                    Inst::JMP(step),
+                    Inst::JEZ(step),
+                    Inst::JNZ(step),
+                    Inst::CALL(step),
+                ][rng.below(4) as usize]
+            }
+            8 => Inst::LOOP(rng.reg(), rng.below(9) as isize - 4),
+            _ => rng.inst(),
+        };
+        for _ in 0..2000 {
+            let len = rng.below(40) as usize;
+            let program: Vec<Inst> = (0..len).map(|_| inst(&mut rng)).collect();
+            let run = |fuel: Option<u64>, fuse: bool| {
+                let config = MachineConfig {
+                    fuse,
+                    ..MachineConfig::default()
+                };
+                let mut machine = Machine::new_with_config(program.clone(), config).unwrap();
+                machine.set_fuel(fuel);
+                machine.set_input((0..4).map(|n| n * 1000));
+                machine.set_output(std::io::sink());
+                let outcome = machine.resume();
+                machine.set_fuel(None);
+                (outcome, machine.snapshot())
+            };
+            // only the programs that stop on their own run without fuel
+            let checked = run(Some(2000), false);
+            if checked.0 == Ok(RunOutcome::OutOfFuel) {
+                continue;
+            }
+            assert_eq!(run(None, false), checked, "{program:?}");
+            assert_eq!(run(None, true), checked, "{program:?}");
+        }
+    }
+
+    #[test]
+    fn fibonacci_generator_yields() {
+        // A and B hold consecutive fibonacci numbers, yield A and move on forever
+        let program = vec![
+            Inst::SET(Reg::B, 1),
+            Inst::PSH(0),
+            Inst::CPY(Path::STK(0), Path::REG(Reg::A)),
+            Inst::YLD,
+            Inst::PSH(0),
+            Inst::CPY(Path::STK(0), Path::REG(Reg::A)),
+            Inst::PSH(0),
+            Inst::CPY(Path::STK(0), Path::REG(Reg::B)),
+            Inst::ADD,
+            Inst::CPY(Path::REG(Reg::A), Path::REG(Reg::B)),
+            Inst::CPY(Path::REG(Reg::B), Path::STK(0)),
+            Inst::POP,
+            Inst::JMP(-11),
+        ];
+        let mut machine = Machine::new(program);
+        let mut values = Vec::new();
+        while values.len() < 10 {
+            match machine.resume().unwrap() {
+                RunOutcome::Yielded(val) => values.push(val),
+                outcome => panic!("generator stopped with {outcome:?}"),
+            }
+        }
+        assert_eq!(values, vec![0, 1, 1, 2, 3, 5, 8, 13, 21, 34]);
+        assert_eq!(machine.ip(), 4);
+        assert_eq!(machine.stack_top(), None);
+
+        // a yield takes one unit of fuel like any other instruction
+        machine.set_fuel(Some(12));
+        assert_eq!(machine.resume(), Ok(RunOutcome::Yielded(55)));
+        assert_eq!(machine.resume(), Ok(RunOutcome::OutOfFuel));
+        machine.set_fuel(None);
+        assert_eq!(machine.step().unwrap().status, StepStatus::Running);
+        assert_eq!(machine.resume(), Ok(RunOutcome::Yielded(89)));
+    }
+
+    #[test]
+    fn ping_pong_over_ports() {
+        // A sends 1 to 5 and yields what comes back, B doubles whatever it receives
+        let ping = vec![
+            Inst::SET(Reg::C, 5),
+            Inst::SET(Reg::A, 1),
+            Inst::PSH(0),
+            Inst::CPY(Path::STK(0), Path::REG(Reg::A)),
+            Inst::SND(0),
+            Inst::RCV(0),
+            Inst::YLD,
+            Inst::PSH(0),
+            Inst::CPY(Path::STK(0), Path::REG(Reg::A)),
+            Inst::PSH(1),
+            Inst::ADD,
+            Inst::CPY(Path::REG(Reg::A), Path::STK(0)),
+            Inst::POP,
+            Inst::LOOP(Reg::C, -11),
+            Inst::HLT,
+        ];
+        let pong = vec![
+            Inst::RCV(0),
+            Inst::PSH(2),
+            Inst::MUL,
+            Inst::SND(0),
+            Inst::JMP(-4),
+        ];
+        let (a_tx, b_rx) = std::sync::mpsc::channel();
+        let (b_tx, a_rx) = std::sync::mpsc::channel();
+        let mut a = Machine::new(ping);
+        let mut b = Machine::new(pong);
+        a.connect_port(0, a_tx, a_rx);
+        b.connect_port(0, b_tx, b_rx);
+
+        let mut received = Vec::new();
+        loop {
+            match a.resume().unwrap() {
+                RunOutcome::Yielded(val) => received.push(val),
+                RunOutcome::WaitingOnPort(0) => {
+                    assert_eq!(b.resume(), Ok(RunOutcome::WaitingOnPort(0)));
+                }
+                RunOutcome::Halted => break,
+                outcome => panic!("unexpected {outcome:?}"),
+            }
+        }
+        assert_eq!(received, vec![2, 4, 6, 8, 10]);
+        assert_eq!(b.ip(), 0);
+
+        // both waiting on each other is a deadlock the host can see
+        let (a_tx, b_rx) = std::sync::mpsc::channel();
+        let (b_tx, a_rx) = std::sync::mpsc::channel();
+        let mut a = Machine::new(vec![Inst::RCV(1), Inst::SND(1), Inst::HLT]);
+        let mut b = Machine::new(vec![Inst::RCV(1), Inst::SND(1), Inst::HLT]);
+        a.connect_port(1, a_tx, a_rx);
+        b.connect_port(1, b_tx, b_rx);
+        a.set_fuel(Some(10));
+        assert_eq!(a.resume(), Ok(RunOutcome::WaitingOnPort(1)));
+        assert_eq!(b.resume(), Ok(RunOutcome::WaitingOnPort(1)));
+        assert_eq!((a.instructions(), a.fuel()), (0, Some(10)));
+
+        drop(b);
+        assert!(matches!(
+            a.resume(),
+            Err(VmError::Exec {
+                fault: Fault::PortClosed(1),
+                ..
+            })
+        ));
+        let mut machine = Machine::new(vec![Inst::PSH(1), Inst::SND(3)]);
+        assert!(matches!(
+            machine.resume(),
+            Err(VmError::Exec {
+                fault: Fault::NoPort(3),
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn division_variants() {
+        let cases = [(7, 2), (-7, 2), (7, -2), (-7, -2), (6, 3), (Word::MIN, -1)];
+        let table = [
+            (Inst::DIV, [3, -3, -3, 3, 2, Word::MIN]),
+            (Inst::MOD, [1, -1, 1, -1, 0, 0]),
+            (Inst::DIVF, [3, -4, -4, 3, 2, Word::MIN]),
+            (Inst::MODF, [1, 1, -1, -1, 0, 0]),
+            (Inst::DIVU, [3, Word::MAX - 3, 0, 0, 2, 0]),
+            (Inst::MODU, [1, 1, 7, -7, 0, Word::MIN]),
+        ];
+        for (inst, expected) in table {
+            for ((a, b), expected) in cases.into_iter().zip(expected) {
+                let program = vec![Inst::PSH(a), Inst::PSH(b), inst, Inst::HLT];
+                let mut machine = Machine::new(program);
+                machine.resume().unwrap();
+                assert_eq!(machine.stack_top(), Some(expected), "{inst} on {a} {b}");
+            }
+
+            let mut machine = Machine::new(vec![Inst::PSH(1), Inst::PSH(0), inst, Inst::HLT]);
+            let err = machine.resume().unwrap_err();
+            assert_eq!(err.fault(), Some(&Fault::DivideByZero), "{inst}");
+        }
+    }
+
+    #[test]
+    fn bitwise_operations() {
+        let bits = Word::BITS as Word;
+        let cases = [(0b1100, 0b1010), (-8, 1), (1, bits - 1), (-1, bits + 1)];
+        let table = [
+            (Inst::AND, [0b1000, 0, 1, bits + 1]),
+            (Inst::OR, [0b1110, -7, bits - 1, -1]),
+            (Inst::XOR, [0b0110, -7, bits - 2, -bits - 2]),
+            (Inst::SHL, [0b1100 << 10, -16, Word::MIN, -2]),
+            (Inst::SHR, [0, Word::MAX - 3, 0, Word::MAX]),
+        ];
+        for (inst, expected) in table {
+            for ((a, b), expected) in cases.into_iter().zip(expected) {
+                let program = vec![Inst::PSH(a), Inst::PSH(b), inst, Inst::HLT];
+                let mut machine = Machine::new(program);
+                machine.resume().unwrap();
+                testing::assert_stack_eq(&machine, &[expected]);
+            }
+        }
+
+        let mut machine = Machine::new(vec![
+            Inst::PSH(0),
+            Inst::NOT,
+            Inst::PSH(5),
+            Inst::NOT,
+            Inst::HLT,
+        ]);
+        machine.resume().unwrap();
+        testing::assert_stack_eq(&machine, &[-1, -6]);
+        let err = testing::run_expect_err(vec![Inst::NOT]);
+        assert!(matches!(err.fault(), Some(Fault::Stack(_))));
+    }
+
+    #[test]
+    fn negation_and_extremes() {
+        for (val, neg, abs) in [
+            (5, -5, 5),
+            (-5, 5, 5),
+            (0, 0, 0),
+            (Word::MIN, Word::MIN, Word::MIN),
+        ] {
+            let program = vec![
+                Inst::PSH(val),
+                Inst::NEG,
+                Inst::PSH(val),
+                Inst::ABS,
+                Inst::HLT,
+            ];
+            let mut machine = Machine::new(program);
+            machine.resume().unwrap();
+            testing::assert_stack_eq(&machine, &[neg, abs]);
+        }
+
+        for (a, b) in [(3, -4), (-4, 3), (2, 2)] {
+            let program = vec![
+                Inst::PSH(a),
+                Inst::PSH(b),
+                Inst::MIN,
+                Inst::PSH(a),
+                Inst::PSH(b),
+                Inst::MAX,
+                Inst::HLT,
+            ];
+            let mut machine = Machine::new(program);
+            machine.resume().unwrap();
+            testing::assert_stack_eq(&machine, &[a.min(b), a.max(b)]);
+        }
+    }
+
+    #[test]
+    fn float_arithmetic() {
+        let table = [
+            (Inst::FADD, 7.5),
+            (Inst::FSUB, 4.5),
+            (Inst::FMUL, 9.0),
+            (Inst::FDIV, 4.0),
+        ];
+        for (inst, expected) in table {
+            let program = vec![Inst::FPSH(6.0), Inst::FPSH(1.5), inst, Inst::HLT];
+            let mut machine = Machine::new(program);
+            machine.resume().unwrap();
+            assert_eq!(machine.stack_top().map(float), Some(expected), "{inst}");
+        }
+
+        let program = vec![
+            Inst::PSH(-7),
+            Inst::ITOF,
+            Inst::FPSH(2.0),
+            Inst::FDIV,
+            Inst::FTOI,
+            Inst::FPSH(1.0),
+            Inst::FPSH(0.0),
+            Inst::FDIV,
+            Inst::FTOI,
+            Inst::FPSH(0.0),
+            Inst::DUP,
+            Inst::FDIV,
+            Inst::FTOI,
+            Inst::HLT,
+        ];
+        let mut machine = Machine::new(program);
+        machine.resume().unwrap();
+        testing::assert_stack_eq(&machine, &[-3, Word::MAX, 0]);
+    }
+
+    #[test]
+    fn fixed_point_arithmetic() {
+        let one = 1 << FIXED_POINT_BITS;
+        let cases = [
+            (Inst::QMUL, 3 * one / 2, 5 * one / 2, 15 * one / 4),
+            (Inst::QMUL, -one / 2, 3, -2),
+            (Inst::QDIV, 3 * one, 2 * one, 3 * one / 2),
+            (Inst::QDIV, -one, 3 * one, -21845),
+        ];
+        for (inst, a, b, expected) in cases {
+            let program = vec![Inst::PSH(a), Inst::PSH(b), inst, Inst::HLT];
+            let mut machine = Machine::new(program);
+            machine.resume().unwrap();
+            assert_eq!(machine.stack_top(), Some(expected), "{inst} on {a} {b}");
+        }
+
+        let program = vec![Inst::PSH(one), Inst::PSH(0), Inst::QDIV, Inst::HLT];
+        let err = testing::run_expect_err(program);
+        assert_eq!(err.fault(), Some(&Fault::DivideByZero));
+
+        let program = vec![
+            Inst::PSH(Word::MAX),
+            Inst::PSH(-2 * one),
+            Inst::QMUL,
+            Inst::HLT,
+        ];
+        let config = MachineConfig {
+            overflow: Overflow::Saturate,
+            ..MachineConfig::default()
+        };
+        let mut machine = Machine::new_with_config(program, config).unwrap();
+        machine.resume().unwrap();
+        testing::assert_stack_eq(&machine, &[Word::MIN]);
+    }
+
+    #[test]
+    fn heap_arrays() {
+        let program = vec![
+            Inst::ALLOC(3),
+            Inst::ALLOC(2),
+            Inst::DUP,
+            Inst::PSH(1),
+            Inst::PSH(42),
+            Inst::AIDXSTORE,
+            Inst::PSH(1),
+            Inst::AIDXLOAD,
+            Inst::HLT,
+        ];
+        let mut machine = Machine::new(program);
+        machine.run().unwrap();
+        testing::assert_stack_eq(&machine, &[1, 42]);
+        let heap = vec![Some(vec![0; 3]), Some(vec![0, 42])];
+        assert_eq!(machine.snapshot().heap, heap);
+
+        for (program, fault) in [
+            (
+                vec![Inst::ALLOC(2), Inst::PSH(2), Inst::AIDXLOAD],
+                Fault::ArrayIndex {
+                    handle: 1,
+                    index: 2,
+                    len: 2,
+                },
+            ),
+            (
+                vec![Inst::ALLOC(2), Inst::PSH(-1), Inst::PSH(0), Inst::AIDXSTORE],
+                Fault::ArrayIndex {
+                    handle: 1,
+                    index: -1,
+                    len: 2,
+                },
+            ),
+            (
+                vec![Inst::PSH(0), Inst::PSH(0), Inst::AIDXLOAD],
+                Fault::BadHandle(0),
+            ),
+        ] {
+            let err = testing::run_expect_err(program);
+            assert_eq!(err.fault(), Some(&fault));
+        }
+
+        let config = MachineConfig {
+            heap_size: 4,
+            ..MachineConfig::default()
+        };
+        let program = vec![Inst::ALLOC(3), Inst::ALLOC(2), Inst::HLT];
+        let mut machine = Machine::new_with_config(program, config).unwrap();
+        let err = machine.run().unwrap_err();
+        assert_eq!(err.fault(), Some(&Fault::OutOfMemory(2)));
+
+        // an array nothing refers to is collected to make room
+        let program = vec![Inst::ALLOC(3), Inst::POP, Inst::ALLOC(4), Inst::HLT];
+        let mut machine = Machine::new_with_config(program, config).unwrap();
+        machine.run().unwrap();
+        testing::assert_stack_eq(&machine, &[1]);
+        assert_eq!(machine.snapshot().heap, vec![Some(vec![0; 4])]);
+    }
+
+    #[test]
+    fn garbage_collection() {
+        // the array with handle 1 holds the handle of the second, in register A is the third
+        let program = vec![
+            Inst::ALLOC(1),
+            Inst::DUP,
+            Inst::PSH(0),
+            Inst::ALLOC(2),
+            Inst::AIDXSTORE,
+            Inst::ALLOC(3),
+            Inst::CPY(Path::REG(Reg::A), Path::STK(0)),
+            Inst::POP,
+            Inst::ALLOC(4),
+            Inst::POP,
+            Inst::HLT,
+        ];
+        let mut machine = Machine::new(program);
+        machine.run().unwrap();
+        assert_eq!(machine.collect_garbage(), 4);
+        let heap = machine.snapshot().heap;
+        assert_eq!(heap.iter().flatten().map(Vec::len).sum::<usize>(), 6);
+
+        machine.clear_stack();
+        assert_eq!(machine.collect_garbage(), 3);
+        machine.registers_mut().insert(Reg::A, 0);
+        assert_eq!(machine.collect_garbage(), 3);
+        assert!(machine.snapshot().heap.is_empty());
+    }
+
+    #[test]
+    fn typed_mode() {
+        let typed = MachineConfig {
+            typed: true,
+            ..MachineConfig::default()
+        };
+        let program = vec![
+            Inst::FPSH(1.5),
+            Inst::PSH(2),
+            Inst::ITOF,
+            Inst::FADD,
+            Inst::PSH(3),
+            Inst::SWAP,
+            Inst::STOREL(0),
+            Inst::LOADL(0),
+            Inst::FTOI,
+            Inst::SYS(2),
+            Inst::SYS(2),
+            Inst::AND,
+            Inst::JNZ(0),
+            Inst::HLT,
+        ];
+        let mut machine = Machine::new_with_config(program, typed).unwrap();
+        machine.set_syscall_handler(Box::new(Host));
+        machine.run().unwrap();
+        assert_eq!(machine.values(), [Value::Float(3.5), Value::Int(3)]);
+        assert_eq!(machine.snapshot().types, [Type::Float, Type::Int]);
+
+        let program = vec![Inst::PSH(1), Inst::FPSH(2.0), Inst::ADD, Inst::HLT];
+        let mut machine = Machine::new_with_config(program.clone(), typed).unwrap();
+        let err = machine.run().unwrap_err();
+        let mismatch = Fault::TypeMismatch {
+            expected: Type::Int,
+            found: Type::Float,
+        };
+        assert_eq!(err.fault(), Some(&mismatch));
+        assert!(err.is_recoverable());
+        assert!(err
+            .to_string()
+            .contains("type mismatch: expected int, found float"));
+        assert_eq!(machine.ip(), 2);
+        assert_eq!(machine.values(), [Value::Int(1), Value::Float(2.0)]);
+
+        // an untyped machine adds the bits
+        let mut machine = Machine::new(program);
+        machine.run().unwrap();
+        assert_eq!(machine.values(), [Value::Int(1 + float_word(2.0))]);
+
+        let program = vec![Inst::FPSH(0.5), Inst::STORE(0), Inst::HLT];
+        let mut machine = Machine::new_with_config(program, typed).unwrap();
+        let err = machine.run().unwrap_err();
+        assert_eq!(err.fault(), Some(&mismatch));
+    }
+
+    #[test]
+    fn conditional_jumps() {
+        let values = [-3, 0, 5, Word::MIN];
+        let table = [
+            (Inst::JEZ(2), [false, true, false, false]),
+            (Inst::JNZ(2), [true, false, true, true]),
+            (Inst::JLT(2), [true, false, false, true]),
+            (Inst::JGT(2), [false, false, true, false]),
+            (Inst::JLE(2), [true, true, false, true]),
+            (Inst::JGE(2), [false, true, true, false]),
+        ];
+        for (inst, expected) in table {
+            for (val, taken) in values.into_iter().zip(expected) {
+                // a taken jump skips the PSH and halts with the value popped
+                let program = vec![Inst::PSH(val), inst, Inst::PSH(1), Inst::HLT];
+                let mut machine = Machine::new(program);
+                machine.resume().unwrap();
+                let stack: &[Word] = if taken { &[] } else { &[1] };
+                assert_eq!(machine.stack(), stack, "{inst} on {val}");
+            }
+
+            let mut machine = Machine::new(vec![inst, Inst::HLT]);
+            let err = machine.resume().unwrap_err();
+            assert_eq!(err.fault(), Some(&Fault::Stack(StackError::PopErr)));
+        }
+
+        // adds B = 5, 4, ..., 1 into A and leaves the loop once B is zero
+        let program = vec![
+            Inst::SET(Reg::B, 5),
+            Inst::PSH(0),
+            Inst::CPY(Path::STK(0), Path::REG(Reg::B)),
+            Inst::JEZ(15),
+            Inst::PSH(0),
+            Inst::CPY(Path::STK(0), Path::REG(Reg::B)),
+            Inst::PSH(0),
+            Inst::CPY(Path::STK(0), Path::REG(Reg::A)),
+            Inst::ADD,
+            Inst::CPY(Path::REG(Reg::A), Path::STK(0)),
+            Inst::POP,
+            Inst::PSH(0),
+            Inst::CPY(Path::STK(0), Path::REG(Reg::B)),
+            Inst::PSH(1),
+            Inst::SUB,
+            Inst::CPY(Path::REG(Reg::B), Path::STK(0)),
+            Inst::POP,
+            Inst::JMP(-16),
+            Inst::HLT,
+        ];
+        let mut machine = Machine::new(program);
+        machine.resume().unwrap();
+        assert_eq!(machine.results_named(&[Reg::A, Reg::B]), vec![15, 0]);
+        assert_eq!(machine.stack(), &[]);
+    }
+
+    #[test]
+    fn compare_and_flag_jumps() {
+        let pairs = [(3, 3), (2, 5), (5, 2), (-1, 1), (Word::MIN, 1)];
+        let table = [
+            (Cond::EQ, [true, false, false, false, false]),
+            (Cond::NE, [false, true, true, true, true]),
+            (Cond::LT, [false, true, false, true, true]),
+            (Cond::LE, [true, true, false, true, true]),
+            (Cond::GT, [false, false, true, false, false]),
+            (Cond::GE, [true, false, true, false, false]),
+            (Cond::LTU, [false, true, false, false, false]),
+            (Cond::GEU, [true, false, true, true, true]),
+        ];
+        for (cond, expected) in table {
+            for ((a, b), taken) in pairs.into_iter().zip(expected) {
+                // a taken jump skips the PSH, CMP leaves both values on the stack
+                let program = vec![
+                    Inst::PSH(a),
+                    Inst::PSH(b),
+                    Inst::CMP,
+                    Inst::JF(cond, 2),
+                    Inst::PSH(0),
+                    Inst::HLT,
+                ];
+                let mut machine = Machine::new(program);
+                machine.resume().unwrap();
+                let stack: &[Word] = if taken { &[a, b] } else { &[a, b, 0] };
+                assert_eq!(machine.stack(), stack, "{cond} on {a} {b}");
+            }
+        }
+
+        let flags = |a, b| {
+            let mut machine = Machine::new(vec![Inst::PSH(a), Inst::PSH(b), Inst::CMP]);
+            machine.step().unwrap();
+            machine.step().unwrap();
+            let change = machine.step().unwrap().delta.flags.unwrap();
+            assert_eq!(change.old, Flags::default());
+            assert_eq!(change.new, machine.flags());
+            machine.flags()
+        };
+        let overflow = Flags {
+            zero: false,
+            negative: false,
+            overflow: true,
+            carry: false,
+        };
+        assert_eq!(flags(Word::MIN, 1), overflow);
+        let borrow = Flags {
+            zero: false,
+            negative: true,
+            overflow: false,
+            carry: true,
+        };
+        assert_eq!(flags(1, 2), borrow);
+
+        let err = Machine::new(vec![Inst::PSH(1), Inst::CMP])
+            .run()
+            .unwrap_err();
+        assert!(matches!(
+            err,
+            VmError::Exec {
+                ip: 1,
+                inst: Inst::CMP,
+                fault: Fault::Path(_),
+                ..
+            }
+        ));
+    }
+
+    #[test]
+    fn recursive_calls() {
+        let source = "
+                psh 6
+                call fact
+                hlt
+            fact:                   ; n on the stack becomes n!
+                psh 1
+                cmp
+                pop
+                jf le base
+                psh 0
+                cpy stk[0] stk[1]
+                psh 1
+                sub
+                call fact
+                mul
+                ret
+            base:
+                pop
+                psh 1
+                ret
+        ";
+        let mut machine = Machine::new(crate::assemble(source).unwrap());
+        machine.step().unwrap();
+        let call = machine.step().unwrap();
+        assert_eq!(call.delta.jump, Some(3));
+        assert_eq!(machine.call_stack(), &[Frame { ret: 2, fp: 0 }]);
+        machine.resume().unwrap();
+        assert_eq!(machine.stack(), &[720]);
+        assert_eq!(machine.call_stack(), &[]);
+
+        // calls the next instruction, which jumps back to the call
+        let mut machine = Machine::new(vec![Inst::CALL(0), Inst::JMP(-1)]);
+        let err = machine.run().unwrap_err();
+        assert!(matches!(
+            err,
+            VmError::Exec {
+                ip: 0,
+                fault: Fault::CallDepth,
+                ..
+            }
+        ));
+        assert_eq!(machine.call_stack().len(), CALL_DEPTH);
+
+        let err = Machine::new(vec![Inst::RET]).run().unwrap_err();
+        assert_eq!(err.fault(), Some(&Fault::NoCaller));
+    }
+
+    #[test]
+    fn frame_locals() {
+        // sum_squares(a, b) stores a * a + b * b over its first argument
+        let source = "
+                psh 7           ; stays below the frame
+                psh 3
+                psh 4
+                call sum_squares
+                pop
+                hlt
+            sum_squares:
+                loadl -2
+                loadl -2
+                mul             ; local 0
+                loadl -1
+                loadl -1
+                mul             ; local 1
+                call add_locals
+                storel -2
+                ret
+            add_locals:         ; adds the caller's locals 0 and 1 into its local 0
+                loadl -2
+                loadl -1
+                add
+                storel -2
+                pop
+                ret
+        ";
+        let mut machine = Machine::new(crate::assemble(source).unwrap());
+        assert_eq!(machine.frame_pointer(), 0);
+        for _ in 0..5 {
+            machine.step().unwrap();
+        }
+        assert_eq!(machine.frame_pointer(), 3);
+        machine.resume().unwrap();
+        assert_eq!(machine.stack(), &[7, 25]);
+        assert_eq!(machine.frame_pointer(), 0);
+
+        let mut machine = Machine::new(vec![Inst::PSH(1), Inst::STOREL(0), Inst::HLT]);
+        let err = machine.run().unwrap_err();
+        assert_eq!(err.fault(), Some(&Fault::BadLocal(0)));
+        assert_eq!(machine.stack(), &[1]);
+
+        let mut machine = Machine::new(vec![Inst::PSH(1), Inst::CALL(0), Inst::LOADL(-2)]);
+        let err = machine.run().unwrap_err();
+        assert_eq!(err.fault(), Some(&Fault::BadLocal(-2)));
+    }
+
+    fn fault_message(program: Vec<Inst>) -> String {
+        let mut machine = Machine::new(program);
+        match machine.run_report().halt {
+            HaltCause::Fault(e) => e.to_string(),
+            _ => panic!("program did not fault"),
+        }
+    }
+
+    #[test]
+    fn stack_faults_are_undone() {
+        let mut machine = Machine::new(vec![Inst::PSH(1), Inst::ADD, Inst::HLT]);
+        let err = machine.run().unwrap_err();
+        assert!(err.is_recoverable());
+        assert_eq!(machine.ip(), 1);
+        testing::assert_stack_eq(&machine, &[1]);
+        let mut fixed = machine.snapshot();
+        fixed.stack.push(2);
+        machine.restore(&fixed);
+        assert_eq!(machine.resume(), Ok(RunOutcome::Halted));
+        testing::assert_stack_eq(&machine, &[3]);
+
+        let config = MachineConfig {
+            stack_size: 2,
+            ..MachineConfig::default()
+        };
+        let program = vec![Inst::PSH(1), Inst::PSH(2), Inst::DUP, Inst::HLT];
+        let mut machine = Machine::new_with_config(program, config).unwrap();
+        assert!(machine.run().unwrap_err().is_recoverable());
+        assert_eq!(machine.ip(), 2);
+        testing::assert_stack_eq(&machine, &[1, 2]);
+
+        // what the host did before a system call failed is not undone
+        let mut machine = Machine::new(vec![Inst::PSH(5), Inst::SYS(0), Inst::HLT]);
+        machine.set_syscall_handler(Box::new(Host));
+        assert!(!machine.run().unwrap_err().is_recoverable());
+
+        let mut machine = Machine::new(vec![Inst::PSH(1), Inst::JMP(4)]);
+        assert!(!machine.run().unwrap_err().is_recoverable());
+        let err = Machine::new(vec![Inst::PSH(1)]).run().unwrap_err();
+        assert!(err.is_recoverable());
+    }
+
+    #[test]
+    fn undone_input_is_read_again() {
+        let config = MachineConfig {
+            stack_size: 1,
+            ..MachineConfig::default()
+        };
+        let program = vec![Inst::PSH(9), Inst::IN, Inst::HLT];
+        let mut machine = Machine::new_with_config(program, config).unwrap();
+        machine.set_input(vec![1, 2, 3].into_iter());
+        machine.start_recording();
+        let err = machine.run().unwrap_err();
+        assert!(err.is_recoverable());
+        assert_eq!(machine.ip(), 1);
+        machine.clear_stack();
+        assert_eq!(machine.resume(), Ok(RunOutcome::Halted));
+        testing::assert_stack_eq(&machine, &[1]);
+        let recording = machine.take_recording().unwrap();
+        assert_eq!(recording.events, vec![InputEvent::In(1)]);
+    }
+
+    #[test]
+    fn divide_handler() {
+        let program = vec![
+            Inst::PSH(7),
+            Inst::PSH(0),
+            Inst::MOD,
+            Inst::PSH(3),
+            Inst::ADD,
+            Inst::HLT,
+            // divisions by zero are -1
+            Inst::DROP(2),
+            Inst::PSH(-1),
+            Inst::RET,
+        ];
+        let mut machine = Machine::new(program.clone());
+        machine.set_divide_handler(Some(6));
+        assert_eq!(machine.run(), Ok(RunOutcome::Halted));
+        testing::assert_stack_eq(&machine, &[2]);
+
+        // without a handler the embedder gets to fix the divisor
+        let mut machine = Machine::new(program);
+        let err = machine.run().unwrap_err();
+        assert_eq!(err.fault(), Some(&Fault::DivideByZero));
+        let mut fixed = machine.snapshot();
+        fixed.stack = vec![7, 4];
+        machine.restore(&fixed);
+        assert_eq!(machine.resume(), Ok(RunOutcome::Halted));
+        testing::assert_stack_eq(&machine, &[6]);
+    }
+
+    #[test]
+    fn error_messages_carry_context() {
+        let program = vec![
+            Inst::PSH(1),
+            Inst::PSH(2),
+            Inst::CPY(Path::REG(Reg::A), Path::STK(3)),
+            Inst::HLT,
+        ];
+        assert_eq!(
+            fault_message(program),
+            "invalid stack access at offset 3 (sp=1) while executing `cpy reg.a stk[3]` at ip 2, stack depth 2"
+        );
+
+        let program = vec![Inst::PSH(1), Inst::ADD, Inst::HLT];
+        assert_eq!(
+            fault_message(program),
+            "cannot pop from an empty stack while executing `add` at ip 1, stack depth 1"
+        );
+
+        let program = vec![Inst::PSH(1)];
+        assert_eq!(
+            fault_message(program),
+            "illegal instruction at ip 1...abrupt halt"
+        );
+    }
+
+    fn assert_send<T: Send>() {}
+
+    #[test]
+    fn machine_is_send() {
+        assert_send::<Machine>();
+    }
+
+    #[test]
+    fn parallel_machines_share_program() {
+        let program: Arc<[Inst]> =
+            Arc::from(vec![Inst::PSH(6), Inst::PSH(7), Inst::MUL, Inst::HLT]);
+
+        let handles: Vec<_> = (0..8)
+            .map(|_| {
+                let program = Arc::clone(&program);
+                std::thread::spawn(move || {
+                    let mut machine = Machine::new(program);
+                    machine.run().unwrap();
+                    machine.stack_top().unwrap()
+                })
+            })
+            .collect();
+
+        let total: Word = handles.into_iter().map(|h| h.join().unwrap()).sum();
+        assert_eq!(total, 8 * 42);
+    }
+}
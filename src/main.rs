@@ -1,28 +1,506 @@
+use std::fs;
+use std::io::{self, BufRead, Read, Write};
+use std::process;
+use std::sync::OnceLock;
+
 use vyantra::*;
 
+const USAGE: &str = "usage:
+  vyantra run <program> [-v] [--core <file>] [-- <args>...]
+                                  run a program file, or an assembly file ending in .s, writing
+                                  a core file if it fails. <args> are integers pushed on the
+                                  stack before it starts, the last one at the head
+  vyantra asm <source> -o <out>   assemble a source file into a program file
+  vyantra disasm <program>        print a program file as assembly
+  vyantra transpile <program> -o <out>
+                                  translate a program into a standalone Rust program, or into
+                                  C when <out> ends in .c
+  vyantra debug <program>         step through a program at a prompt
+  vyantra repl                    type instructions at a prompt, running each as it is typed
+  vyantra postmortem <core>       open a core file at the debugger prompt
+  vyantra gdb <program> <addr>    serve a program to a GDB remote protocol client on <addr>
+<program> is read from standard input for `-`, and `-e <source>` gives it as assembly with `;`
+between instructions, such as `vyantra run -e 'psh 1; psh 2; add; out; hlt'`
+`vyantra run` exits with the program's exit code, 254 for codes outside 0 to 254, and every
+command exits with 255 if it fails";
+
+const DEBUG_HELP: &str = "commands:
+  s, step          execute one instruction
+  c, continue      run to the next breakpoint or until the program stops
+  back             undo the last instruction
+  rc, reverse      go back to the previous breakpoint
+  b, break <ip>    stop before the instruction at <ip>
+  d, delete <ip>   remove the breakpoint at <ip>
+  p, print         show the stack and registers
+  l, list          show the instructions around ip
+  q, quit          leave the debugger";
+
+const REPL_HELP: &str = "type an instruction to run it, or a `name:` label or a directive to add it
+to the program for later lines
+  :def <name>      start a subroutine, its lines are added without running them until :end,
+                   which adds its ret. `call <name>` runs it
+  :end             end the subroutine
+  :list            show the program typed so far
+  :reset           start again with an empty program and machine
+  :help            show this help
+  :quit            leave the prompt";
+
+/// Assembly given with `-e`, which [`load`] reads for the path `-e`
+static INLINE: OnceLock<String> = OnceLock::new();
+
 fn main() {
-    let program = vec![
-        Inst::PSH(5),
-        Inst::PSH(6),
-        Inst::POP,
-        Inst::PSH(21),
-        Inst::ADD,
-        Inst::POP,
-        Inst::SET(Reg::A, 12),
-        Inst::SET(Reg::B, 144),
-        Inst::PSH(0),
-        Inst::CPY(Path::STK(0), Path::REG(Reg::B)),
-        Inst::PSH(0),
-        Inst::CPY(Path::STK(0), Path::REG(Reg::A)),
-        Inst::DIV,
-        Inst::POP,
-        Inst::JMP(0),
-        Inst::PSH(69),
-        Inst::CPY(Path::REG(Reg::C), Path::STK(0)),
-        Inst::PSH(0),
-        Inst::CPY(Path::STK(0), Path::REG(Reg::C)),
-        Inst::HLT,
-    ];
-    let mut machine = Machine::new(program);
-    machine.run();
+    let mut args: Vec<String> = std::env::args().skip(1).collect();
+    let end = args
+        .iter()
+        .position(|arg| arg == "--")
+        .unwrap_or(args.len());
+    if let Some(at) = args[..end].iter().position(|arg| arg == "-e") {
+        if at + 1 < end {
+            let source = args.remove(at + 1);
+            INLINE.get_or_init(|| inline_lines(&source));
+        }
+    }
+    let args: Vec<&str> = args.iter().map(String::as_str).collect();
+    // what follows `--` is given to the program
+    let (args, program_args) = match args.iter().position(|&arg| arg == "--") {
+        Some(split) if args.first() == Some(&"run") => (&args[..split], &args[split + 1..]),
+        Some(_) => {
+            eprintln!("{USAGE}");
+            process::exit(FAILED);
+        }
+        None => (&args[..], &[][..]),
+    };
+    let result = match args {
+        ["run", path] => run(path, false, None, program_args),
+        ["run", path, "-v"] | ["run", "-v", path] => run(path, true, None, program_args),
+        ["run", path, "--core", core] => run(path, false, Some(core), program_args),
+        ["run", path, "-v", "--core", core] => run(path, true, Some(core), program_args),
+        ["asm", source, "-o", out] => asm(source, out),
+        ["disasm", path] => disasm(path),
+        ["transpile", path, "-o", out] => transpile_cmd(path, out),
+        ["debug", path] => debug(path),
+        ["repl"] => repl(),
+        ["postmortem", core] => postmortem(core),
+        ["gdb", path, addr] => gdb(path, addr),
+        #[cfg(feature = "dap")]
+        ["dap"] => dap::serve(io::stdin().lock(), io::stdout())
+            .map(|()| 0)
+            .map_err(Into::into),
+        _ => {
+            eprintln!("{USAGE}");
+            process::exit(FAILED);
+        }
+    };
+    match result {
+        Ok(code) => process::exit(exit_status(code)),
+        Err(e) => {
+            eprintln!("vyantra: {e:#}");
+            process::exit(FAILED);
+        }
+    }
+}
+
+/// The exit status of the command, or what went wrong
+type CliResult = Result<i32, Box<dyn std::error::Error>>;
+
+/// Exit status of `vyantra` when the command is wrong or the program fails, which no program's
+/// exit code exits with
+const FAILED: i32 = 255;
+
+/// The status to exit with for the exit code of a program. A process exits with 0 to 255 and
+/// 255 is [`FAILED`], so the codes outside 0 to 254, which the shell would see modulo 256, exit
+/// with 254 and a failing program never exits with 0.
+fn exit_status(code: i32) -> i32 {
+    match code {
+        0..=254 => code,
+        _ => 254,
+    }
+}
+
+/// Read a program file, or assemble a source file when `path` ends in `.s`. For `-` the program
+/// is read from standard input, assembled if it is text and decoded otherwise, and for `-e` it is
+/// the assembly given with `-e`.
+fn load(path: &str) -> Result<Program, Box<dyn std::error::Error>> {
+    let name = match path {
+        "-" => "standard input",
+        path => path,
+    };
+    let read_err = |e: io::Error| format!("cannot read {name}: {e}");
+    let (bytes, assembly) = match path {
+        "-e" => {
+            let source = INLINE.get().ok_or("-e needs the assembly of a program")?;
+            (source.clone().into_bytes(), true)
+        }
+        "-" => {
+            let mut bytes = Vec::new();
+            io::stdin().read_to_end(&mut bytes).map_err(read_err)?;
+            // program files hold words of little endian bytes, which text never has a zero in
+            let text = !bytes.contains(&0) && std::str::from_utf8(&bytes).is_ok();
+            (bytes, text)
+        }
+        path => (fs::read(path).map_err(read_err)?, path.ends_with(".s")),
+    };
+    if assembly {
+        let source = String::from_utf8(bytes).map_err(|e| format!("cannot read {name}: {e}"))?;
+        Ok(assemble_program(&source).map_err(|e| format!("{name}: {e}"))?)
+    } else {
+        Ok(Program::from_bytes(&bytes).map_err(|e| format!("{name}: {e}"))?)
+    }
+}
+
+/// Assembly given with `-e`, with a `;` outside a string ending the line instead of starting a
+/// comment
+fn inline_lines(source: &str) -> String {
+    let mut quoted = false;
+    source
+        .chars()
+        .map(|c| {
+            quoted ^= c == '"';
+            match c {
+                ';' if !quoted => '\n',
+                c => c,
+            }
+        })
+        .collect()
+}
+
+/// Run to the end, printing yielded values as they come and the stack once halted, and exit with
+/// the program's exit code. `IN` reads integers from standard input and the system calls are
+/// the string building ones of `StringSyscalls`. `args` are pushed on the stack before the program
+/// starts, the last at the head. If the program fails and `core` is given, the core file is
+/// written there.
+fn run(path: &str, verbose: bool, core: Option<&str>, args: &[&str]) -> CliResult {
+    let args = args
+        .iter()
+        .map(|arg| {
+            arg.parse()
+                .map_err(|_| format!("argument `{arg}` is not an integer"))
+        })
+        .collect::<Result<Vec<Word>, _>>()?;
+    let mut machine = load(path)?.machine()?;
+    machine.push_args(&args)?;
+    machine.set_verbose(verbose);
+    machine.set_input(ReadInput::stdin());
+    machine.set_syscall_handler(Box::new(StringSyscalls::default()));
+
+    loop {
+        let outcome = machine.resume();
+        if let (Err(_), Some(core), Some(dump)) = (&outcome, core, machine.core_dump()) {
+            dump.write(core)
+                .map_err(|e| format!("cannot write {core}: {e}"))?;
+            eprintln!("vyantra: core written to {core}");
+        }
+        match outcome? {
+            RunOutcome::Yielded(val) => println!("yield {val}"),
+            RunOutcome::Halted => break,
+            outcome => return Err(format!("program stopped: {outcome:?}").into()),
+        }
+    }
+    let stack: Vec<String> = machine.stack().iter().map(Word::to_string).collect();
+    println!("stack: {}", stack.join(" "));
+    Ok(machine.exit_code().unwrap_or(0))
+}
+
+fn asm(source: &str, out: &str) -> CliResult {
+    let bytes = load(source)?.to_bytes()?;
+    fs::write(out, bytes).map_err(|e| format!("cannot write {out}: {e}"))?;
+    Ok(0)
+}
+
+fn disasm(path: &str) -> CliResult {
+    print!("{}", disassemble_program(&load(path)?));
+    Ok(0)
+}
+
+/// Write the Rust translation of a program to `out`, to be built with `rustc -O`, or the C
+/// translation if `out` ends in `.c`
+fn transpile_cmd(path: &str, out: &str) -> CliResult {
+    let program = load(path)?;
+    let source = match out.ends_with(".c") {
+        true => transpile_c(&program),
+        false => transpile(&program),
+    };
+    let source = source.map_err(|e| format!("{path}: {e}"))?;
+    fs::write(out, source).map_err(|e| format!("cannot write {out}: {e}"))?;
+    Ok(0)
+}
+
+/// Instructions `vyantra debug` can go back over
+const DEBUG_HISTORY: usize = 10_000;
+
+fn debug(path: &str) -> CliResult {
+    prompt(Debugger::new(load(path)?.machine()?))
+}
+
+/// Open a core file written by `vyantra run --core` at the debugger prompt, on the instruction
+/// that failed
+fn postmortem(path: &str) -> CliResult {
+    let core = CoreDump::read(path).map_err(|e| format!("cannot read {path}: {e}"))?;
+    println!("{}", core.error);
+    prompt(Debugger::new(core.machine()?))
+}
+
+/// Read debugger commands from standard input until `quit` or the end of input. The prompt owns
+/// standard input, so the program's `IN` has nothing to read.
+fn prompt(mut debugger: Debugger) -> CliResult {
+    debugger.record_history(DEBUG_HISTORY);
+    let mut lines = io::stdin().lock().lines();
+    println!("{DEBUG_HELP}");
+    list(&debugger);
+    loop {
+        print!("(vyantra) ");
+        io::stdout().flush()?;
+        let line = match lines.next() {
+            Some(line) => line?,
+            None => break,
+        };
+        let halted = debugger.machine().exit_code().is_some();
+        match line.split_whitespace().collect::<Vec<_>>().as_slice() {
+            [] => (),
+            ["s" | "step"] | ["c" | "continue"] if halted => println!("the program has halted"),
+            ["s" | "step"] => {
+                match debugger.step() {
+                    Ok(outcome) if outcome.status != StepStatus::Running => {
+                        println!("{:?}", outcome.status)
+                    }
+                    Ok(_) => (),
+                    Err(e) => println!("error: {e}"),
+                }
+                list(&debugger);
+            }
+            ["c" | "continue"] => {
+                match debugger.continue_() {
+                    Ok(DebugStop::Breakpoint(ip)) => println!("breakpoint at {ip}"),
+                    Ok(stop) => println!("{stop:?}"),
+                    Err(e) => println!("error: {e}"),
+                }
+                list(&debugger);
+            }
+            ["back"] => {
+                if !debugger.step_back() {
+                    println!("no earlier state recorded");
+                }
+                list(&debugger);
+            }
+            ["rc" | "reverse"] => {
+                match debugger.reverse_continue() {
+                    Some(ip) => println!("breakpoint at {ip}"),
+                    None => println!("back at the oldest recorded state"),
+                }
+                list(&debugger);
+            }
+            ["b" | "break", ip] => match ip.parse() {
+                Ok(ip) => debugger.add_breakpoint(ip),
+                Err(_) => println!("not an instruction index: {ip}"),
+            },
+            ["d" | "delete", ip] => match ip.parse() {
+                Ok(ip) if debugger.remove_breakpoint(ip) => (),
+                _ => println!("no breakpoint at {ip}"),
+            },
+            ["p" | "print"] => print_state(debugger.machine()),
+            ["l" | "list"] => list(&debugger),
+            ["q" | "quit"] => break,
+            _ => println!("{DEBUG_HELP}"),
+        }
+    }
+    Ok(debugger.machine().exit_code().unwrap_or(0))
+}
+
+/// Print the instructions around `ip`, marking `ip` with `=>` and breakpoints with `*`
+fn list(debugger: &Debugger) {
+    let machine = debugger.machine();
+    let breakpoints: Vec<usize> = debugger.breakpoints().collect();
+    let ip = machine.ip();
+    let end = (ip + 4).min(machine.program().len());
+    for (idx, inst) in machine.program()[..end]
+        .iter()
+        .enumerate()
+        .skip(ip.saturating_sub(3))
+    {
+        let here = if idx == ip { "=>" } else { "  " };
+        let mark = if breakpoints.contains(&idx) { '*' } else { ' ' };
+        println!("{here}{mark}{idx:4}  {inst}");
+    }
+    if ip >= machine.program().len() {
+        println!("=> {ip:4}  (end of program)");
+    }
+}
+
+/// Print the stack and the registers, the numbered registers only when they are not zero
+fn print_state(machine: &Machine) {
+    let stack: Vec<String> = machine.stack().iter().map(Word::to_string).collect();
+    println!("stack: {}", stack.join(" "));
+    let registers = machine.registers();
+    let mut line = String::from("registers:");
+    for reg in [Reg::A, Reg::B, Reg::C, Reg::D, Reg::E, Reg::F] {
+        line.push_str(&format!(" {reg}={}", registers[&reg]));
+    }
+    for n in 0..=u8::MAX {
+        match registers.get(&Reg::R(n)) {
+            Some(val) if *val != 0 => line.push_str(&format!(" {}={val}", Reg::R(n))),
+            _ => (),
+        }
+    }
+    println!("{line}");
+}
+
+/// Most instructions a line typed at `vyantra repl` runs, so that a loop that never ends gives
+/// the prompt back
+const REPL_LIMIT: u64 = 1_000_000;
+
+/// Read lines of assembly from standard input until `:quit` or the end of input, running each
+/// instruction as it is typed and then printing the stack and registers. The prompt owns
+/// standard input, so the program's `IN` has nothing to read.
+fn repl() -> CliResult {
+    let mut repl = Repl::new();
+    // the lines of the subroutine being defined
+    let mut def: Option<String> = None;
+    let mut lines = io::stdin().lock().lines();
+    println!("{REPL_HELP}");
+    loop {
+        print!(
+            "{}",
+            if def.is_some() {
+                "  ... "
+            } else {
+                "(vyantra) "
+            }
+        );
+        io::stdout().flush()?;
+        let line = match lines.next() {
+            Some(line) => line?,
+            None => break,
+        };
+        let added = match line.split_whitespace().collect::<Vec<_>>().as_slice() {
+            [":q" | ":quit"] => break,
+            [":help"] => {
+                println!("{REPL_HELP}");
+                Ok(())
+            }
+            [":list"] => {
+                print!("{}", repl.source);
+                Ok(())
+            }
+            [":reset"] => {
+                repl = Repl::new();
+                def = None;
+                Ok(())
+            }
+            [":def", name] if def.is_none() => {
+                let head = format!("{name}:\n");
+                repl.check(&head).map(|()| def = Some(head))
+            }
+            [":end"] => match def.take() {
+                Some(body) => repl.add(&format!("{body}ret\n"), false),
+                None => Err("no subroutine to end".into()),
+            },
+            [command, ..] if command.starts_with(':') => {
+                println!("{REPL_HELP}");
+                Ok(())
+            }
+            _ => match &mut def {
+                Some(body) => {
+                    let text = format!("{body}{line}\n");
+                    repl.check(&text).map(|()| *body = text)
+                }
+                None => repl.add(&format!("{line}\n"), true),
+            },
+        };
+        if let Err(e) = added {
+            println!("error: {e}");
+        }
+    }
+    Ok(0)
+}
+
+/// The program typed at `vyantra repl` so far and the machine running it
+struct Repl {
+    /// Every line added to the program
+    source: String,
+    program: Program,
+    machine: Machine,
+}
+
+impl Repl {
+    fn new() -> Self {
+        let mut machine = Machine::new(Vec::new());
+        machine.set_syscall_handler(Box::new(StringSyscalls::default()));
+        Repl {
+            source: String::new(),
+            program: Program::default(),
+            machine,
+        }
+    }
+
+    /// Whether the program still assembles with `text` added
+    fn check(&self, text: &str) -> Result<(), Box<dyn std::error::Error>> {
+        let source = format!("{}{text}", self.source);
+        assemble_program(&source)?;
+        Ok(())
+    }
+
+    /// Add `text` to the program if it still assembles with it. The machine keeps its state
+    /// with the new program, and when `run` is set it executes the instructions `text` added.
+    fn add(&mut self, text: &str, run: bool) -> Result<(), Box<dyn std::error::Error>> {
+        let source = format!("{}{text}", self.source);
+        let program = assemble_program(&source)?;
+        let mut machine = program.machine()?;
+        machine.set_syscall_handler(Box::new(StringSyscalls::default()));
+        let mut snapshot = self.machine.snapshot();
+        // words added to the data segment go into data memory, the snapshot has it as it was
+        let data = program.data.iter().enumerate();
+        for (addr, &word) in data.skip(self.program.data.len()) {
+            if let Some(slot) = snapshot.memory.get_mut(addr) {
+                *slot = word;
+            }
+        }
+        let start = self.program.code.len();
+        if run {
+            snapshot.ip = start;
+            snapshot.exit_code = None;
+        }
+        machine.restore(&snapshot);
+        self.source = source;
+        self.program = program;
+        self.machine = machine;
+        if run && start < self.program.code.len() {
+            self.run();
+        }
+        Ok(())
+    }
+
+    /// Run to the end of the program, printing yielded values as they come, then print the
+    /// stack and registers
+    fn run(&mut self) {
+        loop {
+            match self.machine.run_bounded(REPL_LIMIT) {
+                Ok(RunOutcome::Yielded(val)) => println!("yield {val}"),
+                Ok(RunOutcome::Halted) => {
+                    let code = self.machine.exit_code().unwrap_or(0);
+                    println!("halted with exit code {code}");
+                    break;
+                }
+                Ok(outcome) => {
+                    println!("{outcome:?}");
+                    break;
+                }
+                Err(VmError::IllegalInstruction { ip, .. }) if ip == self.program.code.len() => {
+                    break
+                }
+                Err(e) => {
+                    println!("error: {e}");
+                    break;
+                }
+            }
+        }
+        print_state(&self.machine);
+    }
+}
+
+/// Wait for a debugger frontend on `addr` and serve the program to it until it detaches
+fn gdb(path: &str, addr: &str) -> CliResult {
+    let mut stub = gdb::GdbStub::new(load(path)?.machine()?);
+    eprintln!("waiting for a debugger on {addr}");
+    stub.listen(addr)?;
+    Ok(stub.into_machine().exit_code().unwrap_or(0))
 }
@@ -0,0 +1,84 @@
+//! Word addressed data memory for `LOAD` and `STORE`, with memory-mapped devices.
+
+use alloc::boxed::Box;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::ops::Range;
+
+use crate::error::{Fault, VmError};
+use crate::Word;
+
+/// A device mapped into a range of data memory addresses. `LOAD` and `STORE` on a mapped address
+/// call the handler instead of touching the backing memory. It must be `Send` so that the
+/// machine stays `Send`.
+pub trait MmioHandler: Send {
+    fn read(&mut self, addr: usize) -> Result<Word, VmError>;
+
+    fn write(&mut self, addr: usize, val: Word) -> Result<(), VmError>;
+}
+
+struct Mapping {
+    range: Range<usize>,
+    handler: Box<dyn MmioHandler>,
+}
+
+pub(crate) struct Memory {
+    pub(crate) words: Vec<Word>,
+    mappings: Vec<Mapping>,
+}
+
+impl Memory {
+    pub(crate) fn new(size: usize) -> Self {
+        Memory {
+            words: vec![0; size],
+            mappings: Vec::new(),
+        }
+    }
+
+    pub(crate) fn map(
+        &mut self,
+        range: Range<usize>,
+        handler: Box<dyn MmioHandler>,
+    ) -> Result<(), VmError> {
+        let overlaps = |other: &Range<usize>| range.start < other.end && other.start < range.end;
+        if self.mappings.iter().any(|m| overlaps(&m.range)) {
+            return Err(VmError::OverlappingMapping { range });
+        }
+        self.mappings.push(Mapping { range, handler });
+        Ok(())
+    }
+
+    fn device(&mut self, addr: usize) -> Option<&mut Box<dyn MmioHandler>> {
+        self.mappings
+            .iter_mut()
+            .find(|m| m.range.contains(&addr))
+            .map(|m| &mut m.handler)
+    }
+
+    /// Whether a device is mapped over `addr`
+    pub(crate) fn is_mapped(&self, addr: usize) -> bool {
+        self.mappings.iter().any(|m| m.range.contains(&addr))
+    }
+
+    pub(crate) fn load(&mut self, addr: usize) -> Result<Word, Fault> {
+        if let Some(device) = self.device(addr) {
+            return device.read(addr).map_err(|e| Fault::Host(Box::new(e)));
+        }
+        self.words.get(addr).copied().ok_or(Fault::BadAddress(addr))
+    }
+
+    pub(crate) fn store(&mut self, addr: usize, val: Word) -> Result<(), Fault> {
+        if let Some(device) = self.device(addr) {
+            return device
+                .write(addr, val)
+                .map_err(|e| Fault::Host(Box::new(e)));
+        }
+        match self.words.get_mut(addr) {
+            Some(word) => {
+                *word = val;
+                Ok(())
+            }
+            None => Err(Fault::BadAddress(addr)),
+        }
+    }
+}
@@ -0,0 +1,367 @@
+//! Callbacks into the host for every executed instruction, for loggers, profilers and
+//! visualizers, and a [`Tracer`] built on them, which needs the `std` feature.
+
+use alloc::string::{String, ToString};
+use alloc::vec;
+use alloc::vec::Vec;
+use core::fmt;
+#[cfg(feature = "std")]
+use std::io::{self, BufRead, Write};
+#[cfg(feature = "std")]
+use std::sync::{Arc, Mutex};
+
+use crate::json::Json;
+#[cfg(feature = "std")]
+use crate::Recording;
+use crate::{Inst, Reg, Word};
+
+/// Watches a machine run, attached with [`Machine::set_observer`](crate::Machine::set_observer).
+/// Every method does nothing by default, so an observer only implements what it needs. It must
+/// be `Send` so that the machine stays `Send`.
+///
+/// An instruction that faults gets a `before_inst` and no `after_inst`. A `RCV` that finds
+/// nothing to receive gets neither an `after_inst` nor a push, and its `before_inst` is called
+/// again when it is retried.
+pub trait Observer: Send {
+    /// `inst`, found at `ip`, is about to be executed
+    fn before_inst(&mut self, ip: usize, inst: Inst) {
+        let _ = (ip, inst);
+    }
+
+    /// `inst`, found at `ip`, was executed
+    fn after_inst(&mut self, ip: usize, inst: Inst) {
+        let _ = (ip, inst);
+    }
+
+    /// `val` was pushed on to the stack
+    fn on_stack_push(&mut self, val: Word) {
+        let _ = val;
+    }
+
+    /// `val` was popped off the stack
+    fn on_stack_pop(&mut self, val: Word) {
+        let _ = val;
+    }
+
+    /// The stack was emptied of its `count` values, by `CLR` or
+    /// [`Machine::clear_stack`](crate::Machine::clear_stack), with no `on_stack_pop` for them
+    fn on_stack_clear(&mut self, count: usize) {
+        let _ = count;
+    }
+
+    /// `reg` was set from `old` to `new`
+    fn on_register_write(&mut self, reg: Reg, old: Word, new: Word) {
+        let _ = (reg, old, new);
+    }
+
+    /// The program halted with `exit_code`, 0 for `HLT`
+    fn on_halt(&mut self, exit_code: i32) {
+        let _ = exit_code;
+    }
+}
+
+/// An observer writing a line of JSON for every executed instruction, for analysis after the
+/// run:
+///
+/// ```text
+/// {"ip":4,"op":"set","operands":["a","12"],"depth":1,"registers":{"a":[0,12]}}
+/// ```
+///
+/// `depth` is the depth of the stack after the instruction, counted from when the tracer was
+/// attached, and `registers` holds the old and new value of every register the instruction
+/// wrote. An instruction that faults is written when the tracer is dropped or sees the next
+/// instruction, with `"completed":false`. Errors writing the trace are ignored.
+#[cfg(feature = "std")]
+pub struct Tracer {
+    out: Sink,
+    depth: usize,
+
+    /// The instruction being executed
+    current: Option<Traced>,
+}
+
+/// Where a tracer puts the steps it traced
+#[cfg(feature = "std")]
+enum Sink {
+    Writer(Box<dyn Write + Send>),
+
+    /// Collected for [`Machine::replay`](crate::Machine::replay)
+    Steps(Arc<Mutex<Vec<TraceStep>>>),
+}
+
+/// An instruction and the registers it wrote so far, as old and new values
+#[cfg(feature = "std")]
+struct Traced {
+    ip: usize,
+    inst: Inst,
+    registers: Vec<(Reg, Word, Word)>,
+}
+
+#[cfg(feature = "std")]
+impl Tracer {
+    pub fn new(out: impl Write + Send + 'static) -> Self {
+        Tracer {
+            out: Sink::Writer(Box::new(out)),
+            depth: 0,
+            current: None,
+        }
+    }
+
+    /// A tracer adding the steps to `steps` instead of writing them
+    pub(crate) fn collect(steps: Arc<Mutex<Vec<TraceStep>>>) -> Self {
+        Tracer {
+            out: Sink::Steps(steps),
+            depth: 0,
+            current: None,
+        }
+    }
+
+    /// Write the line of the current instruction, if there is one
+    fn write_current(&mut self, completed: bool) {
+        let Some(Traced {
+            ip,
+            inst,
+            registers,
+        }) = self.current.take()
+        else {
+            return;
+        };
+        let text = inst.to_string();
+        let mut words = text.split_whitespace();
+        let step = TraceStep {
+            ip,
+            op: words.next().unwrap_or("").to_string(),
+            operands: words.map(String::from).collect(),
+            depth: self.depth,
+            registers,
+            completed,
+        };
+        match &mut self.out {
+            Sink::Writer(out) => {
+                let _ = writeln!(out, "{step}");
+            }
+            Sink::Steps(steps) => steps.lock().unwrap().push(step),
+        }
+    }
+
+    fn flush(&mut self) {
+        if let Sink::Writer(out) = &mut self.out {
+            let _ = out.flush();
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl Observer for Tracer {
+    fn before_inst(&mut self, ip: usize, inst: Inst) {
+        self.write_current(false);
+        self.current = Some(Traced {
+            ip,
+            inst,
+            registers: Vec::new(),
+        });
+    }
+
+    fn after_inst(&mut self, _ip: usize, _inst: Inst) {
+        self.write_current(true);
+    }
+
+    fn on_stack_push(&mut self, _val: Word) {
+        self.depth += 1;
+    }
+
+    fn on_stack_pop(&mut self, _val: Word) {
+        self.depth = self.depth.saturating_sub(1);
+    }
+
+    fn on_stack_clear(&mut self, count: usize) {
+        self.depth = self.depth.saturating_sub(count);
+    }
+
+    fn on_register_write(&mut self, reg: Reg, old: Word, new: Word) {
+        if let Some(current) = &mut self.current {
+            current.registers.push((reg, old, new));
+        }
+    }
+
+    fn on_halt(&mut self, _exit_code: i32) {
+        self.flush();
+    }
+}
+
+#[cfg(feature = "std")]
+impl Drop for Tracer {
+    fn drop(&mut self) {
+        self.write_current(false);
+        self.flush();
+    }
+}
+
+/// One line of a trace, an executed instruction
+#[derive(Clone, Debug, PartialEq)]
+pub struct TraceStep {
+    pub ip: usize,
+
+    /// The mnemonic of the instruction and its operands, as the assembler writes them
+    pub op: String,
+    pub operands: Vec<String>,
+
+    /// Depth of the stack after the instruction
+    pub depth: usize,
+
+    /// Every register the instruction wrote, with the old and the new value
+    pub registers: Vec<(Reg, Word, Word)>,
+
+    /// `false` for an instruction that faulted
+    pub completed: bool,
+}
+
+impl TraceStep {
+    fn to_json(&self) -> Json {
+        let operands = self.operands.iter().map(|operand| operand.as_str().into());
+        let mut fields = vec![
+            ("ip", self.ip.into()),
+            ("op", self.op.as_str().into()),
+            ("operands", Json::Arr(operands.collect())),
+            ("depth", self.depth.into()),
+        ];
+        if !self.registers.is_empty() {
+            let changes = self.registers.iter().map(|(reg, old, new)| {
+                let values = Json::Arr(vec![(*old).into(), (*new).into()]);
+                (reg.to_string(), values)
+            });
+            fields.push(("registers", Json::Obj(changes.collect())));
+        }
+        if !self.completed {
+            fields.push(("completed", false.into()));
+        }
+        Json::obj(fields)
+    }
+
+    #[cfg(feature = "std")]
+    fn from_json(json: &Json) -> Option<TraceStep> {
+        let number = |key| json.get(key).and_then(Json::as_i64);
+        let mut registers = Vec::new();
+        if let Some(Json::Obj(changes)) = json.get("registers") {
+            for (name, values) in changes {
+                let [old, new] = values.as_arr()? else {
+                    return None;
+                };
+                let reg = crate::asm::reg(name).ok()?;
+                registers.push((reg, old.as_i64()? as Word, new.as_i64()? as Word));
+            }
+        }
+        let operands = json.get("operands")?.as_arr()?.iter();
+        Some(TraceStep {
+            ip: number("ip")?.try_into().ok()?,
+            op: json.get("op")?.as_str()?.to_string(),
+            operands: operands
+                .map(|operand| operand.as_str().map(String::from))
+                .collect::<Option<_>>()?,
+            depth: number("depth")?.try_into().ok()?,
+            registers,
+            completed: json.get("completed").and_then(Json::as_bool) != Some(false),
+        })
+    }
+}
+
+/// A step as a line of the trace, without the newline
+impl fmt::Display for TraceStep {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.to_json())
+    }
+}
+
+/// A recorded run for [`Machine::replay`](crate::Machine::replay): the steps a [`Tracer`]
+/// wrote and the input the program was given, recorded with
+/// [`Machine::start_recording`](crate::Machine::start_recording).
+#[derive(Clone, Debug, Default, PartialEq)]
+#[cfg(feature = "std")]
+pub struct Trace {
+    pub steps: Vec<TraceStep>,
+    pub input: Recording,
+}
+
+#[cfg(feature = "std")]
+impl Trace {
+    /// Read the lines a [`Tracer`] wrote, to replay with `input`. Blank lines are skipped.
+    pub fn read(reader: impl BufRead, input: Recording) -> io::Result<Trace> {
+        let mut steps = Vec::new();
+        for (idx, line) in reader.lines().enumerate() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            match Json::parse(&line).as_ref().and_then(TraceStep::from_json) {
+                Some(step) => steps.push(step),
+                None => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("line {} is not a trace step", idx + 1),
+                    ))
+                }
+            }
+        }
+        Ok(Trace { steps, input })
+    }
+}
+
+#[cfg(all(test, feature = "std"))]
+mod tests {
+    use super::*;
+    use crate::{Machine, Path};
+    use std::sync::{Arc, Mutex};
+
+    #[derive(Clone, Default)]
+    struct Shared(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Shared {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn trace(program: Vec<Inst>) -> Vec<String> {
+        let out = Shared::default();
+        let mut machine = Machine::new(program);
+        machine.set_observer(Tracer::new(out.clone()));
+        let _ = machine.run();
+        drop(machine);
+        let text = String::from_utf8(out.0.lock().unwrap().clone()).unwrap();
+        text.lines().map(String::from).collect()
+    }
+
+    #[test]
+    fn json_lines() {
+        let lines = trace(vec![
+            Inst::PSH(3),
+            Inst::CPY(Path::REG(Reg::B), Path::STK(0)),
+            Inst::LOOP(Reg::B, 0),
+            Inst::HLT,
+        ]);
+        assert_eq!(
+            lines,
+            [
+                r#"{"ip":0,"op":"psh","operands":["3"],"depth":1}"#,
+                r#"{"ip":1,"op":"cpy","operands":["reg.b","stk[0]"],"depth":1,"registers":{"b":[0,3]}}"#,
+                r#"{"ip":2,"op":"loop","operands":["b","0"],"depth":1,"registers":{"b":[3,2]}}"#,
+                r#"{"ip":3,"op":"hlt","operands":[],"depth":1}"#,
+            ]
+        );
+
+        // emptying the stack takes the depth back to zero at once
+        let lines = trace(vec![Inst::PSH(1), Inst::PSH(2), Inst::CLR, Inst::HLT]);
+        assert_eq!(lines[2], r#"{"ip":2,"op":"clr","operands":[],"depth":0}"#);
+
+        // the faulting instruction is written when the machine goes away
+        let lines = trace(vec![Inst::PSH(1), Inst::ADD, Inst::HLT]);
+        assert_eq!(
+            lines[1],
+            r#"{"ip":1,"op":"add","operands":[],"depth":1,"completed":false}"#
+        );
+    }
+}
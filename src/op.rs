@@ -0,0 +1,104 @@
+//! The program of a machine decoded for its plain run loop.
+//!
+//! The instructions that loop heavy programs spend their time in get an [`Op`] of their own,
+//! eight bytes where an [`Inst`] takes 32 (sixteen with the `word64` feature), with jump targets
+//! made absolute and checked to be in the program. The loop executes an op only when it cannot fail
+//! and without keeping anything to undo it by, since nothing watches the run. Every other
+//! instruction, and an op that would fail, is executed the usual way.
+
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+
+use crate::fuse::superinstruction;
+use crate::{Inst, Path, Reg, Word};
+
+/// An instruction decoded for the plain run loop
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub(crate) enum Op {
+    /// Execute the instruction at this position of the program
+    Inst,
+
+    Psh(Word),
+    Pop,
+    Dup,
+    Add,
+    Sub,
+    Mul,
+    Cmp,
+    Set(Reg, Word),
+    Inc(Reg),
+    Dec(Reg),
+
+    /// `CPY dst src` between registers
+    CpyReg(Reg, Reg),
+
+    /// `CPY reg stk[0]`
+    CpyTop(Reg),
+
+    /// The jumps, to the instruction at the absolute position
+    Jmp(u32),
+    Jez(u32),
+    Jnz(u32),
+    Loop(Reg, u32),
+    Call(u32),
+    Ret,
+
+    /// Superinstructions, standing for the instruction here and the next one: `PSH val` then
+    /// `ADD`, `SUB` or `MUL`
+    AddImm(Word),
+    SubImm(Word),
+    MulImm(Word),
+
+    /// `CPY reg stk[0]` then `POP`, moving the head of the stack to `reg`
+    PopTo(Reg),
+}
+
+/// The op for each instruction of `program`, with the superinstructions where sequences start
+/// if `fuse` is set
+pub(crate) fn decode(program: &[Inst], fuse: bool) -> Arc<[Op]> {
+    let ops: Vec<Op> = program
+        .iter()
+        .enumerate()
+        .map(|(ip, &inst)| {
+            let fused = match program.get(ip + 1) {
+                Some(&next) if fuse => superinstruction(inst, next),
+                _ => None,
+            };
+            fused.unwrap_or_else(|| op(program.len(), ip, inst))
+        })
+        .collect();
+    ops.into()
+}
+
+/// The op of `inst` at `ip` in a program of `len` instructions
+fn op(len: usize, ip: usize, inst: Inst) -> Op {
+    // a zero step continues with the next instruction
+    let target = |step: isize| match (ip as isize).checked_add(step) {
+        Some(target) if step != 0 && target >= 0 && (target as usize) < len => {
+            u32::try_from(target).ok()
+        }
+        _ => None,
+    };
+    let op = match inst {
+        Inst::PSH(val) => Some(Op::Psh(val)),
+        Inst::POP => Some(Op::Pop),
+        Inst::DUP => Some(Op::Dup),
+        Inst::ADD => Some(Op::Add),
+        Inst::SUB => Some(Op::Sub),
+        Inst::MUL => Some(Op::Mul),
+        Inst::CMP => Some(Op::Cmp),
+        Inst::SET(reg, val) => Some(Op::Set(reg, val)),
+        Inst::INC(reg) => Some(Op::Inc(reg)),
+        Inst::DEC(reg) => Some(Op::Dec(reg)),
+        Inst::CPY(Path::REG(dst), Path::REG(src)) => Some(Op::CpyReg(dst, src)),
+        Inst::CPY(Path::REG(dst), Path::STK(0)) => Some(Op::CpyTop(dst)),
+        Inst::JMP(step) => target(step).map(Op::Jmp),
+        Inst::JEZ(step) => target(step).map(Op::Jez),
+        Inst::JNZ(step) => target(step).map(Op::Jnz),
+        Inst::LOOP(reg, step) => target(step).map(|target| Op::Loop(reg, target)),
+        Inst::CALL(step) => target(step).map(Op::Call),
+        Inst::RET => Some(Op::Ret),
+        _ => None,
+    };
+    op.unwrap_or(Op::Inst)
+}
@@ -0,0 +1,132 @@
+//! The registers of a machine, kept in arrays indexed by register instead of a map so that
+//! reading and writing one costs no hashing.
+
+use alloc::vec;
+use alloc::vec::Vec;
+use core::fmt;
+use core::ops::Index;
+
+use crate::{Reg, Word};
+
+/// Register values by register, the form snapshots and reports keep them in. A `HashMap` with
+/// the `std` feature and a `BTreeMap` without.
+#[cfg(feature = "std")]
+pub type RegisterMap = std::collections::HashMap<Reg, Word>;
+#[cfg(not(feature = "std"))]
+pub type RegisterMap = alloc::collections::BTreeMap<Reg, Word>;
+
+/// The named registers, in order
+const NAMED: [Reg; 6] = [Reg::A, Reg::B, Reg::C, Reg::D, Reg::E, Reg::F];
+
+/// Every register a machine has with its value: `A` to `F`, and the numbered registers from
+/// `Reg::R(0)` up to the count the machine was created with. It reads like a map from register
+/// to value, and iterates the registers in that order.
+#[derive(Clone, PartialEq, Eq)]
+pub struct RegisterFile {
+    named: [Word; 6],
+    numbered: Vec<Word>,
+}
+
+impl RegisterFile {
+    /// Registers all zero, with `count` numbered ones
+    pub(crate) fn new(count: usize) -> Self {
+        RegisterFile {
+            named: [0; 6],
+            numbered: vec![0; count],
+        }
+    }
+
+    /// Value of `reg`, `None` if the machine does not have it
+    pub fn get(&self, reg: &Reg) -> Option<&Word> {
+        match *reg {
+            Reg::R(n) => self.numbered.get(n as usize),
+            named => Some(&self.named[RegisterFile::named_slot(named)]),
+        }
+    }
+
+    pub fn get_mut(&mut self, reg: &Reg) -> Option<&mut Word> {
+        match *reg {
+            Reg::R(n) => self.numbered.get_mut(n as usize),
+            named => Some(&mut self.named[RegisterFile::named_slot(named)]),
+        }
+    }
+
+    pub fn contains_key(&self, reg: &Reg) -> bool {
+        self.get(reg).is_some()
+    }
+
+    /// Set `reg` to `val` and return its old value. A register the machine does not have is
+    /// not added, it stays missing and `None` is returned.
+    pub fn insert(&mut self, reg: Reg, val: Word) -> Option<Word> {
+        self.get_mut(&reg).map(|slot| core::mem::replace(slot, val))
+    }
+
+    /// Number of registers, six more than the numbered ones
+    pub fn len(&self) -> usize {
+        self.named.len() + self.numbered.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Every register with its value, `A` to `F` first and then the numbered ones in order
+    pub fn iter(&self) -> impl Iterator<Item = (Reg, Word)> + '_ {
+        let named = NAMED.into_iter().zip(self.named);
+        let numbered = self.numbered.iter().enumerate();
+        named.chain(numbered.map(|(n, &val)| (Reg::R(n as u8), val)))
+    }
+
+    /// Every register's value, in the order of [`iter`](RegisterFile::iter)
+    pub fn values(&self) -> impl Iterator<Item = &Word> {
+        self.named.iter().chain(&self.numbered)
+    }
+
+    /// The registers as a map
+    pub fn to_map(&self) -> RegisterMap {
+        self.iter().collect()
+    }
+
+    /// Put back the values of `map`. A register missing from it becomes zero and one the machine
+    /// does not have is left out.
+    pub(crate) fn replace(&mut self, map: &RegisterMap) {
+        for reg in NAMED {
+            self.named[RegisterFile::named_slot(reg)] = map.get(&reg).copied().unwrap_or(0);
+        }
+        for (n, slot) in self.numbered.iter_mut().enumerate() {
+            *slot = map.get(&Reg::R(n as u8)).copied().unwrap_or(0);
+        }
+    }
+
+    pub(crate) fn clear(&mut self) {
+        self.named = [0; 6];
+        self.numbered.fill(0);
+    }
+
+    fn named_slot(reg: Reg) -> usize {
+        match reg {
+            Reg::A => 0,
+            Reg::B => 1,
+            Reg::C => 2,
+            Reg::D => 3,
+            Reg::E => 4,
+            Reg::F => 5,
+            Reg::R(_) => unreachable!("numbered registers have no named slot"),
+        }
+    }
+}
+
+impl Index<&Reg> for RegisterFile {
+    type Output = Word;
+
+    fn index(&self, reg: &Reg) -> &Word {
+        self.get(reg)
+            .unwrap_or_else(|| panic!("the machine has no register {reg}"))
+    }
+}
+
+impl fmt::Debug for RegisterFile {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_map().entries(self.iter()).finish()
+    }
+}
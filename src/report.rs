@@ -0,0 +1,112 @@
+//! Summary of a finished run.
+
+use core::time::Duration;
+
+use crate::error::VmError;
+use crate::registers::RegisterMap;
+use crate::Word;
+
+/// Why a run stopped.
+#[derive(Clone, Debug, PartialEq)]
+pub enum HaltCause {
+    /// The program executed `HLT` or `EXIT`
+    Halted,
+
+    /// The machine ran out of fuel
+    OutOfFuel,
+
+    /// The tick callback paused the machine
+    Paused,
+
+    /// The run took longer than its timeout
+    TimedOut,
+
+    /// The program yielded a value with `YLD`
+    Yielded(Word),
+
+    /// `RCV` found nothing on this port
+    WaitingOnPort(u8),
+
+    /// The program faulted, the machine is left as it was when the error happened
+    Fault(VmError),
+}
+
+/// Why a resumable run returned without an error. Unless the program halted, the machine can be
+/// run again to pick up where it stopped.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum RunOutcome {
+    /// The program executed `HLT` or `EXIT`
+    Halted,
+
+    /// The fuel ran out before the next instruction
+    OutOfFuel,
+
+    /// The tick callback asked to pause
+    Paused,
+
+    /// The deadline of [`Machine::run_with_timeout`](crate::Machine::run_with_timeout) passed
+    TimedOut,
+
+    /// The program yielded a value with `YLD`, resuming continues after the `YLD`
+    Yielded(Word),
+
+    /// `RCV` found nothing on this port. The machine is left on the `RCV`, so resuming it once
+    /// the peer has sent something picks the value up. When every machine of a system is
+    /// waiting the system is deadlocked.
+    WaitingOnPort(u8),
+}
+
+impl From<RunOutcome> for HaltCause {
+    fn from(outcome: RunOutcome) -> Self {
+        match outcome {
+            RunOutcome::Halted => HaltCause::Halted,
+            RunOutcome::OutOfFuel => HaltCause::OutOfFuel,
+            RunOutcome::Paused => HaltCause::Paused,
+            RunOutcome::TimedOut => HaltCause::TimedOut,
+            RunOutcome::Yielded(val) => HaltCause::Yielded(val),
+            RunOutcome::WaitingOnPort(port) => HaltCause::WaitingOnPort(port),
+        }
+    }
+}
+
+/// What happened during a call to [`Machine::run_report`](crate::Machine::run_report).
+#[derive(Clone, Debug)]
+pub struct ExecutionReport {
+    /// Where the program started
+    pub entry: usize,
+
+    /// Instructions executed by the machine so far, counting the one that halted it
+    pub instructions: u64,
+
+    pub halt: HaltCause,
+
+    /// Wall time spent in the run
+    pub elapsed: Duration,
+
+    /// Exit code if the program halted, see [`Machine::exit_code`](crate::Machine::exit_code)
+    pub exit_code: Option<i32>,
+
+    /// Head of the stack when the run stopped
+    pub stack_top: Option<Word>,
+
+    /// Register values when the run stopped
+    pub registers: RegisterMap,
+}
+
+/// How a program ended, from [`Machine::halt_state`](crate::Machine::halt_state).
+#[derive(Clone, Debug, PartialEq)]
+pub struct HaltState {
+    /// The operand of the `EXIT` the program halted with, or 0 for `HLT`
+    pub exit_code: i32,
+
+    pub stack_top: Option<Word>,
+
+    pub registers: RegisterMap,
+}
+
+impl ExecutionReport {
+    /// True if the run ended with `HLT`
+    pub fn halted(&self) -> bool {
+        self.halt == HaltCause::Halted
+    }
+}
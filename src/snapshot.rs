@@ -0,0 +1,47 @@
+//! Copies of a machine's state, taken with [`Machine::snapshot`](crate::Machine::snapshot) and
+//! put back with [`Machine::restore`](crate::Machine::restore).
+
+use alloc::vec::Vec;
+
+use crate::registers::RegisterMap;
+use crate::{Flags, Frame, Type, Word};
+
+/// Everything a program can change about a machine at one point of its run. Restoring it later
+/// rewinds the machine to that point, so a long computation can be checkpointed or a debugger
+/// can step backwards.
+///
+/// What is attached to the machine (the program, input, output, ports, handlers) is not part
+/// of a snapshot.
+#[derive(Clone, Debug, PartialEq)]
+pub struct MachineSnapshot {
+    /// Index of the next instruction to execute
+    pub ip: usize,
+
+    /// Values on the stack, the bottom first
+    pub stack: Vec<Word>,
+
+    /// Types of the values on the stack of a typed machine, empty if it is not typed
+    pub types: Vec<Type>,
+
+    pub registers: RegisterMap,
+
+    pub flags: Flags,
+
+    /// Calls in progress, the latest last
+    pub calls: Vec<Frame>,
+
+    /// Frame pointer of the current call
+    pub fp: usize,
+
+    /// Data memory
+    pub memory: Vec<Word>,
+
+    /// Heap arrays, the one with handle `h` at `h - 1`, `None` where an array was freed
+    pub heap: Vec<Option<Vec<Word>>>,
+
+    /// Instructions executed so far
+    pub executed: u64,
+
+    /// Exit code if the program had halted
+    pub exit_code: Option<i32>,
+}
@@ -0,0 +1,241 @@
+//! The stack of a machine and what its values are kept in.
+
+use alloc::vec::Vec;
+use core::fmt;
+
+use crate::error::{PathError, StackError};
+use crate::Word;
+
+/// What a machine keeps the values on its stack in, the type parameter of
+/// [`Machine`](crate::Machine). A `Vec<Word>`, the default, is allocated with the machine and
+/// can grow up to its `MachineConfig::max_stack_size`. A [`FixedStack`] is an array inside the
+/// machine, sized at compile time, which never allocates. It must be `Send` so that the machine
+/// stays `Send`.
+pub trait StackStorage: fmt::Debug + Send {
+    /// Empty storage, with room set aside for `capacity` values where that means something
+    fn with_capacity(capacity: usize) -> Self
+    where
+        Self: Sized;
+
+    /// Most values it can hold, whatever the machine's config says
+    fn max_len(&self) -> usize;
+
+    /// The values, the bottom first
+    fn as_slice(&self) -> &[Word];
+
+    fn as_mut_slice(&mut self) -> &mut [Word];
+
+    /// Add `val` on top. The machine only pushes when there are fewer than `max_len` values.
+    fn push(&mut self, val: Word);
+
+    /// Take the value on top, `None` when empty
+    fn pop(&mut self) -> Option<Word>;
+
+    /// Keep the `len` values at the bottom and drop the rest, nothing happens when there are
+    /// not more than `len`
+    fn truncate(&mut self, len: usize);
+}
+
+impl StackStorage for Vec<Word> {
+    fn with_capacity(capacity: usize) -> Self {
+        Vec::with_capacity(capacity)
+    }
+
+    fn max_len(&self) -> usize {
+        usize::MAX
+    }
+
+    fn as_slice(&self) -> &[Word] {
+        self
+    }
+
+    fn as_mut_slice(&mut self) -> &mut [Word] {
+        self
+    }
+
+    fn push(&mut self, val: Word) {
+        Vec::push(self, val)
+    }
+
+    fn pop(&mut self) -> Option<Word> {
+        Vec::pop(self)
+    }
+
+    fn truncate(&mut self, len: usize) {
+        Vec::truncate(self, len)
+    }
+}
+
+/// A stack of at most `N` values kept in an array, for a machine created with
+/// [`Machine::new_with_storage`](crate::Machine::new_with_storage). The machine holds the
+/// array itself, so its stack costs no allocation, and a stack size in the machine's config
+/// larger than `N` is cut down to `N`.
+#[derive(Clone)]
+pub struct FixedStack<const N: usize> {
+    values: [Word; N],
+    len: usize,
+}
+
+impl<const N: usize> StackStorage for FixedStack<N> {
+    fn with_capacity(_capacity: usize) -> Self {
+        FixedStack {
+            values: [0; N],
+            len: 0,
+        }
+    }
+
+    fn max_len(&self) -> usize {
+        N
+    }
+
+    fn as_slice(&self) -> &[Word] {
+        &self.values[..self.len]
+    }
+
+    fn as_mut_slice(&mut self) -> &mut [Word] {
+        &mut self.values[..self.len]
+    }
+
+    fn push(&mut self, val: Word) {
+        self.values[self.len] = val;
+        self.len += 1;
+    }
+
+    fn pop(&mut self) -> Option<Word> {
+        self.len = self.len.checked_sub(1)?;
+        Some(self.values[self.len])
+    }
+
+    fn truncate(&mut self, len: usize) {
+        self.len = self.len.min(len);
+    }
+}
+
+/// Only the values on the stack, like a `Vec` of them
+impl<const N: usize> fmt::Debug for FixedStack<N> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_list().entries(self.as_slice()).finish()
+    }
+}
+
+#[derive(Debug)]
+pub(crate) struct Stack<S = Vec<Word>> {
+    pub(crate) memory: S,
+    pub(crate) sp: isize,
+    /// Most elements the stack holds
+    limit: usize,
+}
+
+impl<S: StackStorage> Stack<S> {
+    /// A stack with room for `capacity` elements that grows up to `limit` elements, or as many
+    /// as the storage holds if that is fewer
+    pub(crate) fn new(capacity: usize, limit: usize) -> Self {
+        let memory = S::with_capacity(capacity);
+        Stack {
+            limit: limit.min(memory.max_len()),
+            memory,
+            sp: -1,
+        }
+    }
+
+    /// The values, the bottom first
+    pub(crate) fn values(&self) -> &[Word] {
+        self.memory.as_slice()
+    }
+
+    pub(crate) fn values_mut(&mut self) -> &mut [Word] {
+        self.memory.as_mut_slice()
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.values().len()
+    }
+
+    /// Elements the stack grows up to
+    pub(crate) fn limit(&self) -> usize {
+        self.limit
+    }
+
+    pub(crate) fn is_full(&self) -> bool {
+        self.len() >= self.limit
+    }
+
+    /// Position in `memory` of a stack index relative to the head of the stack
+    fn position(&self, idx: isize) -> Option<usize> {
+        let pos = self.sp.checked_sub(idx)?;
+        if pos >= 0 && (pos as usize) < self.len() {
+            Some(pos as usize)
+        } else {
+            None
+        }
+    }
+
+    fn bad_access(&self, idx: isize) -> PathError {
+        PathError::StackErr {
+            requested: idx,
+            sp: self.sp,
+        }
+    }
+
+    /// get value at stack index
+    pub(crate) fn get_at_idx(&self, idx: isize) -> Result<Word, PathError> {
+        match self.position(idx) {
+            Some(pos) => Ok(self.values()[pos]),
+            None => Err(self.bad_access(idx)),
+        }
+    }
+
+    /// set value at stack index
+    pub(crate) fn set_at_idx(&mut self, idx: isize, val: Word) -> Result<(), PathError> {
+        match self.position(idx) {
+            Some(pos) => {
+                self.values_mut()[pos] = val;
+                Ok(())
+            }
+            None => Err(self.bad_access(idx)),
+        }
+    }
+
+    /// Pop from the stack
+    pub(crate) fn pop(&mut self) -> Result<Word, StackError> {
+        match self.memory.pop() {
+            Some(val) => {
+                self.sp -= 1;
+                Ok(val)
+            }
+            None => Err(StackError::PopErr),
+        }
+    }
+
+    /// Replace every element with `values`, the bottom first, keeping as many as fit the
+    /// storage
+    pub(crate) fn replace(&mut self, values: &[Word]) {
+        self.memory.truncate(0);
+        for &val in values.iter().take(self.memory.max_len()) {
+            self.memory.push(val);
+        }
+        self.sp = self.len() as isize - 1;
+    }
+
+    /// Drop every element above the `len` at the bottom
+    pub(crate) fn truncate(&mut self, len: usize) {
+        self.memory.truncate(len);
+        self.sp = self.len() as isize - 1;
+    }
+
+    /// Drop every element, leaving the stack empty
+    pub(crate) fn clear(&mut self) {
+        self.truncate(0);
+    }
+
+    /// Push something on to the stack
+    pub(crate) fn push(&mut self, value: Word) -> Result<(), StackError> {
+        if !self.is_full() {
+            self.sp += 1;
+            self.memory.push(value);
+            Ok(())
+        } else {
+            Err(StackError::PushErr)
+        }
+    }
+}
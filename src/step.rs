@@ -0,0 +1,112 @@
+//! Result of executing a single instruction with [`Machine::step`](crate::Machine::step).
+
+use alloc::vec::Vec;
+
+use crate::{Flags, Frame, MachineSnapshot, Reg, Word};
+
+/// Whether the machine can keep going after a step.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum StepStatus {
+    Running,
+
+    /// The instruction was a `YLD` of this value
+    Yielded(Word),
+
+    /// The instruction was a `RCV` on this port with nothing to receive, it was not executed
+    WaitingOnPort(u8),
+
+    Halted,
+}
+
+/// A register write, with the value it replaced.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct RegisterChange {
+    pub reg: Reg,
+    pub old: Word,
+    pub new: Word,
+}
+
+/// A write to an existing stack slot, `offset` counts from the head of the stack like
+/// `Path::STK` does.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct SlotChange {
+    pub offset: isize,
+    pub old: Word,
+    pub new: Word,
+}
+
+/// A write to a word of data memory, not to a device mapped over it.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct MemoryChange {
+    pub addr: usize,
+    pub old: Word,
+    pub new: Word,
+}
+
+/// A write to element `index` of the heap array `handle`.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct HeapChange {
+    pub handle: Word,
+    pub index: Word,
+    pub old: Word,
+    pub new: Word,
+}
+
+/// The flags set by a `CMP`, with the ones they replaced.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct FlagsChange {
+    pub old: Flags,
+    pub new: Flags,
+}
+
+/// What a single instruction changed. Filled in by the interpreter as it goes, so it costs
+/// nothing beyond the values it records.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct StateDelta {
+    pub register: Option<RegisterChange>,
+
+    /// Values popped, in the order they came off the stack
+    pub popped: Vec<Word>,
+
+    /// Values pushed, in the order they went on
+    pub pushed: Vec<Word>,
+
+    pub stack_write: Option<SlotChange>,
+
+    pub memory_write: Option<MemoryChange>,
+
+    pub heap_write: Option<HeapChange>,
+
+    pub flags: Option<FlagsChange>,
+
+    /// Where `ip` moved to, if it did not just move on to the next instruction
+    pub jump: Option<usize>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct StepOutcome {
+    pub status: StepStatus,
+    pub delta: StateDelta,
+}
+
+/// What a debugger keeps of an executed instruction to go back over it, see
+/// [`Machine::step_undoable`](crate::Machine::step_undoable).
+pub(crate) enum PastStep {
+    /// The whole state before a `SYS` or an `ALLOC`, whose changes a delta does not cover, or
+    /// before any instruction of a typed machine
+    State(MachineSnapshot),
+
+    /// The state before the instruction that its delta does not record, and the delta
+    Delta {
+        ip: usize,
+        executed: u64,
+        exit_code: Option<i32>,
+        fp: usize,
+
+        /// Calls in progress, and the latest of them, which a `RET` pops
+        calls: usize,
+        frame: Option<Frame>,
+
+        delta: StateDelta,
+    },
+}
@@ -0,0 +1,200 @@
+//! Calls into the host: system calls made by `SYS` and named host functions called by `HCALL`.
+
+use alloc::boxed::Box;
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+
+use crate::error::{Fault, OutputError, PathError, StackError, VmError};
+use crate::io::SysEffect;
+use crate::stack::StackStorage;
+use crate::{Inst, Machine, Reg, Type, Value, Word};
+
+/// The host side of `SYS`. The handler is given the call number and reads its arguments from and
+/// writes its results to the machine through a [`SysCtx`], so each call decides its own
+/// convention. It must be `Send` so that the machine stays `Send`.
+pub trait SyscallHandler: Send {
+    fn syscall(&mut self, number: u32, ctx: &mut SysCtx) -> Result<(), VmError>;
+}
+
+/// A host function for `HCALL`. It gets its arguments in the order they were pushed and
+/// returns the value to push. It must be `Send` so that the machine stays `Send`.
+pub type HostFn = Box<dyn FnMut(&[Word]) -> Word + Send>;
+
+/// A registered host function and how many arguments it pops
+pub(crate) struct HostFunction {
+    pub(crate) arity: usize,
+    pub(crate) f: HostFn,
+}
+
+/// What a system call reaches of a machine, whatever its stack is kept in
+pub(crate) trait SysMachine {
+    fn pop(&mut self) -> Result<Word, StackError>;
+    fn push_typed(&mut self, val: Word, ty: Type) -> Result<(), StackError>;
+    fn get_reg_value(&self, reg: &Reg) -> Result<Word, PathError>;
+    fn set_reg_value(&mut self, reg: Reg, val: Word) -> Result<(), PathError>;
+    fn string(&self, id: Word) -> Option<&str>;
+    fn write_output(&mut self, text: &str) -> Result<(), OutputError>;
+
+    /// Depth of the stack
+    fn depth(&self) -> usize;
+}
+
+impl<S: StackStorage> SysMachine for Machine<S> {
+    fn pop(&mut self) -> Result<Word, StackError> {
+        Machine::pop(self)
+    }
+
+    fn push_typed(&mut self, val: Word, ty: Type) -> Result<(), StackError> {
+        Machine::push_typed(self, val, ty)
+    }
+
+    fn get_reg_value(&self, reg: &Reg) -> Result<Word, PathError> {
+        Machine::get_reg_value(self, reg)
+    }
+
+    fn set_reg_value(&mut self, reg: Reg, val: Word) -> Result<(), PathError> {
+        Machine::set_reg_value(self, reg, val)
+    }
+
+    fn string(&self, id: Word) -> Option<&str> {
+        Machine::string(self, id)
+    }
+
+    fn write_output(&mut self, text: &str) -> Result<(), OutputError> {
+        Machine::write_output(self, text)
+    }
+
+    fn depth(&self) -> usize {
+        self.stack().len()
+    }
+}
+
+/// The machine as a system call sees it. Failures are errors raised by the `SYS` instruction, so
+/// a handler can pass them on with `?`.
+pub struct SysCtx<'a> {
+    pub(crate) machine: &'a mut dyn SysMachine,
+    pub(crate) ip: usize,
+    pub(crate) number: u32,
+
+    /// What the call did so far, while the machine is recording
+    pub(crate) effects: Option<Vec<SysEffect>>,
+}
+
+impl SysCtx<'_> {
+    /// Pop an argument off the stack
+    pub fn pop(&mut self) -> Result<Word, VmError> {
+        let val = self.machine.pop();
+        let val = val.map_err(|e| self.fault(Fault::Stack(e)))?;
+        self.record(SysEffect::Pop);
+        Ok(val)
+    }
+
+    /// Push a result on to the stack
+    pub fn push(&mut self, val: Word) -> Result<(), VmError> {
+        self.push_value(Value::Int(val))
+    }
+
+    /// Push a result with its type, which a typed machine tags the slot with. A replayed
+    /// call pushes ints.
+    pub fn push_value(&mut self, value: Value) -> Result<(), VmError> {
+        let val = value.word();
+        let pushed = self.machine.push_typed(val, value.ty());
+        pushed.map_err(|e| self.fault(Fault::Stack(e)))?;
+        self.record(SysEffect::Push(val));
+        Ok(())
+    }
+
+    pub fn reg(&self, reg: Reg) -> Result<Word, VmError> {
+        let val = self.machine.get_reg_value(&reg);
+        val.map_err(|e| self.fault(Fault::Path(e)))
+    }
+
+    pub fn set_reg(&mut self, reg: Reg, val: Word) -> Result<(), VmError> {
+        let set = self.machine.set_reg_value(reg, val);
+        set.map_err(|e| self.fault(Fault::Path(e)))?;
+        self.record(SysEffect::SetReg(reg, val));
+        Ok(())
+    }
+
+    /// String `id` of the machine's string table
+    pub fn string(&self, id: Word) -> Result<&str, VmError> {
+        let string = self.machine.string(id);
+        string.ok_or_else(|| self.fault(Fault::NoString(id)))
+    }
+
+    /// Write `text` to the machine's output, where `OUT` writes
+    pub fn write(&mut self, text: &str) -> Result<(), VmError> {
+        let written = self.machine.write_output(text);
+        written.map_err(|e| self.fault(Fault::Output(e)))
+    }
+
+    /// The error for a call number the handler does not know
+    pub fn unknown(&self) -> VmError {
+        self.fault(Fault::NoSyscall(self.number))
+    }
+
+    fn record(&mut self, effect: SysEffect) {
+        if let Some(effects) = &mut self.effects {
+            effects.push(effect);
+        }
+    }
+
+    fn fault(&self, fault: Fault) -> VmError {
+        let depth = self.machine.depth();
+        fault.at(self.ip, Inst::SYS(self.number), depth)
+    }
+}
+
+/// System calls that build up a string from strings of the string table, numbers and
+/// characters, and then print it, for output `PRINTS` and `OUT` can not write alone. The
+/// calls are numbered from `0x100` so that a handler of its own can keep the low numbers and
+/// pass the rest on to this one.
+#[derive(Clone, Debug, Default)]
+pub struct StringSyscalls {
+    /// The string built so far
+    pub text: String,
+}
+
+impl StringSyscalls {
+    /// Pop a string id and append that string
+    pub const APPEND: u32 = 0x100;
+
+    /// Pop a value and append it in decimal
+    pub const APPEND_INT: u32 = 0x101;
+
+    /// Pop a Unicode code point and append the character, U+FFFD for a value that is not one
+    pub const APPEND_CHAR: u32 = 0x102;
+
+    /// Push the length of the string so far, in characters
+    pub const LEN: u32 = 0x103;
+
+    /// Write the string so far to the output, without a newline, and start a new one
+    pub const PRINT: u32 = 0x104;
+}
+
+impl SyscallHandler for StringSyscalls {
+    fn syscall(&mut self, number: u32, ctx: &mut SysCtx) -> Result<(), VmError> {
+        match number {
+            StringSyscalls::APPEND => {
+                let id = ctx.pop()?;
+                self.text.push_str(ctx.string(id)?);
+            }
+            StringSyscalls::APPEND_INT => {
+                let val = ctx.pop()?;
+                self.text.push_str(&val.to_string());
+            }
+            StringSyscalls::APPEND_CHAR => {
+                let code = ctx.pop()?;
+                let ch = u32::try_from(code).ok().and_then(char::from_u32);
+                self.text.push(ch.unwrap_or(char::REPLACEMENT_CHARACTER));
+            }
+            StringSyscalls::LEN => ctx.push(self.text.chars().count() as Word)?,
+            StringSyscalls::PRINT => {
+                ctx.write(&self.text)?;
+                self.text.clear();
+            }
+            _ => return Err(ctx.unknown()),
+        }
+        Ok(())
+    }
+}
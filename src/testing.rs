@@ -0,0 +1,227 @@
+//! Assertions for testing programs, enabled by the `test-util` feature.
+//!
+//! They read the machine through its public accessors and panic with both the expected and the
+//! actual state laid out, so a failing test shows what differs without digging through a dump.
+
+use std::fmt::Write;
+use std::sync::Arc;
+
+use crate::{Flags, Inst, Machine, Reg, RunOutcome, VmError, Word};
+
+/// Panic unless the stack holds exactly `expected`, the bottom first
+#[track_caller]
+pub fn assert_stack_eq(machine: &Machine, expected: &[Word]) {
+    if machine.stack() != expected {
+        panic!(
+            "stack differs\n  expected: {expected:?}\n     found: {:?}\n{}",
+            machine.stack(),
+            State::of(machine)
+        );
+    }
+}
+
+/// Panic unless `reg` holds `expected`. A register the machine does not have reads as zero.
+#[track_caller]
+pub fn assert_reg(machine: &Machine, reg: Reg, expected: Word) {
+    let found = machine.results_named(&[reg])[0];
+    if found != expected {
+        panic!(
+            "register {reg} differs\n  expected: {expected}\n     found: {found}\n{}",
+            State::of(machine)
+        );
+    }
+}
+
+/// Run `program` on a fresh machine and return the error it stops with, panicking if it stops
+/// without one
+#[track_caller]
+pub fn run_expect_err(program: impl Into<Arc<[Inst]>>) -> VmError {
+    let mut machine = Machine::new(program);
+    match machine.run() {
+        Ok(outcome) => panic!(
+            "program stopped with {outcome:?} instead of an error\n{}",
+            State::of(&machine)
+        ),
+        Err(e) => e,
+    }
+}
+
+/// Run both programs on fresh machines and panic unless they end the same way with the same
+/// stack, registers, flags and data memory. Where the instruction pointer ends up and how many
+/// instructions ran are not compared, so a program can be checked against an optimized or
+/// relinked version of itself.
+#[track_caller]
+pub fn assert_same_final_state(a: impl Into<Arc<[Inst]>>, b: impl Into<Arc<[Inst]>>) {
+    let mut first = Machine::new(a);
+    let mut second = Machine::new(b);
+    let first_end = End::of(first.run());
+    let second_end = End::of(second.run());
+    let first_state = State::of(&first);
+    let second_state = State::of(&second);
+
+    let mut diff = String::new();
+    if first_end != second_end {
+        let _ = writeln!(diff, "  first stopped with {first_end:?}");
+        let _ = writeln!(diff, "  second stopped with {second_end:?}");
+    }
+    first_state.diff(&second_state, &mut diff);
+    if !diff.is_empty() {
+        panic!("final states differ\n{diff}first program:\n{first_state}second program:\n{second_state}");
+    }
+}
+
+/// How a run ended, errors compared by their fault since the two programs can fail at
+/// different instruction pointers
+#[derive(Debug, PartialEq)]
+enum End {
+    Stopped(RunOutcome),
+    Failed(String),
+}
+
+impl End {
+    fn of(result: Result<RunOutcome, VmError>) -> Self {
+        match result {
+            Ok(outcome) => End::Stopped(outcome),
+            Err(e) => match e.fault() {
+                Some(fault) => End::Failed(fault.to_string()),
+                None => End::Failed(e.to_string()),
+            },
+        }
+    }
+}
+
+/// The parts of a machine the assertions compare
+struct State {
+    ip: usize,
+    stack: Vec<Word>,
+    registers: Vec<(Reg, Word)>,
+    flags: Flags,
+    memory: Vec<Word>,
+}
+
+impl State {
+    fn of(machine: &Machine) -> Self {
+        State {
+            ip: machine.ip(),
+            stack: machine.stack().to_vec(),
+            registers: machine.sorted_registers(),
+            flags: machine.flags(),
+            memory: machine.memory().to_vec(),
+        }
+    }
+
+    /// Write a line to `out` for every difference between `self` and `other`
+    fn diff(&self, other: &State, out: &mut String) {
+        if self.stack != other.stack {
+            let _ = writeln!(out, "  stack: {:?} != {:?}", self.stack, other.stack);
+        }
+        for (reg, val) in &self.registers {
+            let theirs = other
+                .registers
+                .iter()
+                .find(|(r, _)| r == reg)
+                .map_or(0, |(_, v)| *v);
+            if *val != theirs {
+                let _ = writeln!(out, "  register {reg}: {val} != {theirs}");
+            }
+        }
+        if self.flags != other.flags {
+            let _ = writeln!(out, "  flags: {:?} != {:?}", self.flags, other.flags);
+        }
+        let len = self.memory.len().max(other.memory.len());
+        for addr in 0..len {
+            let ours = self.memory.get(addr).copied().unwrap_or(0);
+            let theirs = other.memory.get(addr).copied().unwrap_or(0);
+            if ours != theirs {
+                let _ = writeln!(out, "  memory[{addr}]: {ours} != {theirs}");
+            }
+        }
+    }
+}
+
+impl std::fmt::Display for State {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "  ip: {}", self.ip)?;
+        writeln!(f, "  stack (bottom first): {:?}", self.stack)?;
+        write!(f, "  registers:")?;
+        for (reg, val) in &self.registers {
+            write!(f, " {reg}={val}")?;
+        }
+        writeln!(f)?;
+        writeln!(f, "  flags: {:?}", self.flags)?;
+        let used: Vec<String> = self
+            .memory
+            .iter()
+            .enumerate()
+            .filter(|(_, val)| **val != 0)
+            .map(|(addr, val)| format!("[{addr}]={val}"))
+            .collect();
+        writeln!(f, "  memory (non-zero): {}", used.join(" "))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Fault, Path};
+    use std::panic;
+
+    fn panic_message(f: impl FnOnce()) -> String {
+        let err = panic::catch_unwind(panic::AssertUnwindSafe(f)).unwrap_err();
+        match err.downcast::<String>() {
+            Ok(message) => *message,
+            Err(_) => panic!("panic payload was not a string"),
+        }
+    }
+
+    #[test]
+    fn failures_show_the_state() {
+        let mut machine = Machine::new(vec![Inst::PSH(4), Inst::SET(Reg::A, 3), Inst::HLT]);
+        machine.run().unwrap();
+        assert_stack_eq(&machine, &[4]);
+        assert_reg(&machine, Reg::A, 3);
+
+        let message = panic_message(|| assert_stack_eq(&machine, &[4, 5]));
+        assert!(message.starts_with("stack differs\n  expected: [4, 5]\n     found: [4]\n"));
+        assert!(message.contains("  registers: a=3 b=0"));
+
+        let message = panic_message(|| assert_reg(&machine, Reg::A, 2));
+        assert!(message.starts_with("register a differs\n  expected: 2\n     found: 3\n"));
+    }
+
+    #[test]
+    fn expected_errors() {
+        let err = run_expect_err(vec![Inst::PSH(1), Inst::PSH(0), Inst::DIV, Inst::HLT]);
+        assert_eq!(err.fault(), Some(&Fault::DivideByZero));
+
+        let message = panic_message(|| {
+            run_expect_err(vec![Inst::HLT]);
+        });
+        assert!(message.starts_with("program stopped with Halted instead of an error"));
+    }
+
+    #[test]
+    fn differential_runs() {
+        // the same result computed with a different instruction sequence
+        assert_same_final_state(
+            vec![Inst::PSH(3), Inst::PSH(3), Inst::MUL, Inst::HLT],
+            vec![
+                Inst::PSH(3),
+                Inst::PSH(0),
+                Inst::CPY(Path::STK(0), Path::STK(1)),
+                Inst::MUL,
+                Inst::HLT,
+            ],
+        );
+
+        let message = panic_message(|| {
+            assert_same_final_state(
+                vec![Inst::PSH(9), Inst::SET(Reg::B, 1), Inst::HLT],
+                vec![Inst::PSH(8), Inst::HLT],
+            )
+        });
+        assert!(message.contains("  stack: [9] != [8]\n"));
+        assert!(message.contains("  register b: 1 != 0\n"));
+        assert!(!message.contains("stopped with"));
+    }
+}
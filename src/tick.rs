@@ -0,0 +1,59 @@
+//! Periodic callbacks into the host while a program runs.
+
+use alloc::boxed::Box;
+
+use crate::{RegisterFile, Word};
+
+/// What the tick callback wants the machine to do next.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum TickAction {
+    Continue,
+
+    /// Stop the run with `RunOutcome::Paused`, the machine can be resumed later
+    Pause,
+}
+
+/// The tick callback, it must be `Send` so that the machine stays `Send`.
+pub type TickFn = Box<dyn FnMut(&mut TickCtx) -> TickAction + Send>;
+
+/// A view of the machine handed to the tick callback.
+pub struct TickCtx<'a> {
+    pub(crate) ip: usize,
+    pub(crate) instructions: u64,
+    pub(crate) stack_top: Option<Word>,
+    pub(crate) registers: &'a RegisterFile,
+    pub(crate) tick: u64,
+}
+
+impl TickCtx<'_> {
+    /// How many times the callback has fired, counting this time
+    pub fn tick(&self) -> u64 {
+        self.tick
+    }
+
+    /// Index of the next instruction to execute
+    pub fn ip(&self) -> usize {
+        self.ip
+    }
+
+    /// Instructions executed by the machine so far
+    pub fn instructions(&self) -> u64 {
+        self.instructions
+    }
+
+    pub fn stack_top(&self) -> Option<Word> {
+        self.stack_top
+    }
+
+    pub fn registers(&self) -> &RegisterFile {
+        self.registers
+    }
+}
+
+/// A registered tick callback and where it is in its interval
+pub(crate) struct Ticker {
+    pub(crate) interval: u64,
+    pub(crate) since: u64,
+    pub(crate) count: u64,
+    pub(crate) callback: TickFn,
+}
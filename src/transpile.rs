@@ -0,0 +1,665 @@
+//! Translation of a program into the source of a standalone Rust program that does what
+//! `vyantra run` does with it.
+//!
+//! The stack becomes a `Vec`, the registers, flags and frame pointer become locals of `main`,
+//! and every instruction becomes an arm of a `match` on the instruction pointer, so the control
+//! flow of the program stays as it was. Operands known when translating are resolved then:
+//! constants, strings, jump targets and the faults they are sure to cause. The translated
+//! program runs like a machine with the default [`MachineConfig`](crate::MachineConfig), and
+//! when it fails it prints the error the machine would have failed with, without the context.
+//!
+//! Instructions that need the host (`SYS`, `HCALL`, `SND`, `RCV`) or the heap do not translate.
+//! [`transpile_c`] translates into C instead, for a host to embed.
+
+mod c;
+
+pub use c::transpile_c;
+
+use std::error::Error;
+use std::fmt::{self, Write};
+
+use crate::asm::Program;
+use crate::{
+    float_word, Cond, Fault, Inst, Path, PathError, Reg, StackError, Word, CALL_DEPTH,
+    FIXED_POINT_BITS, GP_REGISTERS, MEMORY_SIZE, STACK_SIZE,
+};
+
+/// Why a program does not translate.
+#[derive(Clone, Debug, PartialEq)]
+pub enum TranspileError {
+    /// The instruction at `ip` has no translation
+    Unsupported { ip: usize, inst: Inst },
+
+    /// The data segment is larger than data memory
+    DataTooLarge { len: usize, size: usize },
+}
+
+impl Error for TranspileError {}
+
+impl fmt::Display for TranspileError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TranspileError::Unsupported { ip, inst } => {
+                write!(f, "`{inst}` at ip {ip} cannot be translated")
+            }
+            TranspileError::DataTooLarge { len, size } => write!(
+                f,
+                "data segment of {len} words does not fit {size} words of data memory"
+            ),
+        }
+    }
+}
+
+/// The source of a Rust program doing what `program` does
+pub fn transpile(program: &Program) -> Result<String, TranspileError> {
+    if program.data.len() > MEMORY_SIZE {
+        return Err(TranspileError::DataTooLarge {
+            len: program.data.len(),
+            size: MEMORY_SIZE,
+        });
+    }
+    let mut out = String::new();
+    prelude(&mut out, program);
+    for (ip, &inst) in program.code.iter().enumerate() {
+        let arm = Arm {
+            program,
+            ip,
+            inst,
+            at: format!("{:?}", format!("`{inst}` at ip {ip}")),
+        };
+        let body = arm.body()?;
+        let _ = writeln!(out, "            // {inst}");
+        let _ = writeln!(out, "            {ip} => {{");
+        for line in body.lines() {
+            let _ = writeln!(out, "                {line}");
+        }
+        let _ = writeln!(out, "            }}");
+    }
+    out.push_str(
+        "            _ => fail(&format!(\"illegal instruction at ip {ip}...abrupt halt\")),
+        }
+    }
+}
+",
+    );
+    Ok(out)
+}
+
+/// Everything before the first instruction: the helpers and the start of `main`
+fn prelude(out: &mut String, program: &Program) {
+    let (word, uword, float) = match Word::BITS {
+        64 => ("i64", "u64", "f64"),
+        _ => ("i32", "u32", "f32"),
+    };
+    let data: Vec<String> = program.data.iter().map(|&val| literal(val)).collect();
+    let pop_err = StackError::PopErr.to_string();
+    let push_err = StackError::PushErr.to_string();
+    let registers: Vec<String> = (0..GP_REGISTERS).map(|n| format!("mut r{n}")).collect();
+    let _ = write!(
+        out,
+        r#"//! Translated from a vyantra program by `vyantra transpile`. It runs like `vyantra run`:
+//! `IN` reads integers from standard input, `OUT` and `PRINTS` write lines to standard output,
+//! yielded values are printed, and so is the stack once the program halts.
+
+#![allow(unused, unreachable_code, unreachable_patterns, clippy::all)]
+
+use std::io::{{self, BufRead}};
+use std::process;
+
+type Word = {word};
+type UWord = {uword};
+type Float = {float};
+
+const STACK_SIZE: usize = {STACK_SIZE};
+const MEMORY_SIZE: usize = {MEMORY_SIZE};
+const CALL_DEPTH: usize = {CALL_DEPTH};
+
+/// The data segment, at the start of data memory
+const DATA: [Word; {len}] = [{data}];
+
+/// Flags set by `CMP`
+#[derive(Default)]
+struct Flags {{
+    zero: bool,
+    negative: bool,
+    overflow: bool,
+    carry: bool,
+}}
+
+impl Flags {{
+    fn compare(a: Word, b: Word) -> Flags {{
+        let (diff, overflow) = a.overflowing_sub(b);
+        Flags {{
+            zero: diff == 0,
+            negative: diff < 0,
+            overflow,
+            carry: (a as UWord) < (b as UWord),
+        }}
+    }}
+
+    fn less(&self) -> bool {{
+        self.negative != self.overflow
+    }}
+}}
+
+/// Integers from standard input, separated by spaces or newlines
+#[derive(Default)]
+struct Input {{
+    /// What is left of the line read last, the next word last
+    words: Vec<String>,
+}}
+
+impl Input {{
+    fn next(&mut self) -> Option<Word> {{
+        while self.words.is_empty() {{
+            let mut line = String::new();
+            match io::stdin().lock().read_line(&mut line) {{
+                Ok(0) | Err(_) => return None,
+                Ok(_) => self.words = line.split_whitespace().rev().map(String::from).collect(),
+            }}
+        }}
+        self.words.pop()?.parse().ok()
+    }}
+}}
+
+fn fail(message: &str) -> ! {{
+    eprintln!("vyantra: {{message}}");
+    process::exit(255)
+}}
+
+/// Fail with `message`, the fault of the instruction `at` with `depth` values on the stack
+fn fault(message: &str, at: &str, depth: usize) -> ! {{
+    fail(&format!("{{message}} while executing {{at}}, stack depth {{depth}}"))
+}}
+
+/// Pop `N` values, the head of the stack last, popping none if there are fewer
+fn pop<const N: usize>(stack: &mut Vec<Word>, at: &str) -> [Word; N] {{
+    if stack.len() < N {{
+        fault({pop_err:?}, at, stack.len());
+    }}
+    let mut vals = [0; N];
+    vals.copy_from_slice(&stack[stack.len() - N..]);
+    stack.truncate(stack.len() - N);
+    vals
+}}
+
+fn push(stack: &mut Vec<Word>, val: Word, at: &str) {{
+    if stack.len() >= STACK_SIZE {{
+        fault({push_err:?}, at, stack.len());
+    }}
+    stack.push(val);
+}}
+
+/// Position in the stack of the value `offset` from the head
+fn slot(stack: &[Word], offset: isize, at: &str) -> usize {{
+    let sp = stack.len() as isize - 1;
+    match sp.checked_sub(offset) {{
+        Some(pos) if pos >= 0 && pos <= sp => pos as usize,
+        _ => fault(&format!("invalid stack access at offset {{offset}} (sp={{sp}})"), at, stack.len()),
+    }}
+}}
+
+/// Position in the stack of local `slot` of the frame at `fp`, among the first `len` values
+fn local(stack: &[Word], len: usize, fp: usize, slot: isize, at: &str) -> usize {{
+    match (fp as isize).checked_add(slot) {{
+        Some(pos) if pos >= 0 && (pos as usize) < len => pos as usize,
+        _ => fault(&format!("local slot {{slot}} is not on the stack"), at, stack.len()),
+    }}
+}}
+
+/// Data memory address `offset` words past the value of a register
+fn address(base: Word, offset: usize, at: &str, depth: usize) -> usize {{
+    let addr = (base as UWord as usize).saturating_add(offset);
+    if addr >= MEMORY_SIZE {{
+        fault(&format!("data memory address {{addr}} does not exist"), at, depth);
+    }}
+    addr
+}}
+
+fn float(word: Word) -> Float {{
+    Float::from_bits(word as UWord)
+}}
+
+fn float_word(val: Float) -> Word {{
+    val.to_bits() as Word
+}}
+
+fn halt(stack: &[Word], code: i32) -> ! {{
+    let stack: Vec<String> = stack.iter().map(Word::to_string).collect();
+    println!("stack: {{}}", stack.join(" "));
+    // 255 is a failure of the program, its codes outside 0 to 254 exit with 254 so they still fail
+    process::exit(if (0..=254).contains(&code) {{ code }} else {{ 254 }})
+}}
+
+fn main() {{
+    let mut stack: Vec<Word> = Vec::with_capacity(STACK_SIZE);
+    let (mut a, mut b, mut c, mut d, mut e, mut f): (Word, Word, Word, Word, Word, Word) =
+        (0, 0, 0, 0, 0, 0);
+    let [{registers}]: [Word; {GP_REGISTERS}] = [0; {GP_REGISTERS}];
+    let mut memory = vec![0 as Word; MEMORY_SIZE];
+    memory[..DATA.len()].copy_from_slice(&DATA);
+    let mut flags = Flags::default();
+    // return address and frame pointer of each call in progress
+    let mut calls: Vec<(usize, usize)> = Vec::new();
+    let mut fp: usize = 0;
+    let mut input = Input::default();
+    let mut ip: usize = 0;
+    loop {{
+        match ip {{
+"#,
+        len = data.len(),
+        data = data.join(", "),
+        registers = registers.join(", "),
+    );
+}
+
+/// A word as a Rust literal
+pub(super) fn literal(val: Word) -> String {
+    match val {
+        Word::MIN => "Word::MIN".to_string(),
+        val => val.to_string(),
+    }
+}
+
+/// Where a jump by `step` from `ip` goes, `None` if that leaves the program. A zero step
+/// continues with the next instruction.
+fn target(program: &Program, ip: usize, step: isize) -> Option<usize> {
+    if step == 0 {
+        return Some(ip + 1);
+    }
+    let target = (ip as isize).checked_add(step)?;
+    (target >= 0 && (target as usize) < program.code.len()).then_some(target as usize)
+}
+
+/// The local holding `reg`, `None` for a numbered register the machine does not have
+pub(super) fn register(reg: Reg) -> Option<String> {
+    match reg {
+        Reg::R(n) if n as usize >= GP_REGISTERS => None,
+        reg => Some(reg.to_string()),
+    }
+}
+
+/// Condition of a `JF` on the flags
+fn condition(cond: Cond) -> &'static str {
+    match cond {
+        Cond::EQ => "flags.zero",
+        Cond::NE => "!flags.zero",
+        Cond::LT => "flags.less()",
+        Cond::LE => "flags.less() || flags.zero",
+        Cond::GT => "!flags.less() && !flags.zero",
+        Cond::GE => "!flags.less()",
+        Cond::LTU => "flags.carry",
+        Cond::GEU => "!flags.carry",
+    }
+}
+
+/// The translation of one instruction
+struct Arm<'a> {
+    program: &'a Program,
+    ip: usize,
+    inst: Inst,
+
+    /// The instruction and its position, a Rust string literal for the fault messages
+    at: String,
+}
+
+impl Arm<'_> {
+    /// Statements executing the instruction and moving `ip` on
+    fn body(&self) -> Result<String, TranspileError> {
+        let at = &self.at;
+        let next = format!("ip = {};", self.ip + 1);
+        let body = match self.inst {
+            Inst::PSH(val) => format!("push(&mut stack, {}, {at});\n{next}", literal(val)),
+            Inst::PSHC(idx) => match self.program.pool.get(idx as usize) {
+                Some(&val) => format!("push(&mut stack, {}, {at});\n{next}", literal(val)),
+                None => self.fault(Fault::NoConstant(idx)),
+            },
+            Inst::FPSH(val) => {
+                let word = literal(float_word(val));
+                format!("push(&mut stack, {word}, {at});\n{next}")
+            }
+            Inst::POP => format!("pop::<1>(&mut stack, {at});\n{next}"),
+            Inst::DUP | Inst::OVER => {
+                let offset = if self.inst == Inst::DUP { 0 } else { 1 };
+                format!(
+                    "let x = stack[slot(&stack, {offset}, {at})];\n\
+                     push(&mut stack, x, {at});\n{next}"
+                )
+            }
+            Inst::SWAP => format!(
+                "let [x, y] = pop::<2>(&mut stack, {at});\n\
+                 stack.extend([y, x]);\n{next}"
+            ),
+            Inst::ROT => format!(
+                "let [x, y, z] = pop::<3>(&mut stack, {at});\n\
+                 stack.extend([y, z, x]);\n{next}"
+            ),
+            Inst::DROP(count) => format!(
+                "if stack.len() < {count} {{\n    fault({:?}, {at}, stack.len());\n}}\n\
+                 stack.truncate(stack.len() - {count});\n{next}",
+                StackError::PopErr.to_string()
+            ),
+            Inst::CLR => format!("stack.clear();\n{next}"),
+            Inst::IN => format!(
+                "let Some(x) = input.next() else {{\n    \
+                 fault({:?}, {at}, stack.len())\n}};\n\
+                 push(&mut stack, x, {at});\n{next}",
+                Fault::NoInput.to_string()
+            ),
+            Inst::OUT => format!("let [x] = pop::<1>(&mut stack, {at});\nprintln!(\"{{x}}\");\n{next}"),
+            Inst::PRINTS(id) => match self.program.strings.get(id as usize) {
+                Some(text) => format!("println!(\"{{}}\", {text:?});\n{next}"),
+                None => self.fault(Fault::NoString(Word::from(id))),
+            },
+            Inst::YLD => format!(
+                "let [x] = pop::<1>(&mut stack, {at});\nprintln!(\"yield {{x}}\");\n{next}"
+            ),
+            Inst::ADD | Inst::SUB | Inst::MUL => {
+                let op = match self.inst {
+                    Inst::ADD => "add",
+                    Inst::SUB => "sub",
+                    _ => "mul",
+                };
+                self.binary(&format!("x.wrapping_{op}(y)"))
+            }
+            Inst::QMUL => self.binary(&format!(
+                "((x as i128 * y as i128) >> {FIXED_POINT_BITS}) as Word"
+            )),
+            Inst::DIV
+            | Inst::MOD
+            | Inst::DIVF
+            | Inst::MODF
+            | Inst::DIVU
+            | Inst::MODU
+            | Inst::QDIV => {
+                let val = match self.inst {
+                    Inst::DIV => "x.wrapping_div(y)".to_string(),
+                    Inst::MOD => "x.wrapping_rem(y)".to_string(),
+                    Inst::DIVF => "{\n    let (q, r) = (x.wrapping_div(y), x.wrapping_rem(y));\n    \
+                                   if r != 0 && (r < 0) != (y < 0) { q.wrapping_sub(1) } else { q }\n}"
+                        .to_string(),
+                    Inst::MODF => "{\n    let r = x.wrapping_rem(y);\n    \
+                                   if r != 0 && (r < 0) != (y < 0) { r.wrapping_add(y) } else { r }\n}"
+                        .to_string(),
+                    Inst::DIVU => "(x as UWord / y as UWord) as Word".to_string(),
+                    Inst::MODU => "(x as UWord % y as UWord) as Word".to_string(),
+                    _ => format!("(((x as i128) << {FIXED_POINT_BITS}) / y as i128) as Word"),
+                };
+                format!(
+                    "let [x, y] = pop::<2>(&mut stack, {at});\n\
+                     if y == 0 {{\n    fault({:?}, {at}, stack.len() + 2);\n}}\n\
+                     stack.push({val});\n{next}",
+                    Fault::DivideByZero.to_string()
+                )
+            }
+            Inst::AND => self.binary("x & y"),
+            Inst::OR => self.binary("x | y"),
+            Inst::XOR => self.binary("x ^ y"),
+            Inst::SHL => self.binary("x.wrapping_shl(y as u32)"),
+            Inst::SHR => self.binary("(x as UWord).wrapping_shr(y as u32) as Word"),
+            Inst::MIN => self.binary("x.min(y)"),
+            Inst::MAX => self.binary("x.max(y)"),
+            Inst::NOT => self.unary("!x"),
+            Inst::NEG => self.unary("x.wrapping_neg()"),
+            Inst::ABS => self.unary("x.wrapping_abs()"),
+            Inst::FADD => self.binary("float_word(float(x) + float(y))"),
+            Inst::FSUB => self.binary("float_word(float(x) - float(y))"),
+            Inst::FMUL => self.binary("float_word(float(x) * float(y))"),
+            Inst::FDIV => self.binary("float_word(float(x) / float(y))"),
+            Inst::ITOF => self.unary("float_word(x as Float)"),
+            Inst::FTOI => self.unary("float(x) as Word"),
+            Inst::CMP => format!(
+                "let y = stack[slot(&stack, 0, {at})];\n\
+                 let x = stack[slot(&stack, 1, {at})];\n\
+                 flags = Flags::compare(x, y);\n{next}"
+            ),
+            Inst::SET(reg, val) => match register(reg) {
+                Some(reg) => format!("{reg} = {};\n{next}", literal(val)),
+                None => self.fault(Fault::Path(PathError::RegErr { reg })),
+            },
+            Inst::INC(reg) | Inst::DEC(reg) => match register(reg) {
+                Some(name) => {
+                    let op = if self.inst == Inst::INC(reg) { "add" } else { "sub" };
+                    format!("{name} = {name}.wrapping_{op}(1);\n{next}")
+                }
+                None => self.fault(Fault::Path(PathError::RegErr { reg })),
+            },
+            Inst::SETP(dst, val) => match self.write(dst, &literal(val)) {
+                Ok(write) => format!("{write}\n{next}"),
+                Err(fault) => fault,
+            },
+            Inst::CPY(dst, src) => match (self.read(src), self.write(dst, "x")) {
+                (Ok(read), Ok(write)) => format!("let x = {read};\n{write}\n{next}"),
+                (Err(fault), _) => fault,
+                (Ok(read), Err(fault)) => format!("let x = {read};\n{fault}"),
+            },
+            Inst::LOAD(addr) if addr >= MEMORY_SIZE => self.fault(Fault::BadAddress(addr)),
+            Inst::LOAD(addr) => format!("push(&mut stack, memory[{addr}], {at});\n{next}"),
+            Inst::STORE(addr) if addr >= MEMORY_SIZE => format!(
+                "pop::<1>(&mut stack, {at});\nfault({:?}, {at}, stack.len());",
+                Fault::BadAddress(addr).to_string()
+            ),
+            Inst::STORE(addr) => {
+                format!("let [x] = pop::<1>(&mut stack, {at});\nmemory[{addr}] = x;\n{next}")
+            }
+            Inst::LOADR(reg, offset) => match register(reg) {
+                Some(reg) => format!(
+                    "let addr = address({reg}, {offset}, {at}, stack.len());\n\
+                     push(&mut stack, memory[addr], {at});\n{next}"
+                ),
+                None => self.fault(Fault::Path(PathError::RegErr { reg })),
+            },
+            Inst::STORER(reg, offset) => match register(reg) {
+                Some(reg) => format!(
+                    "let base = {reg};\n\
+                     let [x] = pop::<1>(&mut stack, {at});\n\
+                     memory[address(base, {offset}, {at}, stack.len())] = x;\n{next}"
+                ),
+                None => self.fault(Fault::Path(PathError::RegErr { reg })),
+            },
+            Inst::JMP(step) => self.jump(step).unwrap_or_else(|fault| fault),
+            Inst::LOOP(reg, step) => match register(reg) {
+                Some(reg) => format!(
+                    "{reg} = {reg}.wrapping_sub(1);\nif {reg} != 0 {{\n{}\n}} else {{\n    {next}\n}}",
+                    indent(&self.jump(step).unwrap_or_else(|fault| fault))
+                ),
+                None => self.fault(Fault::Path(PathError::RegErr { reg })),
+            },
+            Inst::JEZ(step)
+            | Inst::JNZ(step)
+            | Inst::JLT(step)
+            | Inst::JGT(step)
+            | Inst::JLE(step)
+            | Inst::JGE(step) => {
+                let test = match self.inst {
+                    Inst::JEZ(_) => "x == 0",
+                    Inst::JNZ(_) => "x != 0",
+                    Inst::JLT(_) => "x < 0",
+                    Inst::JGT(_) => "x > 0",
+                    Inst::JLE(_) => "x <= 0",
+                    _ => "x >= 0",
+                };
+                format!(
+                    "let [x] = pop::<1>(&mut stack, {at});\nif {test} {{\n{}\n}} else {{\n    {next}\n}}",
+                    indent(&self.jump(step).unwrap_or_else(|fault| fault))
+                )
+            }
+            Inst::JF(cond, step) => format!(
+                "if {} {{\n{}\n}} else {{\n    {next}\n}}",
+                condition(cond),
+                indent(&self.jump(step).unwrap_or_else(|fault| fault))
+            ),
+            Inst::CALL(step) => {
+                let depth = format!(
+                    "if calls.len() >= CALL_DEPTH {{\n    fault({:?}, {at}, stack.len());\n}}",
+                    Fault::CallDepth.to_string()
+                );
+                match self.jump(step) {
+                    Ok(jump) => format!(
+                        "{depth}\ncalls.push(({}, fp));\nfp = stack.len();\n{jump}",
+                        self.ip + 1
+                    ),
+                    Err(fault) => format!("{depth}\n{fault}"),
+                }
+            }
+            Inst::RET => format!(
+                "let Some((ret, caller)) = calls.pop() else {{\n    \
+                 fault({:?}, {at}, stack.len())\n}};\nip = ret;\nfp = caller;",
+                Fault::NoCaller.to_string()
+            ),
+            Inst::LOADL(slot) => format!(
+                "let x = stack[local(&stack, stack.len(), fp, {slot}, {at})];\n\
+                 push(&mut stack, x, {at});\n{next}"
+            ),
+            Inst::STOREL(slot) => format!(
+                "let pos = local(&stack, stack.len().saturating_sub(1), fp, {slot}, {at});\n\
+                 let [x] = pop::<1>(&mut stack, {at});\nstack[pos] = x;\n{next}"
+            ),
+            // program files have no jump tables
+            Inst::TBL(id) => self.fault(Fault::NoSuchTable(id)),
+            Inst::TRAP(code) => {
+                let message = match self.program.traps.get(code as usize) {
+                    Some(message) => format!("trap {code} at ip {}: {message}", self.ip),
+                    None => format!("trap {code} at ip {}", self.ip),
+                };
+                format!(
+                    "let [x] = pop::<1>(&mut stack, {at});\nif x == 0 {{\n    fail({message:?});\n}}\n{next}"
+                )
+            }
+            Inst::HLT => "halt(&stack, 0);".to_string(),
+            Inst::EXIT(code) => format!("halt(&stack, {code});"),
+            Inst::SYS(_)
+            | Inst::HCALL(_)
+            | Inst::SND(_)
+            | Inst::RCV(_)
+            | Inst::ALLOC(_)
+            | Inst::AIDXLOAD
+            | Inst::AIDXSTORE => {
+                return Err(TranspileError::Unsupported {
+                    ip: self.ip,
+                    inst: self.inst,
+                })
+            }
+        };
+        Ok(body)
+    }
+
+    /// An instruction that pops two values and pushes `val` of them, `x` below `y`
+    fn binary(&self, val: &str) -> String {
+        let at = &self.at;
+        format!(
+            "let [x, y] = pop::<2>(&mut stack, {at});\nstack.push({val});\nip = {};",
+            self.ip + 1
+        )
+    }
+
+    /// An instruction that pops `x` and pushes `val`
+    fn unary(&self, val: &str) -> String {
+        let at = &self.at;
+        format!(
+            "let [x] = pop::<1>(&mut stack, {at});\nstack.push({val});\nip = {};",
+            self.ip + 1
+        )
+    }
+
+    /// Failing with `fault`, which the instruction always causes
+    fn fault(&self, fault: Fault) -> String {
+        format!("fault({:?}, {}, stack.len());", fault.to_string(), self.at)
+    }
+
+    /// Moving `ip` by `step`, or the fault if that leaves the program
+    fn jump(&self, step: isize) -> Result<String, String> {
+        match target(self.program, self.ip, step) {
+            Some(target) => Ok(format!("ip = {target};")),
+            None => Err(self.fault(Fault::BadJump { step })),
+        }
+    }
+
+    /// An expression for the value at `path`, or the fault if `path` is a register the machine
+    /// does not have
+    fn read(&self, path: Path) -> Result<String, String> {
+        let at = &self.at;
+        match path {
+            Path::REG(reg) => self.reg(reg),
+            Path::STK(offset) => Ok(format!("stack[slot(&stack, {offset}, {at})]")),
+            Path::STKR(reg, offset) => {
+                let reg = self.reg(reg)?;
+                Ok(format!(
+                    "stack[slot(&stack, ({offset}isize).saturating_add({reg} as isize), {at})]"
+                ))
+            }
+        }
+    }
+
+    /// A statement storing `val` at `path`, like [`Arm::read`]
+    fn write(&self, path: Path, val: &str) -> Result<String, String> {
+        let at = &self.at;
+        match path {
+            Path::REG(reg) => Ok(format!("{} = {val};", self.reg(reg)?)),
+            Path::STK(offset) => Ok(format!(
+                "let pos = slot(&stack, {offset}, {at});\nstack[pos] = {val};"
+            )),
+            Path::STKR(reg, offset) => {
+                let reg = self.reg(reg)?;
+                Ok(format!(
+                    "let pos = slot(&stack, ({offset}isize).saturating_add({reg} as isize), {at});\n\
+                     stack[pos] = {val};"
+                ))
+            }
+        }
+    }
+
+    fn reg(&self, reg: Reg) -> Result<String, String> {
+        register(reg).ok_or_else(|| self.fault(Fault::Path(PathError::RegErr { reg })))
+    }
+}
+
+/// `code` moved in one level
+pub(super) fn indent(code: &str) -> String {
+    let lines: Vec<String> = code.lines().map(|line| format!("    {line}")).collect();
+    lines.join("\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assemble_program;
+
+    #[test]
+    fn translation() {
+        let program = assemble_program(
+            ".data table: 10 20\n\
+             prints \"sum\"\n\
+             load 1\n\
+             call double\n\
+             out\n\
+             jmp 7\n\
+             double: dup\n\
+             add\n\
+             ret\n\
+             exit 3\n",
+        )
+        .unwrap();
+        let source = transpile(&program).unwrap();
+        assert!(source.contains("const DATA: [Word; 2] = [10, 20];"));
+        assert!(source.contains("println!(\"{}\", \"sum\");"));
+        // jumps and calls go straight to their targets
+        assert!(source.contains(
+            "calls.push((3, fp));\n                fp = stack.len();\n                ip = 5;"
+        ));
+        assert!(source.contains("halt(&stack, 3);"));
+        assert!(source.contains(
+            "fault(\"jump by 7 leaves the program\", \"`jmp 7` at ip 4\", stack.len());"
+        ));
+
+        let program = Program {
+            code: vec![Inst::PSH(1), Inst::ALLOC(4)],
+            ..Program::default()
+        };
+        let err = TranspileError::Unsupported {
+            ip: 1,
+            inst: Inst::ALLOC(4),
+        };
+        assert_eq!(transpile(&program), Err(err));
+    }
+}
@@ -0,0 +1,847 @@
+//! Translation of a program into C, for hosts with a C compiler and no Rust.
+//!
+//! The translated program is a machine of its own: `struct vyantra` holds the stack, registers,
+//! data memory and calls, and `vyantra_run` executes the program on it until it halts, yields,
+//! traps or fails, returning which as its status. A trap returns `VY_TRAP` plus its code. The host
+//! reaches into the machine the way a [`SyscallHandler`](crate::SyscallHandler) does, `SYS` calling
+//! its `syscall` hook with the call number and the hook popping its arguments and pushing its
+//! results with `vy_pop` and `vy_push`. `IN` and `OUT` go through hooks as well, so the file
+//! needs only the freestanding headers. Built with `VYANTRA_MAIN` defined, it also has a `main`
+//! that behaves like `vyantra run`.
+//!
+//! Fault messages are the machine's, leaving out what is only known while running, like the
+//! stack pointer of a bad stack access.
+
+use std::fmt::Write;
+
+use super::{indent, target, TranspileError};
+use crate::asm::Program;
+use crate::{
+    float_word, Cond, Fault, Inst, Path, PathError, Reg, StackError, Word, CALL_DEPTH,
+    FIXED_POINT_BITS, GP_REGISTERS, MEMORY_SIZE, STACK_SIZE,
+};
+
+/// The C source of a machine running `program`
+pub fn transpile_c(program: &Program) -> Result<String, TranspileError> {
+    if program.data.len() > MEMORY_SIZE {
+        return Err(TranspileError::DataTooLarge {
+            len: program.data.len(),
+            size: MEMORY_SIZE,
+        });
+    }
+    let (word, uword, float, wide, bits) = match Word::BITS {
+        64 => (
+            "int64_t",
+            "uint64_t",
+            "double",
+            "__extension__ typedef __int128",
+            "64",
+        ),
+        _ => ("int32_t", "uint32_t", "float", "typedef int64_t", "32"),
+    };
+    let mut data: Vec<String> = program.data.iter().map(|&val| literal(val)).collect();
+    if data.is_empty() {
+        // C has no empty arrays
+        data.push(literal(0));
+    }
+    let code: Vec<String> = program
+        .code
+        .iter()
+        .map(|inst| string(&inst.to_string()))
+        .collect();
+    let mut out = PRELUDE
+        .replace("$WORD_BITS", bits)
+        .replace("$UWORD", uword)
+        .replace("$WORD", word)
+        .replace("$FLOAT", float)
+        .replace("$WIDE", wide)
+        .replace("$FIXED_POINT_BITS", &FIXED_POINT_BITS.to_string())
+        .replace("$STACK_SIZE", &STACK_SIZE.to_string())
+        .replace("$MEMORY_SIZE", &MEMORY_SIZE.to_string())
+        .replace("$CALL_DEPTH", &CALL_DEPTH.to_string())
+        .replace("$REGISTERS", &(6 + GP_REGISTERS).to_string())
+        .replace("$DATA_LEN", &program.data.len().to_string())
+        .replace("$DATA", &data.join(", "))
+        .replace("$POP_ERR", &string(&StackError::PopErr.to_string()))
+        .replace("$PUSH_ERR", &string(&StackError::PushErr.to_string()));
+    for (ip, &inst) in program.code.iter().enumerate() {
+        let case = Case { program, ip, inst };
+        let body = case.body()?;
+        let _ = writeln!(out, "        case {ip}: /* {inst} */");
+        for line in body.lines() {
+            let _ = writeln!(out, "            {line}");
+        }
+    }
+    let end = END
+        .replace("$CODE_LEN", &program.code.len().to_string())
+        .replace("$CODE", &code.join(",\n    "));
+    out.push_str(&end);
+    Ok(out)
+}
+
+/// Everything before the first instruction: the interface, the helpers and the start of
+/// `vyantra_run`
+const PRELUDE: &str = r#"/*
+ * Translated from a vyantra program by `vyantra transpile`.
+ *
+ * Call vyantra_init on a struct vyantra, set the hooks it needs, and call vyantra_run until it
+ * returns VY_HALTED or fails. Define VYANTRA_MAIN for a main that runs the program like
+ * `vyantra run`.
+ */
+
+#include <limits.h>
+#include <stddef.h>
+#include <stdint.h>
+
+typedef $WORD vy_word;
+typedef $UWORD vy_uword;
+typedef $FLOAT vy_float;
+/* Wide enough for the product of two words */
+$WIDE vy_wide;
+
+#define VY_WORD_BITS $WORD_BITS
+#define VY_STACK_SIZE $STACK_SIZE
+#define VY_MEMORY_SIZE $MEMORY_SIZE
+#define VY_CALL_DEPTH $CALL_DEPTH
+#define VY_REGISTERS $REGISTERS
+
+/* Registers, the numbered register n is VY_R0 + n */
+enum { VY_A, VY_B, VY_C, VY_D, VY_E, VY_F, VY_R0 };
+
+/* What vyantra_run stopped on */
+enum {
+    /* HLT or EXIT, with the exit code in exit_code */
+    VY_HALTED = 0,
+    /* YLD, with the value in yielded. Run again to go on. */
+    VY_YIELDED = 1,
+    /* The instruction at ip failed, fault says why */
+    VY_FAULT = 2,
+    /* TRAP returns VY_TRAP plus its code, with its message in fault if it has one */
+    VY_TRAP = 0x10000
+};
+
+struct vyantra {
+    vy_word stack[VY_STACK_SIZE];
+    /* Number of values on the stack */
+    size_t sp;
+    vy_word regs[VY_REGISTERS];
+    vy_word memory[VY_MEMORY_SIZE];
+    struct {
+        unsigned char zero, negative, overflow, carry;
+    } flags;
+    /* Return address and frame pointer of each call in progress */
+    struct {
+        size_t ret, fp;
+    } calls[VY_CALL_DEPTH];
+    size_t depth;
+    size_t fp;
+    size_t ip;
+    int exit_code;
+    vy_word yielded;
+    const char *fault;
+
+    /*
+     * Hooks into the host, each optional. Like the system call hook, input returns 0 or a
+     * status, any status but 0 meaning there is no input. A system call that returns a status
+     * stops vyantra_run with it, ip left on the SYS.
+     */
+    int (*syscall)(struct vyantra *vm, uint32_t number);
+    int (*input)(struct vyantra *vm, vy_word *val);
+    void (*output)(struct vyantra *vm, const char *text);
+    void *user;
+};
+
+/* The data segment, at the start of data memory */
+static const vy_word vy_data[] = {$DATA};
+static const size_t vy_data_len = $DATA_LEN;
+
+/* Reset vm to the start of the program, with the hooks unset */
+void vyantra_init(struct vyantra *vm)
+{
+    size_t i;
+    vm->sp = 0;
+    for (i = 0; i < VY_REGISTERS; i++)
+        vm->regs[i] = 0;
+    for (i = 0; i < VY_MEMORY_SIZE; i++)
+        vm->memory[i] = i < vy_data_len ? vy_data[i] : 0;
+    vm->flags.zero = vm->flags.negative = vm->flags.overflow = vm->flags.carry = 0;
+    vm->depth = 0;
+    vm->fp = 0;
+    vm->ip = 0;
+    vm->exit_code = 0;
+    vm->yielded = 0;
+    vm->fault = 0;
+    vm->syscall = 0;
+    vm->input = 0;
+    vm->output = 0;
+    vm->user = 0;
+}
+
+/* The stack of a system call, like SysCtx: 0 or VY_FAULT, with fault set */
+int vy_pop(struct vyantra *vm, vy_word *val)
+{
+    if (vm->sp == 0) {
+        vm->fault = $POP_ERR;
+        return VY_FAULT;
+    }
+    *val = vm->stack[--vm->sp];
+    return 0;
+}
+
+int vy_push(struct vyantra *vm, vy_word val)
+{
+    if (vm->sp >= VY_STACK_SIZE) {
+        vm->fault = $PUSH_ERR;
+        return VY_FAULT;
+    }
+    vm->stack[vm->sp++] = val;
+    return 0;
+}
+
+void vy_write(struct vyantra *vm, const char *text)
+{
+    if (vm->output)
+        vm->output(vm, text);
+}
+
+static inline void vy_write_word(struct vyantra *vm, vy_word val)
+{
+    char text[24];
+    char *at = text + sizeof text;
+    vy_uword mag = val < 0 ? (vy_uword)0 - (vy_uword)val : (vy_uword)val;
+    *--at = 0;
+    *--at = '\n';
+    do {
+        *--at = (char)('0' + mag % 10);
+        mag /= 10;
+    } while (mag);
+    if (val < 0)
+        *--at = '-';
+    vy_write(vm, at);
+}
+
+/* Arithmetic wraps around like the machine's, as C leaves signed overflow undefined */
+static inline vy_word vy_add(vy_word x, vy_word y) { return (vy_word)((vy_uword)x + (vy_uword)y); }
+static inline vy_word vy_sub(vy_word x, vy_word y) { return (vy_word)((vy_uword)x - (vy_uword)y); }
+static inline vy_word vy_mul(vy_word x, vy_word y) { return (vy_word)((vy_uword)x * (vy_uword)y); }
+static inline vy_word vy_neg(vy_word x) { return (vy_word)((vy_uword)0 - (vy_uword)x); }
+static inline vy_word vy_abs(vy_word x) { return x < 0 ? vy_neg(x) : x; }
+static inline vy_word vy_shl(vy_word x, vy_word y) { return (vy_word)((vy_uword)x << ((vy_uword)y % VY_WORD_BITS)); }
+static inline vy_word vy_shr(vy_word x, vy_word y) { return (vy_word)((vy_uword)x >> ((vy_uword)y % VY_WORD_BITS)); }
+static inline vy_word vy_min(vy_word x, vy_word y) { return x < y ? x : y; }
+static inline vy_word vy_max(vy_word x, vy_word y) { return x > y ? x : y; }
+
+/* Division by a divisor that is not zero */
+static inline vy_word vy_div(vy_word x, vy_word y) { return y == -1 ? vy_neg(x) : x / y; }
+static inline vy_word vy_mod(vy_word x, vy_word y) { return y == -1 ? 0 : x % y; }
+static inline vy_word vy_divf(vy_word x, vy_word y)
+{
+    vy_word r = vy_mod(x, y);
+    return r != 0 && (r < 0) != (y < 0) ? vy_sub(vy_div(x, y), 1) : vy_div(x, y);
+}
+static inline vy_word vy_modf(vy_word x, vy_word y)
+{
+    vy_word r = vy_mod(x, y);
+    return r != 0 && (r < 0) != (y < 0) ? vy_add(r, y) : r;
+}
+static inline vy_word vy_divu(vy_word x, vy_word y) { return (vy_word)((vy_uword)x / (vy_uword)y); }
+static inline vy_word vy_modu(vy_word x, vy_word y) { return (vy_word)((vy_uword)x % (vy_uword)y); }
+
+/* Fixed point, with $FIXED_POINT_BITS fraction bits */
+static inline vy_word vy_qmul(vy_word x, vy_word y) { return (vy_word)(((vy_wide)x * y) >> $FIXED_POINT_BITS); }
+static inline vy_word vy_qdiv(vy_word x, vy_word y) { return (vy_word)((vy_wide)x * ((vy_wide)1 << $FIXED_POINT_BITS) / y); }
+
+static inline vy_float vy_float_of(vy_word x)
+{
+    union { vy_word word; vy_float val; } bits;
+    bits.word = x;
+    return bits.val;
+}
+
+static inline vy_word vy_word_of(vy_float val)
+{
+    union { vy_word word; vy_float val; } bits;
+    bits.val = val;
+    return bits.word;
+}
+
+/* A float to the nearest word toward zero, saturating, and zero for NaN */
+static inline vy_word vy_ftoi(vy_float val)
+{
+    vy_word max = (vy_word)((vy_uword)-1 >> 1);
+    if (val != val)
+        return 0;
+    if (val >= (vy_float)max)
+        return max;
+    if (val <= (vy_float)(-max - 1))
+        return -max - 1;
+    return (vy_word)val;
+}
+
+static inline void vy_compare(struct vyantra *vm, vy_word x, vy_word y)
+{
+    vy_word diff = vy_sub(x, y);
+    vm->flags.zero = diff == 0;
+    vm->flags.negative = diff < 0;
+    vm->flags.overflow = ((x ^ y) & (x ^ diff)) < 0;
+    vm->flags.carry = (vy_uword)x < (vy_uword)y;
+}
+
+#define VY_LESS (vm->flags.negative != vm->flags.overflow)
+
+/* Position in the stack of the value offset + base from the head, SIZE_MAX if there is none */
+static inline size_t vy_slot(const struct vyantra *vm, long long offset, vy_word base)
+{
+    if ((base > 0 && offset > LLONG_MAX - base) || (base < 0 && offset < LLONG_MIN - base))
+        return SIZE_MAX;
+    offset += base;
+    if (offset < 0 || (unsigned long long)offset >= vm->sp)
+        return SIZE_MAX;
+    return vm->sp - 1 - (size_t)offset;
+}
+
+/* Position in the stack of a local slot, once pop more values are popped */
+static inline size_t vy_local(const struct vyantra *vm, long long slot, size_t pop)
+{
+    size_t len = vm->sp < pop ? 0 : vm->sp - pop;
+    long long pos;
+    if (slot > LLONG_MAX - (long long)vm->fp)
+        return SIZE_MAX;
+    pos = (long long)vm->fp + slot;
+    if (pos < 0 || (unsigned long long)pos >= len)
+        return SIZE_MAX;
+    return (size_t)pos;
+}
+
+/* Data memory address offset words past base, SIZE_MAX if there is none */
+static inline size_t vy_address(vy_word base, size_t offset)
+{
+    vy_uword addr = (vy_uword)base;
+    if (addr >= VY_MEMORY_SIZE || offset >= VY_MEMORY_SIZE - addr)
+        return SIZE_MAX;
+    return (size_t)addr + offset;
+}
+
+#define VY_FAIL(message)          \
+    do {                          \
+        vm->fault = (message);    \
+        return VY_FAULT;          \
+    } while (0)
+#define VY_NEED(n)                \
+    if (vm->sp < (n))             \
+        VY_FAIL($POP_ERR)
+#define VY_ROOM()                         \
+    if (vm->sp >= VY_STACK_SIZE)          \
+        VY_FAIL($PUSH_ERR)
+/* The value n from the head of the stack */
+#define VY_STK(n) (vm->stack[vm->sp - 1 - (n)])
+
+/* Run from ip until the program halts, yields, traps or fails, returning which */
+int vyantra_run(struct vyantra *vm)
+{
+    vy_word x = 0, y = 0;
+    size_t pos = 0;
+    int status = 0;
+    (void)x, (void)y, (void)pos, (void)status;
+    for (;;) {
+        switch (vm->ip) {
+"#;
+
+/// The end of `vyantra_run`, and `main`
+const END: &str = r#"        default:
+            VY_FAIL("illegal instruction");
+        }
+    }
+}
+
+#ifdef VYANTRA_MAIN
+#include <errno.h>
+#include <stdio.h>
+#include <stdlib.h>
+
+/* The program as assembly, an instruction for each ip */
+static const char *const vy_code[] = {
+    $CODE
+};
+static const size_t vy_code_len = $CODE_LEN;
+
+/* Integers from standard input, separated by spaces or newlines */
+static int vy_stdin(struct vyantra *vm, vy_word *val)
+{
+    char word[64], *end;
+    long long parsed;
+    (void)vm;
+    if (scanf("%63s", word) != 1)
+        return VY_FAULT;
+    errno = 0;
+    parsed = strtoll(word, &end, 10);
+    if (*end || end == word || errno || (vy_word)parsed != parsed)
+        return VY_FAULT;
+    *val = (vy_word)parsed;
+    return 0;
+}
+
+static inline void vy_stdout(struct vyantra *vm, const char *text)
+{
+    (void)vm;
+    fputs(text, stdout);
+}
+
+int main(void)
+{
+    static struct vyantra vm;
+    size_t i;
+    int status;
+    vyantra_init(&vm);
+    vm.input = vy_stdin;
+    vm.output = vy_stdout;
+    while ((status = vyantra_run(&vm)) == VY_YIELDED)
+        printf("yield %lld\n", (long long)vm.yielded);
+    if (status == VY_HALTED) {
+        printf("stack: ");
+        for (i = 0; i < vm.sp; i++)
+            printf(i ? " %lld" : "%lld", (long long)vm.stack[i]);
+        printf("\n");
+        /* 255 is a failure of the program, its codes outside 0 to 254 exit with 254 */
+        return vm.exit_code >= 0 && vm.exit_code <= 254 ? vm.exit_code : 254;
+    }
+    fflush(stdout);
+    if (status >= VY_TRAP && vm.fault)
+        fprintf(stderr, "vyantra: trap %d at ip %zu: %s\n", status - VY_TRAP, vm.ip, vm.fault);
+    else if (status >= VY_TRAP)
+        fprintf(stderr, "vyantra: trap %d at ip %zu\n", status - VY_TRAP, vm.ip);
+    else if (vm.ip >= vy_code_len)
+        fprintf(stderr, "vyantra: illegal instruction at ip %zu...abrupt halt\n", vm.ip);
+    else
+        fprintf(stderr, "vyantra: %s while executing `%s` at ip %zu, stack depth %zu\n",
+                vm.fault, vy_code[vm.ip], vm.ip, vm.sp);
+    return 255;
+}
+#endif
+"#;
+
+/// A word as a C literal, the digits being those of the Rust literal
+fn literal(val: Word) -> String {
+    match val {
+        Word::MIN => format!("({} - 1)", literal(Word::MIN + 1)),
+        val if Word::BITS == 64 => format!("INT64_C({})", super::literal(val)),
+        val => format!("INT32_C({})", super::literal(val)),
+    }
+}
+
+/// `text` as a C string literal, escaping anything that is not printable ASCII
+fn string(text: &str) -> String {
+    let mut literal = String::from("\"");
+    for byte in text.bytes() {
+        match byte {
+            b'"' => literal.push_str("\\\""),
+            b'\\' => literal.push_str("\\\\"),
+            b'?' => literal.push_str("\\?"),
+            b' '..=b'~' => literal.push(byte as char),
+            _ => {
+                let _ = write!(literal, "\\{byte:03o}");
+            }
+        }
+    }
+    literal.push('"');
+    literal
+}
+
+/// Index into `regs` of `reg`, `None` for a numbered register the machine does not have, as
+/// for the Rust translation
+fn register(reg: Reg) -> Option<String> {
+    super::register(reg)?;
+    let index = match reg {
+        Reg::R(n) => return Some(format!("vm->regs[VY_R0 + {n}]")),
+        Reg::A => "VY_A",
+        Reg::B => "VY_B",
+        Reg::C => "VY_C",
+        Reg::D => "VY_D",
+        Reg::E => "VY_E",
+        Reg::F => "VY_F",
+    };
+    Some(format!("vm->regs[{index}]"))
+}
+
+/// Condition of a `JF` on the flags
+fn condition(cond: Cond) -> &'static str {
+    match cond {
+        Cond::EQ => "vm->flags.zero",
+        Cond::NE => "!vm->flags.zero",
+        Cond::LT => "VY_LESS",
+        Cond::LE => "VY_LESS || vm->flags.zero",
+        Cond::GT => "!VY_LESS && !vm->flags.zero",
+        Cond::GE => "!VY_LESS",
+        Cond::LTU => "vm->flags.carry",
+        Cond::GEU => "!vm->flags.carry",
+    }
+}
+
+/// The translation of one instruction
+struct Case<'a> {
+    program: &'a Program,
+    ip: usize,
+    inst: Inst,
+}
+
+impl Case<'_> {
+    /// Statements executing the instruction and moving `ip` on
+    fn body(&self) -> Result<String, TranspileError> {
+        let next = self.next();
+        let body = match self.inst {
+            Inst::PSH(val) => format!(
+                "VY_ROOM();\nvm->stack[vm->sp++] = {};\n{next}",
+                literal(val)
+            ),
+            Inst::PSHC(idx) => match self.program.pool.get(idx as usize) {
+                Some(&val) => {
+                    format!(
+                        "VY_ROOM();\nvm->stack[vm->sp++] = {};\n{next}",
+                        literal(val)
+                    )
+                }
+                None => self.fault(Fault::NoConstant(idx)),
+            },
+            Inst::FPSH(val) => format!(
+                "VY_ROOM();\nvm->stack[vm->sp++] = {};\n{next}",
+                literal(float_word(val))
+            ),
+            Inst::POP => format!("VY_NEED(1);\nvm->sp--;\n{next}"),
+            Inst::DUP | Inst::OVER => {
+                let offset = if self.inst == Inst::DUP { 0 } else { 1 };
+                format!(
+                    "if (vm->sp <= {offset})\n    {}\nx = VY_STK({offset});\nVY_ROOM();\n\
+                     vm->stack[vm->sp++] = x;\n{next}",
+                    self.stack_fault(offset)
+                )
+            }
+            Inst::SWAP => format!(
+                "VY_NEED(2);\nx = VY_STK(1);\nVY_STK(1) = VY_STK(0);\nVY_STK(0) = x;\n{next}"
+            ),
+            Inst::ROT => format!(
+                "VY_NEED(3);\nx = VY_STK(2);\nVY_STK(2) = VY_STK(1);\nVY_STK(1) = VY_STK(0);\n\
+                 VY_STK(0) = x;\n{next}"
+            ),
+            Inst::DROP(count) => format!("VY_NEED({count});\nvm->sp -= {count};\n{next}"),
+            Inst::CLR => format!("vm->sp = 0;\n{next}"),
+            Inst::IN => format!(
+                "if (!vm->input || vm->input(vm, &x) != 0)\n    VY_FAIL({});\nVY_ROOM();\n\
+                 vm->stack[vm->sp++] = x;\n{next}",
+                string(&Fault::NoInput.to_string())
+            ),
+            Inst::OUT => format!("VY_NEED(1);\nvy_write_word(vm, vm->stack[--vm->sp]);\n{next}"),
+            Inst::PRINTS(id) => match self.program.strings.get(id as usize) {
+                Some(text) => format!("vy_write(vm, {});\n{next}", string(&format!("{text}\n"))),
+                None => self.fault(Fault::NoString(Word::from(id))),
+            },
+            Inst::YLD => format!(
+                "VY_NEED(1);\nvm->yielded = vm->stack[--vm->sp];\nvm->ip = {};\nreturn VY_YIELDED;",
+                self.ip + 1
+            ),
+            Inst::ADD => self.binary("vy_add(x, y)"),
+            Inst::SUB => self.binary("vy_sub(x, y)"),
+            Inst::MUL => self.binary("vy_mul(x, y)"),
+            Inst::QMUL => self.binary("vy_qmul(x, y)"),
+            Inst::DIV
+            | Inst::MOD
+            | Inst::DIVF
+            | Inst::MODF
+            | Inst::DIVU
+            | Inst::MODU
+            | Inst::QDIV => format!(
+                "VY_NEED(2);\nif (VY_STK(0) == 0)\n    VY_FAIL({});\n{}",
+                string(&Fault::DivideByZero.to_string()),
+                self.binary(&format!("vy_{}(x, y)", self.inst.mnemonic().to_lowercase()))
+            ),
+            Inst::AND => self.binary("x & y"),
+            Inst::OR => self.binary("x | y"),
+            Inst::XOR => self.binary("x ^ y"),
+            Inst::SHL => self.binary("vy_shl(x, y)"),
+            Inst::SHR => self.binary("vy_shr(x, y)"),
+            Inst::MIN => self.binary("vy_min(x, y)"),
+            Inst::MAX => self.binary("vy_max(x, y)"),
+            Inst::NOT => self.unary("~x"),
+            Inst::NEG => self.unary("vy_neg(x)"),
+            Inst::ABS => self.unary("vy_abs(x)"),
+            Inst::FADD => self.binary("vy_word_of(vy_float_of(x) + vy_float_of(y))"),
+            Inst::FSUB => self.binary("vy_word_of(vy_float_of(x) - vy_float_of(y))"),
+            Inst::FMUL => self.binary("vy_word_of(vy_float_of(x) * vy_float_of(y))"),
+            Inst::FDIV => self.binary("vy_word_of(vy_float_of(x) / vy_float_of(y))"),
+            Inst::ITOF => self.unary("vy_word_of((vy_float)x)"),
+            Inst::FTOI => self.unary("vy_ftoi(vy_float_of(x))"),
+            Inst::CMP => format!(
+                "if (vm->sp <= 0)\n    {}\nif (vm->sp <= 1)\n    {}\n\
+                 vy_compare(vm, VY_STK(1), VY_STK(0));\n{next}",
+                self.stack_fault(0),
+                self.stack_fault(1)
+            ),
+            Inst::SET(reg, val) => match register(reg) {
+                Some(reg) => format!("{reg} = {};\n{next}", literal(val)),
+                None => self.reg_fault(reg),
+            },
+            Inst::INC(reg) | Inst::DEC(reg) => match register(reg) {
+                Some(name) => {
+                    let op = if self.inst == Inst::INC(reg) {
+                        "add"
+                    } else {
+                        "sub"
+                    };
+                    format!("{name} = vy_{op}({name}, 1);\n{next}")
+                }
+                None => self.reg_fault(reg),
+            },
+            Inst::SETP(dst, val) => match self.write(dst, &literal(val)) {
+                Ok(write) => format!("{write}\n{next}"),
+                Err(fault) => fault,
+            },
+            Inst::CPY(dst, src) => match (self.read(src), self.write(dst, "x")) {
+                (Ok(read), Ok(write)) => format!("{read}\n{write}\n{next}"),
+                (Err(fault), _) => fault,
+                (Ok(read), Err(fault)) => format!("{read}\n{fault}"),
+            },
+            Inst::LOAD(addr) if addr >= MEMORY_SIZE => self.fault(Fault::BadAddress(addr)),
+            Inst::LOAD(addr) => {
+                format!("VY_ROOM();\nvm->stack[vm->sp++] = vm->memory[{addr}];\n{next}")
+            }
+            Inst::STORE(addr) if addr >= MEMORY_SIZE => {
+                format!(
+                    "VY_NEED(1);\nvm->sp--;\n{}",
+                    self.fault(Fault::BadAddress(addr))
+                )
+            }
+            Inst::STORE(addr) => {
+                format!("VY_NEED(1);\nvm->memory[{addr}] = vm->stack[--vm->sp];\n{next}")
+            }
+            Inst::LOADR(reg, offset) => match register(reg) {
+                Some(reg) => format!(
+                    "pos = vy_address({reg}, {offset});\nif (pos == SIZE_MAX)\n    \
+                     VY_FAIL(\"data memory address does not exist\");\nVY_ROOM();\n\
+                     vm->stack[vm->sp++] = vm->memory[pos];\n{next}"
+                ),
+                None => self.reg_fault(reg),
+            },
+            Inst::STORER(reg, offset) => match register(reg) {
+                Some(reg) => format!(
+                    "VY_NEED(1);\nx = vm->stack[--vm->sp];\npos = vy_address({reg}, {offset});\n\
+                     if (pos == SIZE_MAX)\n    VY_FAIL(\"data memory address does not exist\");\n\
+                     vm->memory[pos] = x;\n{next}"
+                ),
+                None => self.reg_fault(reg),
+            },
+            Inst::JMP(step) => self.jump(step),
+            Inst::LOOP(reg, step) => match register(reg) {
+                Some(reg) => format!(
+                    "{reg} = vy_sub({reg}, 1);\nif ({reg} != 0) {{\n{}\n}}\n{next}",
+                    indent(&self.jump(step))
+                ),
+                None => self.reg_fault(reg),
+            },
+            Inst::JEZ(step)
+            | Inst::JNZ(step)
+            | Inst::JLT(step)
+            | Inst::JGT(step)
+            | Inst::JLE(step)
+            | Inst::JGE(step) => {
+                let test = match self.inst {
+                    Inst::JEZ(_) => "x == 0",
+                    Inst::JNZ(_) => "x != 0",
+                    Inst::JLT(_) => "x < 0",
+                    Inst::JGT(_) => "x > 0",
+                    Inst::JLE(_) => "x <= 0",
+                    _ => "x >= 0",
+                };
+                format!(
+                    "VY_NEED(1);\nx = vm->stack[--vm->sp];\nif ({test}) {{\n{}\n}}\n{next}",
+                    indent(&self.jump(step))
+                )
+            }
+            Inst::JF(cond, step) => format!(
+                "if ({}) {{\n{}\n}}\n{next}",
+                condition(cond),
+                indent(&self.jump(step))
+            ),
+            Inst::CALL(step) => {
+                let depth = format!(
+                    "if (vm->depth >= VY_CALL_DEPTH)\n    VY_FAIL({});",
+                    string(&Fault::CallDepth.to_string())
+                );
+                match target(self.program, self.ip, step) {
+                    Some(target) => format!(
+                        "{depth}\nvm->calls[vm->depth].ret = {};\nvm->calls[vm->depth].fp = vm->fp;\n\
+                         vm->depth++;\nvm->fp = vm->sp;\nvm->ip = {target};\ncontinue;",
+                        self.ip + 1
+                    ),
+                    None => format!("{depth}\n{}", self.fault(Fault::BadJump { step })),
+                }
+            }
+            Inst::RET => format!(
+                "if (vm->depth == 0)\n    VY_FAIL({});\nvm->depth--;\n\
+                 vm->ip = vm->calls[vm->depth].ret;\nvm->fp = vm->calls[vm->depth].fp;\ncontinue;",
+                string(&Fault::NoCaller.to_string())
+            ),
+            Inst::LOADL(slot) => format!(
+                "pos = vy_local(vm, {slot}, 0);\nif (pos == SIZE_MAX)\n    VY_FAIL({});\n\
+                 x = vm->stack[pos];\nVY_ROOM();\nvm->stack[vm->sp++] = x;\n{next}",
+                string(&Fault::BadLocal(slot).to_string())
+            ),
+            Inst::STOREL(slot) => format!(
+                "pos = vy_local(vm, {slot}, 1);\nif (pos == SIZE_MAX)\n    VY_FAIL({});\n\
+                 vm->stack[pos] = vm->stack[--vm->sp];\n{next}",
+                string(&Fault::BadLocal(slot).to_string())
+            ),
+            // program files have no jump tables
+            Inst::TBL(id) => self.fault(Fault::NoSuchTable(id)),
+            Inst::TRAP(code) => {
+                let message = match self.program.traps.get(code as usize) {
+                    Some(message) => string(message),
+                    None => "0".to_string(),
+                };
+                format!(
+                    "VY_NEED(1);\nif (vm->stack[--vm->sp] == 0) {{\n    vm->fault = {message};\n    \
+                     return VY_TRAP + {code};\n}}\n{next}"
+                )
+            }
+            Inst::SYS(number) => format!(
+                "if (!vm->syscall)\n    VY_FAIL({});\nstatus = vm->syscall(vm, {number});\n\
+                 if (status != 0)\n    return status;\n{next}",
+                string(&Fault::NoSyscall(number).to_string())
+            ),
+            Inst::HLT => "vm->exit_code = 0;\nreturn VY_HALTED;".to_string(),
+            Inst::EXIT(code) => format!("vm->exit_code = {code};\nreturn VY_HALTED;"),
+            Inst::HCALL(_)
+            | Inst::SND(_)
+            | Inst::RCV(_)
+            | Inst::ALLOC(_)
+            | Inst::AIDXLOAD
+            | Inst::AIDXSTORE => {
+                return Err(TranspileError::Unsupported {
+                    ip: self.ip,
+                    inst: self.inst,
+                })
+            }
+        };
+        Ok(body)
+    }
+
+    fn next(&self) -> String {
+        format!("vm->ip = {};\ncontinue;", self.ip + 1)
+    }
+
+    /// An instruction that pops `y` and replaces `x` below it with `val`
+    fn binary(&self, val: &str) -> String {
+        format!(
+            "VY_NEED(2);\ny = vm->stack[--vm->sp];\nx = VY_STK(0);\nVY_STK(0) = {val};\n{}",
+            self.next()
+        )
+    }
+
+    /// An instruction that replaces `x` at the head of the stack with `val`
+    fn unary(&self, val: &str) -> String {
+        format!(
+            "VY_NEED(1);\nx = VY_STK(0);\nVY_STK(0) = {val};\n{}",
+            self.next()
+        )
+    }
+
+    /// Failing with `fault`, which the instruction always causes
+    fn fault(&self, fault: Fault) -> String {
+        format!("VY_FAIL({});", string(&fault.to_string()))
+    }
+
+    fn reg_fault(&self, reg: Reg) -> String {
+        self.fault(Fault::Path(PathError::RegErr { reg }))
+    }
+
+    /// The fault of stack `offset` not being on the stack, without the stack pointer
+    fn stack_fault(&self, offset: isize) -> String {
+        let message = format!("invalid stack access at offset {offset}");
+        format!("VY_FAIL({});", string(&message))
+    }
+
+    /// Moving `ip` by `step`, or the fault if that leaves the program
+    fn jump(&self, step: isize) -> String {
+        match target(self.program, self.ip, step) {
+            Some(target) => format!("vm->ip = {target};\ncontinue;"),
+            None => self.fault(Fault::BadJump { step }),
+        }
+    }
+
+    /// A statement putting the value at `path` in `x`, or the fault if `path` is a register the
+    /// machine does not have
+    fn read(&self, path: Path) -> Result<String, String> {
+        match path {
+            Path::REG(reg) => Ok(format!("x = {};", self.reg(reg)?)),
+            Path::STK(offset) if offset < 0 => Err(self.stack_fault(offset)),
+            Path::STK(offset) => Ok(format!(
+                "if (vm->sp <= {offset})\n    {}\nx = VY_STK({offset});",
+                self.stack_fault(offset)
+            )),
+            Path::STKR(reg, offset) => Ok(format!(
+                "pos = vy_slot(vm, {offset}, {});\nif (pos == SIZE_MAX)\n    \
+                 VY_FAIL(\"invalid stack access\");\nx = vm->stack[pos];",
+                self.reg(reg)?
+            )),
+        }
+    }
+
+    /// A statement storing `val` at `path`, like [`Case::read`]
+    fn write(&self, path: Path, val: &str) -> Result<String, String> {
+        match path {
+            Path::REG(reg) => Ok(format!("{} = {val};", self.reg(reg)?)),
+            Path::STK(offset) if offset < 0 => Err(self.stack_fault(offset)),
+            Path::STK(offset) => Ok(format!(
+                "if (vm->sp <= {offset})\n    {}\nVY_STK({offset}) = {val};",
+                self.stack_fault(offset)
+            )),
+            Path::STKR(reg, offset) => Ok(format!(
+                "pos = vy_slot(vm, {offset}, {});\nif (pos == SIZE_MAX)\n    \
+                 VY_FAIL(\"invalid stack access\");\nvm->stack[pos] = {val};",
+                self.reg(reg)?
+            )),
+        }
+    }
+
+    fn reg(&self, reg: Reg) -> Result<String, String> {
+        register(reg).ok_or_else(|| self.reg_fault(reg))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assemble_program;
+
+    #[test]
+    fn translation_to_c() {
+        let program = assemble_program(
+            ".trap \"negative\"\n\
+             prints \"a\\b?\"\n\
+             psh -1\n\
+             dup\n\
+             trap \"negative\"\n\
+             sys 4\n\
+             jmp -6\n",
+        )
+        .unwrap();
+        let source = transpile_c(&program).unwrap();
+        assert!(source.contains("vy_write(vm, \"a\\\\b\\?\\012\");"));
+        assert!(source.contains(&format!("vm->stack[vm->sp++] = {};", literal(-1))));
+        assert_eq!(
+            literal(Word::MIN),
+            format!("({} - 1)", literal(Word::MIN + 1))
+        );
+        assert!(source.contains("vm->fault = \"negative\";\n                return VY_TRAP + 0;"));
+        assert!(source.contains("status = vm->syscall(vm, 4);"));
+        assert!(source.contains("VY_FAIL(\"jump by -6 leaves the program\");"));
+
+        let program = Program {
+            code: vec![Inst::HCALL(0)],
+            ..Program::default()
+        };
+        let err = TranspileError::Unsupported {
+            ip: 0,
+            inst: Inst::HCALL(0),
+        };
+        assert_eq!(transpile_c(&program), Err(err));
+    }
+}
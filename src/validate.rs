@@ -0,0 +1,396 @@
+//! Static checks and analysis of a program before it is run.
+
+use alloc::vec;
+use alloc::vec::Vec;
+use core::fmt;
+
+use crate::{Inst, JumpTable};
+
+/// Problems found in a program without running it.
+#[derive(Debug, PartialEq)]
+pub enum ValidationError {
+    /// `TBL` at `ip` refers to a jump table that was not supplied
+    MissingTable { ip: usize, table: usize },
+
+    /// An entry of a jump table points outside the program
+    TableTarget {
+        table: usize,
+        index: usize,
+        target: usize,
+    },
+
+    /// The default target of a jump table points outside the program
+    TableDefault { table: usize, target: usize },
+
+    /// More numbered registers were asked for than `Reg::R` can name
+    RegisterCount(usize),
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for ValidationError {}
+
+impl fmt::Display for ValidationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ValidationError::MissingTable { ip, table } => {
+                write!(f, "TBL at ip {ip} refers to missing jump table {table}")
+            }
+
+            ValidationError::TableTarget {
+                table,
+                index,
+                target,
+            } => {
+                write!(
+                    f,
+                    "entry {index} of jump table {table} targets {target}, outside the program"
+                )
+            }
+
+            ValidationError::TableDefault { table, target } => {
+                write!(
+                    f,
+                    "default of jump table {table} targets {target}, outside the program"
+                )
+            }
+
+            ValidationError::RegisterCount(count) => {
+                write!(f, "cannot have {count} numbered registers, at most 256")
+            }
+        }
+    }
+}
+
+/// Check that every `TBL` refers to an existing table and every table entry lands inside the
+/// program.
+pub fn validate_program(program: &[Inst], tables: &[JumpTable]) -> Result<(), ValidationError> {
+    for (table_id, table) in tables.iter().enumerate() {
+        if let Some((index, &target)) = table
+            .targets
+            .iter()
+            .enumerate()
+            .find(|(_, &target)| target >= program.len())
+        {
+            return Err(ValidationError::TableTarget {
+                table: table_id,
+                index,
+                target,
+            });
+        }
+        if let Some(target) = table.default.filter(|&target| target >= program.len()) {
+            return Err(ValidationError::TableDefault {
+                table: table_id,
+                target,
+            });
+        }
+    }
+
+    for (ip, inst) in program.iter().enumerate() {
+        if let Inst::TBL(table) = *inst {
+            if table >= tables.len() {
+                return Err(ValidationError::MissingTable { ip, table });
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// A straight run of instructions that is only entered at its first instruction.
+#[derive(Clone, Debug, PartialEq)]
+pub struct BasicBlock {
+    /// Index of the first instruction
+    pub start: usize,
+
+    /// One past the index of the last instruction
+    pub end: usize,
+
+    /// Start of every block control can move to from this one
+    pub successors: Vec<usize>,
+
+    /// Whether the block can be reached from ip 0 or a jump table
+    pub reachable: bool,
+}
+
+/// Control flow graph of a program.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Cfg {
+    /// Blocks in program order
+    pub blocks: Vec<BasicBlock>,
+
+    /// Indices of every instruction that can never execute
+    pub unreachable: Vec<usize>,
+}
+
+/// Build the control flow graph of a program that uses no jump tables. `TBL` has no known
+/// targets here, use [`analyze_cfg_with_tables`] for programs that have them.
+pub fn analyze_cfg(program: &[Inst]) -> Cfg {
+    analyze_cfg_with_tables(program, &[])
+}
+
+/// Build the control flow graph of a program. Execution starts at ip 0, and every target of a
+/// jump table is treated as reachable as well.
+pub fn analyze_cfg_with_tables(program: &[Inst], tables: &[JumpTable]) -> Cfg {
+    let roots = roots(program, tables);
+    let reachable = reachable(program, &roots);
+
+    let mut leaders = vec![false; program.len()];
+    for &root in &roots {
+        leaders[root] = true;
+    }
+    for ip in 0..program.len() {
+        let next = successors(program, ip);
+        if ends_block(program[ip]) || next.iter().any(|&target| target != ip + 1) {
+            if ip + 1 < program.len() {
+                leaders[ip + 1] = true;
+            }
+            for target in next {
+                leaders[target] = true;
+            }
+        }
+    }
+
+    let starts: Vec<usize> = (0..program.len()).filter(|&ip| leaders[ip]).collect();
+    let blocks = starts
+        .iter()
+        .enumerate()
+        .map(|(i, &start)| {
+            let end = starts.get(i + 1).copied().unwrap_or(program.len());
+            BasicBlock {
+                start,
+                end,
+                successors: successors(program, end - 1),
+                reachable: reachable[start],
+            }
+        })
+        .collect();
+
+    Cfg {
+        blocks,
+        unreachable: (0..program.len()).filter(|&ip| !reachable[ip]).collect(),
+    }
+}
+
+/// Remove every unreachable instruction, rewriting the relative offsets of the jumps that are
+/// left and the absolute targets in the jump tables to match.
+pub fn strip_unreachable(program: &[Inst], tables: &[JumpTable]) -> (Vec<Inst>, Vec<JumpTable>) {
+    let reachable = reachable(program, &roots(program, tables));
+
+    // new index of every surviving instruction
+    let mut new_index = vec![None; program.len()];
+    let mut kept = 0;
+    for ip in 0..program.len() {
+        if reachable[ip] {
+            new_index[ip] = Some(kept);
+            kept += 1;
+        }
+    }
+    // a valid target is a successor of a reachable instruction, so it survived as well. Jumps
+    // that leave the program are left alone, they still fault when executed.
+    let relocate = |from: usize, step: isize| -> isize {
+        let target = (from as isize)
+            .checked_add(step)
+            .and_then(|target| usize::try_from(target).ok())
+            .and_then(|target| new_index.get(target).copied().flatten());
+        match (step, new_index[from], target) {
+            (0, _, _) => 0,
+            (_, Some(from), Some(target)) => target as isize - from as isize,
+            _ => step,
+        }
+    };
+
+    let stripped = (0..program.len())
+        .filter(|&ip| reachable[ip])
+        .map(|ip| {
+            let mut inst = program[ip];
+            if let Some(step) = inst.jump_offset_mut() {
+                *step = relocate(ip, *step);
+            }
+            inst
+        })
+        .collect();
+    let relocate_abs = |target: usize| new_index.get(target).copied().flatten().unwrap_or(target);
+    let tables = tables
+        .iter()
+        .map(|table| JumpTable {
+            targets: table.targets.iter().map(|&t| relocate_abs(t)).collect(),
+            default: table.default.map(relocate_abs),
+        })
+        .collect();
+
+    (stripped, tables)
+}
+
+/// Whether control never simply falls through this instruction to the next
+fn ends_block(inst: Inst) -> bool {
+    matches!(
+        inst,
+        Inst::JMP(_) | Inst::TBL(_) | Inst::RET | Inst::HLT | Inst::EXIT(_)
+    )
+}
+
+/// Instructions control can move to after executing the one at `ip`. Jump table targets are
+/// not included, they are roots instead.
+fn successors(program: &[Inst], ip: usize) -> Vec<usize> {
+    let jump = |step: isize| -> Option<usize> {
+        if step == 0 {
+            return Some(ip + 1);
+        }
+        let target = (ip as isize).checked_add(step)?;
+        usize::try_from(target).ok()
+    };
+    let next = match program[ip] {
+        Inst::JMP(step) => vec![jump(step)],
+        Inst::TBL(_) | Inst::RET | Inst::HLT | Inst::EXIT(_) => vec![],
+        inst => match inst.jump_offset() {
+            Some(step) => vec![Some(ip + 1), jump(step)],
+            None => vec![Some(ip + 1)],
+        },
+    };
+
+    let mut next: Vec<usize> = next
+        .into_iter()
+        .flatten()
+        .filter(|&target| target < program.len())
+        .collect();
+    next.dedup();
+    next
+}
+
+/// Where execution can start: ip 0 and every jump table target
+fn roots(program: &[Inst], tables: &[JumpTable]) -> Vec<usize> {
+    let mut roots = vec![0];
+    for table in tables {
+        roots.extend(table.targets.iter().chain(table.default.iter()));
+    }
+    roots.retain(|&root| root < program.len());
+    roots
+}
+
+fn reachable(program: &[Inst], roots: &[usize]) -> Vec<bool> {
+    let mut reachable = vec![false; program.len()];
+    let mut pending = roots.to_vec();
+    while let Some(ip) = pending.pop() {
+        if !reachable[ip] {
+            reachable[ip] = true;
+            pending.extend(successors(program, ip));
+        }
+    }
+    reachable
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Machine, Reg};
+
+    #[test]
+    fn table_targets_must_be_inside_program() {
+        let program = [Inst::PSH(0), Inst::TBL(0), Inst::HLT];
+
+        assert_eq!(
+            validate_program(&program, &[JumpTable::new(vec![0, 2])]),
+            Ok(())
+        );
+        assert_eq!(
+            validate_program(&program, &[JumpTable::new(vec![0, 3])]),
+            Err(ValidationError::TableTarget {
+                table: 0,
+                index: 1,
+                target: 3
+            })
+        );
+        assert_eq!(
+            validate_program(&program, &[JumpTable::new(vec![0]).with_default(9)]),
+            Err(ValidationError::TableDefault {
+                table: 0,
+                target: 9
+            })
+        );
+        assert_eq!(
+            validate_program(&program, &[]),
+            Err(ValidationError::MissingTable { ip: 1, table: 0 })
+        );
+    }
+
+    #[test]
+    fn code_after_halt_is_unreachable() {
+        let program = [Inst::PSH(1), Inst::HLT, Inst::PSH(2), Inst::HLT];
+        let cfg = analyze_cfg(&program);
+        assert_eq!(cfg.unreachable, vec![2, 3]);
+        assert_eq!(cfg.blocks.len(), 2);
+        assert!(cfg.blocks[0].reachable);
+        assert!(!cfg.blocks[1].reachable);
+    }
+
+    #[test]
+    fn blocks_reached_by_jumps() {
+        let program = vec![
+            Inst::JMP(3),
+            Inst::PSH(1), // skipped by the jump
+            Inst::HLT,    // only reached by the backward jump
+            Inst::PSH(2),
+            Inst::JMP(-2),
+            Inst::PSH(9), // after an unconditional jump
+        ];
+        let cfg = analyze_cfg(&program);
+        assert_eq!(cfg.unreachable, vec![1, 5]);
+        let starts: Vec<usize> = cfg.blocks.iter().map(|b| b.start).collect();
+        assert_eq!(starts, vec![0, 1, 2, 3, 5]);
+        assert_eq!(cfg.blocks[3].successors, vec![2]);
+
+        let (stripped, _) = strip_unreachable(&program, &[]);
+        assert_eq!(
+            stripped,
+            vec![Inst::JMP(2), Inst::HLT, Inst::PSH(2), Inst::JMP(-2)]
+        );
+        let mut machine = Machine::new(stripped);
+        machine.run().unwrap();
+        assert_eq!(machine.stack_top(), Some(2));
+    }
+
+    #[test]
+    fn calls_return_to_the_next_instruction() {
+        let program = vec![
+            Inst::CALL(3),
+            Inst::HLT,
+            Inst::PSH(9), // between the caller and the subroutine
+            Inst::PSH(1),
+            Inst::RET,
+            Inst::PSH(2), // after the return
+        ];
+        let cfg = analyze_cfg(&program);
+        assert_eq!(cfg.unreachable, vec![2, 5]);
+        assert_eq!(cfg.blocks[0].successors, vec![1, 3]);
+
+        let (stripped, _) = strip_unreachable(&program, &[]);
+        assert_eq!(
+            stripped,
+            vec![Inst::CALL(2), Inst::HLT, Inst::PSH(1), Inst::RET]
+        );
+        let mut machine = Machine::new(stripped);
+        machine.run().unwrap();
+        assert_eq!(machine.stack_top(), Some(1));
+    }
+
+    #[test]
+    fn jump_table_targets_are_roots() {
+        let program = [
+            Inst::PSH(0),
+            Inst::TBL(0),
+            Inst::HLT,
+            Inst::SET(Reg::A, 1),
+            Inst::HLT,
+        ];
+        let tables = [JumpTable::new(vec![3])];
+        assert_eq!(analyze_cfg(&program).unreachable, vec![2, 3, 4]);
+        assert_eq!(
+            analyze_cfg_with_tables(&program, &tables).unreachable,
+            vec![2]
+        );
+
+        let (stripped, tables) = strip_unreachable(&program, &tables);
+        assert_eq!(stripped.len(), 4);
+        assert_eq!(tables[0].targets, vec![2]);
+    }
+}
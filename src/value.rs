@@ -0,0 +1,79 @@
+//! Tagged values of the typed execution mode, see
+//! [`MachineConfig::typed`](crate::MachineConfig::typed).
+
+use core::fmt;
+
+use crate::{float, float_word, Float, Word};
+
+/// What a stack slot holds on a typed machine
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Type {
+    Int,
+    Float,
+    Bool,
+
+    /// A handle to something the host or the machine keeps, not a number to compute with
+    Ref,
+}
+
+impl fmt::Display for Type {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Type::Int => write!(f, "int"),
+            Type::Float => write!(f, "float"),
+            Type::Bool => write!(f, "bool"),
+            Type::Ref => write!(f, "ref"),
+        }
+    }
+}
+
+/// A stack slot with its type. The slot itself is still a word: a float is its bits, a bool is
+/// 1 or 0 and a ref is the handle.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Value {
+    Int(Word),
+    Float(Float),
+    Bool(bool),
+    Ref(Word),
+}
+
+impl Value {
+    /// The value of `ty` held in `word`, any word but zero is a true bool
+    pub fn from_word(word: Word, ty: Type) -> Value {
+        match ty {
+            Type::Int => Value::Int(word),
+            Type::Float => Value::Float(float(word)),
+            Type::Bool => Value::Bool(word != 0),
+            Type::Ref => Value::Ref(word),
+        }
+    }
+
+    pub fn ty(&self) -> Type {
+        match self {
+            Value::Int(_) => Type::Int,
+            Value::Float(_) => Type::Float,
+            Value::Bool(_) => Type::Bool,
+            Value::Ref(_) => Type::Ref,
+        }
+    }
+
+    /// The word the stack holds for the value
+    pub fn word(&self) -> Word {
+        match *self {
+            Value::Int(val) | Value::Ref(val) => val,
+            Value::Float(val) => float_word(val),
+            Value::Bool(val) => val as Word,
+        }
+    }
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Value::Int(val) => write!(f, "{val}"),
+            Value::Float(val) => write!(f, "{val}"),
+            Value::Bool(val) => write!(f, "{val}"),
+            Value::Ref(val) => write!(f, "ref {val}"),
+        }
+    }
+}